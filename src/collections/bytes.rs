@@ -4,7 +4,7 @@
 use crate::runtime::RuntimeBuf;
 
 use std::{
-    fmt,
+    cmp, fmt,
     ops::{Deref, DerefMut},
     sync::Arc,
 };
@@ -21,6 +21,20 @@ pub struct Bytes {
     len: usize,
 }
 
+impl Bytes {
+    /// Splits the buffer into consecutive pieces of `size` bytes, the last of which may be
+    /// shorter. The pieces share this buffer's allocation, so nothing is copied.
+    pub fn chunks(&self, size: usize) -> impl Iterator<Item = Bytes> {
+        assert!(size > 0);
+        let buf = self.clone();
+        (0..self.len).step_by(size).map(move |start| Bytes {
+            buf: buf.buf.clone(),
+            offset: buf.offset + start,
+            len: cmp::min(size, buf.len - start),
+        })
+    }
+}
+
 /// Equality of Bytes only depends on the data values and not in the offset of the buffer.
 impl PartialEq for Bytes {
     fn eq(&self, rhs: &Self) -> bool {
@@ -187,4 +201,32 @@ mod tests {
         buf.trim(2);
         assert_eq!(*buf, data[..2]);
     }
+
+    /// Tests for splitting a buffer into chunks.
+    #[test]
+    fn buf_chunks() {
+        let mut data = vec![0u8; 3000];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let buf = Bytes::from_slice(&data);
+        let chunks: Vec<Bytes> = buf.chunks(1000).collect();
+        assert_eq!(chunks.len(), 3);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.len(), 1000);
+            assert_eq!(chunk[..], data[(i * 1000)..((i + 1) * 1000)]);
+            // No copies: each chunk points into the original allocation.
+            assert!(Arc::ptr_eq(
+                chunk.buf.as_ref().unwrap(),
+                buf.buf.as_ref().unwrap()
+            ));
+        }
+
+        // The last chunk takes whatever is left.
+        let mut tail = buf.clone();
+        tail.adjust(500);
+        let lens: Vec<usize> = tail.chunks(1000).map(|c| c.len()).collect();
+        assert_eq!(lens, vec![1000, 1000, 500]);
+        assert!(Bytes::empty().chunks(1000).next().is_none());
+    }
 }
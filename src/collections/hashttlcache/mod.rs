@@ -5,12 +5,16 @@
 mod tests;
 
 use std::{
-    collections::hash_map::Entry as HashMapEntry,
+    collections::hash_map::{self, Entry as HashMapEntry, RandomState},
     collections::HashMap,
-    hash::Hash,
+    hash::{BuildHasher, Hash},
     time::{Duration, Instant},
 };
 
+/// How many dead values an insertion drops from the graveyard, so that it
+/// stays bounded even if nobody calls `try_evict`.
+const GRAVEYARD_PRUNE_BATCH: usize = 4;
+
 /// # TTL Cache Entry
 ///
 /// Values may be assigned to an expiration time or not.
@@ -36,11 +40,14 @@ impl<V> Record<V> {
 /// Entries in this structure fall in one of the following kinds: those that
 /// have an expiration time, and those that don't. The latter are assigned to
 /// `None` expiration.
-pub struct HashTtlCache<K, V> {
+///
+/// Keys are hashed with `S`, which can be swapped for a faster hasher where
+/// HashDoS resistance isn't a concern.
+pub struct HashTtlCache<K, V, S = RandomState> {
     /// Living values.
-    map: HashMap<K, Record<V>>,
+    map: HashMap<K, Record<V>, S>,
     /// Dead values.
-    graveyard: HashMap<K, V>,
+    graveyard: HashMap<K, V, S>,
     /// Default expiration.
     default_ttl: Option<Duration>,
     /// Current time.
@@ -53,13 +60,28 @@ where
 {
     /// Instantiates an TTL cache.
     pub fn new(now: Instant, default_ttl: Option<Duration>) -> HashTtlCache<K, V> {
+        Self::with_hasher(now, default_ttl, RandomState::new())
+    }
+}
+
+impl<K, V, S> HashTtlCache<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Default,
+{
+    /// Instantiates an TTL cache that hashes keys with `hash_builder`.
+    pub fn with_hasher(
+        now: Instant,
+        default_ttl: Option<Duration>,
+        hash_builder: S,
+    ) -> HashTtlCache<K, V, S> {
         if let Some(ttl) = default_ttl {
             assert!(ttl > Duration::new(0, 0));
         };
 
         HashTtlCache {
-            map: HashMap::default(),
-            graveyard: HashMap::default(),
+            map: HashMap::with_hasher(hash_builder),
+            graveyard: HashMap::with_hasher(S::default()),
             default_ttl,
             clock: now,
         }
@@ -88,6 +110,10 @@ where
 
         self.cleanup();
 
+        // Any dead value for this key is superseded now.
+        self.graveyard.remove(&key);
+        self.prune_graveyard(GRAVEYARD_PRUNE_BATCH);
+
         let r = Record { value, expiration };
         match self.map.entry(key) {
             HashMapEntry::Occupied(mut o) => {
@@ -108,26 +134,95 @@ where
         self.insert_with_ttl(key, value, self.default_ttl)
     }
 
-    /// Removes an entry from the cache.
-    pub fn remove(&mut self, _key: &K) -> Option<V> {
-        todo!()
+    /// Removes an entry from the cache, returning its value if it hadn't
+    /// expired yet. Whatever is in the graveyard is left for the next cleanup.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let record = self.map.remove(key)?;
+        if record.has_expired(self.clock) {
+            None
+        } else {
+            Some(record.value)
+        }
     }
 
-    // Gets an entry from the cache.
+    // Gets an entry from the cache, unless it has expired.
     pub fn get(&self, key: &K) -> Option<&V> {
-        return self.map.get(key).map(|r| &r.value);
+        match self.map.get(key) {
+            Some(record) if !record.has_expired(self.clock) => Some(&record.value),
+            _ => None,
+        }
+    }
+
+    /// Checks whether there's an entry for `key` that hasn't expired.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns the number of entries that haven't expired.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Checks whether every entry (if any) has expired.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    // Iterator over the entries that haven't expired.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.map.iter(),
+            clock: self.clock,
+        }
     }
 
-    // Iterator.
-    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+    /// Iterator over the entries that haven't expired, along with how long
+    /// each has left to live (`None` for those that never expire).
+    pub fn iter_with_ttl(&self) -> impl Iterator<Item = (&K, &V, Option<Duration>)> {
         let clock = self.clock;
-        self.map.iter().flat_map(move |(key, record)| {
-            if record.has_expired(clock) {
-                None
-            } else {
-                Some((key, &record.value))
-            }
-        })
+        self.map
+            .iter()
+            .filter(move |(_, record)| !record.has_expired(clock))
+            .map(move |(key, record)| {
+                let ttl = record.expiration.map(|e| e - clock);
+                (key, &record.value, ttl)
+            })
+    }
+
+    /// Evicts up to `count` dead entries, returning them. Entries that have
+    /// been collected into the graveyard go first, followed by any that have
+    /// expired since the last cleanup. Living entries are never evicted.
+    pub fn try_evict(&mut self, count: usize) -> HashMap<K, V> {
+        let mut evicted = HashMap::default();
+
+        let buried: Vec<K> = self.graveyard.keys().take(count).cloned().collect();
+        for k in buried {
+            let (k, v) = self.graveyard.remove_entry(&k).unwrap();
+            evicted.insert(k, v);
+        }
+
+        let clock = self.clock;
+        let expired: Vec<K> = self
+            .map
+            .iter()
+            .filter(|(_, r)| r.has_expired(clock))
+            .map(|(k, _)| k.clone())
+            .take(count - evicted.len())
+            .collect();
+        for k in expired {
+            let (k, r) = self.map.remove_entry(&k).unwrap();
+            evicted.insert(k, r.value);
+        }
+
+        evicted
+    }
+
+    /// Drops up to `count` dead values.
+    fn prune_graveyard(&mut self, count: usize) {
+        let buried: Vec<K> = self.graveyard.keys().take(count).cloned().collect();
+        for k in buried {
+            self.graveyard.remove(&k);
+        }
     }
 
     /// Collect dead entries in the cache.
@@ -148,3 +243,66 @@ where
         }
     }
 }
+
+/// # TTL Cache Iterator
+///
+/// Borrows the entries of a cache that haven't expired.
+pub struct Iter<'a, K, V> {
+    inner: hash_map::Iter<'a, K, Record<V>>,
+    clock: Instant,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let clock = self.clock;
+        self.inner
+            .find(|(_, record)| !record.has_expired(clock))
+            .map(|(key, record)| (key, &record.value))
+    }
+}
+
+/// # TTL Cache Owning Iterator
+///
+/// Moves the entries that haven't expired out of a cache.
+pub struct IntoIter<K, V> {
+    inner: hash_map::IntoIter<K, Record<V>>,
+    clock: Instant,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let clock = self.clock;
+        self.inner
+            .find(|(_, record)| !record.has_expired(clock))
+            .map(|(key, record)| (key, record.value))
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a HashTtlCache<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Default,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K, V, S> IntoIterator for HashTtlCache<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            inner: self.map.into_iter(),
+            clock: self.clock,
+        }
+    }
+}
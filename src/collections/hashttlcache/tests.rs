@@ -2,7 +2,11 @@
 // Licensed under the MIT license.
 
 use super::HashTtlCache;
-use std::time::{Duration, Instant};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::BuildHasherDefault,
+    time::{Duration, Instant},
+};
 
 /// Tests that objects with an explicit TTL get evicted at the right time.
 #[test]
@@ -123,3 +127,204 @@ fn replace_object() {
     cache.cleanup();
     assert!(cache.get(&"a").is_none());
 }
+
+/// Tests that removed objects are gone from the cache.
+#[test]
+fn remove_before_expiry() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(2);
+    let mut cache = HashTtlCache::new(now, None);
+
+    cache.insert_with_ttl("a", 'a', Some(ttl));
+    cache.advance_clock(now + Duration::from_secs(1));
+    assert!(cache.remove(&"a") == Some('a'));
+    assert!(cache.get(&"a").is_none());
+
+    // Removing it again finds nothing.
+    assert!(cache.remove(&"a").is_none());
+}
+
+/// Tests that removing missing or expired objects returns nothing.
+#[test]
+fn remove_missing_or_expired() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+    let mut cache = HashTtlCache::new(now, None);
+
+    assert!(cache.remove(&"a").is_none());
+
+    cache.insert_with_ttl("a", 'a', Some(ttl));
+    cache.advance_clock(now + ttl);
+    assert!(cache.remove(&"a").is_none());
+    assert!(cache.get(&"a").is_none());
+}
+
+/// Tests that expired objects are not returned, even before a cleanup.
+#[test]
+fn get_expired_without_cleanup() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+    let mut cache = HashTtlCache::new(now, None);
+
+    cache.insert_with_ttl("a", 'a', Some(ttl));
+    cache.advance_clock(now + Duration::from_secs(2));
+    assert!(cache.get(&"a").is_none());
+}
+
+/// Tests that the default TTL given to the constructor applies to plain inserts.
+#[test]
+fn insert_uses_default_ttl() {
+    let now = Instant::now();
+    let mut cache = HashTtlCache::new(now, Some(Duration::from_secs(1)));
+
+    cache.insert("a", 'a');
+    cache.advance_clock(now + Duration::from_secs(2));
+    assert!(cache.get(&"a").is_none());
+    assert_eq!(cache.iter().count(), 0);
+}
+
+/// Tests that eviction reclaims dead entries but leaves refreshed ones alone.
+#[test]
+fn evict_expired_and_refreshed() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+    let mut cache = HashTtlCache::new(now, None);
+
+    cache.insert_with_ttl("a", 'a', Some(ttl));
+    cache.insert_with_ttl("b", 'b', Some(ttl));
+    cache.insert_with_ttl("c", 'c', Some(ttl));
+
+    // Everything expires, which puts it in the graveyard, and then "b" is refreshed.
+    let later = now + ttl;
+    cache.advance_clock(later);
+    cache.insert_with_ttl("b", 'B', None);
+
+    // "d" expires without a cleanup in between.
+    cache.insert_with_ttl("d", 'd', Some(ttl));
+    cache.advance_clock(later + ttl);
+
+    let evicted = cache.try_evict(2);
+    assert_eq!(evicted.len(), 2);
+    let evicted_more = cache.try_evict(10);
+    assert_eq!(evicted_more.len(), 1);
+    for k in &["a", "c", "d"] {
+        assert!(evicted.contains_key(k) || evicted_more.contains_key(k));
+    }
+    assert!(cache.get(&"b") == Some(&'B'));
+    assert!(cache.try_evict(10).is_empty());
+}
+
+/// Tests that expired entries are evicted even before a cleanup, and living ones never are.
+#[test]
+fn evict_without_cleanup() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+    let mut cache = HashTtlCache::new(now, None);
+
+    cache.insert_with_ttl("a", 'a', Some(ttl));
+    cache.insert_with_ttl("b", 'b', Some(ttl + ttl));
+    cache.insert_with_ttl("c", 'c', None);
+    cache.advance_clock(now + ttl);
+
+    let evicted = cache.try_evict(10);
+    assert_eq!(evicted.len(), 1);
+    assert!(evicted.get(&"a") == Some(&'a'));
+    assert!(cache.get(&"b") == Some(&'b'));
+    assert!(cache.get(&"c") == Some(&'c'));
+}
+
+/// Tests that iterating over a cache skips expired objects.
+#[test]
+fn into_iter_skips_expired() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+    let mut cache = HashTtlCache::new(now, None);
+
+    cache.insert_with_ttl("a", 'a', None);
+    cache.insert_with_ttl("b", 'b', Some(ttl));
+    cache.advance_clock(now + ttl);
+
+    let mut borrowed = vec![];
+    for (k, v) in &cache {
+        borrowed.push((*k, *v));
+    }
+    assert_eq!(borrowed, vec![("a", 'a')]);
+
+    let owned: Vec<_> = cache.into_iter().collect();
+    assert_eq!(owned, vec![("a", 'a')]);
+}
+
+/// Tests that caches with a custom hasher behave like the default ones.
+#[test]
+fn custom_hasher() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+    let mut cache: HashTtlCache<_, _, BuildHasherDefault<DefaultHasher>> =
+        HashTtlCache::with_hasher(now, Some(ttl), BuildHasherDefault::default());
+
+    cache.insert("a", 'a');
+    cache.insert_with_ttl("b", 'b', None);
+    assert!(cache.get(&"a") == Some(&'a'));
+
+    cache.advance_clock(now + ttl);
+    assert!(cache.get(&"a").is_none());
+    assert!(cache.remove(&"b") == Some('b'));
+    assert_eq!(cache.try_evict(10).len(), 1);
+}
+
+/// Tests that expired objects don't count as being in the cache.
+#[test]
+fn len_and_contains_key() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+    let mut cache = HashTtlCache::new(now, None);
+    assert!(cache.is_empty());
+
+    cache.insert_with_ttl("a", 'a', None);
+    cache.insert_with_ttl("b", 'b', Some(ttl));
+    assert_eq!(cache.len(), 2);
+    assert!(cache.contains_key(&"b"));
+
+    cache.advance_clock(now + ttl);
+    assert_eq!(cache.len(), 1);
+    assert!(!cache.is_empty());
+    assert!(cache.contains_key(&"a"));
+    assert!(!cache.contains_key(&"b"));
+
+    cache.remove(&"a");
+    assert_eq!(cache.len(), 0);
+    assert!(cache.is_empty());
+}
+
+/// Tests that repeated insertions don't let the graveyard grow without bound.
+#[test]
+fn graveyard_stays_bounded() {
+    let mut now = Instant::now();
+    let ttl = Duration::from_millis(1);
+    let mut cache = HashTtlCache::new(now, None);
+
+    for i in 0..1000 {
+        // Re-resolve the same key, as well as a new one every time.
+        cache.insert_with_ttl(0, i, Some(ttl));
+        cache.insert_with_ttl(i + 1, i, Some(ttl));
+        now += ttl;
+        cache.advance_clock(now);
+        assert!(cache.graveyard.len() <= 2);
+    }
+}
+
+/// Tests that iter_with_ttl reports the time left on each living entry.
+#[test]
+fn iter_with_ttl() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(2);
+    let mut cache = HashTtlCache::new(now, None);
+    cache.insert_with_ttl("a", 'a', Some(ttl));
+    cache.insert_with_ttl("b", 'b', Some(ttl / 2));
+    cache.insert("c", 'c');
+
+    cache.advance_clock(now + ttl / 2);
+    let mut entries: Vec<_> = cache.iter_with_ttl().collect();
+    entries.sort();
+    assert!(entries == vec![(&"a", &'a', Some(ttl / 2)), (&"c", &'c', None)]);
+}
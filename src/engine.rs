@@ -5,19 +5,29 @@ use crate::protocols::posix::operations::PosixOperation;
 use crate::{
     fail::Fail,
     file_table::{File, FileDescriptor, FileTable},
+    memory_budget::MemoryBudget,
     operations::ResultFuture,
     protocols::{
         arp,
         ethernet2::frame::{EtherType2, Ethernet2Header},
         ipv4, posix,
-        tcp::operations::{AcceptFuture, ConnectFuture, PopFuture, PushFuture},
-        udp::{UdpOperation, UdpPopFuture},
+        tcp::{
+            congestion_ctrl as cc,
+            operations::{AcceptFuture, ConnectFuture, PopFuture, PushFuture},
+            AllowList, ConnectionCounters, ConnectionId, SeqNumber,
+        },
+        udp::{UdpOperation, UdpPopFuture, UdpPopWithInfoFuture},
         Protocol,
     },
     runtime::Runtime,
     scheduler::Operation,
 };
-use std::{future::Future, net::Ipv4Addr, time::Duration};
+use futures::{future::LocalBoxFuture, FutureExt};
+use std::{
+    future::Future,
+    net::{Ipv4Addr, Shutdown},
+    time::{Duration, Instant},
+};
 
 #[cfg(test)]
 use crate::protocols::ethernet2::MacAddress;
@@ -32,6 +42,7 @@ pub struct Engine<RT: Runtime> {
     ipv4: ipv4::Peer<RT>,
     posix_stack: bool,
     file_table: FileTable,
+    memory: MemoryBudget,
 }
 
 impl<RT: Runtime> Engine<RT> {
@@ -40,7 +51,8 @@ impl<RT: Runtime> Engine<RT> {
         let file_table = FileTable::new();
         let arp = arp::Peer::new(now, rt.clone(), rt.arp_options())?;
         let posix = posix::PosixPeer::new(rt.clone());
-        let ipv4 = ipv4::Peer::new(rt.clone(), arp.clone(), file_table.clone());
+        let memory = MemoryBudget::default();
+        let ipv4 = ipv4::Peer::new(rt.clone(), arp.clone(), file_table.clone(), memory.clone());
         Ok(Engine {
             rt,
             arp,
@@ -48,6 +60,7 @@ impl<RT: Runtime> Engine<RT> {
             ipv4,
             posix_stack: false,
             file_table,
+            memory,
         })
     }
 
@@ -64,6 +77,17 @@ impl<RT: Runtime> Engine<RT> {
         self.posix_stack = true;
     }
 
+    /// Caps the total bytes buffered across all TCP and UDP sockets, or removes the cap if
+    /// `limit` is `None`.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory.set_limit(limit);
+    }
+
+    /// Bytes currently buffered across all TCP and UDP sockets.
+    pub fn memory_used(&self) -> usize {
+        self.memory.used()
+    }
+
     /// New incoming data has arrived. Route it to the correct parse out the Ethernet header and
     /// allow the correct protocol to handle it. The underlying protocol will futher parse the data
     /// and inform the correct task that its data has arrived.
@@ -199,6 +223,54 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.udp.pop(fd)
     }
 
+    pub fn udp_pop_with_info(
+        &mut self,
+        fd: FileDescriptor,
+    ) -> Result<UdpPopWithInfoFuture<RT>, Fail> {
+        if self.posix_stack {
+            return Err(Fail::Unsupported {
+                details: "Not supported on the POSIX stack",
+            });
+        }
+        match self.file_table.get(fd) {
+            Some(File::UdpSocket) => Ok(self.ipv4.udp.pop_with_info(fd)),
+            _ => Err(Fail::BadFileDescriptor {}),
+        }
+    }
+
+    pub fn udp_keepalive(
+        &mut self,
+        fd: FileDescriptor,
+        keepalive: Option<(Duration, RT::Buf)>,
+    ) -> Result<(), Fail> {
+        self.ipv4.udp.set_keepalive(fd, keepalive)
+    }
+
+    pub fn udp_bound_endpoints(&self) -> Vec<ipv4::Endpoint> {
+        self.ipv4.udp.bound_endpoints()
+    }
+
+    pub fn arp_pending_queries(&self) -> Vec<Ipv4Addr> {
+        self.arp.pending_queries()
+    }
+
+    pub fn arp_entries(&self) -> Vec<arp::Entry> {
+        self.arp.entries()
+    }
+
+    pub fn arp_flush_subnet(&self, prefix: Ipv4Addr, prefix_len: u8) -> usize {
+        self.arp.flush_subnet(prefix, prefix_len)
+    }
+
+    pub fn arp_announce(&self) {
+        self.arp.send_gratuitous()
+    }
+
+    /// Sends the frames held back since the last flush, if the runtime coalesces transmits.
+    pub fn flush_transmits(&self) {
+        self.arp.flush_transmits()
+    }
+
     pub fn pop(&mut self, fd: FileDescriptor) -> Result<Operation<RT>, Fail> {
         if self.posix_stack {
             let op = PosixOperation::<RT>::Pop(ResultFuture::new(self.posix.pop(fd)));
@@ -227,6 +299,48 @@ impl<RT: Runtime> Engine<RT> {
         }
     }
 
+    pub fn shutdown(&mut self, fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
+        if self.posix_stack {
+            self.posix.shutdown(fd, how)
+        } else {
+            match self.file_table.get(fd) {
+                Some(File::TcpSocket) => self.ipv4.tcp.shutdown(fd, how),
+                Some(File::UdpSocket) => Err(Fail::NotConnected {}),
+                _ => Err(Fail::BadFileDescriptor {}),
+            }
+        }
+    }
+
+    pub fn is_readable(&self, fd: FileDescriptor) -> bool {
+        match self.file_table.get(fd) {
+            Some(File::TcpSocket) => self.ipv4.tcp.is_readable(fd),
+            Some(File::UdpSocket) => self.ipv4.udp.is_readable(fd),
+            _ => false,
+        }
+    }
+
+    pub fn is_writable(&self, fd: FileDescriptor) -> bool {
+        match self.file_table.get(fd) {
+            Some(File::TcpSocket) => self.ipv4.tcp.is_writable(fd),
+            Some(File::UdpSocket) => self.ipv4.udp.is_writable(fd),
+            _ => false,
+        }
+    }
+
+    /// Returns a future that completes once the readiness of `fd` may have changed, or `None` if
+    /// we can't watch `fd` in its current state.
+    pub fn readiness_changed(&self, fd: FileDescriptor) -> Option<LocalBoxFuture<'static, ()>> {
+        match self.file_table.get(fd) {
+            Some(File::TcpSocket) => self.ipv4.tcp.readiness_changed(fd),
+            Some(File::UdpSocket) => self
+                .ipv4
+                .udp
+                .readiness_changed(fd)
+                .map(FutureExt::boxed_local),
+            _ => None,
+        }
+    }
+
     pub fn tcp_socket(&mut self) -> FileDescriptor {
         self.ipv4.tcp.socket()
     }
@@ -267,6 +381,135 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.tcp.listen(socket_fd, backlog)
     }
 
+    pub fn tcp_set_accept_filter(
+        &mut self,
+        socket_fd: FileDescriptor,
+        filter: AllowList,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_accept_filter(socket_fd, filter)
+    }
+
+    pub fn tcp_drain(
+        &mut self,
+        socket_fd: FileDescriptor,
+    ) -> Result<LocalBoxFuture<'static, ()>, Fail> {
+        self.ipv4.tcp.drain(socket_fd)
+    }
+
+    pub fn tcp_accept_queue_len(&self, socket_fd: FileDescriptor) -> Result<usize, Fail> {
+        self.ipv4.tcp.accept_queue_len(socket_fd)
+    }
+
+    pub fn tcp_backlog(&self, socket_fd: FileDescriptor) -> Result<usize, Fail> {
+        self.ipv4.tcp.backlog(socket_fd)
+    }
+
+    pub fn tcp_set_tos(&mut self, socket_fd: FileDescriptor, tos: u8) -> Result<(), Fail> {
+        self.ipv4.tcp.set_tos(socket_fd, tos)
+    }
+
+    pub fn tcp_set_congestion_ctrl(
+        &mut self,
+        socket_fd: FileDescriptor,
+        constructor: cc::CongestionControlConstructor<RT>,
+        options: Option<cc::Options>,
+    ) -> Result<(), Fail> {
+        self.ipv4
+            .tcp
+            .set_congestion_ctrl(socket_fd, constructor, options)
+    }
+
+    pub fn tcp_connection_id(&self, socket_fd: FileDescriptor) -> Result<ConnectionId, Fail> {
+        self.ipv4.tcp.connection_id(socket_fd)
+    }
+
+    pub fn tcp_rejected_segments(&self, socket_fd: FileDescriptor) -> Result<u64, Fail> {
+        self.ipv4.tcp.rejected_segments(socket_fd)
+    }
+
+    pub fn tcp_counters(&self, socket_fd: FileDescriptor) -> Result<ConnectionCounters, Fail> {
+        self.ipv4.tcp.counters(socket_fd)
+    }
+
+    pub fn tcp_take_error(&self, socket_fd: FileDescriptor) -> Result<Option<Fail>, Fail> {
+        self.ipv4.tcp.take_error(socket_fd)
+    }
+
+    pub fn tcp_path_mtu(&self, socket_fd: FileDescriptor) -> Result<usize, Fail> {
+        self.ipv4.tcp.path_mtu(socket_fd)
+    }
+
+    pub fn tcp_effective_send_window(&self, socket_fd: FileDescriptor) -> Result<u32, Fail> {
+        self.ipv4.tcp.effective_send_window(socket_fd)
+    }
+
+    pub fn tcp_snd_una(&self, socket_fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.ipv4.tcp.snd_una(socket_fd)
+    }
+
+    pub fn tcp_snd_nxt(&self, socket_fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.ipv4.tcp.snd_nxt(socket_fd)
+    }
+
+    pub fn tcp_unacked_segments(&self, socket_fd: FileDescriptor) -> Result<usize, Fail> {
+        self.ipv4.tcp.unacked_segments(socket_fd)
+    }
+
+    pub fn tcp_cc_phase(&self, socket_fd: FileDescriptor) -> Result<cc::CcPhase, Fail> {
+        self.ipv4.tcp.cc_phase(socket_fd)
+    }
+
+    pub fn tcp_retransmit_deadline(
+        &self,
+        socket_fd: FileDescriptor,
+    ) -> Result<Option<Instant>, Fail> {
+        self.ipv4.tcp.retransmit_deadline(socket_fd)
+    }
+
+    pub fn tcp_rcv_nxt(&self, socket_fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.ipv4.tcp.rcv_nxt(socket_fd)
+    }
+
+    pub fn tcp_reset_counters(
+        &self,
+        socket_fd: FileDescriptor,
+    ) -> Result<ConnectionCounters, Fail> {
+        self.ipv4.tcp.reset_counters(socket_fd)
+    }
+
+    pub fn tcp_set_quickack(
+        &mut self,
+        socket_fd: FileDescriptor,
+        quickack: bool,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_quickack(socket_fd, quickack)
+    }
+
+    pub fn tcp_set_rcvlowat(
+        &mut self,
+        socket_fd: FileDescriptor,
+        bytes: usize,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_rcvlowat(socket_fd, bytes)
+    }
+
+    pub fn tcp_set_rnext_key_id(
+        &mut self,
+        socket_fd: FileDescriptor,
+        recv_id: u8,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_rnext_key_id(socket_fd, recv_id)
+    }
+
+    pub fn tcp_current_key_id(&self, socket_fd: FileDescriptor) -> Result<u8, Fail> {
+        self.ipv4.tcp.current_key_id(socket_fd)
+    }
+
+    #[cfg(test)]
+    pub fn arp_lookups(&self) -> usize {
+        self.arp.lookups()
+    }
+
     #[cfg(test)]
     pub fn arp_query(&self, ipv4_addr: Ipv4Addr) -> impl Future<Output = Result<MacAddress, Fail>> {
         self.arp.query(ipv4_addr)
@@ -282,8 +525,41 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.tcp_rto(handle)
     }
 
+    #[cfg(test)]
+    pub fn tcp_set_peer_window(
+        &self,
+        handle: FileDescriptor,
+        window_size: u32,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_peer_window(handle, window_size)
+    }
+
+    #[cfg(test)]
+    pub fn tcp_force_rto(&self, handle: FileDescriptor) -> Result<(), Fail> {
+        self.ipv4.tcp.force_rto(handle)
+    }
+
+    #[cfg(test)]
+    pub fn tcp_time_wait_deadline(&self, handle: FileDescriptor) -> Result<Option<Instant>, Fail> {
+        self.ipv4.tcp.time_wait_deadline(handle)
+    }
+
+    #[cfg(test)]
+    pub fn tcp_congestion_ctrl_debug(&self, handle: FileDescriptor) -> Result<String, Fail> {
+        self.ipv4.tcp.congestion_ctrl_debug(handle)
+    }
+
     #[cfg(test)]
     pub fn export_arp_cache(&self) -> HashMap<Ipv4Addr, MacAddress> {
         self.arp.export_cache()
     }
 }
+
+impl<RT: Runtime> Drop for Engine<RT> {
+    /// Background tasks are cancelled when the peers that own their handles go away, and the
+    /// scheduler reaps them on its next poll. The POSIX peer's task keeps the peer itself alive,
+    /// though, so we have to cancel it by hand.
+    fn drop(&mut self) {
+        self.posix.cancel_background();
+    }
+}
@@ -12,6 +12,7 @@ type Str = &'static str;
 custom_error! {#[derive(Clone, PartialEq)] pub Fail
     ConnectionAborted{} = "connection aborted",
     ConnectionRefused{} = "connection refused",
+    ConnectionReset{} = "connection reset by peer",
     IoError {} = "IO Error",
     BorrowMutError {} = "BorrowMut Error",
     Ignored{details: Str} = "operation had no effect ({details})",
@@ -31,6 +32,8 @@ custom_error! {#[derive(Clone, PartialEq)] pub Fail
     AddressFamilySupport {} = "address family not supported",
     SocketTypeSupport {} = "socket type not supported",
     BadFileDescriptor {} = "bad file descriptor",
+    NotConnected {} = "transport endpoint is not connected",
+    BrokenPipe {} = "broken pipe",
 }
 
 impl From<IoError> for Fail {
@@ -73,6 +76,7 @@ impl Fail {
         match self {
             Fail::ConnectionAborted {} => libc::ECONNABORTED,
             Fail::ConnectionRefused {} => libc::ECONNREFUSED,
+            Fail::ConnectionReset {} => libc::ECONNRESET,
             Fail::Ignored { .. } => 0,
             Fail::Malformed { .. } => libc::EILSEQ,
             Fail::Misdelivered {} => libc::EHOSTUNREACH,
@@ -92,6 +96,8 @@ impl Fail {
             Fail::AddressFamilySupport { .. } => libc::EAFNOSUPPORT,
             Fail::SocketTypeSupport { .. } => libc::ESOCKTNOSUPPORT,
             Fail::BadFileDescriptor { .. } => libc::EBADF,
+            Fail::NotConnected {} => libc::ENOTCONN,
+            Fail::BrokenPipe {} => libc::EPIPE,
         }
     }
 }
@@ -83,3 +83,44 @@ impl Default for FileTable {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{File, FileTable};
+
+    #[test]
+    fn test_alloc_get_free() {
+        let file_table = FileTable::new();
+        let tcp_fd = file_table.alloc(File::TcpSocket);
+        let udp_fd = file_table.alloc(File::UdpSocket);
+        assert_ne!(tcp_fd, udp_fd);
+        assert_eq!(file_table.get(tcp_fd), Some(File::TcpSocket));
+        assert_eq!(file_table.get(udp_fd), Some(File::UdpSocket));
+
+        assert_eq!(file_table.free(tcp_fd), Some(File::TcpSocket));
+        assert_eq!(file_table.get(tcp_fd), None);
+        assert_eq!(file_table.get(udp_fd), Some(File::UdpSocket));
+
+        // Freed descriptors get reused.
+        assert_eq!(file_table.alloc(File::UdpSocket), tcp_fd);
+        assert_eq!(file_table.get(tcp_fd), Some(File::UdpSocket));
+    }
+
+    #[test]
+    fn test_invalid_descriptors() {
+        let file_table = FileTable::new();
+        assert_eq!(file_table.get(0), None);
+        assert_eq!(file_table.free(0), None);
+
+        let fd = file_table.alloc(File::TcpSocket);
+        assert_eq!(file_table.get(fd + 1), None);
+        assert_eq!(file_table.get(u32::MAX), None);
+        assert_eq!(file_table.free(u32::MAX), None);
+
+        // Freeing twice is harmless and doesn't disturb other descriptors.
+        let other_fd = file_table.alloc(File::UdpSocket);
+        assert_eq!(file_table.free(fd), Some(File::TcpSocket));
+        assert_eq!(file_table.free(fd), None);
+        assert_eq!(file_table.get(other_fd), Some(File::UdpSocket));
+    }
+}
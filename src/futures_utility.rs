@@ -5,7 +5,11 @@ use crate::fail::Fail;
 use async_trait::async_trait;
 use futures::future::FusedFuture;
 use futures::FutureExt;
-use std::future::Future;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 /// Provides useful high-level future-related methods.
 #[async_trait(?Send)]
@@ -25,3 +29,26 @@ pub(crate) trait UtilityMethods: Future + FusedFuture + Unpin {
 
 // Implement UtiliytMethods for any Future that implements Unpin and FusedFuture.
 impl<F: ?Sized> UtilityMethods for F where F: Future + Unpin + FusedFuture {}
+
+/// Returns a future that's pending the first time it's polled, waking itself so that the scheduler
+/// gets to everything else that's ready before polling it again.
+pub(crate) fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub(crate) struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        ctx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -33,8 +33,10 @@ mod futures_utility;
 pub mod interop;
 pub mod libos;
 pub mod logging;
+pub mod memory_budget;
 pub mod operations;
 pub mod options;
+pub mod poll_set;
 pub mod protocols;
 pub mod runtime;
 pub mod scheduler;
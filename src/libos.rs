@@ -4,20 +4,30 @@
 //! LibOS defines the PDPIX (portable data plane interface) abstraction. PDPIX centers around
 //! the IO Queue abstraction, thus providing a standard interface for different kernel bypass
 //! mechanisms.
+#[cfg(feature = "diagnostics")]
+use crate::protocols::tcp::SeqNumber;
 use crate::{
     engine::Engine,
     fail::Fail,
     file_table::FileDescriptor,
     interop::{dmtr_qresult_t, dmtr_sgarray_t},
     operations::OperationResult,
+    poll_set::{Interest, PollSet},
+    protocols::arp,
     protocols::ipv4::Endpoint,
+    protocols::tcp::{congestion_ctrl as cc, AllowList, ConnectionCounters, ConnectionId},
+    protocols::udp::UdpPopWithInfoFuture,
     protocols::Protocol,
     runtime::Runtime,
     scheduler::{Operation, SchedulerHandle},
 };
+use futures::future::LocalBoxFuture;
 use libc::c_int;
 use must_let::must_let;
-use std::time::Instant;
+use std::{
+    net::{Ipv4Addr, Shutdown},
+    time::{Duration, Instant},
+};
 
 const TIMER_RESOLUTION: usize = 64;
 const MAX_RECV_ITERS: usize = 2;
@@ -25,6 +35,29 @@ const MAX_RECV_ITERS: usize = 2;
 /// Queue Token for our IO Queue abstraction. Analogous to a file descriptor in POSIX.
 pub type QToken = u64;
 
+/// Borrows the scheduler handle behind a queue token. Unless the handle is taken to collect its
+/// result, it goes back to being a raw token when the guard is dropped, even on an early return or
+/// a panic, so the operation stays alive for the application to wait on later.
+struct HandleGuard(Option<SchedulerHandle>);
+
+impl HandleGuard {
+    fn has_completed(&self) -> bool {
+        self.0.as_ref().unwrap().has_completed()
+    }
+
+    fn take(&mut self) -> SchedulerHandle {
+        self.0.take().unwrap()
+    }
+}
+
+impl Drop for HandleGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            handle.into_raw();
+        }
+    }
+}
+
 pub struct LibOS<RT: Runtime> {
     engine: Engine<RT>,
     rt: RT,
@@ -34,6 +67,7 @@ pub struct LibOS<RT: Runtime> {
 impl<RT: Runtime> LibOS<RT> {
     pub fn new(rt: RT) -> Result<Self, Fail> {
         let engine = Engine::new(rt.clone())?;
+        engine.arp_announce();
         Ok(Self {
             engine,
             rt,
@@ -49,6 +83,20 @@ impl<RT: Runtime> LibOS<RT> {
         self.engine.use_posix_stack();
     }
 
+    ///
+    /// **Brief**
+    ///
+    /// Bounds the total memory held in send and receive buffers across all TCP
+    /// and UDP sockets, on top of any per-socket limits. Once the cap is
+    /// reached, pushes fail, incoming data is dropped and TCP connections
+    /// advertise a smaller window until buffered data is consumed. A `limit` of
+    /// `None` removes the cap.
+    ///
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        trace!("set_memory_limit(): limit={:?}", limit);
+        self.engine.set_memory_limit(limit);
+    }
+
     ///
     /// **Brief**
     ///
@@ -133,6 +181,72 @@ impl<RT: Runtime> LibOS<RT> {
         self.engine.listen(fd, backlog)
     }
 
+    ///
+    /// **Brief**
+    ///
+    /// Restricts the source addresses that may connect to the listening socket
+    /// referred to by `fd`. SYNs from addresses rejected by `filter` are either
+    /// dropped or answered with a RST, depending on the filter's configuration.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, `Ok(())` is returned. Upon failure, `Fail` is
+    /// returned instead.
+    ///
+    pub fn set_accept_filter(&mut self, fd: FileDescriptor, filter: AllowList) -> Result<(), Fail> {
+        trace!("set_accept_filter(): fd={:?} filter={:?}", fd, filter);
+        self.engine.tcp_set_accept_filter(fd, filter)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Stops accepting new connections on the listening socket `fd` while
+    /// letting the connections already accepted from it finish. The listener
+    /// is closed, so new connection requests are refused with a RST.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, a future is returned that resolves once all
+    /// of the connections accepted from `fd` have closed. Upon failure, `Fail`
+    /// is returned instead.
+    ///
+    pub fn drain(&mut self, fd: FileDescriptor) -> Result<LocalBoxFuture<'static, ()>, Fail> {
+        trace!("drain(): fd={:?}", fd);
+        self.engine.tcp_drain(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns the number of connections on the listening socket `fd` that have
+    /// completed the handshake but haven't been [accept](Self::accept)ed yet.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the queue length is returned. Upon failure,
+    /// `Fail` is returned instead.
+    ///
+    pub fn accept_queue_len(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        trace!("accept_queue_len(): fd={:?}", fd);
+        self.engine.tcp_accept_queue_len(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns the `backlog` that the listening socket `fd` was created with.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the backlog is returned. Upon failure, `Fail`
+    /// is returned instead.
+    ///
+    pub fn backlog(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        trace!("backlog(): fd={:?}", fd);
+        self.engine.tcp_backlog(fd)
+    }
+
     ///
     /// **Brief**
     ///
@@ -186,6 +300,481 @@ impl<RT: Runtime> LibOS<RT> {
         self.engine.close(fd)
     }
 
+    ///
+    /// **Brief**
+    ///
+    /// Shuts down reading, writing or both on the connection referred to by
+    /// `fd`. Unlike [close](Self::close), shutting down writing still lets the
+    /// remote side send data, which can be popped until it closes in turn.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, `Ok(())` is returned. Upon failure, `Fail` is
+    /// returned instead.
+    ///
+    pub fn shutdown(&mut self, fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
+        trace!("shutdown(): fd={:?} how={:?}", fd, how);
+        self.engine.shutdown(fd, how)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Checks whether a `pop` on the socket referred to by `fd` would complete
+    /// immediately, either because data is buffered or because the remote side
+    /// has closed the connection. A listening socket is readable when it has a
+    /// connection waiting to be accepted. This doesn't create a queue token.
+    ///
+    /// **Return Value**
+    ///
+    /// Returns `true` if the socket is readable and `false` otherwise,
+    /// including for unknown file descriptors.
+    ///
+    pub fn is_readable(&self, fd: FileDescriptor) -> bool {
+        self.engine.is_readable(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Checks whether the socket referred to by `fd` is connected and has room
+    /// in its send buffer for more data. This doesn't create a queue token.
+    ///
+    /// **Return Value**
+    ///
+    /// Returns `true` if the socket is writable and `false` otherwise,
+    /// including for unknown file descriptors.
+    ///
+    pub fn is_writable(&self, fd: FileDescriptor) -> bool {
+        self.engine.is_writable(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Runs background work until at least one of the file descriptors
+    /// registered in `poll_set` is ready. Unlike `wait_any`, this only rechecks
+    /// sockets whose state has changed, so it scales to many sockets.
+    ///
+    /// **Return Value**
+    ///
+    /// Returns the ready file descriptors along with the events they're ready
+    /// for, in ascending file descriptor order. File descriptors that can't be
+    /// watched (e.g. closed ones) are reported once with `error` set, rather
+    /// than waited on forever.
+    ///
+    pub fn wait_poll_set(&mut self, poll_set: &mut PollSet) -> Vec<(FileDescriptor, Interest)> {
+        trace!("wait_poll_set(): {} fds", poll_set.len());
+        loop {
+            self.poll_bg_work();
+            let ready = poll_set.poll(&self.engine);
+            if !ready.is_empty() {
+                return ready;
+            }
+        }
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Enables periodic heartbeats on the connected UDP socket referred to by
+    /// `fd`, sending `payload` to the remote endpoint every `interval`. Passing
+    /// `None` disables them again. This is useful for keeping NAT bindings alive.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, `Ok(())` is returned. Upon failure, `Fail` is
+    /// returned instead.
+    ///
+    pub fn set_udp_keepalive(
+        &mut self,
+        fd: FileDescriptor,
+        keepalive: Option<(Duration, RT::Buf)>,
+    ) -> Result<(), Fail> {
+        trace!("set_udp_keepalive(): fd={:?}", fd);
+        self.engine.udp_keepalive(fd, keepalive)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Lists the local endpoints that UDP sockets are currently bound to, which
+    /// helps track down datagrams dropped for arriving at an unbound port.
+    ///
+    /// **Return Value**
+    ///
+    /// The bound endpoints are returned, in no particular order.
+    ///
+    pub fn bound_udp_endpoints(&self) -> Vec<Endpoint> {
+        trace!("bound_udp_endpoints()");
+        self.engine.udp_bound_endpoints()
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Lists the IPv4 addresses that ARP is still trying to resolve, i.e. those
+    /// with a request sent out and no reply received yet.
+    ///
+    /// **Return Value**
+    ///
+    /// The addresses with outstanding queries are returned, in no particular
+    /// order.
+    ///
+    pub fn pending_arp_queries(&self) -> Vec<Ipv4Addr> {
+        trace!("pending_arp_queries()");
+        self.engine.arp_pending_queries()
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Lists the ARP cache, like `show arp`: each resolved IPv4 address with
+    /// its MAC address and how long the resolution has left, unless it's
+    /// static.
+    ///
+    /// **Return Value**
+    ///
+    /// The cache entries are returned, in no particular order.
+    ///
+    pub fn show_arp(&self) -> Vec<arp::Entry> {
+        trace!("show_arp()");
+        self.engine.arp_entries()
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Flushes the learned ARP cache entries for IPv4 addresses within
+    /// `prefix/prefix_len`, e.g. after the subnet's gateway has changed.
+    /// Static entries are kept.
+    ///
+    /// **Return Value**
+    ///
+    /// The number of entries flushed is returned.
+    ///
+    pub fn flush_arp_subnet(&mut self, prefix: Ipv4Addr, prefix_len: u8) -> usize {
+        trace!("flush_arp_subnet(): prefix={}/{}", prefix, prefix_len);
+        self.engine.arp_flush_subnet(prefix, prefix_len)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Sets the IPv4 TOS byte (DSCP and ECN) used for outgoing segments on the
+    /// established TCP connection referred to by `fd`, analogous to
+    /// `setsockopt(IP_TOS)`. The change applies to every segment sent from now on.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, `Ok(())` is returned. Upon failure, `Fail` is
+    /// returned instead.
+    ///
+    pub fn set_tos(&mut self, fd: FileDescriptor, tos: u8) -> Result<(), Fail> {
+        trace!("set_tos(): fd={:?} tos={:?}", fd, tos);
+        self.engine.tcp_set_tos(fd, tos)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Enables or disables immediate ACKs on the established TCP connection
+    /// referred to by `fd`, analogous to `setsockopt(TCP_QUICKACK)`. While
+    /// enabled, every in-order segment is acknowledged as soon as it arrives.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, `Ok(())` is returned. Upon failure, `Fail` is
+    /// returned instead.
+    ///
+    pub fn set_quickack(&mut self, fd: FileDescriptor, quickack: bool) -> Result<(), Fail> {
+        trace!("set_quickack(): fd={:?} quickack={:?}", fd, quickack);
+        self.engine.tcp_set_quickack(fd, quickack)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Sets the receive low-watermark of the established TCP connection
+    /// referred to by `fd`, analogous to `setsockopt(SO_RCVLOWAT)`. A `pop`
+    /// then completes only once at least `bytes` bytes are buffered, or the
+    /// peer closes the connection, and returns everything buffered. The default
+    /// is one byte.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, `Ok(())` is returned. Upon failure, `Fail` is
+    /// returned instead.
+    ///
+    pub fn set_rcvlowat(&mut self, fd: FileDescriptor, bytes: usize) -> Result<(), Fail> {
+        trace!("set_rcvlowat(): fd={:?} bytes={:?}", fd, bytes);
+        self.engine.tcp_set_rcvlowat(fd, bytes)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Chooses the congestion control algorithm (e.g. `cc::Cubic::new` or
+    /// `cc::NewReno::new`) for the TCP socket referred to by `fd`, analogous to
+    /// `setsockopt(TCP_CONGESTION)`. The socket must not have connected or
+    /// listened yet; connections accepted on a listening socket inherit its
+    /// choice.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, `Ok(())` is returned. Upon failure, `Fail` is
+    /// returned instead.
+    ///
+    pub fn set_congestion_ctrl(
+        &mut self,
+        fd: FileDescriptor,
+        constructor: cc::CongestionControlConstructor<RT>,
+        options: Option<cc::Options>,
+    ) -> Result<(), Fail> {
+        trace!("set_congestion_ctrl(): fd={:?}", fd);
+        self.engine
+            .tcp_set_congestion_ctrl(fd, constructor, options)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Asks the peer of the TCP-AO authenticated connection referred to by `fd`
+    /// to switch to signing its segments with our key `recv_id`. The peer
+    /// switches once it sees the new RNextKeyID on a segment from us.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, `Ok(())` is returned. Upon failure, `Fail` is
+    /// returned instead.
+    ///
+    pub fn set_rnext_key_id(&mut self, fd: FileDescriptor, recv_id: u8) -> Result<(), Fail> {
+        trace!("set_rnext_key_id(): fd={:?} recv_id={:?}", fd, recv_id);
+        self.engine.tcp_set_rnext_key_id(fd, recv_id)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns a stable identifier for the established TCP connection referred
+    /// to by `fd`. Unlike file descriptors, connection identifiers are never
+    /// reused, so they can be used to correlate log messages.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the connection identifier is returned. Upon
+    /// failure, `Fail` is returned instead.
+    ///
+    pub fn connection_id(&self, fd: FileDescriptor) -> Result<ConnectionId, Fail> {
+        trace!("connection_id(): fd={:?}", fd);
+        self.engine.tcp_connection_id(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns how many segments received on the established TCP connection
+    /// referred to by `fd` fell outside of the receive window and were
+    /// dropped (and answered with an ACK) instead of being processed.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the number of rejected segments is
+    /// returned. Upon failure, `Fail` is returned instead.
+    ///
+    pub fn rejected_segments(&self, fd: FileDescriptor) -> Result<u64, Fail> {
+        trace!("rejected_segments(): fd={:?}", fd);
+        self.engine.tcp_rejected_segments(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns the byte, segment and retransmission counters of the
+    /// established TCP connection referred to by `fd`, counting from when the
+    /// connection was established or the counters were last reset.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the counters are returned. Upon failure,
+    /// `Fail` is returned instead.
+    ///
+    pub fn counters(&self, fd: FileDescriptor) -> Result<ConnectionCounters, Fail> {
+        trace!("counters(): fd={:?}", fd);
+        self.engine.tcp_counters(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Zeroes the counters of the established TCP connection referred to by
+    /// `fd`, so that periodic monitoring can read the traffic since the last
+    /// reset.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the counters from just before the reset are
+    /// returned. Upon failure, `Fail` is returned instead.
+    ///
+    pub fn reset_counters(&mut self, fd: FileDescriptor) -> Result<ConnectionCounters, Fail> {
+        trace!("reset_counters(): fd={:?}", fd);
+        self.engine.tcp_reset_counters(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Collects the error that terminated the established TCP connection
+    /// referred to by `fd`, like `getsockopt(SO_ERROR)`. The error is cleared,
+    /// so later calls return `None` until the connection fails again.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the pending error (if any) is returned.
+    /// Upon failure, `Fail` is returned instead.
+    ///
+    pub fn take_error(&mut self, fd: FileDescriptor) -> Result<Option<Fail>, Fail> {
+        trace!("take_error(): fd={:?}", fd);
+        self.engine.tcp_take_error(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Reports the effective path MTU of the established TCP connection
+    /// referred to by `fd`: the MSS it sends with plus IPv4 and TCP header
+    /// overhead. This starts out from the MSS negotiated in the handshake and
+    /// goes down as path MTU discovery finds smaller links.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the path MTU in bytes is returned. Upon
+    /// failure, `Fail` is returned instead.
+    ///
+    pub fn path_mtu(&mut self, fd: FileDescriptor) -> Result<usize, Fail> {
+        trace!("path_mtu(): fd={:?}", fd);
+        self.engine.tcp_path_mtu(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Reports the send window of the established TCP connection referred to
+    /// by `fd`: the window field of the last segment from the peer, scaled by
+    /// the window scale the peer negotiated in the handshake.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the window in bytes is returned. Upon
+    /// failure, `Fail` is returned instead.
+    ///
+    pub fn effective_send_window(&self, fd: FileDescriptor) -> Result<u32, Fail> {
+        trace!("effective_send_window(): fd={:?}", fd);
+        self.engine.tcp_effective_send_window(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns SND.UNA, the oldest sequence number that hasn't been
+    /// acknowledged yet, for the connection referred to by `fd`. This is only
+    /// meant for debugging.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the sequence number is returned. Upon
+    /// failure, `Fail` is returned instead.
+    ///
+    #[cfg(feature = "diagnostics")]
+    pub fn snd_una(&self, fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.engine.tcp_snd_una(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns SND.NXT, the sequence number of the next byte we'll send, for
+    /// the connection referred to by `fd`. This is only meant for debugging.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the sequence number is returned. Upon
+    /// failure, `Fail` is returned instead.
+    ///
+    #[cfg(feature = "diagnostics")]
+    pub fn snd_nxt(&self, fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.engine.tcp_snd_nxt(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns the number of segments that have been sent but not yet
+    /// acknowledged on the connection referred to by `fd`. This is only meant
+    /// for debugging.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the number of segments is returned. Upon
+    /// failure, `Fail` is returned instead.
+    ///
+    #[cfg(feature = "diagnostics")]
+    pub fn unacked_segments(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        self.engine.tcp_unacked_segments(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns whether the congestion controller of the connection referred to
+    /// by `fd` is in slow start, congestion avoidance, fast recovery or
+    /// recovery from a retransmission timeout. This is only meant for
+    /// debugging.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the phase is returned. Upon failure, `Fail`
+    /// is returned instead.
+    ///
+    #[cfg(feature = "diagnostics")]
+    pub fn cc_phase(&self, fd: FileDescriptor) -> Result<cc::CcPhase, Fail> {
+        self.engine.tcp_cc_phase(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns when the retransmission timer of the connection referred to by
+    /// `fd` will next fire, or `None` if it isn't running because nothing is
+    /// in flight. This is only meant for debugging.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the deadline is returned. Upon failure,
+    /// `Fail` is returned instead.
+    ///
+    #[cfg(feature = "diagnostics")]
+    pub fn retransmit_deadline(&self, fd: FileDescriptor) -> Result<Option<Instant>, Fail> {
+        self.engine.tcp_retransmit_deadline(fd)
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Returns RCV.NXT, the sequence number of the next byte we expect to
+    /// receive, for the connection referred to by `fd`. This is only meant for
+    /// debugging.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, the sequence number is returned. Upon
+    /// failure, `Fail` is returned instead.
+    ///
+    #[cfg(feature = "diagnostics")]
+    pub fn rcv_nxt(&self, fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.engine.tcp_rcv_nxt(fd)
+    }
+
     /// Create a push request for Demikernel to asynchronously write data from `sga` to the
     /// IO connection represented by `fd`. This operation returns immediately with a `QToken`.
     /// The data has been written when [`wait`ing](Self::wait) on the QToken returns.
@@ -236,13 +825,33 @@ impl<RT: Runtime> LibOS<RT> {
     }
 
     /// Create a pop request to write data from IO connection represented by `fd` into a buffer
-    /// allocated by the application.
+    /// allocated by the application. On a TCP connection, the request completes with an empty
+    /// buffer once the remote side has closed and all its data has been popped.
     pub fn pop(&mut self, fd: FileDescriptor) -> Result<QToken, Fail> {
         trace!("pop(): fd={:?}", fd);
         let future = self.engine.pop(fd)?;
         Ok(self.rt.scheduler().insert(future).into_raw())
     }
 
+    ///
+    /// **Brief**
+    ///
+    /// Pops a datagram from the UDP socket `fd`, like `recvmsg()` with
+    /// `IP_PKTINFO`. The socket only needs to be bound, and the local address
+    /// the datagram was sent to is reported alongside it, so that a server can
+    /// reply from the same address.
+    ///
+    /// **Return Value**
+    ///
+    /// Upon successful completion, a future is returned that resolves to the
+    /// datagram's source endpoint, the local address it was sent to, and its
+    /// payload. Upon failure, `Fail` is returned instead.
+    ///
+    pub fn pop_with_info(&mut self, fd: FileDescriptor) -> Result<UdpPopWithInfoFuture<RT>, Fail> {
+        trace!("pop_with_info(): fd={:?}", fd);
+        self.engine.udp_pop_with_info(fd)
+    }
+
     // If this returns a result, `qt` is no longer valid.
     pub fn poll(&mut self, qt: QToken) -> Option<dmtr_qresult_t> {
         trace!("poll(): qt={:?}", qt);
@@ -300,30 +909,61 @@ impl<RT: Runtime> LibOS<RT> {
     /// finished.
     pub fn wait_any(&mut self, qts: &[QToken]) -> (usize, dmtr_qresult_t) {
         trace!("wait_any(): qts={:?}", qts);
-        loop {
-            self.poll_bg_work();
-            for (i, &qt) in qts.iter().enumerate() {
-                let handle = self.rt.scheduler().from_raw_handle(qt).unwrap();
-                if handle.has_completed() {
-                    let (qd, r) = self.take_operation(handle);
-                    return (i, dmtr_qresult_t::pack(&self.rt, r, qd, qt));
-                }
-                handle.into_raw();
-            }
-        }
+        let (i, qd, r) = self.select_operation(qts).unwrap();
+        (i, dmtr_qresult_t::pack(&self.rt, r, qd, qts[i]))
     }
 
     pub fn wait_any2(&mut self, qts: &[QToken]) -> (usize, FileDescriptor, OperationResult<RT>) {
         trace!("wait_any2(): qts={:?}", qts);
+        self.select_operation(qts).unwrap()
+    }
+
+    ///
+    /// **Brief**
+    ///
+    /// Blocks until one of the operations referred to by `qts` completes. Only
+    /// the queue token of the completed operation is consumed: all the others
+    /// remain valid, and can be waited on again later.
+    ///
+    /// **Return Value**
+    ///
+    /// The index in `qts` of the completed operation is returned, along with
+    /// its result. If `qts` is empty or holds a queue token that's invalid or
+    /// has already been consumed, `Fail::Invalid` is returned instead, and none
+    /// of the tokens are consumed.
+    ///
+    pub fn select(&mut self, qts: &[QToken]) -> Result<(usize, OperationResult<RT>), Fail> {
+        trace!("select(): qts={:?}", qts);
+        let (i, _, r) = self.select_operation(qts)?;
+        Ok((i, r))
+    }
+
+    fn select_operation(
+        &mut self,
+        qts: &[QToken],
+    ) -> Result<(usize, FileDescriptor, OperationResult<RT>), Fail> {
+        if qts.is_empty() {
+            return Err(Fail::Invalid {
+                details: "no queue tokens",
+            });
+        }
+        let mut guards = Vec::with_capacity(qts.len());
+        for &qt in qts {
+            match self.rt.scheduler().from_raw_handle(qt) {
+                Some(handle) => guards.push(HandleGuard(Some(handle))),
+                None => {
+                    return Err(Fail::Invalid {
+                        details: "queue token",
+                    })
+                }
+            }
+        }
         loop {
             self.poll_bg_work();
-            for (i, &qt) in qts.iter().enumerate() {
-                let handle = self.rt.scheduler().from_raw_handle(qt).unwrap();
-                if handle.has_completed() {
-                    let (qd, r) = self.take_operation(handle);
-                    return (i, qd, r);
-                }
-                handle.into_raw();
+            if let Some(i) = guards.iter().position(|guard| guard.has_completed()) {
+                let handle = guards[i].take();
+                let (qd, r) = self.take_operation(handle);
+                return Ok((i, qd, r));
             }
         }
     }
@@ -345,9 +985,36 @@ impl<RT: Runtime> LibOS<RT> {
         }
     }
 
+    ///
+    /// **Brief**
+    ///
+    /// Feeds a recorded sequence of inbound frames (e.g. a capture of a real
+    /// peer) into the stack. Each frame is delivered at its timestamp relative
+    /// to the first one, by advancing the runtime's clock to match, and the
+    /// scheduler runs between frames. This is meant for regression tests on
+    /// runtimes with a mock clock.
+    ///
+    pub fn replay(&mut self, frames: impl Iterator<Item = (Instant, RT::Buf)>) {
+        trace!("replay()");
+        let start = self.rt.now();
+        let mut first_timestamp = None;
+        for (timestamp, frame) in frames {
+            let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+            let when = start + timestamp.saturating_duration_since(first_timestamp);
+            if when > self.rt.now() {
+                self.rt.advance_clock(when);
+            }
+            self.rt.scheduler().poll();
+            if let Err(e) = self.engine.receive(frame) {
+                warn!("Dropped replayed packet: {:?}", e);
+            }
+        }
+        self.rt.scheduler().poll();
+    }
+
     /// Scheduler will poll all futures that are ready to make progress.
     /// Then ask the runtime to receive new data which we will forward to the engine to parse and
-    /// route to the correct protocol.
+    /// route to the correct protocol. Finally, flush the frames the stack held back for batching.
     fn poll_bg_work(&mut self) {
         self.rt.scheduler().poll();
         for _ in 0..MAX_RECV_ITERS {
@@ -365,5 +1032,222 @@ impl<RT: Runtime> LibOS<RT> {
             self.rt.advance_clock(Instant::now());
         }
         self.ts_iters = (self.ts_iters + 1) % TIMER_RESOLUTION;
+        self.engine.flush_transmits();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LibOS;
+    use crate::{
+        collections::bytes::BytesMut,
+        engine::Engine,
+        fail::Fail,
+        operations::OperationResult,
+        protocols::{ip, ipv4, Protocol},
+        runtime::Runtime,
+        test_helpers::{self, DecodedFrame, TestRuntime, L4},
+    };
+    use futures::task::noop_waker_ref;
+    use must_let::must_let;
+    use std::{
+        convert::TryFrom,
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_replay_reproduces_exchange() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        // Start in the past, so the replayed timestamps stay behind the real clock that LibOS
+        // advances to when it polls.
+        let mut now = Instant::now() - Duration::from_secs(1);
+
+        let mut alice = test_helpers::new_alice2(now);
+        let mut bob = test_helpers::new_bob2(now);
+
+        let listen_port = ip::Port::try_from(80).unwrap();
+        let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+        let listen_fd = bob.tcp_socket();
+        bob.tcp_bind(listen_fd, listen_addr).unwrap();
+        bob.tcp_listen(listen_fd, 1).unwrap();
+        let mut accept_future = bob.tcp_accept(listen_fd);
+
+        let alice_fd = alice.tcp_socket();
+        let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+        // Record everything Bob receives from Alice, with the time it arrived.
+        let mut recording = vec![];
+        let mut deliver = |now: Instant, alice: &TestRuntime, bob: &mut Engine<TestRuntime>| {
+            let frame = alice.pop_frame();
+            recording.push((now, frame.clone()));
+            bob.receive(frame).unwrap();
+        };
+
+        alice.rt().poll_scheduler();
+        deliver(now, alice.rt(), &mut bob);
+        bob.rt().poll_scheduler();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        deliver(now, alice.rt(), &mut bob);
+
+        must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+        let buf = BytesMut::from(&b"hello, replay"[..]).freeze();
+        let mut write_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        deliver(now, alice.rt(), &mut bob);
+
+        let mut pop_future = bob.tcp_pop(bob_fd);
+        must_let!(let Poll::Ready(Ok(expected)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+        // A fresh Bob, seeded the same way, sees the same bytes when the recording is replayed.
+        let mut libos = LibOS::new(test_helpers::new_bob_runtime(now)).unwrap();
+        let listen_fd = libos.socket(libc::AF_INET, libc::SOCK_STREAM, 0).unwrap();
+        libos.bind(listen_fd, listen_addr).unwrap();
+        libos.listen(listen_fd, 1).unwrap();
+        let accept_qt = libos.accept(listen_fd).unwrap();
+
+        libos.replay(recording.into_iter());
+
+        must_let!(let (_, OperationResult::Accept(fd)) = libos.wait2(accept_qt));
+        let pop_qt = libos.pop(fd).unwrap();
+        must_let!(let (_, OperationResult::Pop(_, received)) = libos.wait2(pop_qt));
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_select_leaves_other_operations_valid() {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice2(now);
+        let mut libos = LibOS::new(test_helpers::new_bob_runtime(now)).unwrap();
+
+        let alice_addr =
+            ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(54321).unwrap());
+        let alice_fd = alice.socket(Protocol::Udp);
+        alice.bind(alice_fd, alice_addr).unwrap();
+
+        // Wait for a datagram on any of three sockets.
+        let mut bob_addrs = vec![];
+        let mut qts = vec![];
+        for port in 80..83 {
+            let bob_addr =
+                ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(port).unwrap());
+            let fd = libos.socket(libc::AF_INET, libc::SOCK_DGRAM, 0).unwrap();
+            libos.bind(fd, bob_addr).unwrap();
+            qts.push(libos.pop(fd).unwrap());
+            bob_addrs.push(bob_addr);
+        }
+
+        let buf = BytesMut::from(&b"second"[..]).freeze();
+        alice.pushto(alice_fd, buf.clone(), bob_addrs[1]).unwrap();
+        alice.rt().poll_scheduler();
+        libos.rt().push_frame(alice.rt().pop_frame());
+
+        must_let!(let Ok((1, OperationResult::Pop(Some(from), received))) = libos.select(&qts));
+        assert_eq!(from, alice_addr);
+        assert_eq!(received, buf);
+
+        // The operations that didn't complete are still pending, and can still complete.
+        assert!(libos.poll(qts[0]).is_none());
+        assert!(libos.poll(qts[2]).is_none());
+        let buf = BytesMut::from(&b"first"[..]).freeze();
+        alice.pushto(alice_fd, buf.clone(), bob_addrs[0]).unwrap();
+        alice.rt().poll_scheduler();
+        libos.rt().push_frame(alice.rt().pop_frame());
+        must_let!(let Ok((0, OperationResult::Pop(_, received))) = libos.select(&[qts[0], qts[2]]));
+        assert_eq!(received, buf);
+
+        // Consumed tokens are rejected without disturbing the others.
+        must_let!(let Err(Fail::Invalid { .. }) = libos.select(&[qts[2], qts[0]]));
+        must_let!(let Err(Fail::Invalid { .. }) = libos.select(&[]));
+        assert!(libos.poll(qts[2]).is_none());
+        libos.drop_qtoken(qts[2]);
+    }
+
+    #[test]
+    fn test_drop_cancels_background_tasks() {
+        let now = Instant::now();
+        let rt = test_helpers::new_bob_runtime(now);
+        rt.poll_scheduler();
+        let baseline = rt.scheduler().num_tasks();
+
+        let libos = LibOS::new(rt.clone()).unwrap();
+        rt.poll_scheduler();
+        assert!(rt.scheduler().num_tasks() > baseline);
+
+        drop(libos);
+        rt.poll_scheduler();
+        assert_eq!(rt.scheduler().num_tasks(), baseline);
+    }
+
+    #[test]
+    fn test_transmits_coalesced_per_poll() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice2(now);
+        let rt = test_helpers::new_bob_runtime(now);
+        rt.set_coalesce_transmits(true);
+        let mut libos = LibOS::new(rt.clone()).unwrap();
+        libos.poll_bg_work();
+        must_let!(let DecodedFrame::Arp(_) = test_helpers::decode_frame(&rt.pop_frame()));
+
+        let listen_addr =
+            ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(80).unwrap());
+        let listen_fd = alice.tcp_socket();
+        alice.tcp_bind(listen_fd, listen_addr).unwrap();
+        alice.tcp_listen(listen_fd, 3).unwrap();
+
+        // Open three connections from Bob to Alice.
+        let mut alice_fds = vec![];
+        for _ in 0..3 {
+            let mut accept_future = alice.tcp_accept(listen_fd);
+            let fd = libos.socket(libc::AF_INET, libc::SOCK_STREAM, 0).unwrap();
+            let qt = libos.connect(fd, listen_addr).unwrap();
+            libos.poll_bg_work();
+            alice.receive(rt.pop_frame()).unwrap();
+            alice.rt().poll_scheduler();
+            rt.push_frame(alice.rt().pop_frame());
+            must_let!(let (_, OperationResult::Connect) = libos.wait2(qt));
+            alice.receive(rt.pop_frame()).unwrap();
+            must_let!(let Poll::Ready(Ok(alice_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+            alice_fds.push(alice_fd);
+        }
+        assert!(rt.try_pop_frame().is_none());
+
+        // Alice sends a segment on each of them, which Bob doesn't ACK right away.
+        for &alice_fd in &alice_fds {
+            let buf = BytesMut::from(&b"hello"[..]).freeze();
+            let mut push_future = alice.tcp_push(alice_fd, buf);
+            must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+            alice.rt().poll_scheduler();
+            rt.push_frame(alice.rt().pop_frame());
+        }
+        for _ in 0..3 {
+            libos.poll_bg_work();
+        }
+        assert!(rt.try_pop_frame().is_none());
+        let num_batches = rt.transmit_batches().len();
+
+        // When the delayed ACKs come due, they all go out in one batch.
+        rt.advance_clock(rt.now() + Duration::from_millis(500));
+        libos.poll_bg_work();
+        assert_eq!(rt.transmit_batches()[num_batches..], [3]);
+        for _ in 0..3 {
+            let frame = rt.pop_frame();
+            must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(tcp_hdr), .. } = test_helpers::decode_frame(&frame));
+            assert!(tcp_hdr.ack);
+        }
     }
 }
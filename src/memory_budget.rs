@@ -0,0 +1,71 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Accounting for the memory held in socket buffers, so we can put a cap on the total across all
+//! TCP and UDP sockets on top of any per-socket limits.
+
+use crate::fail::Fail;
+use std::{cell::Cell, rc::Rc};
+
+/// A shared count of the bytes held in send and receive buffers, with an optional limit. Clones
+/// share the same count, so every socket created by an [Engine](crate::engine::Engine) draws from
+/// the same budget.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBudget {
+    used: Rc<Cell<usize>>,
+    limit: Rc<Cell<Option<usize>>>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            used: Rc::new(Cell::new(0)),
+            limit: Rc::new(Cell::new(limit)),
+        }
+    }
+
+    /// Changes the limit. Lowering it below what's already in use doesn't free anything, but no
+    /// new reservations succeed until enough has been released.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.set(limit);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit.get()
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+
+    /// How many more bytes can be reserved before hitting the limit.
+    pub fn available(&self) -> usize {
+        match self.limit.get() {
+            Some(limit) => limit.saturating_sub(self.used.get()),
+            None => usize::MAX,
+        }
+    }
+
+    /// Accounts for `bytes` more of buffered data, failing if that would take us over the limit.
+    pub fn try_reserve(&self, bytes: usize) -> Result<(), Fail> {
+        if bytes > self.available() {
+            return Err(Fail::ResourceExhausted {
+                details: "Buffer memory limit reached",
+            });
+        }
+        self.used.set(self.used.get() + bytes);
+        Ok(())
+    }
+
+    /// Accounts for `bytes` of data we've already buffered, even if that takes us over the limit.
+    pub fn reserve(&self, bytes: usize) {
+        self.used.set(self.used.get() + bytes);
+    }
+
+    /// Returns `bytes` previously reserved to the budget.
+    pub fn release(&self, bytes: usize) {
+        let used = self.used.get();
+        debug_assert!(bytes <= used, "Releasing more memory than was reserved");
+        self.used.set(used.saturating_sub(bytes));
+    }
+}
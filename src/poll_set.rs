@@ -0,0 +1,208 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Readiness notifications for many sockets at once, in the spirit of `epoll`.
+//!
+//! Rather than rechecking every registered socket on each call, a [PollSet] only rechecks the
+//! sockets that are (or may be) ready. Once a socket is found not to be ready, we spawn a
+//! background task that waits for its state to change, which puts it back on the list to check.
+//! These tasks are only polled by the scheduler when they're woken, so idle sockets cost nothing.
+
+use crate::{
+    engine::Engine, file_table::FileDescriptor, runtime::Runtime, scheduler::SchedulerHandle,
+};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+/// Readiness events a [PollSet] watches for (when registering) or reports (when waiting).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Interest {
+    pub readable: bool,
+    pub writable: bool,
+    /// Reported (whether or not it was asked for) for a file descriptor we can't watch, e.g.
+    /// because it's been closed or isn't a connected or listening socket. It isn't checked again
+    /// until it's registered again.
+    pub error: bool,
+}
+
+impl Interest {
+    pub const READABLE: Interest = Interest {
+        readable: true,
+        writable: false,
+        error: false,
+    };
+    pub const WRITABLE: Interest = Interest {
+        readable: false,
+        writable: true,
+        error: false,
+    };
+    pub const BOTH: Interest = Interest {
+        readable: true,
+        writable: true,
+        error: false,
+    };
+    pub const ERROR: Interest = Interest {
+        readable: false,
+        writable: false,
+        error: true,
+    };
+
+    pub fn is_empty(&self) -> bool {
+        !self.readable && !self.writable && !self.error
+    }
+}
+
+#[derive(Default)]
+pub struct PollSet {
+    interests: HashMap<FileDescriptor, Interest>,
+    /// File descriptors whose readiness may have changed since we last checked them.
+    dirty: Rc<RefCell<HashSet<FileDescriptor>>>,
+    /// Background tasks waiting for the state of a file descriptor to change.
+    watchers: HashMap<FileDescriptor, SchedulerHandle>,
+}
+
+impl PollSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts watching `fd` for the events in `interest`, replacing any previous registration.
+    pub fn register(&mut self, fd: FileDescriptor, interest: Interest) {
+        self.interests.insert(fd, interest);
+        self.watchers.remove(&fd);
+        self.dirty.borrow_mut().insert(fd);
+    }
+
+    pub fn deregister(&mut self, fd: FileDescriptor) {
+        self.interests.remove(&fd);
+        self.watchers.remove(&fd);
+        self.dirty.borrow_mut().remove(&fd);
+    }
+
+    pub fn len(&self) -> usize {
+        self.interests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interests.is_empty()
+    }
+
+    /// Returns the registered file descriptors that are ready right now, without blocking.
+    /// Readiness is level-triggered: a file descriptor is reported by every call for as long as
+    /// it stays ready.
+    pub fn poll<RT: Runtime>(&mut self, engine: &Engine<RT>) -> Vec<(FileDescriptor, Interest)> {
+        let dirty: Vec<FileDescriptor> = self.dirty.borrow_mut().drain().collect();
+        let mut ready = vec![];
+        for fd in dirty {
+            let interest = match self.interests.get(&fd) {
+                Some(i) => *i,
+                None => continue,
+            };
+            let events = Interest {
+                readable: interest.readable && engine.is_readable(fd),
+                writable: interest.writable && engine.is_writable(fd),
+                error: false,
+            };
+            if !events.is_empty() {
+                self.watchers.remove(&fd);
+                self.dirty.borrow_mut().insert(fd);
+                ready.push((fd, events));
+                continue;
+            }
+            match engine.readiness_changed(fd) {
+                Some(changed) => {
+                    let dirty = self.dirty.clone();
+                    let handle = engine.rt().spawn(async move {
+                        changed.await;
+                        dirty.borrow_mut().insert(fd);
+                    });
+                    self.watchers.insert(fd, handle);
+                }
+                // We can't watch this file descriptor in its current state (e.g. it's a TCP
+                // socket that's still connecting), so we'd never hear about it becoming ready.
+                None => ready.push((fd, Interest::ERROR)),
+            }
+        }
+        ready.sort_by_key(|&(fd, _)| fd);
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interest, PollSet};
+    use crate::{
+        collections::bytes::BytesMut,
+        protocols::{ip, ipv4, Protocol},
+        test_helpers,
+    };
+    use std::{convert::TryFrom, time::Instant};
+
+    #[test]
+    fn test_wait_returns_ready_sockets() {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice2(now);
+        let mut bob = test_helpers::new_bob2(now);
+
+        let mut poll_set = PollSet::new();
+        let mut bob_fds = vec![];
+        for i in 0..100 {
+            let port = ip::Port::try_from(10000 + i).unwrap();
+            let fd = bob.socket(Protocol::Udp);
+            bob.bind(fd, ipv4::Endpoint::new(test_helpers::BOB_IPV4, port))
+                .unwrap();
+            poll_set.register(fd, Interest::READABLE);
+            bob_fds.push((fd, port));
+        }
+        assert_eq!(poll_set.len(), 100);
+        assert!(poll_set.poll(&bob).is_empty());
+
+        let alice_port = ip::Port::try_from(54321).unwrap();
+        let alice_fd = alice.socket(Protocol::Udp);
+        alice
+            .bind(
+                alice_fd,
+                ipv4::Endpoint::new(test_helpers::ALICE_IPV4, alice_port),
+            )
+            .unwrap();
+        let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+        let mut expected = vec![];
+        for &ix in &[3, 42, 97] {
+            let (fd, port) = bob_fds[ix];
+            let to = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+            alice.pushto(alice_fd, buf.clone(), to).unwrap();
+            alice.rt().poll_scheduler();
+            bob.receive(alice.rt().pop_frame()).unwrap();
+            expected.push((fd, Interest::READABLE));
+        }
+
+        bob.rt().poll_scheduler();
+        assert_eq!(poll_set.poll(&bob), expected);
+        // Readiness is level-triggered, so the sockets are reported again until they're drained.
+        assert_eq!(poll_set.poll(&bob), expected);
+    }
+
+    #[test]
+    fn test_unwatchable_fd_reported_once() {
+        let now = Instant::now();
+        let mut bob = test_helpers::new_bob2(now);
+
+        // An unbound UDP socket can't become readable, so there's nothing to wait for.
+        let fd = bob.socket(Protocol::Udp);
+        let mut poll_set = PollSet::new();
+        poll_set.register(fd, Interest::READABLE);
+        assert_eq!(poll_set.poll(&bob), vec![(fd, Interest::ERROR)]);
+        assert!(poll_set.poll(&bob).is_empty());
+
+        // Registering it again once it's bound lets us watch it.
+        let port = ip::Port::try_from(10000).unwrap();
+        bob.bind(fd, ipv4::Endpoint::new(test_helpers::BOB_IPV4, port))
+            .unwrap();
+        poll_set.register(fd, Interest::READABLE);
+        assert!(poll_set.poll(&bob).is_empty());
+        assert_eq!(poll_set.len(), 1);
+    }
+}
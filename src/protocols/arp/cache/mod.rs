@@ -4,16 +4,32 @@
 #[cfg(test)]
 mod tests;
 
-use crate::{collections::HashTtlCache, protocols::ethernet2::MacAddress};
+use crate::{
+    collections::HashTtlCache,
+    protocols::{ethernet2::MacAddress, ipv4::prefix_contains},
+};
 
 use std::{
-    collections::HashMap,
+    cell::Cell,
+    collections::{HashMap, HashSet},
     net::Ipv4Addr,
+    rc::Rc,
     time::{Duration, Instant},
 };
 
 const DUMMY_MAC_ADDRESS: MacAddress = MacAddress::new([0; 6]);
 
+/// An address resolution, as listed by [ArpCache::entries].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArpEntry {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddress,
+    /// How long until the resolution expires, unless it's static.
+    pub remaining_ttl: Option<Duration>,
+    /// Static resolutions never expire.
+    pub is_static: bool,
+}
+
 #[derive(Debug)]
 struct Record {
     link_addr: MacAddress,
@@ -22,16 +38,22 @@ struct Record {
 
 ///
 /// # ARP Cache
-/// - TODO: Allow multiple waiters for the same address
-/// - TODO: Deregister waiters here when the receiver goes away.
-/// - TODO: Implement eviction.
-/// - TODO: Implement remove.
 pub struct ArpCache {
     /// Cache for IPv4 Addresses
     cache: HashTtlCache<Ipv4Addr, Record>,
 
     /// Disable ARP?
     disable: bool,
+
+    /// Addresses that are resolved normally even if ARP is disabled.
+    force_resolve: HashSet<Ipv4Addr>,
+
+    /// Have we already handed out the dummy MAC address?
+    dummy_used: Cell<bool>,
+
+    /// Bumped whenever a resolution changes or goes away, so that whoever holds on to one can tell
+    /// that it may be stale without looking it up again.
+    generation: Rc<Cell<u64>>,
 }
 
 impl ArpCache {
@@ -45,6 +67,9 @@ impl ArpCache {
         let mut peer = ArpCache {
             cache: HashTtlCache::new(now, default_ttl),
             disable,
+            force_resolve: HashSet::new(),
+            dummy_used: Cell::new(false),
+            generation: Rc::new(Cell::new(0)),
         };
 
         // Populate cache.
@@ -72,26 +97,118 @@ impl ArpCache {
             link_addr,
             ipv4_addr,
         };
-        self.cache.insert(ipv4_addr, record).map(|r| r.link_addr)
+        let old = self.cache.insert(ipv4_addr, record).map(|r| r.link_addr);
+        if old.map_or(false, |old| old != link_addr) {
+            self.bump_generation();
+        }
+        old
+    }
+
+    /// Invalidates the resolution of an IPv4 address, returning the MAC address it resolved to.
+    /// Does nothing if ARP is disabled.
+    pub fn remove(&mut self, ipv4_addr: Ipv4Addr) -> Option<MacAddress> {
+        if self.disable {
+            return None;
+        }
+        self.bump_generation();
+        self.cache.remove(&ipv4_addr).map(|r| r.link_addr)
+    }
+
+    /// Returns the counter that's bumped whenever a resolution changes or goes away.
+    pub fn generation(&self) -> Rc<Cell<u64>> {
+        self.generation.clone()
+    }
+
+    fn bump_generation(&self) {
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    /// Caches an address resolution that never expires.
+    pub fn insert_static(
+        &mut self,
+        ipv4_addr: Ipv4Addr,
+        link_addr: MacAddress,
+    ) -> Option<MacAddress> {
+        let record = Record {
+            link_addr,
+            ipv4_addr,
+        };
+        let old = self
+            .cache
+            .insert_with_ttl(ipv4_addr, record, None)
+            .map(|r| r.link_addr);
+        if old.map_or(false, |old| old != link_addr) {
+            self.bump_generation();
+        }
+        old
+    }
+
+    /// Lists the address resolutions in the cache, along with how long they have left.
+    pub fn entries(&self) -> Vec<ArpEntry> {
+        self.cache
+            .iter_with_ttl()
+            .map(|(&ip, record, remaining_ttl)| ArpEntry {
+                ip,
+                mac: record.link_addr,
+                remaining_ttl,
+                is_static: remaining_ttl.is_none(),
+            })
+            .collect()
+    }
+
+    /// Forces an IPv4 address to be resolved through ARP even if ARP is disabled.
+    pub fn force_resolve(&mut self, ipv4_addr: Ipv4Addr) {
+        self.force_resolve.insert(ipv4_addr);
     }
 
     /// Gets the MAC address of given IPv4 address.
     pub fn get(&self, ipv4_addr: Ipv4Addr) -> Option<&MacAddress> {
-        if self.disable {
+        if self.disable && !self.force_resolve.contains(&ipv4_addr) {
+            if !self.dummy_used.replace(true) {
+                debug!("ARP disabled, resolving {} to dummy MAC address", ipv4_addr);
+            }
             Some(&DUMMY_MAC_ADDRESS)
         } else {
             self.cache.get(&ipv4_addr).map(|r| &r.link_addr)
         }
     }
 
-    /// Advances internal clock of the ARP Cache.
-    pub fn advance_clock(&mut self, now: Instant) {
-        self.cache.advance_clock(now)
+    /// Advances internal clock of the ARP Cache, evicting the resolutions that expire along the
+    /// way. Returns how many were evicted.
+    pub fn advance_clock(&mut self, now: Instant) -> usize {
+        self.cache.advance_clock(now);
+        let evicted = self.cache.try_evict(usize::MAX).len();
+        if evicted > 0 {
+            self.bump_generation();
+        }
+        evicted
+    }
+
+    /// Invalidates the learned resolutions for addresses in `prefix/prefix_len`, leaving static
+    /// ones alone. Returns how many were removed.
+    pub fn flush_subnet(&mut self, prefix: Ipv4Addr, prefix_len: u8) -> usize {
+        assert!(prefix_len <= 32);
+        let flushed: Vec<Ipv4Addr> = self
+            .cache
+            .iter_with_ttl()
+            .filter(|&(&ip, _, remaining_ttl)| {
+                remaining_ttl.is_some() && prefix_contains(prefix, prefix_len, ip)
+            })
+            .map(|(&ip, _, _)| ip)
+            .collect();
+        for ip in &flushed {
+            self.cache.remove(ip);
+        }
+        if !flushed.is_empty() {
+            self.bump_generation();
+        }
+        flushed.len()
     }
 
     /// Clears the ARP cache.
     #[allow(unused)]
     pub fn clear(&mut self) {
+        self.bump_generation();
         self.cache.clear();
     }
 }
@@ -24,6 +24,100 @@ fn evit_with_default_ttl() {
     assert!(cache.get(test_helpers::ALICE_IPV4).is_none());
 }
 
+/// Tests that advancing the clock evicts expired entries without a clear.
+#[test]
+fn evict_on_advance_clock() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+
+    let mut cache = ArpCache::new(now, Some(ttl), None, false);
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    cache.insert(test_helpers::BOB_IPV4, test_helpers::BOB_MAC);
+
+    // Nothing has expired yet.
+    assert_eq!(cache.advance_clock(now + ttl / 2), 0);
+    assert!(cache.get(test_helpers::ALICE_IPV4) == Some(&test_helpers::ALICE_MAC));
+
+    // Refresh one of the entries, and let the other one expire.
+    cache.insert(test_helpers::BOB_IPV4, test_helpers::BOB_MAC);
+    assert_eq!(cache.advance_clock(now + ttl), 1);
+    assert!(cache.get(test_helpers::ALICE_IPV4).is_none());
+    assert!(cache.get(test_helpers::BOB_IPV4) == Some(&test_helpers::BOB_MAC));
+}
+
+/// Tests that a removed entry is no longer resolved.
+#[test]
+fn remove() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(1);
+
+    let mut cache = ArpCache::new(now, Some(ttl), None, false);
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    assert!(cache.remove(test_helpers::ALICE_IPV4) == Some(test_helpers::ALICE_MAC));
+    assert!(cache.get(test_helpers::ALICE_IPV4).is_none());
+    assert!(cache.remove(test_helpers::ALICE_IPV4).is_none());
+
+    // With ARP disabled, removals are ignored.
+    let mut cache = ArpCache::new(now, Some(ttl), None, true);
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    assert!(cache.remove(test_helpers::ALICE_IPV4).is_none());
+}
+
+/// Tests that entries reports how long learned resolutions have left.
+#[test]
+fn entries() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(10);
+
+    let mut cache = ArpCache::new(now, Some(ttl), None, false);
+    cache.insert(test_helpers::ALICE_IPV4, test_helpers::ALICE_MAC);
+    cache.insert_static(test_helpers::BOB_IPV4, test_helpers::BOB_MAC);
+    cache.advance_clock(now + Duration::from_secs(4));
+
+    let mut entries = cache.entries();
+    entries.sort_by_key(|e| e.ip);
+    assert!(
+        entries
+            == vec![
+                ArpEntry {
+                    ip: test_helpers::ALICE_IPV4,
+                    mac: test_helpers::ALICE_MAC,
+                    remaining_ttl: Some(Duration::from_secs(6)),
+                    is_static: false,
+                },
+                ArpEntry {
+                    ip: test_helpers::BOB_IPV4,
+                    mac: test_helpers::BOB_MAC,
+                    remaining_ttl: None,
+                    is_static: true,
+                },
+            ]
+    );
+}
+
+/// Tests that flushing a subnet drops only its learned entries.
+#[test]
+fn flush_subnet() {
+    let now = Instant::now();
+    let ttl = Duration::from_secs(10);
+
+    let mut cache = ArpCache::new(now, Some(ttl), None, false);
+    cache.insert(Ipv4Addr::new(10, 0, 1, 1), test_helpers::ALICE_MAC);
+    cache.insert(Ipv4Addr::new(10, 0, 1, 2), test_helpers::BOB_MAC);
+    cache.insert_static(Ipv4Addr::new(10, 0, 1, 3), test_helpers::CARRIE_MAC);
+    cache.insert(Ipv4Addr::new(10, 0, 2, 1), test_helpers::ALICE_MAC);
+
+    assert_eq!(cache.flush_subnet(Ipv4Addr::new(10, 0, 1, 0), 24), 2);
+    let mut remaining: Vec<Ipv4Addr> = cache.entries().into_iter().map(|e| e.ip).collect();
+    remaining.sort();
+    assert_eq!(
+        remaining,
+        vec![Ipv4Addr::new(10, 0, 1, 3), Ipv4Addr::new(10, 0, 2, 1)]
+    );
+    assert!(cache.get(Ipv4Addr::new(10, 0, 1, 1)).is_none());
+    assert_eq!(cache.flush_subnet(Ipv4Addr::new(10, 0, 1, 0), 24), 0);
+}
+
 /// Tests import on the ARP Cache.
 #[test]
 fn import() {
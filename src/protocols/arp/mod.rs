@@ -10,5 +10,7 @@ mod peer;
 #[cfg(test)]
 mod tests;
 
+pub use cache::ArpEntry as Entry;
 pub use options::ArpOptions as Options;
+pub use pdu::{ArpOperation, ArpPdu};
 pub use peer::ArpPeer as Peer;
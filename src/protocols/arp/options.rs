@@ -2,17 +2,23 @@
 // Licensed under the MIT license.
 
 use crate::protocols::ethernet2::MacAddress;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{net::Ipv4Addr, time::Duration};
 
 #[derive(Clone, Debug)]
 pub struct ArpOptions {
     pub cache_ttl: Duration,
+    /// How long we wait for a reply to the first request for an address.
     pub request_timeout: Duration,
+    /// How many times a request is retransmitted before the query fails.
     pub retry_count: usize,
+    /// Factor the timeout grows by with each retransmission. 1 keeps it fixed.
+    pub backoff: u32,
 
     pub initial_values: HashMap<Ipv4Addr, MacAddress>,
     pub disable_arp: bool,
+    /// Addresses that are resolved through ARP even when `disable_arp` is set.
+    pub force_resolve: HashSet<Ipv4Addr>,
 }
 
 impl Default for ArpOptions {
@@ -21,8 +27,10 @@ impl Default for ArpOptions {
             cache_ttl: Duration::from_secs(15),
             request_timeout: Duration::from_secs(20),
             retry_count: 5,
+            backoff: 1,
             initial_values: HashMap::new(),
             disable_arp: false,
+            force_resolve: HashSet::new(),
         }
     }
 }
@@ -39,8 +47,10 @@ impl ArpOptions {
             cache_ttl,
             request_timeout,
             retry_count,
+            backoff: 1,
             initial_values,
             disable_arp,
+            force_resolve: HashSet::new(),
         }
     }
 
@@ -61,4 +71,15 @@ impl ArpOptions {
         self.retry_count = value;
         self
     }
+
+    pub fn backoff(mut self, value: u32) -> Self {
+        assert!(value > 0);
+        self.backoff = value;
+        self
+    }
+
+    pub fn force_resolve(mut self, value: Ipv4Addr) -> Self {
+        self.force_resolve.insert(value);
+        self
+    }
 }
@@ -4,13 +4,14 @@
 use crate::{fail::Fail, protocols::ethernet2::MacAddress, runtime::RuntimeBuf};
 use byteorder::{ByteOrder, NetworkEndian};
 use num_traits::FromPrimitive;
-use std::{convert::TryInto, net::Ipv4Addr};
+use std::net::Ipv4Addr;
 
 const ARP_HTYPE_ETHER2: u16 = 1;
 const ARP_HLEN_ETHER2: u8 = 6;
 const ARP_PTYPE_IPV4: u16 = 0x800;
 const ARP_PLEN_IPV4: u8 = 4;
-const ARP_MESSAGE_SIZE: usize = 28;
+/// Size of the fields that come before the addresses.
+const ARP_FIXED_SIZE: usize = 8;
 
 #[repr(u16)]
 #[derive(FromPrimitive, Clone, Copy, Debug, PartialEq, Eq)]
@@ -22,13 +23,17 @@ pub enum ArpOperation {
 ///
 /// # Protocol Data Unit (PDU) for ARP
 ///
+/// The hardware and protocol types and address lengths are kept as they appear on the wire, so
+/// PDUs for other link types survive a round trip through `parse` and `serialize`. Addresses are
+/// still stored as Ethernet and IPv4 addresses, though, so they can be at most that long; shorter
+/// ones take up the leading bytes.
+///
 #[derive(Clone, Debug)]
 pub struct ArpPdu {
-    // We only support Ethernet/Ipv4, so omit these fields.
-    // hardware_type: u16,
-    // protocol_type: u16,
-    // hardware_address_len: u8,
-    // protocol_address_len: u8,
+    pub hardware_type: u16,
+    pub protocol_type: u16,
+    pub hardware_address_len: u8,
+    pub protocol_address_len: u8,
     pub operation: ArpOperation,
     pub sender_hardware_addr: MacAddress,
     pub sender_protocol_addr: Ipv4Addr,
@@ -37,7 +42,7 @@ pub struct ArpPdu {
 }
 
 impl ArpPdu {
-    /// Creates an ARP protocol data unit.
+    /// Creates an ARP protocol data unit for Ethernet/IPv4.
     pub fn new(
         op: ArpOperation,
         local_link_addr: MacAddress,
@@ -46,6 +51,10 @@ impl ArpPdu {
         remote_ipv4_addr: Ipv4Addr,
     ) -> Self {
         Self {
+            hardware_type: ARP_HTYPE_ETHER2,
+            protocol_type: ARP_PTYPE_IPV4,
+            hardware_address_len: ARP_HLEN_ETHER2,
+            protocol_address_len: ARP_PLEN_IPV4,
             operation: op,
             sender_hardware_addr: local_link_addr,
             sender_protocol_addr: local_ipv4_addr,
@@ -54,38 +63,36 @@ impl ArpPdu {
         }
     }
 
+    /// Whether this PDU maps IPv4 addresses to Ethernet addresses, which is all we resolve.
+    pub fn is_ethernet_ipv4(&self) -> bool {
+        self.hardware_type == ARP_HTYPE_ETHER2
+            && self.protocol_type == ARP_PTYPE_IPV4
+            && self.hardware_address_len == ARP_HLEN_ETHER2
+            && self.protocol_address_len == ARP_PLEN_IPV4
+    }
+
     /// Computes the size of the target ARP PDU.
     pub fn compute_size(&self) -> usize {
-        ARP_MESSAGE_SIZE
+        ARP_FIXED_SIZE
+            + 2 * (self.hardware_address_len as usize + self.protocol_address_len as usize)
     }
 
     pub fn parse<T: RuntimeBuf>(buf: T) -> Result<Self, Fail> {
-        if buf.len() < ARP_MESSAGE_SIZE {
+        if buf.len() < ARP_FIXED_SIZE {
             return Err(Fail::Malformed {
                 details: "ARP message too short",
             });
         }
-        let buf: &[u8; ARP_MESSAGE_SIZE] = &buf[..ARP_MESSAGE_SIZE].try_into().unwrap();
         let hardware_type = NetworkEndian::read_u16(&buf[0..2]);
-        if hardware_type != ARP_HTYPE_ETHER2 {
-            return Err(Fail::Unsupported {
-                details: "Unsupported HTYPE",
-            });
-        }
         let protocol_type = NetworkEndian::read_u16(&buf[2..4]);
-        if protocol_type != ARP_PTYPE_IPV4 {
-            return Err(Fail::Unsupported {
-                details: "Unsupported PTYPE",
-            });
-        }
         let hardware_address_len = buf[4];
-        if hardware_address_len != ARP_HLEN_ETHER2 {
+        if hardware_address_len > ARP_HLEN_ETHER2 {
             return Err(Fail::Unsupported {
                 details: "Unsupported HLEN",
             });
         }
         let protocol_address_len = buf[5];
-        if protocol_address_len != ARP_PLEN_IPV4 {
+        if protocol_address_len > ARP_PLEN_IPV4 {
             return Err(Fail::Unsupported {
                 details: "Unsupported PLEN",
             });
@@ -95,31 +102,60 @@ impl ArpPdu {
                 details: "Unsupported OPER",
             }
         })?;
-        let sender_hardware_addr = MacAddress::from_bytes(&buf[8..14]);
-        let sender_protocol_addr = Ipv4Addr::from(NetworkEndian::read_u32(&buf[14..18]));
-        let target_hardware_addr = MacAddress::from_bytes(&buf[18..24]);
-        let target_protocol_addr = Ipv4Addr::from(NetworkEndian::read_u32(&buf[24..28]));
-        let pdu = Self {
+        let mut pdu = Self {
+            hardware_type,
+            protocol_type,
+            hardware_address_len,
+            protocol_address_len,
             operation,
-            sender_hardware_addr,
-            sender_protocol_addr,
-            target_hardware_addr,
-            target_protocol_addr,
+            sender_hardware_addr: MacAddress::nil(),
+            sender_protocol_addr: Ipv4Addr::UNSPECIFIED,
+            target_hardware_addr: MacAddress::nil(),
+            target_protocol_addr: Ipv4Addr::UNSPECIFIED,
         };
+        if buf.len() < pdu.compute_size() {
+            return Err(Fail::Malformed {
+                details: "ARP message too short",
+            });
+        }
+
+        let hlen = hardware_address_len as usize;
+        let plen = protocol_address_len as usize;
+        let mut pos = ARP_FIXED_SIZE;
+        let mut read = |len: usize| {
+            let mut octets = [0u8; ARP_HLEN_ETHER2 as usize];
+            octets[..len].copy_from_slice(&buf[pos..(pos + len)]);
+            pos += len;
+            octets
+        };
+        pdu.sender_hardware_addr = MacAddress::new(read(hlen));
+        let octets = read(plen);
+        pdu.sender_protocol_addr = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+        pdu.target_hardware_addr = MacAddress::new(read(hlen));
+        let octets = read(plen);
+        pdu.target_protocol_addr = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
         Ok(pdu)
     }
 
     /// Serializes the target ARP PDU.
     pub fn serialize(&self, buf: &mut [u8]) {
-        let buf: &mut [u8; ARP_MESSAGE_SIZE] = (&mut buf[..ARP_MESSAGE_SIZE]).try_into().unwrap();
-        NetworkEndian::write_u16(&mut buf[0..2], ARP_HTYPE_ETHER2);
-        NetworkEndian::write_u16(&mut buf[2..4], ARP_PTYPE_IPV4);
-        buf[4] = ARP_HLEN_ETHER2;
-        buf[5] = ARP_PLEN_IPV4;
+        let buf = &mut buf[..self.compute_size()];
+        NetworkEndian::write_u16(&mut buf[0..2], self.hardware_type);
+        NetworkEndian::write_u16(&mut buf[2..4], self.protocol_type);
+        buf[4] = self.hardware_address_len;
+        buf[5] = self.protocol_address_len;
         NetworkEndian::write_u16(&mut buf[6..8], self.operation as u16);
-        buf[8..14].copy_from_slice(&self.sender_hardware_addr.octets());
-        buf[14..18].copy_from_slice(&self.sender_protocol_addr.octets());
-        buf[18..24].copy_from_slice(&self.target_hardware_addr.octets());
-        buf[24..28].copy_from_slice(&self.target_protocol_addr.octets());
+
+        let hlen = self.hardware_address_len as usize;
+        let plen = self.protocol_address_len as usize;
+        let mut pos = ARP_FIXED_SIZE;
+        let mut write = |octets: &[u8], len: usize| {
+            buf[pos..(pos + len)].copy_from_slice(&octets[..len]);
+            pos += len;
+        };
+        write(&self.sender_hardware_addr.octets(), hlen);
+        write(&self.sender_protocol_addr.octets(), plen);
+        write(&self.target_hardware_addr.octets(), hlen);
+        write(&self.target_protocol_addr.octets(), plen);
     }
 }
@@ -2,7 +2,7 @@
 // Licensed under the MIT license.
 
 use super::{
-    cache::ArpCache,
+    cache::{ArpCache, ArpEntry},
     msg::ArpMessage,
     options::ArpOptions,
     pdu::{ArpOperation, ArpPdu},
@@ -14,7 +14,7 @@ use crate::{
         frame::{EtherType2, Ethernet2Header},
         MacAddress,
     },
-    runtime::Runtime,
+    runtime::{PacketBuf, Runtime},
     scheduler::SchedulerHandle,
 };
 use futures::{
@@ -22,9 +22,10 @@ use futures::{
     FutureExt,
 };
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::HashMap,
     future::Future,
+    mem,
     net::Ipv4Addr,
     rc::Rc,
     time::{Duration, Instant},
@@ -32,72 +33,122 @@ use std::{
 
 ///
 /// Arp Peer
-/// - TODO: Allow multiple waiters for the same address
 #[derive(Clone)]
 pub struct ArpPeer<RT: Runtime> {
     rt: RT,
     cache: Rc<RefCell<ArpCache>>,
     background: Rc<SchedulerHandle>,
-    waiters: Rc<RefCell<HashMap<Ipv4Addr, Sender<MacAddress>>>>,
+    /// Queries waiting on a reply, by the address they're resolving, in the order they started.
+    waiters: Rc<RefCell<Waiters>>,
+    next_waiter_id: Rc<Cell<u64>>,
     options: ArpOptions,
+    /// The cache's generation counter, which we can read without borrowing the cache.
+    generation: Rc<Cell<u64>>,
+    /// Destinations we reach through a gateway rather than on-link, learned from ICMP redirects.
+    host_routes: Rc<RefCell<HostRoutes>>,
+    /// Frames held back until the end of the LibOS poll, if the runtime coalesces transmits.
+    unflushed: Rc<RefCell<Vec<RT::Buf>>>,
+    /// Number of `try_query` calls, so tests can check when the cache is bypassed.
+    #[cfg(test)]
+    lookups: Rc<Cell<usize>>,
 }
 
 impl<RT: Runtime> ArpPeer<RT> {
     pub fn new(now: Instant, rt: RT, options: ArpOptions) -> Result<ArpPeer<RT>, Fail> {
-        let cache = Rc::new(RefCell::new(ArpCache::new(
+        let mut cache = ArpCache::new(
             now,
             Some(options.cache_ttl),
             Some(&options.initial_values),
             options.disable_arp,
-        )));
+        );
+        for &ipv4_addr in &options.force_resolve {
+            cache.force_resolve(ipv4_addr);
+        }
+        let generation = cache.generation();
+        let cache = Rc::new(RefCell::new(cache));
+
+        let host_routes = Rc::new(RefCell::new(HashMap::default()));
 
-        let handle = rt.spawn(Self::background(rt.clone(), cache.clone()));
+        let handle = rt.spawn(Self::background(
+            rt.clone(),
+            cache.clone(),
+            host_routes.clone(),
+            generation.clone(),
+        ));
         let peer = ArpPeer {
             rt,
             cache,
             background: Rc::new(handle),
             waiters: Rc::new(RefCell::new(HashMap::default())),
+            next_waiter_id: Rc::new(Cell::new(0)),
             options,
+            generation,
+            host_routes,
+            unflushed: Rc::new(RefCell::new(vec![])),
+            #[cfg(test)]
+            lookups: Rc::new(Cell::new(0)),
         };
 
         Ok(peer)
     }
 
-    /// Drops a waiter for a target IP address.
-    fn do_drop(&mut self, ipv4_addr: Ipv4Addr) {
-        self.waiters.borrow_mut().remove(&ipv4_addr);
-    }
-
     fn do_insert(&mut self, ipv4_addr: Ipv4Addr, link_addr: MacAddress) -> Option<MacAddress> {
-        if let Some(sender) = self.waiters.borrow_mut().remove(&ipv4_addr) {
-            let _ = sender.send(link_addr);
+        if let Some(senders) = self.waiters.borrow_mut().remove(&ipv4_addr) {
+            for (_, sender) in senders {
+                let _ = sender.send(link_addr);
+            }
         }
         self.cache.borrow_mut().insert(ipv4_addr, link_addr)
     }
 
-    fn do_wait_link_addr(&mut self, ipv4_addr: Ipv4Addr) -> impl Future<Output = MacAddress> {
+    /// Waits for `ipv4_addr` to be resolved. The returned [Waiter] keeps us registered until it's
+    /// dropped.
+    fn do_wait_link_addr(
+        &mut self,
+        ipv4_addr: Ipv4Addr,
+    ) -> (impl Future<Output = Result<MacAddress, Fail>>, Waiter) {
         let (tx, rx): (Sender<MacAddress>, Receiver<MacAddress>) = channel();
-        if let Some(&link_addr) = self.cache.borrow().get(ipv4_addr) {
-            let _ = tx.send(link_addr);
-        } else {
-            assert!(
-                self.waiters.borrow_mut().insert(ipv4_addr, tx).is_none(),
-                "Duplicate waiter for {:?}",
-                ipv4_addr
-            );
-        }
-        rx.map(|r| r.expect("Dropped waiter?"))
+        let id = self.next_waiter_id.get();
+        self.next_waiter_id.set(id + 1);
+        self.waiters
+            .borrow_mut()
+            .entry(ipv4_addr)
+            .or_insert_with(Vec::new)
+            .push((id, tx));
+        let waiter = Waiter {
+            id,
+            ipv4_addr,
+            waiters: self.waiters.clone(),
+        };
+        // Our sender is only dropped after a reply has been sent on it.
+        let future = rx.map(|r| r.map_err(|_| Fail::Timeout {}));
+        (future, waiter)
     }
 
-    /// Background task that cleans up the ARP cache from time to time.
-    async fn background(rt: RT, cache: Rc<RefCell<ArpCache>>) {
+    /// Background task that cleans up the ARP cache and host routes from time to time.
+    async fn background(
+        rt: RT,
+        cache: Rc<RefCell<ArpCache>>,
+        host_routes: Rc<RefCell<HostRoutes>>,
+        generation: Rc<Cell<u64>>,
+    ) {
         loop {
             let current_time = rt.now();
             {
-                let mut cache = cache.borrow_mut();
-                cache.advance_clock(current_time);
-                // TODO: re-enable eviction once TCP/IP stack is fully functional.
-                // cache.clear();
+                let evicted = cache.borrow_mut().advance_clock(current_time);
+                if evicted > 0 {
+                    debug!("Evicted {} stale ARP cache entries", evicted);
+                }
+            }
+            {
+                let mut host_routes = host_routes.borrow_mut();
+                let before = host_routes.len();
+                host_routes.retain(|_, &mut (_, expiry)| expiry > current_time);
+                if host_routes.len() < before {
+                    // Resolutions made through the expired routes now point at the wrong host.
+                    generation.set(generation.get() + 1);
+                    debug!("Expired {} host routes", before - host_routes.len());
+                }
             }
             rt.wait(Duration::from_secs(1)).await;
         }
@@ -111,6 +162,11 @@ impl<RT: Runtime> ArpPeer<RT> {
         // > [optionally check the protocol length ar$pln]
         let pdu = ArpPdu::parse(buf)?;
         debug!("Received {:?}", pdu);
+        if !pdu.is_ethernet_ipv4() {
+            return Err(Fail::Unsupported {
+                details: "ARP is only supported for Ethernet/IPv4",
+            });
+        }
 
         // from RFC 826:
         // > Merge_flag := false
@@ -166,7 +222,7 @@ impl<RT: Runtime> ArpPeer<RT> {
                     ),
                 );
                 debug!("Responding {:?}", reply);
-                self.rt.transmit(reply);
+                self.transmit(reply);
                 Ok(())
             }
             ArpOperation::Reply => {
@@ -183,10 +239,49 @@ impl<RT: Runtime> ArpPeer<RT> {
     }
 
     pub fn try_query(&self, ipv4_addr: Ipv4Addr) -> Option<MacAddress> {
-        self.cache.borrow().get(ipv4_addr).cloned()
+        #[cfg(test)]
+        self.lookups.set(self.lookups.get() + 1);
+        let next_hop = self.next_hop(ipv4_addr);
+        self.cache.borrow().get(next_hop).cloned()
+    }
+
+    /// Sends traffic for `dst` through `gateway` for the next `ttl`, rather than straight to it. A
+    /// `gateway` of `dst` itself takes us back to reaching it on-link.
+    pub fn add_host_route(&self, dst: Ipv4Addr, gateway: Ipv4Addr, ttl: Duration) {
+        {
+            let mut host_routes = self.host_routes.borrow_mut();
+            if gateway == dst {
+                host_routes.remove(&dst);
+            } else {
+                host_routes.insert(dst, (gateway, self.rt.now() + ttl));
+            }
+        }
+        // Resolutions of `dst` made before now went to the old next hop.
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    /// Returns the address whose link address we send traffic for `dst` to: the gateway of an
+    /// unexpired host route, or `dst` itself.
+    pub fn next_hop(&self, dst: Ipv4Addr) -> Ipv4Addr {
+        match self.host_routes.borrow().get(&dst) {
+            Some(&(gateway, expiry)) if expiry > self.rt.now() => gateway,
+            _ => dst,
+        }
+    }
+
+    /// Returns a counter that changes whenever a resolution in the cache changes or goes away. A
+    /// resolution looked up while it had some value can be reused for as long as it keeps it.
+    pub fn generation(&self) -> u64 {
+        self.generation.get()
+    }
+
+    #[cfg(test)]
+    pub fn lookups(&self) -> usize {
+        self.lookups.get()
     }
 
     pub fn query(&self, ipv4_addr: Ipv4Addr) -> impl Future<Output = Result<MacAddress, Fail>> {
+        let ipv4_addr = self.next_hop(ipv4_addr);
         let rt = self.rt.clone();
         let mut arp = self.clone();
         let cache = self.cache.clone();
@@ -195,6 +290,8 @@ impl<RT: Runtime> ArpPeer<RT> {
             if let Some(&link_addr) = cache.borrow().get(ipv4_addr) {
                 return Ok(link_addr);
             }
+            let (arp_response, waiter) = arp.do_wait_link_addr(ipv4_addr);
+            let mut arp_response = arp_response.fuse();
             let msg = ArpMessage::new(
                 Ethernet2Header {
                     dst_addr: MacAddress::broadcast(),
@@ -209,36 +306,132 @@ impl<RT: Runtime> ArpPeer<RT> {
                     ipv4_addr,
                 ),
             );
-            let mut arp_response = arp.do_wait_link_addr(ipv4_addr).fuse();
 
             // from TCP/IP illustrated, chapter 4:
             // > The frequency of the ARP request is very close to one per
             // > second, the maximum suggested by [RFC1122].
-            let result = {
-                for i in 0..arp_options.retry_count + 1 {
-                    rt.transmit(msg.clone());
-                    let timer = rt.wait(arp_options.request_timeout);
-
-                    match arp_response.with_timeout(timer).await {
-                        Ok(link_addr) => {
-                            debug!("ARP result available ({})", link_addr);
-                            return Ok(link_addr);
-                        }
-                        Err(_) => {
-                            warn!("ARP request timeout; attempt {}.", i + 1);
-                        }
+            let mut timeout = arp_options.request_timeout;
+            for i in 0..arp_options.retry_count + 1 {
+                // Concurrent queries for the same address share one stream of requests, sent by
+                // whichever has been waiting longest. If that one goes away, the next in line
+                // takes over on its next attempt.
+                if waiter.is_first() {
+                    arp.transmit(msg.clone());
+                }
+                let timer = rt.wait(timeout);
+                timeout = timeout.saturating_mul(arp_options.backoff);
+
+                match arp_response.with_timeout(timer).await {
+                    Ok(Ok(link_addr)) => {
+                        debug!("ARP result available ({})", link_addr);
+                        return Ok(link_addr);
+                    }
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => {
+                        warn!("ARP request timeout; attempt {}.", i + 1);
                     }
                 }
-                Err(Fail::Timeout {})
-            };
+            }
+            Err(Fail::Timeout {})
+        }
+    }
+
+    /// Announces our own address binding with a gratuitous ARP request, so that peers and switches
+    /// on the segment update their tables (after a failover, say).
+    pub fn send_gratuitous(&self) {
+        if self.options.disable_arp {
+            return;
+        }
+        let msg = ArpMessage::new(
+            Ethernet2Header {
+                dst_addr: MacAddress::broadcast(),
+                src_addr: self.rt.local_link_addr(),
+                ether_type: EtherType2::Arp,
+            },
+            ArpPdu::new(
+                ArpOperation::Request,
+                self.rt.local_link_addr(),
+                self.rt.local_ipv4_addr(),
+                MacAddress::broadcast(),
+                self.rt.local_ipv4_addr(),
+            ),
+        );
+        debug!("Announcing {:?}", msg);
+        self.transmit(msg);
+    }
 
-            arp.do_drop(ipv4_addr);
+    /// Sends a frame. Every protocol transmits through us, since they all resolve the next hop
+    /// with us, so this is where frames are held back if the runtime wants everything we send
+    /// within a LibOS poll in one batch.
+    pub fn transmit(&self, pkt: impl PacketBuf<RT::Buf>) {
+        if self.rt.coalesce_transmits() {
+            let frame = self.rt.serialize(pkt);
+            self.unflushed.borrow_mut().push(frame);
+        } else {
+            self.rt.transmit(pkt);
+        }
+    }
 
-            result
+    /// Hands the frames held back since the last flush to the runtime, in one batch.
+    pub fn flush_transmits(&self) {
+        let frames = mem::take(&mut *self.unflushed.borrow_mut());
+        if !frames.is_empty() {
+            self.rt.transmit_batch(frames);
         }
     }
 
+    /// Returns the addresses we're still waiting on an ARP reply for.
+    pub fn pending_queries(&self) -> Vec<Ipv4Addr> {
+        self.waiters.borrow().keys().cloned().collect()
+    }
+
+    /// Lists the address resolutions in the cache.
+    pub fn entries(&self) -> Vec<ArpEntry> {
+        self.cache.borrow().entries()
+    }
+
+    /// Drops the learned resolutions for a subnet, returning how many there were.
+    pub fn flush_subnet(&self, prefix: Ipv4Addr, prefix_len: u8) -> usize {
+        self.cache.borrow_mut().flush_subnet(prefix, prefix_len)
+    }
+
     pub fn export_cache(&self) -> HashMap<Ipv4Addr, MacAddress> {
         self.cache.borrow().export()
     }
 }
+
+type Waiters = HashMap<Ipv4Addr, Vec<(u64, Sender<MacAddress>)>>;
+
+/// Gateway and expiry of each destination we don't reach on-link.
+type HostRoutes = HashMap<Ipv4Addr, (Ipv4Addr, Instant)>;
+
+/// A query's place in [ArpPeer]'s waiters, which it gives up when dropped. Without this, a query
+/// future dropped before the reply arrives would be left registered for good.
+struct Waiter {
+    id: u64,
+    ipv4_addr: Ipv4Addr,
+    waiters: Rc<RefCell<Waiters>>,
+}
+
+impl Waiter {
+    /// Whether we're the longest-waiting query for our address.
+    fn is_first(&self) -> bool {
+        self.waiters
+            .borrow()
+            .get(&self.ipv4_addr)
+            .and_then(|senders| senders.first())
+            .map_or(false, |&(id, _)| id == self.id)
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut waiters = self.waiters.borrow_mut();
+        if let Some(senders) = waiters.get_mut(&self.ipv4_addr) {
+            senders.retain(|&(id, _)| id != self.id);
+            if senders.is_empty() {
+                waiters.remove(&self.ipv4_addr);
+            }
+        }
+    }
+}
@@ -1,10 +1,17 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::pdu::{ArpOperation, ArpPdu};
+use super::{
+    pdu::{ArpOperation, ArpPdu},
+    Options,
+};
 
 use crate::{
-    fail::Fail, protocols::ethernet2::frame::Ethernet2Header, runtime::Runtime, test_helpers,
+    collections::bytes::Bytes,
+    fail::Fail,
+    protocols::ethernet2::{frame::Ethernet2Header, MacAddress},
+    runtime::{Runtime, RuntimeBuf},
+    test_helpers,
 };
 
 use futures::{
@@ -15,6 +22,7 @@ use futures::{
 use must_let::must_let;
 
 use std::{
+    collections::HashMap,
     future::Future,
     task::Poll,
     time::{Duration, Instant},
@@ -149,3 +157,220 @@ fn no_reply() {
 
     must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(fut.as_mut(), &mut ctx));
 }
+
+/// Tests that addresses forced to resolve still go through ARP when ARP is disabled.
+#[test]
+fn disabled_with_force_resolve() {
+    let now = Instant::now();
+    let options = Options::new(
+        Duration::from_secs(600),
+        Duration::from_secs(1),
+        2,
+        HashMap::new(),
+        true,
+    )
+    .force_resolve(test_helpers::CARRIE_IPV4);
+    let alice = test_helpers::new_alice_with_arp_options(now, options);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    // Other addresses resolve immediately to the dummy MAC address.
+    let mut fut = alice.arp_query(test_helpers::BOB_IPV4).boxed_local();
+    must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(link_addr, MacAddress::new([0; 6]));
+
+    // The forced address sends out a real ARP request.
+    let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    let bytes = alice.rt().pop_frame();
+    let (_, payload) = Ethernet2Header::parse(bytes).unwrap();
+    let arp = ArpPdu::parse(payload).unwrap();
+    assert_eq!(arp.operation, ArpOperation::Request);
+    assert_eq!(arp.target_protocol_addr, test_helpers::CARRIE_IPV4);
+}
+
+/// Tests that unresolved addresses are reported until a reply arrives.
+#[test]
+fn pending_queries() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    assert!(alice.arp_pending_queries().is_empty());
+
+    let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    assert_eq!(alice.arp_pending_queries(), vec![test_helpers::CARRIE_IPV4]);
+
+    carrie.receive(alice.rt().pop_frame()).unwrap();
+    carrie.rt().advance_clock(now);
+    alice.receive(carrie.rt().pop_frame()).unwrap();
+
+    must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(link_addr, test_helpers::CARRIE_MAC);
+    assert!(alice.arp_pending_queries().is_empty());
+}
+
+/// Tests that concurrent queries for the same address share one request and one reply.
+#[test]
+fn concurrent_queries() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let mut fut1 = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    let mut fut2 = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(fut1.as_mut(), &mut ctx).is_pending());
+    assert!(Future::poll(fut2.as_mut(), &mut ctx).is_pending());
+
+    // Only the first query sends a request.
+    let request = alice.rt().pop_frame();
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    carrie.receive(request).unwrap();
+    carrie.rt().advance_clock(now);
+    alice.receive(carrie.rt().pop_frame()).unwrap();
+
+    must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut1.as_mut(), &mut ctx));
+    assert_eq!(link_addr, test_helpers::CARRIE_MAC);
+    must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut2.as_mut(), &mut ctx));
+    assert_eq!(link_addr, test_helpers::CARRIE_MAC);
+    assert!(alice.arp_pending_queries().is_empty());
+}
+
+/// Tests that a dropped query stops sending requests, and that a query waiting behind it takes
+/// over the requests if it's still around.
+#[test]
+fn dropped_query() {
+    let mut now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let options = alice.rt().arp_options();
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let mut fut1 = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    let mut fut2 = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(fut1.as_mut(), &mut ctx).is_pending());
+    assert!(Future::poll(fut2.as_mut(), &mut ctx).is_pending());
+    alice.rt().pop_frame();
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    drop(fut1);
+    now += options.request_timeout;
+    alice.rt().advance_clock(now);
+    assert!(Future::poll(fut2.as_mut(), &mut ctx).is_pending());
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let arp = ArpPdu::parse(payload).unwrap();
+    assert_eq!(arp.operation, ArpOperation::Request);
+
+    drop(fut2);
+    assert!(alice.arp_pending_queries().is_empty());
+    now += options.request_timeout;
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+}
+
+/// Tests that a gratuitous ARP announces our own binding.
+#[test]
+fn gratuitous() {
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+
+    alice.arp_announce();
+    let (header, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    assert_eq!(header.dst_addr, MacAddress::broadcast());
+    assert_eq!(header.src_addr, test_helpers::ALICE_MAC);
+    let arp = ArpPdu::parse(payload).unwrap();
+    assert_eq!(arp.operation, ArpOperation::Request);
+    assert_eq!(arp.sender_hardware_addr, test_helpers::ALICE_MAC);
+    assert_eq!(arp.sender_protocol_addr, test_helpers::ALICE_IPV4);
+    assert_eq!(arp.target_protocol_addr, test_helpers::ALICE_IPV4);
+    assert!(alice.rt().try_pop_frame().is_none());
+}
+
+/// Tests that requests nobody answers are retransmitted with exponential backoff until the
+/// configured number of retries runs out.
+#[test]
+fn retry_backoff() {
+    let mut now = Instant::now();
+    let options = Options::default()
+        .request_timeout(Duration::from_secs(1))
+        .retry_count(3)
+        .backoff(2);
+    let alice = test_helpers::new_alice_with_arp_options(now, options);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    alice.rt().pop_frame();
+
+    for timeout in [1, 2, 4].iter() {
+        // Nothing goes out until the current timeout has run its course.
+        now += Duration::from_secs(*timeout) - Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        assert!(alice.rt().try_pop_frame().is_none());
+
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+        let arp = ArpPdu::parse(payload).unwrap();
+        assert_eq!(arp.operation, ArpOperation::Request);
+    }
+
+    now += Duration::from_secs(8);
+    alice.rt().advance_clock(now);
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(fut.as_mut(), &mut ctx));
+    assert!(alice.rt().try_pop_frame().is_none());
+    assert!(alice.arp_pending_queries().is_empty());
+}
+
+/// Tests that PDUs for other hardware types keep their header fields through a round trip.
+#[test]
+fn pdu_round_trip() {
+    // IEEE 802 networks with Ethernet-sized addresses.
+    let mut pdu = ArpPdu::new(
+        ArpOperation::Reply,
+        test_helpers::ALICE_MAC,
+        test_helpers::ALICE_IPV4,
+        test_helpers::BOB_MAC,
+        test_helpers::BOB_IPV4,
+    );
+    pdu.hardware_type = 6;
+    let mut buf = vec![0u8; pdu.compute_size()];
+    pdu.serialize(&mut buf[..]);
+    let parsed = ArpPdu::parse(Bytes::from_slice(&buf)).unwrap();
+    assert_eq!(parsed.hardware_type, 6);
+    assert_eq!(parsed.protocol_type, pdu.protocol_type);
+    assert!(!parsed.is_ethernet_ipv4());
+    assert_eq!(parsed.sender_hardware_addr, test_helpers::ALICE_MAC);
+    assert_eq!(parsed.target_protocol_addr, test_helpers::BOB_IPV4);
+    let mut reserialized = vec![0u8; parsed.compute_size()];
+    parsed.serialize(&mut reserialized[..]);
+    assert_eq!(reserialized, buf);
+
+    // A link type with shorter addresses, which take up less of the message.
+    pdu.hardware_type = 0x1234;
+    pdu.hardware_address_len = 2;
+    pdu.sender_hardware_addr = MacAddress::new([0xab, 0xcd, 0, 0, 0, 0]);
+    pdu.target_hardware_addr = MacAddress::new([0x12, 0x34, 0, 0, 0, 0]);
+    assert_eq!(pdu.compute_size(), 20);
+    let mut buf = vec![0u8; pdu.compute_size()];
+    pdu.serialize(&mut buf[..]);
+    let parsed = ArpPdu::parse(Bytes::from_slice(&buf)).unwrap();
+    assert_eq!(parsed.hardware_type, 0x1234);
+    assert_eq!(parsed.hardware_address_len, 2);
+    assert_eq!(parsed.sender_hardware_addr, pdu.sender_hardware_addr);
+    assert_eq!(parsed.sender_protocol_addr, test_helpers::ALICE_IPV4);
+    assert_eq!(parsed.target_hardware_addr, pdu.target_hardware_addr);
+    assert_eq!(parsed.target_protocol_addr, test_helpers::BOB_IPV4);
+    let mut reserialized = vec![0u8; parsed.compute_size()];
+    parsed.serialize(&mut reserialized[..]);
+    assert_eq!(reserialized, buf);
+
+    // Addresses longer than we can store are still rejected.
+    buf[4] = 8;
+    must_let!(let Err(Fail::Unsupported { .. }) = ArpPdu::parse(Bytes::from_slice(&buf)));
+}
@@ -1,13 +1,42 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use crate::{fail::Fail, protocols::ethernet2::MacAddress, runtime::RuntimeBuf};
+use crate::{
+    collections::bytes::BytesMut,
+    fail::Fail,
+    protocols::ethernet2::MacAddress,
+    runtime::{PacketBuf, RuntimeBuf},
+};
 use byteorder::{ByteOrder, NetworkEndian};
 use num_traits::FromPrimitive;
-use std::convert::{TryFrom, TryInto};
+use std::{
+    cmp,
+    convert::{TryFrom, TryInto},
+};
 
 pub const MIN_PAYLOAD_SIZE: usize = 46;
 pub const ETHERNET2_HEADER_SIZE: usize = 14;
+/// Smallest frame Ethernet allows on the wire, not counting the FCS.
+pub const MIN_FRAME_SIZE: usize = ETHERNET2_HEADER_SIZE + MIN_PAYLOAD_SIZE;
+
+/// Serializes a frame for transmission. With `pad` set, frames shorter than [MIN_FRAME_SIZE] (bare
+/// ACKs, ARP messages) are zero-padded up to it; runtimes whose NIC pads frames can leave it off.
+pub fn serialize_frame<T: RuntimeBuf>(pkt: impl PacketBuf<T>, pad: bool) -> BytesMut {
+    let header_size = pkt.header_size();
+    let frame_size = header_size + pkt.body_size();
+    let buf_size = if pad {
+        cmp::max(frame_size, MIN_FRAME_SIZE)
+    } else {
+        frame_size
+    };
+
+    let mut buf = BytesMut::zeroed(buf_size);
+    pkt.write_header(&mut buf[..header_size]);
+    if let Some(body) = pkt.take_body() {
+        buf[header_size..frame_size].copy_from_slice(&body[..]);
+    }
+    buf
+}
 
 #[repr(u16)]
 #[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Debug)]
@@ -10,7 +10,7 @@ use crate::{
 
 use byteorder::{ByteOrder, NetworkEndian};
 
-use std::{convert::TryInto, marker::PhantomData};
+use std::{convert::TryInto, net::Ipv4Addr};
 
 #[allow(unused)]
 const MAX_ICMPV4_DATAGRAM_SIZE: usize = 576;
@@ -21,11 +21,23 @@ const MAX_ICMPV4_DATAGRAM_SIZE: usize = 576;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Icmpv4Type2 {
-    EchoReply { id: u16, seq_num: u16 },
-    DestinationUnreachable,
+    EchoReply {
+        id: u16,
+        seq_num: u16,
+    },
+    /// `next_hop_mtu` is only meaningful for "fragmentation needed" errors (RFC 1191).
+    DestinationUnreachable {
+        next_hop_mtu: u16,
+    },
     SourceQuench,
-    RedirectMessage,
-    EchoRequest { id: u16, seq_num: u16 },
+    /// `gateway` is the router the redirect tells us to use for the quoted datagram's destination.
+    RedirectMessage {
+        gateway: Ipv4Addr,
+    },
+    EchoRequest {
+        id: u16,
+        seq_num: u16,
+    },
     RouterAdvertisement,
     RouterSolicitation,
     TimeExceeded,
@@ -43,9 +55,15 @@ impl Icmpv4Type2 {
                 let seq_num = NetworkEndian::read_u16(&rest_of_header[2..4]);
                 Ok(EchoReply { id, seq_num })
             }
-            3 => Ok(DestinationUnreachable),
+            3 => {
+                let next_hop_mtu = NetworkEndian::read_u16(&rest_of_header[2..4]);
+                Ok(DestinationUnreachable { next_hop_mtu })
+            }
             4 => Ok(SourceQuench),
-            5 => Ok(RedirectMessage),
+            5 => {
+                let gateway = Ipv4Addr::from(NetworkEndian::read_u32(&rest_of_header[..]));
+                Ok(RedirectMessage { gateway })
+            }
             8 => {
                 let id = NetworkEndian::read_u16(&rest_of_header[0..2]);
                 let seq_num = NetworkEndian::read_u16(&rest_of_header[2..4]);
@@ -63,14 +81,25 @@ impl Icmpv4Type2 {
         }
     }
 
+    fn serialize_echo(id: u16, seq_num: u16) -> [u8; 4] {
+        let mut rest_of_header = [0u8; 4];
+        NetworkEndian::write_u16(&mut rest_of_header[0..2], id);
+        NetworkEndian::write_u16(&mut rest_of_header[2..4], seq_num);
+        rest_of_header
+    }
+
     fn serialize(&self) -> (u8, [u8; 4]) {
         use Icmpv4Type2::*;
         match self {
-            EchoReply { .. } => (0, [0u8; 4]),
-            DestinationUnreachable => (3, [0u8; 4]),
+            EchoReply { id, seq_num } => (0, Self::serialize_echo(*id, *seq_num)),
+            DestinationUnreachable { next_hop_mtu } => {
+                let mut rest_of_header = [0u8; 4];
+                NetworkEndian::write_u16(&mut rest_of_header[2..4], *next_hop_mtu);
+                (3, rest_of_header)
+            }
             SourceQuench => (4, [0u8; 4]),
-            RedirectMessage => (5, [0u8; 4]),
-            EchoRequest { .. } => (8, [0u8; 4]),
+            RedirectMessage { gateway } => (5, gateway.octets()),
+            EchoRequest { id, seq_num } => (8, Self::serialize_echo(*id, *seq_num)),
             RouterAdvertisement => (9, [0u8; 4]),
             RouterSolicitation => (10, [0u8; 4]),
             TimeExceeded => (11, [0u8; 4]),
@@ -130,7 +159,7 @@ impl Icmpv4Header {
         Ok((Self { icmpv4_type, code }, buf))
     }
 
-    pub fn serialize(&self, buf: &mut [u8]) {
+    pub fn serialize(&self, buf: &mut [u8], body: &[u8]) {
         let buf: &mut [u8; ICMPV4_HEADER_SIZE] =
             (&mut buf[..ICMPV4_HEADER_SIZE]).try_into().unwrap();
         let (type_byte, rest_of_header) = self.icmpv4_type.serialize();
@@ -138,7 +167,7 @@ impl Icmpv4Header {
         buf[1] = self.code;
         // Skip the checksum for now.
         buf[4..8].copy_from_slice(&rest_of_header[..]);
-        let checksum = Self::checksum(buf, &[]);
+        let checksum = Self::checksum(buf, body);
         NetworkEndian::write_u16(&mut buf[2..4], checksum);
     }
 
@@ -174,7 +203,8 @@ pub struct Icmpv4Message<T> {
     ethernet2_hdr: Ethernet2Header,
     ipv4_hdr: Ipv4Header,
     icmpv4_hdr: Icmpv4Header,
-    _body_marker: PhantomData<T>,
+    /// Payload after the ICMP header, e.g. the datagram an error message is about.
+    data: Option<T>,
 }
 
 /// Associated Functions for Icmpv4Message
@@ -189,19 +219,34 @@ impl<T> Icmpv4Message<T> {
             ethernet2_hdr,
             ipv4_hdr,
             icmpv4_hdr,
-            _body_marker: PhantomData,
+            data: None,
+        }
+    }
+
+    /// Creates an ICMP message that carries `data` after its header.
+    pub fn with_data(
+        ethernet2_hdr: Ethernet2Header,
+        ipv4_hdr: Ipv4Header,
+        icmpv4_hdr: Icmpv4Header,
+        data: T,
+    ) -> Self {
+        Self {
+            ethernet2_hdr,
+            ipv4_hdr,
+            icmpv4_hdr,
+            data: Some(data),
         }
     }
 }
 
 /// PacketBuf Trait Implementation for Icmpv4Message
-impl<T> PacketBuf<T> for Icmpv4Message<T> {
+impl<T: RuntimeBuf> PacketBuf<T> for Icmpv4Message<T> {
     fn header_size(&self) -> usize {
         self.ethernet2_hdr.compute_size() + self.ipv4_hdr.compute_size() + self.icmpv4_hdr.size()
     }
 
     fn body_size(&self) -> usize {
-        0
+        self.data.as_ref().map_or(0, |data| data.len())
     }
 
     fn write_header(&self, buf: &mut [u8]) {
@@ -214,7 +259,11 @@ impl<T> PacketBuf<T> for Icmpv4Message<T> {
             .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
         cur_pos += eth_hdr_size;
 
-        let ipv4_payload_len = icmpv4_hdr_size;
+        let body: &[u8] = match self.data {
+            Some(ref data) => &data[..],
+            None => &[],
+        };
+        let ipv4_payload_len = icmpv4_hdr_size + body.len();
         self.ipv4_hdr.serialize(
             &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
             ipv4_payload_len,
@@ -222,10 +271,10 @@ impl<T> PacketBuf<T> for Icmpv4Message<T> {
         cur_pos += ipv4_hdr_size;
 
         self.icmpv4_hdr
-            .serialize(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)]);
+            .serialize(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)], body);
     }
 
     fn take_body(self) -> Option<T> {
-        None
+        self.data
     }
 }
@@ -2,6 +2,12 @@
 // Licensed under the MIT license.
 
 mod datagram;
+mod options;
 mod peer;
 
-pub use peer::Icmpv4Peer as Peer;
+#[cfg(test)]
+mod tests;
+
+pub use datagram::{Icmpv4Header, Icmpv4Message, Icmpv4Type2};
+pub use options::Icmpv4Options as Options;
+pub use peer::{FragmentationNeeded, Icmpv4Peer as Peer};
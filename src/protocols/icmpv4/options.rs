@@ -0,0 +1,85 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::time::Duration;
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// How long a host route learned from a redirect lasts by default.
+const DEFAULT_REDIRECT_TTL: Duration = Duration::from_secs(300);
+
+/// Control Options for ICMPv4
+#[derive(Clone, Debug)]
+pub struct Icmpv4Options {
+    /// Leave echo requests unanswered?
+    disable_echo_reply: bool,
+    /// Install a host route when a redirect tells us of a better next hop? Off by default, since
+    /// anyone on the path can forge a redirect.
+    accept_redirects: bool,
+    /// How long a host route learned from a redirect lasts.
+    redirect_ttl: Duration,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [Icmpv4Options].
+impl Icmpv4Options {
+    /// Creates custom options for ICMPv4.
+    pub fn new(disable_echo_reply: bool) -> Self {
+        Self {
+            disable_echo_reply,
+            accept_redirects: false,
+            redirect_ttl: DEFAULT_REDIRECT_TTL,
+        }
+    }
+
+    /// Sets whether redirects update our next hop for their destination (the equivalent of
+    /// `accept_redirects`). While it's off, every destination is resolved on-link.
+    pub fn set_accept_redirects(mut self, accept_redirects: bool) -> Self {
+        self.accept_redirects = accept_redirects;
+        self
+    }
+
+    /// Sets how long a host route learned from a redirect lasts, after which we go back to
+    /// resolving its destination on-link.
+    pub fn set_redirect_ttl(mut self, redirect_ttl: Duration) -> Self {
+        assert!(redirect_ttl > Duration::new(0, 0));
+        self.redirect_ttl = redirect_ttl;
+        self
+    }
+
+    /// Returns whether or not we ignore echo requests, which makes us unpingable.
+    pub fn disable_echo_reply(&self) -> bool {
+        self.disable_echo_reply
+    }
+
+    /// Returns whether or not redirects install host routes.
+    pub fn accept_redirects(&self) -> bool {
+        self.accept_redirects
+    }
+
+    /// Returns how long a host route learned from a redirect lasts.
+    pub fn redirect_ttl(&self) -> Duration {
+        self.redirect_ttl
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+/// Implementation of [Default] trait for [Icmpv4Options].
+impl Default for Icmpv4Options {
+    /// Creates default options for ICMPv4.
+    fn default() -> Self {
+        Icmpv4Options {
+            disable_echo_reply: false,
+            accept_redirects: false,
+            redirect_ttl: DEFAULT_REDIRECT_TTL,
+        }
+    }
+}
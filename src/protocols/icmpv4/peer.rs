@@ -8,9 +8,12 @@ use crate::{
         arp,
         ethernet2::frame::{EtherType2, Ethernet2Header},
         icmpv4::datagram::Icmpv4Message,
-        ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
+        ip, ipv4,
+        ipv4::datagram::{Ipv4Header, Ipv4Protocol2, IPV4_HEADER_SIZE},
+        tcp::SeqNumber,
     },
     runtime::Runtime,
+    scheduler::SchedulerHandle,
 };
 
 use byteorder::{ByteOrder, NetworkEndian};
@@ -26,10 +29,22 @@ use futures::{
 use crate::futures_utility::UtilityMethods;
 
 use std::{
-    cell::RefCell, collections::HashMap, future::Future, net::Ipv4Addr, num::Wrapping, process,
-    rc::Rc, time::Duration,
+    cell::RefCell, cmp, collections::HashMap, convert::TryFrom, future::Future, net::Ipv4Addr,
+    num::Wrapping, process, rc::Rc, time::Duration,
 };
 
+/// Destination unreachable code for a datagram sent to a port nothing is bound to.
+const PORT_UNREACHABLE: u8 = 3;
+
+/// Destination unreachable code for a datagram that needed fragmenting but had DF set.
+const FRAGMENTATION_NEEDED: u8 = 4;
+
+/// Common MTUs, largest first, for guessing the path MTU when a router doesn't report it (RFC
+/// 1191, section 7). The last is the smallest MTU IPv4 allows.
+const MTU_PLATEAUS: [u16; 11] = [
+    65535, 32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68,
+];
+
 //==============================================================================
 // ReqQueue
 //==============================================================================
@@ -55,6 +70,73 @@ impl ReqQueue {
     }
 }
 
+//==============================================================================
+// FragmentationNeeded
+//==============================================================================
+
+/// A "fragmentation needed" error about a TCP segment we sent, which tells us the MTU of the path
+/// the segment took (RFC 1191). The connection is identified from the segment's headers, which
+/// the error quotes.
+#[derive(Clone, Copy, Debug)]
+pub struct FragmentationNeeded {
+    pub local: ipv4::Endpoint,
+    pub remote: ipv4::Endpoint,
+    /// Sequence number of the segment that was too big.
+    pub seq_num: SeqNumber,
+    /// Total length of the datagram that was too big, from its quoted header.
+    pub datagram_len: u16,
+    /// MTU of the link the datagram couldn't be forwarded onto, or zero if the router predates
+    /// RFC 1191.
+    pub next_hop_mtu: u16,
+}
+
+impl FragmentationNeeded {
+    /// Parses the quoted IPv4 header and the first 8 bytes of its payload, returning `None` if
+    /// they aren't from a TCP segment.
+    fn parse(quoted: &[u8], next_hop_mtu: u16) -> Result<Option<Self>, Fail> {
+        if quoted.len() < IPV4_HEADER_SIZE {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error too small for quoted IPv4 header",
+            });
+        }
+        let ihl = (quoted[0] & 0xf) as usize * 4;
+        if ihl < IPV4_HEADER_SIZE || quoted.len() < ihl + 8 {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error too small for quoted datagram",
+            });
+        }
+        if quoted[9] != Ipv4Protocol2::Tcp as u8 {
+            return Ok(None);
+        }
+        let src_addr = Ipv4Addr::from(NetworkEndian::read_u32(&quoted[12..16]));
+        let dst_addr = Ipv4Addr::from(NetworkEndian::read_u32(&quoted[16..20]));
+        let src_port = ip::Port::try_from(NetworkEndian::read_u16(&quoted[ihl..(ihl + 2)]))?;
+        let dst_port = ip::Port::try_from(NetworkEndian::read_u16(&quoted[(ihl + 2)..(ihl + 4)]))?;
+        let seq_num = Wrapping(NetworkEndian::read_u32(&quoted[(ihl + 4)..(ihl + 8)]));
+        Ok(Some(Self {
+            local: ipv4::Endpoint::new(src_addr, src_port),
+            remote: ipv4::Endpoint::new(dst_addr, dst_port),
+            seq_num,
+            datagram_len: NetworkEndian::read_u16(&quoted[2..4]),
+            next_hop_mtu,
+        }))
+    }
+
+    /// The path MTU this error tells us about. If the router didn't report the next-hop MTU, we
+    /// guess the largest plateau below the length of the datagram that was too big.
+    pub fn path_mtu(&self) -> u16 {
+        let min_mtu = MTU_PLATEAUS[MTU_PLATEAUS.len() - 1];
+        if self.next_hop_mtu != 0 {
+            return cmp::max(self.next_hop_mtu, min_mtu);
+        }
+        MTU_PLATEAUS
+            .iter()
+            .copied()
+            .find(|&mtu| mtu < self.datagram_len)
+            .unwrap_or(min_mtu)
+    }
+}
+
 //==============================================================================
 // Icmpv4Peer
 //==============================================================================
@@ -76,8 +158,19 @@ pub struct Icmpv4Peer<RT: Runtime> {
     /// Underlying ARP Peer
     arp: arp::Peer<RT>,
 
-    /// Transmitter
-    tx: mpsc::UnboundedSender<(Ipv4Addr, u16, u16)>,
+    /// Messages for the background task to send: echo replies and errors.
+    tx: mpsc::UnboundedSender<(Ipv4Addr, Icmpv4Header, RT::Buf)>,
+
+    /// Task sending replies, which lives as long as we do.
+    #[allow(unused)]
+    background: SchedulerHandle,
+
+    /// Leave echo requests unanswered?
+    disable_echo_reply: bool,
+
+    /// Install host routes from redirects, and for how long?
+    accept_redirects: bool,
+    redirect_ttl: Duration,
 
     /// Queue of Requests
     requests: Rc<RefCell<ReqQueue>>,
@@ -91,11 +184,16 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
     pub fn new(rt: RT, arp: arp::Peer<RT>) -> Icmpv4Peer<RT> {
         let (tx, rx) = mpsc::unbounded();
         let requests = ReqQueue::new();
-        rt.spawn(Self::background(rt.clone(), arp.clone(), rx));
+        let background = rt.spawn(Self::background(rt.clone(), arp.clone(), rx));
+        let options = rt.icmpv4_options();
         Icmpv4Peer {
             rt,
             arp,
             tx,
+            background,
+            disable_echo_reply: options.disable_echo_reply(),
+            accept_redirects: options.accept_redirects(),
+            redirect_ttl: options.redirect_ttl(),
             requests: Rc::new(RefCell::new(requests)),
             seq: Wrapping(0),
         }
@@ -105,10 +203,10 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
     async fn background(
         rt: RT,
         arp: arp::Peer<RT>,
-        mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, u16, u16)>,
+        mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, Icmpv4Header, RT::Buf)>,
     ) {
         // Reply requests.
-        while let Some((dst_ipv4_addr, id, seq_num)) = rx.next().await {
+        while let Some((dst_ipv4_addr, icmpv4_hdr, data)) = rx.next().await {
             let r: Result<_, Fail> = try {
                 debug!("initiating ARP query");
                 let dst_link_addr = arp.query(dst_ipv4_addr).await?;
@@ -116,43 +214,114 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                     "ARP query complete ({} -> {})",
                     dst_ipv4_addr, dst_link_addr
                 );
-                // Send reply message.
-                rt.transmit(Icmpv4Message::new(
+                arp.transmit(Icmpv4Message::with_data(
                     Ethernet2Header::new(dst_link_addr, rt.local_link_addr(), EtherType2::Ipv4),
                     Ipv4Header::new(rt.local_ipv4_addr(), dst_ipv4_addr, Ipv4Protocol2::Icmpv4),
-                    Icmpv4Header::new(Icmpv4Type2::EchoReply { id, seq_num }, 0),
+                    icmpv4_hdr,
+                    data,
                 ));
             };
             if let Err(e) = r {
                 warn!(
-                    "reply_to_ping({}, {}, {}) failed: {:?}",
-                    dst_ipv4_addr, id, seq_num, e
+                    "Sending {:?} to {} failed: {:?}",
+                    icmpv4_hdr, dst_ipv4_addr, e
                 )
             }
         }
     }
 
-    /// Parses and handles a ICMP message.
-    pub fn receive(&mut self, ipv4_header: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
-        let (icmpv4_hdr, _) = Icmpv4Header::parse(buf)?;
+    /// Parses and handles a ICMP message. "Fragmentation needed" errors for TCP are returned, for
+    /// the caller to pass on.
+    pub fn receive(
+        &mut self,
+        ipv4_header: &Ipv4Header,
+        buf: RT::Buf,
+    ) -> Result<Option<FragmentationNeeded>, Fail> {
+        let (icmpv4_hdr, data) = Icmpv4Header::parse(buf)?;
         match icmpv4_hdr.icmpv4_type {
             Icmpv4Type2::EchoRequest { id, seq_num } => {
-                self.tx
-                    .unbounded_send((ipv4_header.src_addr, id, seq_num))
-                    .unwrap();
+                if self.disable_echo_reply {
+                    debug!("Ignoring ICMPv4 echo request from {}", ipv4_header.src_addr);
+                } else {
+                    // Echo the request's data back.
+                    let reply = Icmpv4Header::new(Icmpv4Type2::EchoReply { id, seq_num }, 0);
+                    self.tx
+                        .unbounded_send((ipv4_header.src_addr, reply, data))
+                        .unwrap();
+                }
             }
             Icmpv4Type2::EchoReply { id, seq_num } => {
                 if let Some(tx) = self.requests.borrow_mut().remove(&(id, seq_num)) {
                     let _ = tx.send(());
                 }
             }
+            Icmpv4Type2::DestinationUnreachable { next_hop_mtu }
+                if icmpv4_hdr.code == FRAGMENTATION_NEEDED =>
+            {
+                return FragmentationNeeded::parse(&data[..], next_hop_mtu);
+            }
+            Icmpv4Type2::RedirectMessage { gateway } => {
+                self.receive_redirect(ipv4_header.src_addr, gateway, &data[..])?;
+            }
             _ => {
                 warn!("Unsupported ICMPv4 message: {:?}", icmpv4_hdr);
             }
         }
+        Ok(None)
+    }
+
+    /// Sends traffic for the destination of the datagram a redirect quotes through `gateway`.
+    /// RFC 1122, section 3.2.2.2 has us treat network redirects as host redirects, and only
+    /// believe a redirect that comes from our current next hop for the destination.
+    fn receive_redirect(
+        &self,
+        src_addr: Ipv4Addr,
+        gateway: Ipv4Addr,
+        quoted: &[u8],
+    ) -> Result<(), Fail> {
+        if !self.accept_redirects {
+            debug!("Ignoring ICMPv4 redirect from {}", src_addr);
+            return Ok(());
+        }
+        if quoted.len() < IPV4_HEADER_SIZE {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error too small for quoted IPv4 header",
+            });
+        }
+        let quoted_src_addr = Ipv4Addr::from(NetworkEndian::read_u32(&quoted[12..16]));
+        let dst_addr = Ipv4Addr::from(NetworkEndian::read_u32(&quoted[16..20]));
+        if quoted_src_addr != self.rt.local_ipv4_addr()
+            || self.arp.next_hop(dst_addr) != src_addr
+            || gateway == self.rt.local_ipv4_addr()
+        {
+            warn!(
+                "Ignoring ICMPv4 redirect from {} for {} via {}",
+                src_addr, dst_addr, gateway
+            );
+            return Ok(());
+        }
+        debug!("Redirecting {} via {}", dst_addr, gateway);
+        self.arp
+            .add_host_route(dst_addr, gateway, self.redirect_ttl);
         Ok(())
     }
 
+    /// Tells the sender of `datagram`, which starts with its IPv4 header, that nothing is bound to
+    /// the port it was sent to. The error quotes the IPv4 header and the first 8 bytes of the
+    /// payload (RFC 792).
+    pub fn send_port_unreachable(&self, dst_ipv4_addr: Ipv4Addr, mut datagram: RT::Buf) {
+        let ihl = (datagram[0] & 0xf) as usize * 4;
+        let quoted_len = cmp::min(datagram.len(), ihl + 8);
+        datagram.trim(datagram.len() - quoted_len);
+        let error = Icmpv4Header::new(
+            Icmpv4Type2::DestinationUnreachable { next_hop_mtu: 0 },
+            PORT_UNREACHABLE,
+        );
+        self.tx
+            .unbounded_send((dst_ipv4_addr, error, datagram))
+            .unwrap();
+    }
+
     /// Computes the identifier for an ICPM message.
     fn make_id(&self) -> u16 {
         let mut state: u32 = 0xFFFF;
@@ -208,7 +377,7 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                 Ipv4Header::new(rt.local_ipv4_addr(), dst_ipv4_addr, Ipv4Protocol2::Icmpv4),
                 Icmpv4Header::new(echo_request, 0),
             );
-            rt.transmit(msg);
+            arp.transmit(msg);
             let rx = {
                 let (tx, rx) = channel();
                 assert!(requests.borrow_mut().insert((id, seq_num), tx).is_none());
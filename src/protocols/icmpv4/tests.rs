@@ -0,0 +1,185 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{Icmpv4Header, Icmpv4Message, Icmpv4Type2, Options};
+use crate::{
+    collections::bytes::Bytes,
+    engine::Engine,
+    protocols::{
+        arp,
+        ethernet2::{
+            frame::{serialize_frame, EtherType2, Ethernet2Header},
+            MacAddress,
+        },
+        ipv4::datagram::{Ipv4Header, Ipv4Protocol2, IPV4_HEADER_SIZE},
+    },
+    runtime::{Runtime, RuntimeBuf},
+    test_helpers::{self, DecodedFrame, TestRuntime, L4},
+};
+use futures::task::{noop_waker_ref, Context};
+use must_let::must_let;
+use std::{
+    future::Future,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+/// Builds an echo request from Bob to Alice.
+fn echo_request(id: u16, seq_num: u16, data: &[u8]) -> Bytes {
+    let msg = Icmpv4Message::with_data(
+        Ethernet2Header::new(
+            test_helpers::ALICE_MAC,
+            test_helpers::BOB_MAC,
+            EtherType2::Ipv4,
+        ),
+        Ipv4Header::new(
+            test_helpers::BOB_IPV4,
+            test_helpers::ALICE_IPV4,
+            Ipv4Protocol2::Icmpv4,
+        ),
+        Icmpv4Header::new(Icmpv4Type2::EchoRequest { id, seq_num }, 0),
+        Bytes::from_slice(data),
+    );
+    serialize_frame(msg, false).freeze()
+}
+
+/// Tests that echo requests are answered with the same identifier, sequence number and data.
+#[test]
+fn echo_reply() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+
+    alice.receive(echo_request(0x1234, 7, b"ping")).unwrap();
+    alice.rt().poll_scheduler();
+
+    let frame = alice.rt().pop_frame();
+    must_let!(let DecodedFrame::Ipv4 { header, payload: L4::Icmp(icmpv4_hdr, data) } = test_helpers::decode_frame(&frame));
+    assert_eq!(header.src_addr, test_helpers::ALICE_IPV4);
+    assert_eq!(header.dst_addr, test_helpers::BOB_IPV4);
+    assert_eq!(
+        icmpv4_hdr.icmpv4_type,
+        Icmpv4Type2::EchoReply {
+            id: 0x1234,
+            seq_num: 7
+        }
+    );
+    assert_eq!(icmpv4_hdr.code, 0);
+    assert_eq!(&data[..], b"ping");
+    assert!(alice.rt().try_pop_frame().is_none());
+}
+
+/// Tests that echo requests go unanswered when echo replies are disabled.
+#[test]
+fn echo_reply_disabled() {
+    let now = Instant::now();
+    let rt = TestRuntime::new(
+        "alice",
+        now,
+        test_helpers::ALICE_MAC,
+        test_helpers::ALICE_IPV4,
+    );
+    rt.set_icmpv4_options(Options::new(true));
+    let mut alice = Engine::new(rt).unwrap();
+
+    alice.receive(echo_request(0x1234, 7, b"ping")).unwrap();
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+}
+
+/// Builds a redirect from `src_addr` to Alice, telling her to reach Bob through `gateway`.
+fn redirect(src_addr: Ipv4Addr, gateway: Ipv4Addr) -> Bytes {
+    // Quote the IPv4 header of a datagram Alice sent Bob, and the first 8 bytes of its payload.
+    let mut quoted = [0u8; IPV4_HEADER_SIZE + 8];
+    quoted[0] = 0x45;
+    quoted[9] = Ipv4Protocol2::Icmpv4 as u8;
+    quoted[12..16].copy_from_slice(&test_helpers::ALICE_IPV4.octets());
+    quoted[16..20].copy_from_slice(&test_helpers::BOB_IPV4.octets());
+    let msg = Icmpv4Message::with_data(
+        Ethernet2Header::new(
+            test_helpers::ALICE_MAC,
+            test_helpers::BOB_MAC,
+            EtherType2::Ipv4,
+        ),
+        Ipv4Header::new(src_addr, test_helpers::ALICE_IPV4, Ipv4Protocol2::Icmpv4),
+        Icmpv4Header::new(Icmpv4Type2::RedirectMessage { gateway }, 1),
+        Bytes::from_slice(&quoted),
+    );
+    serialize_frame(msg, false).freeze()
+}
+
+/// Builds Alice with Bob and Carrie in her ARP cache.
+fn new_alice_with_carrie(now: Instant, options: Options) -> Engine<TestRuntime> {
+    let rt = TestRuntime::new(
+        "alice",
+        now,
+        test_helpers::ALICE_MAC,
+        test_helpers::ALICE_IPV4,
+    );
+    let mut arp_options = arp::Options::default();
+    arp_options
+        .initial_values
+        .insert(test_helpers::BOB_IPV4, test_helpers::BOB_MAC);
+    arp_options
+        .initial_values
+        .insert(test_helpers::CARRIE_IPV4, test_helpers::CARRIE_MAC);
+    rt.set_arp_options(arp_options);
+    rt.set_icmpv4_options(options);
+    Engine::new(rt).unwrap()
+}
+
+/// Pings Bob, returning the link address the echo request is sent to.
+fn ping_next_hop(alice: &mut Engine<TestRuntime>) -> MacAddress {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut ping = Box::pin(alice.ping(test_helpers::BOB_IPV4, None));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+
+    let frame = alice.rt().pop_frame();
+    must_let!(let DecodedFrame::Ipv4 { header, .. } = test_helpers::decode_frame(&frame));
+    assert_eq!(header.dst_addr, test_helpers::BOB_IPV4);
+    let (eth_hdr, _) = Ethernet2Header::parse(frame).unwrap();
+    eth_hdr.dst_addr
+}
+
+/// Tests that a redirect from our next hop sends traffic for its destination through the new
+/// gateway until the route expires.
+#[test]
+fn redirect_changes_next_hop() {
+    let now = Instant::now();
+    let redirect_ttl = Duration::from_secs(10);
+    let mut alice = new_alice_with_carrie(
+        now,
+        Options::default()
+            .set_accept_redirects(true)
+            .set_redirect_ttl(redirect_ttl),
+    );
+    assert_eq!(ping_next_hop(&mut alice), test_helpers::BOB_MAC);
+
+    // Only our current next hop for Bob can redirect us.
+    alice
+        .receive(redirect(
+            test_helpers::CARRIE_IPV4,
+            test_helpers::CARRIE_IPV4,
+        ))
+        .unwrap();
+    assert_eq!(ping_next_hop(&mut alice), test_helpers::BOB_MAC);
+
+    alice
+        .receive(redirect(test_helpers::BOB_IPV4, test_helpers::CARRIE_IPV4))
+        .unwrap();
+    assert_eq!(ping_next_hop(&mut alice), test_helpers::CARRIE_MAC);
+
+    alice.rt().advance_clock(now + redirect_ttl);
+    assert_eq!(ping_next_hop(&mut alice), test_helpers::BOB_MAC);
+}
+
+/// Tests that redirects are ignored unless they're turned on.
+#[test]
+fn redirect_disabled() {
+    let now = Instant::now();
+    let mut alice = new_alice_with_carrie(now, Options::default());
+
+    alice
+        .receive(redirect(test_helpers::BOB_IPV4, test_helpers::CARRIE_IPV4))
+        .unwrap();
+    assert_eq!(ping_next_hop(&mut alice), test_helpers::BOB_MAC);
+}
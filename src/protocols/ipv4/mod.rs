@@ -5,7 +5,9 @@
 pub mod datagram;
 mod endpoint;
 mod peer;
+mod prefix;
 
 pub use datagram::{Ipv4Header, Ipv4Protocol2};
 pub use endpoint::Ipv4Endpoint as Endpoint;
 pub use peer::Ipv4Peer as Peer;
+pub use prefix::prefix_contains;
@@ -7,6 +7,7 @@ use crate::file_table::FileDescriptor;
 use crate::{
     fail::Fail,
     file_table::FileTable,
+    memory_budget::MemoryBudget,
     protocols::{arp, icmpv4, tcp, udp},
     runtime::Runtime,
 };
@@ -20,10 +21,15 @@ pub struct Ipv4Peer<RT: Runtime> {
 }
 
 impl<RT: Runtime> Ipv4Peer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Ipv4Peer<RT> {
-        let udp = udp::Peer::new(rt.clone(), arp.clone(), file_table.clone());
+    pub fn new(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        file_table: FileTable,
+        memory: MemoryBudget,
+    ) -> Ipv4Peer<RT> {
+        let udp = udp::Peer::new(rt.clone(), arp.clone(), file_table.clone(), memory.clone());
         let icmpv4 = icmpv4::Peer::new(rt.clone(), arp.clone());
-        let tcp = tcp::Peer::new(rt.clone(), arp, file_table);
+        let tcp = tcp::Peer::new(rt.clone(), arp, file_table, memory);
         Ipv4Peer {
             rt,
             icmpv4,
@@ -33,15 +39,27 @@ impl<RT: Runtime> Ipv4Peer<RT> {
     }
 
     pub fn receive(&mut self, buf: RT::Buf) -> Result<(), Fail> {
+        // Kept around for quoting in ICMPv4 errors.
+        let datagram = buf.clone();
         let (header, payload) = Ipv4Header::parse(buf)?;
         debug!("Ipv4 received {:?}", header);
         if header.dst_addr != self.rt.local_ipv4_addr() && !header.dst_addr.is_broadcast() {
             return Err(Fail::Misdelivered {});
         }
         match header.protocol {
-            Ipv4Protocol2::Icmpv4 => self.icmpv4.receive(&header, payload),
+            Ipv4Protocol2::Icmpv4 => match self.icmpv4.receive(&header, payload)? {
+                Some(error) => self.tcp.receive_fragmentation_needed(error),
+                None => Ok(()),
+            },
             Ipv4Protocol2::Tcp => self.tcp.receive(&header, payload),
-            Ipv4Protocol2::Udp => self.udp.receive(&header, payload),
+            Ipv4Protocol2::Udp => match self.udp.receive(&header, payload) {
+                // Never send errors about broadcasts (RFC 1122, section 3.2.2).
+                Err(Fail::ConnectionRefused {}) if !header.dst_addr.is_broadcast() => {
+                    self.icmpv4.send_port_unreachable(header.src_addr, datagram);
+                    Err(Fail::ConnectionRefused {})
+                }
+                r => r,
+            },
         }
     }
 
@@ -0,0 +1,10 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::net::Ipv4Addr;
+
+/// Whether `addr` falls within the prefix `prefix/prefix_len`.
+pub fn prefix_contains(prefix: Ipv4Addr, prefix_len: u8, addr: Ipv4Addr) -> bool {
+    let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+    u32::from(prefix) & mask == u32::from(addr) & mask
+}
@@ -13,7 +13,7 @@ use crate::{
 
 use nix::{self, sys::socket, unistd};
 
-use std::{cell::RefCell, collections::HashMap, rc::Rc, time::Duration};
+use std::{cell::RefCell, collections::HashMap, net::Shutdown, rc::Rc, time::Duration};
 
 //==============================================================================
 // Constants & Structures
@@ -71,6 +71,12 @@ impl<RT: Runtime> PosixPeer<RT> {
         }
     }
 
+    /// Cancels the background task. It holds a reference to our inner state, so it would otherwise
+    /// keep the peer alive, and itself scheduled, after the stack is gone.
+    pub fn cancel_background(&self) {
+        self.inner.borrow_mut()._handle.take();
+    }
+
     /// Periodically pools asynchronous operations.
     async fn background(inner: Rc<RefCell<PosixPeerInner<RT>>>) {
         let rt = inner.borrow().rt.clone();
@@ -176,6 +182,22 @@ impl<RT: Runtime> PosixPeer<RT> {
         Ok(())
     }
 
+    /// Shuts down reading, writing or both on a connection.
+    pub fn shutdown(&self, fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
+        let how = match how {
+            Shutdown::Read => socket::Shutdown::Read,
+            Shutdown::Write => socket::Shutdown::Write,
+            Shutdown::Both => socket::Shutdown::Both,
+        };
+        match socket::shutdown(fd as i32, how) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!("failed to shutdown socket ({:?})", e);
+                Err(Fail::NotConnected {})
+            }
+        }
+    }
+
     /// Pushes data to a remote peer.
     pub fn push(&self, fd: FileDescriptor, buf: RT::Buf) -> futures::PushFuture<RT> {
         let sender = SomeWaker::default();
@@ -0,0 +1,63 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ipv4::prefix_contains;
+use std::net::Ipv4Addr;
+
+/// What a listener does with a SYN whose source address is rejected by its [AllowList].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeniedAction {
+    /// Silently drop the SYN.
+    Drop,
+    /// Answer the SYN with a RST.
+    Reset,
+}
+
+/// Source address filter for a listening socket. A SYN is accepted if its source address isn't
+/// covered by any deny prefix and, when there are allow prefixes, is covered by one of them.
+#[derive(Clone, Debug)]
+pub struct AllowList {
+    allow: Vec<(Ipv4Addr, u8)>,
+    deny: Vec<(Ipv4Addr, u8)>,
+    denied_action: DeniedAction,
+}
+
+impl Default for AllowList {
+    fn default() -> Self {
+        AllowList {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            denied_action: DeniedAction::Drop,
+        }
+    }
+}
+
+impl AllowList {
+    pub fn allow(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32);
+        self.allow.push((addr, prefix_len));
+        self
+    }
+
+    pub fn deny(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32);
+        self.deny.push((addr, prefix_len));
+        self
+    }
+
+    pub fn on_denied(mut self, value: DeniedAction) -> Self {
+        self.denied_action = value;
+        self
+    }
+
+    pub fn denied_action(&self) -> DeniedAction {
+        self.denied_action
+    }
+
+    pub fn allows(&self, addr: Ipv4Addr) -> bool {
+        if self.deny.iter().any(|&(p, l)| prefix_contains(p, l, addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|&(p, l)| prefix_contains(p, l, addr))
+    }
+}
@@ -3,31 +3,38 @@
 
 use super::{
     constants::FALLBACK_MSS,
-    established::state::{receiver::Receiver, sender::Sender, ControlBlock},
+    established::state::{
+        congestion_ctrl as cc, counters::ConnectionCounters, receiver::Receiver,
+        rto::RtoCalculator, sender::Sender, ControlBlock,
+    },
 };
 use crate::{
+    collections::watched::WatchedValue,
     fail::Fail,
+    memory_budget::MemoryBudget,
     protocols::{
         arp,
         ethernet2::frame::{EtherType2, Ethernet2Header},
         ipv4,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
         tcp::{
+            authentication::AuthState,
             segment::{TcpHeader, TcpOptions2, TcpSegment},
-            SeqNumber,
+            timestamps::{self, TimestampState},
+            ConnectionId, SeqNumber,
         },
     },
     runtime::{Runtime, RuntimeBuf},
     scheduler::SchedulerHandle,
 };
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     convert::TryInto,
     future::Future,
     num::Wrapping,
     rc::Rc,
     task::{Context, Poll, Waker},
-    time::Duration,
+    time::Instant,
 };
 
 struct ConnectResult<RT: Runtime> {
@@ -43,6 +50,10 @@ pub struct ActiveOpenSocket<RT: Runtime> {
 
     rt: RT,
     arp: arp::Peer<RT>,
+    memory: MemoryBudget,
+    congestion_ctrl: Option<cc::CongestionControlSelection<RT>>,
+    /// Origin of the connection's timestamp clock.
+    ts_base: Instant,
 
     #[allow(unused)]
     handle: SchedulerHandle,
@@ -56,17 +67,21 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         remote: ipv4::Endpoint,
         rt: RT,
         arp: arp::Peer<RT>,
+        memory: MemoryBudget,
+        congestion_ctrl: Option<cc::CongestionControlSelection<RT>>,
     ) -> Self {
         let result = ConnectResult {
             waker: None,
             result: None,
         };
         let result = Rc::new(RefCell::new(result));
+        let ts_base = rt.now();
 
         let future = Self::background(
             local_isn,
             local,
             remote,
+            ts_base,
             rt.clone(),
             arp.clone(),
             result.clone(),
@@ -80,6 +95,9 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             remote,
             rt,
             arp,
+            memory,
+            congestion_ctrl,
+            ts_base,
 
             handle,
             result,
@@ -97,6 +115,34 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         }
     }
 
+    /// Sends a RST at `seq_num`, e.g. in reply to a segment that acknowledged it.
+    fn send_rst(&self, seq_num: SeqNumber, auth: Option<&AuthState>) {
+        let remote_link_addr = match self.arp.try_query(self.remote.address()) {
+            Some(r) => r,
+            None => {
+                warn!("Not sending RST: no ARP entry for remote");
+                return;
+            }
+        };
+        let mut tcp_hdr = TcpHeader::new(self.local.port, self.remote.port);
+        tcp_hdr.rst = true;
+        tcp_hdr.seq_num = seq_num;
+        let signer = auth.map(|auth| auth.sign(&mut tcp_hdr));
+        let segment = TcpSegment {
+            ethernet2_hdr: Ethernet2Header {
+                dst_addr: remote_link_addr,
+                src_addr: self.rt.local_link_addr(),
+                ether_type: EtherType2::Ipv4,
+            },
+            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
+            tcp_hdr,
+            data: RT::Buf::empty(),
+            tx_checksum_offload: self.rt.tcp_options().tx_checksum_offload,
+            signer,
+        };
+        self.arp.transmit(segment);
+    }
+
     fn set_result(&mut self, result: Result<ControlBlock<RT>, Fail>) {
         let mut r = self.result.borrow_mut();
         if let Some(w) = r.waker.take() {
@@ -105,15 +151,45 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         r.result.replace(result);
     }
 
-    pub fn receive(&mut self, header: &TcpHeader) {
+    /// Handles a segment for this connection, where `segment` holds its raw TCP header and data.
+    pub fn receive(&mut self, header: &TcpHeader, segment: &[u8]) {
+        let tcp_options = self.rt.tcp_options();
+
+        // The SYN+ACK is the first time we learn the remote ISN, which goes into the keys.
+        let auth = AuthState::new(
+            &tcp_options.authentication_keys,
+            self.local,
+            self.remote,
+            self.local_isn,
+            header.seq_num,
+        );
+        if let Some(ref auth) = auth {
+            if let Err(e) = auth.verify(header, segment) {
+                warn!("Dropping segment that failed TCP-AO verification: {:?}", e);
+                return;
+            }
+        }
+
+        let expected_seq = self.local_isn + Wrapping(1);
         if header.rst {
-            self.set_result(Err(Fail::ConnectionRefused {}));
+            // A RST only refuses the connection if it acknowledges our SYN, so that a stray one
+            // can't (RFC 793, page 66).
+            if header.ack && header.ack_num == expected_seq {
+                self.set_result(Err(Fail::ConnectionRefused {}));
+            }
+            return;
+        }
+
+        // An ACK for anything but our SYN belongs to some other connection, so we reset it
+        // (RFC 793, page 66) and keep waiting for our own SYN+ACK.
+        if header.ack && header.ack_num != expected_seq {
+            warn!("Resetting unacceptable ACK in SYN-SENT: {:?}", header);
+            self.send_rst(header.ack_num, auth.as_ref());
             return;
         }
-        let expected_seq = self.local_isn + Wrapping(1);
 
-        // Bail if we didn't receive a SYN+ACK packet with the right sequence number.
-        if !(header.ack && header.syn && header.ack_num == expected_seq) {
+        // Bail if we didn't receive a SYN+ACK packet.
+        if !(header.ack && header.syn) {
             return;
         }
 
@@ -125,13 +201,20 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         };
         let remote_seq_num = header.seq_num + Wrapping(1);
 
-        let tcp_options = self.rt.tcp_options();
+        // Only use timestamps if we advertised them on the SYN and the peer did too.
+        let timestamps = timestamps::parse(header)
+            .filter(|_| tcp_options.timestamps)
+            .map(|(tsval, _)| TimestampState::new(self.ts_base, tsval));
 
         let mut tcp_hdr = TcpHeader::new(self.local.port, self.remote.port);
         tcp_hdr.ack = true;
         tcp_hdr.ack_num = remote_seq_num;
         tcp_hdr.window_size = tcp_options.receive_window_size;
         tcp_hdr.seq_num = self.local_isn + Wrapping(1);
+        if let Some(ref timestamps) = timestamps {
+            tcp_hdr.push_option(timestamps.option(self.rt.now()));
+        }
+        let signer = auth.as_ref().map(|auth| auth.sign(&mut tcp_hdr));
         debug!("Sending ACK: {:?}", tcp_hdr);
 
         let segment = TcpSegment {
@@ -144,11 +227,13 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             tcp_hdr,
             data: RT::Buf::empty(),
             tx_checksum_offload: tcp_options.tx_checksum_offload,
+            signer,
         };
-        self.rt.transmit(segment);
+        self.arp.transmit(segment);
 
         let mut remote_window_scale = None;
         let mut mss = FALLBACK_MSS;
+        let mut sack_permitted = false;
         for option in header.iter_options() {
             match option {
                 TcpOptions2::WindowScale(w) => {
@@ -159,6 +244,10 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
                     info!("Received advertised MSS: {}", m);
                     mss = *m as usize;
                 }
+                TcpOptions2::SelectiveAcknowlegementPermitted => {
+                    info!("Received SACK permitted");
+                    sack_permitted = tcp_options.sack;
+                }
                 _ => continue,
             }
         }
@@ -192,22 +281,53 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             local_window_scale, remote_window_scale
         );
 
+        let (cc_constructor, cc_options) = self.congestion_ctrl.clone().unwrap_or((
+            tcp_options.congestion_ctrl_type,
+            tcp_options.congestion_ctrl_options,
+        ));
         let sender = Sender::new(
             expected_seq,
             tx_window_size,
             remote_window_scale,
             mss,
-            tcp_options.congestion_ctrl_type,
-            tcp_options.congestion_ctrl_options,
+            !tcp_options.nodelay,
+            RtoCalculator::new(
+                tcp_options.rto_initial,
+                tcp_options.rto_min,
+                tcp_options.rto_max,
+            ),
+            cc_constructor,
+            cc_options,
+            self.memory.clone(),
+        );
+        let receiver = Receiver::new(
+            remote_seq_num,
+            rx_window_size,
+            local_window_scale,
+            tcp_options.delack_segs,
+            tcp_options.ack_delay_timeout,
+            tcp_options.rcvbuf_auto.then(|| tcp_options.rcvbuf_max),
+            self.memory.clone(),
         );
-        let receiver = Receiver::new(remote_seq_num, rx_window_size, local_window_scale);
         let cb = ControlBlock {
+            id: ConnectionId::next(),
             local: self.local,
             remote: self.remote,
             rt: self.rt.clone(),
             arp: self.arp.clone(),
+            tos: Cell::new(0),
+            path_mtu: Cell::new(None),
             sender,
             receiver,
+            time_wait_deadline: WatchedValue::new(None),
+            last_received: WatchedValue::new(self.rt.now()),
+            rejected_segments: Cell::new(0),
+            counters: Cell::new(ConnectionCounters::default()),
+            error: Cell::new(None),
+            closed: WatchedValue::new(false),
+            auth,
+            sack_permitted,
+            timestamps,
         };
         self.set_result(Ok(cb));
     }
@@ -216,13 +336,22 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         local_isn: SeqNumber,
         local: ipv4::Endpoint,
         remote: ipv4::Endpoint,
+        ts_base: Instant,
         rt: RT,
         arp: arp::Peer<RT>,
         result: Rc<RefCell<ConnectResult<RT>>>,
     ) -> impl Future<Output = ()> {
         let tcp_options = rt.tcp_options();
-        let handshake_retries = 3usize;
-        let handshake_timeout = Duration::from_secs(5);
+        let handshake_retries = tcp_options.handshake_retries;
+        let handshake_timeout = tcp_options.handshake_timeout;
+        // We don't know the remote ISN yet, so the SYN is signed with zero in its place.
+        let auth = AuthState::new(
+            &tcp_options.authentication_keys,
+            local,
+            remote,
+            local_isn,
+            Wrapping(0),
+        );
 
         async move {
             for _ in 0..handshake_retries {
@@ -239,13 +368,27 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
                 tcp_hdr.seq_num = local_isn;
                 tcp_hdr.window_size = tcp_options.receive_window_size;
 
-                let mss = tcp_options.advertised_mss as u16;
+                let mss = tcp_options.syn_mss(rt.mtu()) as u16;
                 tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
                 info!("Advertising MSS: {}", mss);
 
                 tcp_hdr.push_option(TcpOptions2::WindowScale(tcp_options.window_scale));
                 info!("Advertising window scale: {}", tcp_options.window_scale);
 
+                if tcp_options.sack {
+                    tcp_hdr.push_option(TcpOptions2::SelectiveAcknowlegementPermitted);
+                    info!("Advertising SACK permitted");
+                }
+
+                if tcp_options.timestamps {
+                    tcp_hdr.push_option(TcpOptions2::Timestamp {
+                        sender_timestamp: timestamps::tsval(ts_base, rt.now()),
+                        echo_timestamp: 0,
+                    });
+                    info!("Advertising timestamps");
+                }
+
+                let signer = auth.as_ref().map(|auth| auth.sign(&mut tcp_hdr));
                 debug!("Sending SYN {:?}", tcp_hdr);
                 let segment = TcpSegment {
                     ethernet2_hdr: Ethernet2Header {
@@ -257,8 +400,9 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
                     tcp_hdr,
                     data: RT::Buf::empty(),
                     tx_checksum_offload: tcp_options.tx_checksum_offload,
+                    signer,
                 };
-                rt.transmit(segment);
+                arp.transmit(segment);
                 rt.wait(handshake_timeout).await;
             }
             let mut r = result.borrow_mut();
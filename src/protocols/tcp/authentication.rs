@@ -0,0 +1,450 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! The TCP Authentication Option (TCP-AO, RFC 5925), which protects segments with a MAC keyed by
+//! a secret shared with the peer.
+//!
+//! Each connection derives traffic keys from the master key tuples (MKTs) configured for its peer
+//! using the KDF from RFC 5926, so no two connections (or directions of a connection) share a key.
+//! Once a connection has an MKT, every segment we send carries the option and every segment we
+//! receive must carry a valid one.
+
+use super::{
+    segment::{TcpHeader, TcpOptions2, MIN_TCP_HEADER_SIZE},
+    seq_gt, seq_lt, SeqNumber,
+};
+use crate::{
+    fail::Fail,
+    protocols::{ipv4, ipv4::datagram::Ipv4Protocol2},
+};
+use byteorder::{ByteOrder, NetworkEndian};
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+use sha2::Sha256;
+use std::{cell::Cell, fmt, net::Ipv4Addr, rc::Rc};
+
+pub const AO_OPTION_KIND: u8 = 29;
+
+/// Length of the MAC we put on each segment. Both algorithms are truncated to 96 bits, as in
+/// RFC 5926.
+pub const AO_MAC_LEN: usize = 12;
+
+const KDF_LABEL: &[u8] = b"TCP-AO";
+
+/// Hash function used both to derive traffic keys and to compute MACs with them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MacAlgorithm {
+    HmacSha1,
+    HmacSha256,
+}
+
+impl MacAlgorithm {
+    fn hmac(&self, key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            MacAlgorithm::HmacSha1 => hmac::<Hmac<Sha1>>(key, parts),
+            MacAlgorithm::HmacSha256 => hmac::<Hmac<Sha256>>(key, parts),
+        }
+    }
+
+    fn output_bits(&self) -> u16 {
+        match self {
+            MacAlgorithm::HmacSha1 => 160,
+            MacAlgorithm::HmacSha256 => 256,
+        }
+    }
+}
+
+fn hmac<M: Mac + NewMac>(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = M::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// A master key shared with a peer, along with the key IDs that name it on the wire. Several MKTs
+/// can be configured for the same peer to rotate between them.
+#[derive(Clone)]
+pub struct MasterKeyTuple {
+    remote: Option<Ipv4Addr>,
+    send_id: u8,
+    recv_id: u8,
+    algorithm: MacAlgorithm,
+    master_key: Rc<[u8]>,
+}
+
+impl MasterKeyTuple {
+    /// Creates an MKT for any peer. We put `send_id` in the KeyID of segments we sign with it, and
+    /// the peer puts our `recv_id` (its SendID) in the segments it signs.
+    pub fn new(send_id: u8, recv_id: u8, algorithm: MacAlgorithm, master_key: &[u8]) -> Self {
+        Self {
+            remote: None,
+            send_id,
+            recv_id,
+            algorithm,
+            master_key: master_key.into(),
+        }
+    }
+
+    /// Restricts the MKT to connections with `addr`.
+    pub fn remote(mut self, addr: Ipv4Addr) -> Self {
+        self.remote = Some(addr);
+        self
+    }
+
+    pub fn send_id(&self) -> u8 {
+        self.send_id
+    }
+
+    pub fn recv_id(&self) -> u8 {
+        self.recv_id
+    }
+
+    fn matches(&self, remote: &ipv4::Endpoint) -> bool {
+        self.remote.map_or(true, |addr| addr == remote.addr)
+    }
+
+    /// The KDF from RFC 5926, section 3.1.1, with `context` identifying the connection and
+    /// direction.
+    fn derive(&self, context: &[u8]) -> TrafficKey {
+        let mut output_length = [0; 2];
+        NetworkEndian::write_u16(&mut output_length, self.algorithm.output_bits());
+        let key = self.algorithm.hmac(
+            &self.master_key,
+            &[&[1], KDF_LABEL, context, &output_length],
+        );
+        TrafficKey {
+            algorithm: self.algorithm,
+            key: key.into(),
+        }
+    }
+}
+
+/// Leaves the master key out, so it doesn't end up in logs.
+impl fmt::Debug for MasterKeyTuple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MasterKeyTuple")
+            .field("remote", &self.remote)
+            .field("send_id", &self.send_id)
+            .field("recv_id", &self.recv_id)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+#[derive(Clone)]
+pub struct TrafficKey {
+    algorithm: MacAlgorithm,
+    key: Rc<[u8]>,
+}
+
+impl TrafficKey {
+    /// Computes the MAC of a segment (RFC 5925, section 5.1). `header` is the serialized TCP
+    /// header, whose checksum and MAC (at `mac_offset`) are treated as zeros.
+    fn mac(
+        &self,
+        sne: u32,
+        src_addr: Ipv4Addr,
+        dst_addr: Ipv4Addr,
+        header: &[u8],
+        mac_offset: usize,
+        data: &[u8],
+    ) -> [u8; AO_MAC_LEN] {
+        let mut prefix = [0; 16];
+        NetworkEndian::write_u32(&mut prefix[0..4], sne);
+        prefix[4..8].copy_from_slice(&src_addr.octets());
+        prefix[8..12].copy_from_slice(&dst_addr.octets());
+        prefix[13] = Ipv4Protocol2::Tcp as u8;
+        NetworkEndian::write_u16(&mut prefix[14..16], (header.len() + data.len()) as u16);
+
+        let output = self.algorithm.hmac(
+            &self.key,
+            &[
+                &prefix,
+                &header[..16],
+                &[0; 2],
+                &header[18..mac_offset],
+                &[0; AO_MAC_LEN],
+                &header[(mac_offset + AO_MAC_LEN)..],
+                data,
+            ],
+        );
+        let mut mac = [0; AO_MAC_LEN];
+        mac.copy_from_slice(&output[..AO_MAC_LEN]);
+        mac
+    }
+}
+
+/// What [TcpHeader::serialize] needs to fill in the MAC of an outgoing segment.
+#[derive(Clone)]
+pub struct SegmentSigner {
+    key: TrafficKey,
+    sne: u32,
+}
+
+impl SegmentSigner {
+    pub fn sign(
+        &self,
+        src_addr: Ipv4Addr,
+        dst_addr: Ipv4Addr,
+        header: &[u8],
+        mac_offset: usize,
+        data: &[u8],
+    ) -> [u8; AO_MAC_LEN] {
+        self.key
+            .mac(self.sne, src_addr, dst_addr, header, mac_offset, data)
+    }
+}
+
+/// Counts how many times sequence numbers in one direction have wrapped around, which gives the
+/// sequence number extension (SNE) mixed into each MAC (RFC 5925, section 6.2).
+#[derive(Clone)]
+struct SneTracker {
+    sne: Cell<u32>,
+    highest_seq_no: Cell<SeqNumber>,
+}
+
+impl SneTracker {
+    fn new(isn: SeqNumber) -> Self {
+        Self {
+            sne: Cell::new(0),
+            highest_seq_no: Cell::new(isn),
+        }
+    }
+
+    fn sne(&self, seq_no: SeqNumber) -> u32 {
+        let highest = self.highest_seq_no.get();
+        let sne = self.sne.get();
+        if seq_gt(seq_no, highest) && seq_no.0 < highest.0 {
+            sne.wrapping_add(1)
+        } else if seq_lt(seq_no, highest) && seq_no.0 > highest.0 {
+            // A retransmission from before the last wrap.
+            sne.wrapping_sub(1)
+        } else {
+            sne
+        }
+    }
+
+    fn update(&self, seq_no: SeqNumber) {
+        if seq_gt(seq_no, self.highest_seq_no.get()) {
+            self.sne.set(self.sne(seq_no));
+            self.highest_seq_no.set(seq_no);
+        }
+    }
+}
+
+/// The traffic keys derived from one MKT for a particular connection.
+#[derive(Clone)]
+struct ConnectionKey {
+    send_id: u8,
+    recv_id: u8,
+    send: TrafficKey,
+    recv: TrafficKey,
+}
+
+/// TCP-AO state for a single connection.
+#[derive(Clone)]
+pub struct AuthState {
+    local: ipv4::Endpoint,
+    remote: ipv4::Endpoint,
+    keys: Vec<ConnectionKey>,
+    /// Index into `keys` of the key we sign outgoing segments with.
+    current_key: Cell<usize>,
+    /// RecvID of the key we'd like the peer to sign with, which we advertise as RNextKeyID.
+    rnext_key_id: Cell<u8>,
+    send_sne: SneTracker,
+    recv_sne: SneTracker,
+}
+
+impl AuthState {
+    /// Derives the traffic keys for a connection from the MKTs that match `remote`, returning
+    /// `None` if there aren't any. During the handshake, use zero for an ISN we don't know yet
+    /// (the peer's when sending a SYN, or ours when receiving one).
+    pub fn new(
+        mkts: &[MasterKeyTuple],
+        local: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+        local_isn: SeqNumber,
+        remote_isn: SeqNumber,
+    ) -> Option<Self> {
+        let send_context = Self::context(&local, &remote, local_isn, remote_isn);
+        let recv_context = Self::context(&remote, &local, remote_isn, local_isn);
+        let keys: Vec<_> = mkts
+            .iter()
+            .filter(|mkt| mkt.matches(&remote))
+            .map(|mkt| ConnectionKey {
+                send_id: mkt.send_id,
+                recv_id: mkt.recv_id,
+                send: mkt.derive(&send_context),
+                recv: mkt.derive(&recv_context),
+            })
+            .collect();
+        let first = keys.first()?;
+        let rnext_key_id = first.recv_id;
+        Some(Self {
+            local,
+            remote,
+            keys,
+            current_key: Cell::new(0),
+            rnext_key_id: Cell::new(rnext_key_id),
+            send_sne: SneTracker::new(local_isn),
+            recv_sne: SneTracker::new(remote_isn),
+        })
+    }
+
+    fn context(
+        src: &ipv4::Endpoint,
+        dst: &ipv4::Endpoint,
+        src_isn: SeqNumber,
+        dst_isn: SeqNumber,
+    ) -> [u8; 20] {
+        let mut context = [0; 20];
+        context[0..4].copy_from_slice(&src.addr.octets());
+        context[4..8].copy_from_slice(&dst.addr.octets());
+        NetworkEndian::write_u16(&mut context[8..10], src.port.into());
+        NetworkEndian::write_u16(&mut context[10..12], dst.port.into());
+        NetworkEndian::write_u32(&mut context[12..16], src_isn.0);
+        NetworkEndian::write_u32(&mut context[16..20], dst_isn.0);
+        context
+    }
+
+    /// KeyID of the key we're currently signing with.
+    pub fn current_key_id(&self) -> u8 {
+        self.keys[self.current_key.get()].send_id
+    }
+
+    /// Asks the peer to switch to signing with the key we know as `recv_id`.
+    pub fn set_rnext_key_id(&self, recv_id: u8) -> Result<(), Fail> {
+        if !self.keys.iter().any(|k| k.recv_id == recv_id) {
+            return Err(Fail::ResourceNotFound {
+                details: "No TCP-AO key with that ID",
+            });
+        }
+        self.rnext_key_id.set(recv_id);
+        Ok(())
+    }
+
+    /// Adds the TCP-AO option to an outgoing header, returning what's needed to fill in its MAC
+    /// once the segment is serialized.
+    pub fn sign(&self, header: &mut TcpHeader) -> SegmentSigner {
+        let key = &self.keys[self.current_key.get()];
+        header.push_option(TcpOptions2::Authentication {
+            key_id: key.send_id,
+            rnext_key_id: self.rnext_key_id.get(),
+            mac: [0; AO_MAC_LEN],
+        });
+        let sne = self.send_sne.sne(header.seq_num);
+        self.send_sne.update(header.seq_num);
+        SegmentSigner {
+            key: key.send.clone(),
+            sne,
+        }
+    }
+
+    /// Checks the MAC on an incoming segment, where `segment` holds the raw TCP header and data.
+    /// Valid segments also tell us which of our keys the peer would like us to sign with.
+    pub fn verify(&self, header: &TcpHeader, segment: &[u8]) -> Result<(), Fail> {
+        let (key_id, rnext_key_id, mac) = header
+            .iter_options()
+            .find_map(|option| match option {
+                TcpOptions2::Authentication {
+                    key_id,
+                    rnext_key_id,
+                    mac,
+                } => Some((*key_id, *rnext_key_id, *mac)),
+                _ => None,
+            })
+            .ok_or(Fail::Malformed {
+                details: "Missing TCP-AO option",
+            })?;
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.recv_id == key_id)
+            .ok_or(Fail::Malformed {
+                details: "Unknown TCP-AO key ID",
+            })?;
+
+        let data_offset = (segment[12] >> 4) as usize * 4;
+        let (hdr_buf, data_buf) = segment.split_at(data_offset);
+        let mac_offset = find_mac_offset(hdr_buf).ok_or(Fail::Malformed {
+            details: "Missing TCP-AO option",
+        })?;
+        let sne = self.recv_sne.sne(header.seq_num);
+        let expected = key.recv.mac(
+            sne,
+            self.remote.addr,
+            self.local.addr,
+            hdr_buf,
+            mac_offset,
+            data_buf,
+        );
+        // Compare the whole MAC regardless of where the first difference is.
+        if expected
+            .iter()
+            .zip(&mac)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            != 0
+        {
+            return Err(Fail::Malformed {
+                details: "TCP-AO MAC mismatch",
+            });
+        }
+        self.recv_sne.update(header.seq_num);
+
+        if rnext_key_id != self.current_key_id() {
+            match self.keys.iter().position(|k| k.send_id == rnext_key_id) {
+                Some(ix) => {
+                    debug!("Switching to TCP-AO key {}", rnext_key_id);
+                    self.current_key.set(ix);
+                }
+                None => warn!("Peer requested unknown TCP-AO key {}", rnext_key_id),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds where the MAC of the TCP-AO option starts in a serialized TCP header.
+fn find_mac_offset(header: &[u8]) -> Option<usize> {
+    let mut pos = MIN_TCP_HEADER_SIZE;
+    while pos < header.len() {
+        match header[pos] {
+            0 => return None,
+            1 => pos += 1,
+            kind => {
+                let len = *header.get(pos + 1)? as usize;
+                if kind == AO_OPTION_KIND {
+                    return if pos + 4 + AO_MAC_LEN <= header.len() {
+                        Some(pos + 4)
+                    } else {
+                        None
+                    };
+                }
+                if len < 2 {
+                    return None;
+                }
+                pos += len;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SneTracker;
+    use std::num::Wrapping;
+
+    #[test]
+    fn test_sne_counts_wraparounds() {
+        let tracker = SneTracker::new(Wrapping(u32::MAX - 10));
+        assert_eq!(tracker.sne(Wrapping(u32::MAX)), 0);
+        assert_eq!(tracker.sne(Wrapping(5)), 1);
+        tracker.update(Wrapping(5));
+
+        // Retransmissions of data from before the wrap keep the old SNE.
+        assert_eq!(tracker.sne(Wrapping(u32::MAX - 2)), 0);
+        assert_eq!(tracker.sne(Wrapping(100)), 1);
+    }
+}
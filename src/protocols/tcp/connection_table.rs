@@ -0,0 +1,147 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ipv4;
+use std::{
+    collections::{hash_map, HashMap},
+    net::Ipv4Addr,
+};
+
+/// The local and remote endpoints that identify a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FourTuple {
+    pub local: ipv4::Endpoint,
+    pub remote: ipv4::Endpoint,
+}
+
+impl FourTuple {
+    pub fn new(local: ipv4::Endpoint, remote: ipv4::Endpoint) -> Self {
+        Self { local, remote }
+    }
+}
+
+/// Connections keyed by their [FourTuple], so demultiplexing a segment to its connection is a
+/// single hash lookup however many connections are open.
+pub struct ConnectionTable<T> {
+    connections: HashMap<FourTuple, T>,
+}
+
+impl<T> ConnectionTable<T> {
+    pub fn new() -> Self {
+        Self {
+            connections: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &FourTuple) -> Option<&T> {
+        self.connections.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &FourTuple) -> Option<&mut T> {
+        self.connections.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: FourTuple, connection: T) -> Option<T> {
+        self.connections.insert(key, connection)
+    }
+
+    pub fn remove(&mut self, key: &FourTuple) -> Option<T> {
+        self.connections.remove(key)
+    }
+
+    pub fn values(&self) -> hash_map::Values<FourTuple, T> {
+        self.connections.values()
+    }
+}
+
+/// Listeners keyed by the local endpoint they're bound to, whose address may be the wildcard.
+pub struct ListenerTable<T> {
+    listeners: HashMap<ipv4::Endpoint, T>,
+}
+
+impl<T> ListenerTable<T> {
+    pub fn new() -> Self {
+        Self {
+            listeners: HashMap::new(),
+        }
+    }
+
+    pub fn contains_key(&self, local: &ipv4::Endpoint) -> bool {
+        self.listeners.contains_key(local)
+    }
+
+    pub fn get(&self, local: &ipv4::Endpoint) -> Option<&T> {
+        self.listeners.get(local)
+    }
+
+    pub fn get_mut(&mut self, local: &ipv4::Endpoint) -> Option<&mut T> {
+        self.listeners.get_mut(local)
+    }
+
+    pub fn insert(&mut self, local: ipv4::Endpoint, listener: T) -> Option<T> {
+        self.listeners.insert(local, listener)
+    }
+
+    pub fn remove(&mut self, local: &ipv4::Endpoint) -> Option<T> {
+        self.listeners.remove(local)
+    }
+
+    /// Finds the listener for segments sent to `local`: the one bound to exactly that endpoint if
+    /// there is one, or else the one bound to the wildcard address on its port. Returns the
+    /// endpoint the listener is bound to.
+    pub fn lookup(&self, local: &ipv4::Endpoint) -> Option<ipv4::Endpoint> {
+        if self.listeners.contains_key(local) {
+            return Some(*local);
+        }
+        let wildcard = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, local.port);
+        self.listeners.get(&wildcard).map(|_| wildcard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::ip;
+    use std::convert::TryFrom;
+
+    fn endpoint(addr: [u8; 4], port: u16) -> ipv4::Endpoint {
+        ipv4::Endpoint::new(Ipv4Addr::from(addr), ip::Port::try_from(port).unwrap())
+    }
+
+    #[test]
+    fn connections_demux_on_all_four_fields() {
+        let mut table = ConnectionTable::new();
+        let local = endpoint([10, 0, 0, 1], 80);
+        for port in 1024..1088 {
+            let remote = endpoint([10, 0, 0, 2], port);
+            assert!(table.insert(FourTuple::new(local, remote), port).is_none());
+        }
+        let other = endpoint([10, 0, 0, 3], 1030);
+        table.insert(FourTuple::new(local, other), 0);
+
+        let remote = endpoint([10, 0, 0, 2], 1030);
+        assert_eq!(table.get(&FourTuple::new(local, remote)), Some(&1030));
+        assert_eq!(table.get(&FourTuple::new(local, other)), Some(&0));
+        assert_eq!(table.get(&FourTuple::new(remote, local)), None);
+        assert_eq!(table.remove(&FourTuple::new(local, remote)), Some(1030));
+        assert_eq!(table.get(&FourTuple::new(local, remote)), None);
+        assert_eq!(table.values().count(), 64);
+    }
+
+    #[test]
+    fn specific_listener_shadows_wildcard() {
+        let mut table = ListenerTable::new();
+        let specific = endpoint([10, 0, 0, 1], 80);
+        let wildcard = endpoint([0, 0, 0, 0], 80);
+        table.insert(wildcard, ());
+        assert_eq!(table.lookup(&specific), Some(wildcard));
+
+        table.insert(specific, ());
+        assert_eq!(table.lookup(&specific), Some(specific));
+        assert_eq!(table.lookup(&endpoint([10, 0, 0, 9], 80)), Some(wildcard));
+        assert_eq!(table.lookup(&endpoint([10, 0, 0, 1], 81)), None);
+
+        table.remove(&wildcard);
+        assert_eq!(table.lookup(&endpoint([10, 0, 0, 9], 80)), None);
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+use std::time::Duration;
+
 // from [TCP/IP Illustrated](https://learning.oreilly.com/library/view/tcpip-illustrated-volume/9780132808200/ch13.html):
 // > if no MSS option is provided, a default value of 536 bytes is used.
 pub const FALLBACK_MSS: usize = 536;
@@ -10,3 +12,20 @@ pub const MAX_MSS: usize = u16::max_value() as usize;
 
 // TODO: does this need to be determined through MTU discovery?
 pub const DEFAULT_MSS: usize = 1450;
+
+// RFC 6298, section 2.1: until we have an RTT measurement, the RTO is one second.
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+// RFC 6298, section 2.4: the RTO is rounded up to one second if it comes out any lower.
+pub const DEFAULT_RTO_MIN: Duration = Duration::from_secs(1);
+
+// RFC 6298, section 2.5: a maximum of at least 60 seconds.
+pub const DEFAULT_RTO_MAX: Duration = Duration::from_secs(60);
+
+// The most SACK blocks that fit in the 40 bytes of TCP options, alongside the end of options list.
+// Segments that also carry timestamps or TCP-AO have room for fewer.
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+// RFC 793 suggests an MSL of 2 minutes, but like most implementations we default to something
+// shorter.
+pub const DEFAULT_MSL: Duration = Duration::from_secs(30);
@@ -14,12 +14,6 @@ use std::rc::Rc;
 
 pub async fn acknowledger<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
     loop {
-        // TODO: Implement TCP delayed ACKs, subject to restrictions from RFC 1122
-        // - TCP should implement a delayed ACK
-        // - The delay must be less than 500ms
-        // - For a stream of full-sized segments, there should be an ack for every other segment.
-
-        // TODO: Implement SACKs
         let (ack_deadline, ack_deadline_changed) = cb.receiver.ack_deadline.watch();
         futures::pin_mut!(ack_deadline_changed);
 
@@ -32,8 +26,8 @@ pub async fn acknowledger<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fa
         futures::select_biased! {
             _ = ack_deadline_changed => continue,
             _ = ack_future => {
+                // This may be a duplicate of our last ACK, if out-of-order data prompted it.
                 let recv_seq_no = cb.receiver.recv_seq_no.get();
-                assert_ne!(cb.receiver.ack_seq_no.get(), recv_seq_no);
 
                 let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
 
@@ -9,9 +9,10 @@ use crate::{
     runtime::{Runtime, RuntimeBuf},
 };
 use futures::FutureExt;
-use std::{num::Wrapping, rc::Rc};
+use std::{cell::Cell, num::Wrapping, rc::Rc};
 
-/// Await until our state changes to `ReceivedFin`. Then sends an ACK for the received FIN.
+/// Await until our state changes to `ReceivedFin`. Then sends an ACK for the received FIN. This
+/// also happens for retransmitted FINs, in which case we restart the TIME-WAIT timer if it's running.
 async fn sender_ack_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
     loop {
         // Wait until we receive a FIN.
@@ -21,10 +22,11 @@ async fn sender_ack_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail
             continue;
         }
 
-        // Wait for all data to be acknowledged.
+        // Wait for all data to be acknowledged. If we've already ACKed a previous copy of this FIN,
+        // our ACK sequence number will be one past `recv_seq`.
         let (ack_seq, ack_seq_changed) = cb.receiver.ack_seq_no.watch();
         let recv_seq = cb.receiver.recv_seq_no.get();
-        if ack_seq != recv_seq {
+        if ack_seq != recv_seq && ack_seq != recv_seq + Wrapping(1) {
             ack_seq_changed.await;
             continue;
         }
@@ -39,16 +41,27 @@ async fn sender_ack_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail
         header.ack = true;
         header.ack_num = recv_seq + Wrapping(1);
         cb.emit(header, RT::Buf::empty(), remote_link_addr);
+
+        if cb.time_wait_deadline.get().is_some() {
+            let msl = cb.rt.tcp_options().msl;
+            cb.time_wait_deadline.set(Some(cb.rt.now() + 2 * msl));
+        }
     }
 }
 
 /// Spawns a future that awaits for sender status to change to Closed . Once status is Closed
 /// sends FIN. Then goes back to a awaiting change until/if any further changes to our SenderState.
-async fn sender_send_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+async fn sender_send_fin<RT: Runtime>(
+    cb: Rc<ControlBlock<RT>>,
+    active_close: &Cell<bool>,
+) -> Result<!, Fail> {
     loop {
         let (sender_st, sender_st_changed) = cb.sender.state.watch();
         match sender_st {
-            SenderState::Open | SenderState::SentFin | SenderState::FinAckd => {
+            SenderState::Open
+            | SenderState::SentFin
+            | SenderState::FinAckd
+            | SenderState::TimedOut => {
                 sender_st_changed.await;
                 continue;
             }
@@ -70,6 +83,8 @@ async fn sender_send_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fai
                 header.fin = true;
                 cb.emit(header, RT::Buf::empty(), remote_link_addr);
 
+                // We're the active closer if the other side hasn't sent its FIN yet.
+                active_close.set(cb.receiver.state.get() == ReceiverState::Open);
                 cb.sender.state.set(SenderState::SentFin);
             }
             SenderState::Reset => {
@@ -84,7 +99,10 @@ async fn sender_send_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fai
 }
 
 /// Awaits until connection terminates by our four-way handshake.
-async fn close_wait<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+async fn close_wait<RT: Runtime>(
+    cb: Rc<ControlBlock<RT>>,
+    active_close: &Cell<bool>,
+) -> Result<!, Fail> {
     loop {
         // Wait until the FIN we sent has been ACKed.
         let (sender_st, sender_st_changed) = cb.sender.state.watch();
@@ -100,17 +118,35 @@ async fn close_wait<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
             continue;
         }
 
-        // TODO: Wait for 2*MSL if active close.
+        if active_close.get() && cb.rt.tcp_options().linger_in_time_wait {
+            time_wait(&cb).await;
+        }
         return Err(Fail::ConnectionAborted {});
     }
 }
 
+/// Lingers in TIME-WAIT until 2*MSL passes without the deadline being pushed back by a
+/// retransmitted FIN.
+async fn time_wait<RT: Runtime>(cb: &ControlBlock<RT>) {
+    let msl = cb.rt.tcp_options().msl;
+    cb.time_wait_deadline.set(Some(cb.rt.now() + 2 * msl));
+    loop {
+        let (deadline, deadline_changed) = cb.time_wait_deadline.watch();
+        let deadline = deadline.expect("TIME-WAIT deadline unset");
+        futures::select_biased! {
+            _ = deadline_changed.fuse() => continue,
+            _ = cb.rt.wait_until(deadline).fuse() => return,
+        }
+    }
+}
+
 /// Launches various closures having to do with connection termination. Neither `sender_ack_fin`
 /// nor `sender_send_fin` terminate so the only way to return is via `close_wait`.
 pub async fn connection_terminated<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    let active_close = Cell::new(false);
     futures::select_biased! {
         r = sender_ack_fin(cb.clone()).fuse() => r,
-        r = sender_send_fin(cb.clone()).fuse() => r,
-        r = close_wait(cb).fuse() => r,
+        r = sender_send_fin(cb.clone(), &active_close).fuse() => r,
+        r = close_wait(cb, &active_close).fuse() => r,
     }
 }
@@ -0,0 +1,56 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::super::state::ControlBlock;
+use crate::{fail::Fail, runtime::Runtime};
+use futures::{future, FutureExt};
+use std::rc::Rc;
+
+/// Probes the peer once the connection has gone quiet for the keep-alive time, and fails the
+/// connection if it doesn't answer any of our probes.
+pub async fn keepalive<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    let options = cb.rt.tcp_options();
+    let idle_timeout = match options.keepalive_time {
+        Some(timeout) => timeout,
+        None => future::pending().await,
+    };
+    'top: loop {
+        let (last_received, received) = cb.last_received.watch();
+        futures::pin_mut!(received);
+
+        // While we have data in flight, the retransmitter finds out if the peer has gone away.
+        let (base_seq, base_seq_changed) = cb.sender.base_seq_no.watch();
+        futures::pin_mut!(base_seq_changed);
+        let (sent_seq, sent_seq_changed) = cb.sender.sent_seq_no.watch();
+        futures::pin_mut!(sent_seq_changed);
+        if base_seq != sent_seq {
+            futures::select_biased! {
+                _ = received => continue 'top,
+                _ = base_seq_changed => continue 'top,
+                _ = sent_seq_changed => continue 'top,
+            }
+        }
+
+        futures::select_biased! {
+            _ = received => continue 'top,
+            _ = sent_seq_changed => continue 'top,
+            _ = cb.rt.wait_until(last_received + idle_timeout).fuse() => (),
+        }
+
+        // Any segment from the peer, most likely the ACK for a probe, shows it's still there.
+        for _ in 0..options.keepalive_probes {
+            let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+            cb.send_keepalive(remote_link_addr);
+            futures::select_biased! {
+                _ = received => continue 'top,
+                _ = cb.rt.wait(options.keepalive_intvl).fuse() => (),
+            }
+        }
+        warn!(
+            "{}: No answer to {} keep-alive probes",
+            cb.id, options.keepalive_probes
+        );
+        cb.time_out();
+        return Err(Fail::Timeout {});
+    }
+}
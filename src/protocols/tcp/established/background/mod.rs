@@ -3,12 +3,16 @@
 
 mod acknowledger;
 mod closer;
+mod keepalive;
 mod retransmitter;
 mod sender;
 
 use self::{
-    acknowledger::acknowledger, closer::connection_terminated, retransmitter::retransmitter,
-    sender::sender,
+    acknowledger::acknowledger,
+    closer::connection_terminated,
+    keepalive::keepalive,
+    retransmitter::retransmitter,
+    sender::{sender, stall_detector},
 };
 use super::state::ControlBlock;
 use crate::{file_table::FileDescriptor, runtime::Runtime};
@@ -38,16 +42,25 @@ pub fn background<RT: Runtime>(
         let sender = sender(cb.clone()).fuse();
         futures::pin_mut!(sender);
 
-        let closer = connection_terminated(cb).fuse();
+        let closer = connection_terminated(cb.clone()).fuse();
         futures::pin_mut!(closer);
 
+        let stall_detector = stall_detector(cb.clone()).fuse();
+        futures::pin_mut!(stall_detector);
+
+        let keepalive = keepalive(cb.clone()).fuse();
+        futures::pin_mut!(keepalive);
+
         let r = futures::select_biased! {
             r = acknowledger => r,
             r = retransmitter => r,
             r = sender => r,
             r = closer => r,
+            r = stall_detector => r,
+            r = keepalive => r,
         };
         error!("Connection (fd {}) terminated: {:?}", fd, r);
+        cb.closed.set(true);
 
         // TODO Properly clean up Peer state for this connection.
         // dead_socket_tx
@@ -21,29 +21,40 @@ pub async fn retransmit<RT: Runtime>(
     // Our retransmission timer fired, so we need to resend a packet.
     let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
 
-    let mut unacked_queue = cb.sender.unacked_queue.borrow_mut();
     let mut rto = cb.sender.rto.borrow_mut();
 
-    let seq_no = cb.sender.base_seq_no.get();
-    let segment = match unacked_queue.front_mut() {
-        Some(s) => s,
-        None => panic!("Retransmission timer set with empty acknowledge queue"),
-    };
-
-    // TODO: Repacketization
-
     // NOTE: Congestion Control Don't think we record a failure on Fast Retransmit, but can't find a definitive source.
     match cause {
         RetransmitCause::TimeOut => rto.record_failure(),
         RetransmitCause::FastRetransmit => (),
     };
 
-    // Unset the initial timestamp so we don't use this for RTT estimation.
-    segment.initial_tx.take();
+    // This also unsets the initial timestamp so we don't use the segment for RTT estimation.
+    let seq_no = cb.sender.base_seq_no.get();
+    let segment = match cb
+        .sender
+        .send_buffer
+        .borrow_mut()
+        .retransmit_front(cb.sender.mss.get())
+    {
+        Some(s) => s,
+        None => panic!("Retransmission timer set with empty acknowledge queue"),
+    };
 
     let mut header = cb.tcp_header();
     header.seq_num = seq_no;
-    cb.emit(header, segment.bytes.clone(), remote_link_addr);
+    cb.emit(header, segment, remote_link_addr);
+    cb.update_counters(|c| c.retransmits += 1);
+
+    // If the peer's SACK blocks show it's missing more than the front segment, fill in the other
+    // holes now rather than waiting for each to reach the front in turn.
+    let holes = cb.sender.send_buffer.borrow_mut().retransmit_holes();
+    for (seq_no, segment) in holes {
+        let mut header = cb.tcp_header();
+        header.seq_num = seq_no;
+        cb.emit(header, segment, remote_link_addr);
+        cb.update_counters(|c| c.retransmits += 1);
+    }
 
     // Set new retransmit deadline
     let deadline = cb.rt.now() + rto.estimate();
@@ -1,12 +1,14 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::super::state::{sender::UnackedSegment, ControlBlock};
-use crate::{fail::Fail, runtime::Runtime};
-use futures::FutureExt;
+use super::super::state::ControlBlock;
+use crate::{fail::Fail, futures_utility::yield_now, runtime::Runtime};
+use futures::{future, FutureExt};
 use std::{cmp, num::Wrapping, rc::Rc, time::Duration};
 
 pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    // Segments sent back-to-back since we last had to wait for something.
+    let mut burst = 0;
     'top: loop {
         // First, check to see if there's any unsent data.
         let (unsent_seq, unsent_seq_changed) = cb.sender.unsent_seq_no.watch();
@@ -17,6 +19,7 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         futures::pin_mut!(sent_seq_changed);
 
         if sent_seq == unsent_seq {
+            burst = 0;
             futures::select_biased! {
                 _ = unsent_seq_changed => continue 'top,
                 _ = sent_seq_changed => continue 'top,
@@ -32,20 +35,18 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         // repeatedly send window probes until window opens up.
         if win_sz == 0 {
             let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
-            let buf = cb
-                .sender
-                .pop_one_unsent_byte()
-                .unwrap_or_else(|| panic!("No unsent data? {}, {}", sent_seq, unsent_seq));
-
-            cb.sender.sent_seq_no.modify(|s| s + Wrapping(1));
-            let unacked_segment = UnackedSegment {
-                bytes: buf.clone(),
-                initial_tx: Some(cb.rt.now()),
+            let buf = match cb.sender.pop_unsent(1, cb.rt.now()) {
+                Some(buf) => buf,
+                // Nagle is holding back our last few bytes until the data in flight is ACKed.
+                None => futures::select_biased! {
+                    _ = unsent_seq_changed => continue 'top,
+                    _ = sent_seq_changed => continue 'top,
+                    _ = win_sz_changed => continue 'top,
+                    _ = cb.sender.base_seq_no.watch().1.fuse() => continue 'top,
+                    _ = cb.sender.state.watch().1.fuse() => continue 'top,
+                },
             };
-            cb.sender
-                .unacked_queue
-                .borrow_mut()
-                .push_back(unacked_segment);
+            cb.sender.sent_seq_no.modify(|s| s + Wrapping(1));
 
             let mut header = cb.tcp_header();
             header.seq_num = sent_seq;
@@ -75,7 +76,7 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         // Before we get cwnd for the check, we prompt it to shrink it if the connection has been idle
         cb.sender
             .congestion_ctrl
-            .on_cwnd_check_before_send(&cb.sender);
+            .on_cwnd_check_before_send(&cb.sender, cb.rt.now());
         let (cwnd, cwnd_changed) = cb.sender.congestion_ctrl.watch_cwnd();
         futures::pin_mut!(cwnd_changed);
 
@@ -89,7 +90,8 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         let effective_cwnd = cwnd + ltci;
 
         let Wrapping(sent_data) = sent_seq - base_seq;
-        if win_sz <= sent_data || effective_cwnd <= sent_data || (effective_cwnd - sent_data) <= cb.sender.mss as u32 {
+        if win_sz <= sent_data || effective_cwnd <= sent_data || (effective_cwnd - sent_data) <= cb.sender.mss.get() as u32 {
+            burst = 0;
             futures::select_biased! {
                 _ = base_seq_changed => continue 'top,
                 _ = sent_seq_changed => continue 'top,
@@ -101,23 +103,30 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
 
         // Past this point we have data to send and it's valid to send it!
 
-        // TODO: Nagle's algorithm
         // TODO: Silly window syndrome
         let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
 
         // Form an outgoing packet.
         let max_size = cmp::min(
-            cmp::min((win_sz - sent_data) as usize, cb.sender.mss),
+            cmp::min((win_sz - sent_data) as usize, cb.sender.mss.get()),
             (effective_cwnd - sent_data) as usize,
         );
-        let segment_data = cb
-            .sender
-            .pop_unsent(max_size)
-            .expect("No unsent data with sequence number gap?");
+        let segment_data = match cb.sender.pop_unsent(max_size, cb.rt.now()) {
+            Some(s) => s,
+            // Nagle is holding back a small segment until the data in flight is ACKed, or we close.
+            None => futures::select_biased! {
+                _ = base_seq_changed => continue 'top,
+                _ = unsent_seq_changed => continue 'top,
+                _ = sent_seq_changed => continue 'top,
+                _ = cb.sender.state.watch().1.fuse() => continue 'top,
+            },
+        };
         let segment_data_len = segment_data.len();
         assert!(segment_data_len > 0);
 
-        cb.sender.congestion_ctrl.on_send(&cb.sender, sent_data);
+        cb.sender
+            .congestion_ctrl
+            .on_send(&cb.sender, sent_data, cb.rt.now());
 
         let mut header = cb.tcp_header();
         header.seq_num = sent_seq;
@@ -126,18 +135,51 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         cb.sender
             .sent_seq_no
             .modify(|s| s + Wrapping(segment_data_len as u32));
-        let unacked_segment = UnackedSegment {
-            bytes: segment_data,
-            initial_tx: Some(cb.rt.now()),
-        };
-        cb.sender
-            .unacked_queue
-            .borrow_mut()
-            .push_back(unacked_segment);
 
         if cb.sender.retransmit_deadline.get().is_none() {
             let rto = cb.sender.rto.borrow().estimate();
             cb.sender.retransmit_deadline.set(Some(cb.rt.now() + rto));
         }
+
+        // Leave the rest of a long burst for the next time we're polled, so we don't overflow
+        // shallow queues along the path.
+        burst += 1;
+        if burst >= cb.rt.tcp_options().maxburst {
+            yield_now().await;
+            burst = 0;
+        }
+    }
+}
+
+/// Fails the connection if we have data queued but the head of the send buffer doesn't move for
+/// the send stall timeout, whether because the peer's window stays shut, the peer never ACKs, or
+/// we can't even resolve its link address.
+pub async fn stall_detector<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    let timeout = match cb.rt.tcp_options().send_stall_timeout {
+        Some(timeout) => timeout,
+        None => future::pending().await,
+    };
+    loop {
+        let (base_seq, base_seq_changed) = cb.sender.base_seq_no.watch();
+        futures::pin_mut!(base_seq_changed);
+        let (unsent_seq, unsent_seq_changed) = cb.sender.unsent_seq_no.watch();
+        futures::pin_mut!(unsent_seq_changed);
+
+        if base_seq == unsent_seq {
+            futures::select_biased! {
+                _ = base_seq_changed => continue,
+                _ = unsent_seq_changed => continue,
+            }
+        }
+
+        // Queueing more data behind the head doesn't restart the clock, only ACKs do.
+        futures::select_biased! {
+            _ = base_seq_changed => continue,
+            _ = cb.rt.wait(timeout).fuse() => {
+                warn!("{}: Send buffer stalled for {:?}", cb.id, timeout);
+                cb.time_out();
+                return Err(Fail::Timeout {});
+            },
+        }
     }
 }
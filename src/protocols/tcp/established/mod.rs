@@ -4,19 +4,27 @@
 mod background;
 pub mod state;
 
-use self::{background::background, state::ControlBlock};
+use self::{
+    background::background,
+    state::{congestion_ctrl::CcPhase, ControlBlock},
+};
 use crate::{
     fail::Fail,
     file_table::FileDescriptor,
-    protocols::{ipv4, tcp::segment::TcpHeader},
+    protocols::{
+        ipv4,
+        tcp::{segment::TcpHeader, ConnectionCounters, ConnectionId, SeqNumber},
+    },
     runtime::Runtime,
     scheduler::SchedulerHandle,
 };
 use futures::channel::mpsc;
 use std::{
+    future::Future,
+    net::Shutdown,
     rc::Rc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 pub struct EstablishedSocket<RT: Runtime> {
@@ -60,19 +68,156 @@ impl<RT: Runtime> EstablishedSocket<RT> {
         self.cb.receiver.poll_recv(ctx)
     }
 
+    pub fn poll_push(&self, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        self.cb.poll_push(ctx)
+    }
+
     pub fn close(&self) -> Result<(), Fail> {
         self.cb.close()
     }
 
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Fail> {
+        self.cb.shutdown(how)
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.cb.receiver.is_readable()
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.cb.sender.is_writable()
+    }
+
+    /// Returns a future that completes once any of the state `is_readable` and `is_writable`
+    /// depend on changes.
+    pub fn readiness_changed(&self) -> impl Future<Output = ()> {
+        let cb = self.cb.clone();
+        let readiness = (self.is_readable(), self.is_writable());
+        async move {
+            let (_, recv_base_changed) = cb.receiver.base_seq_no.watch();
+            let (_, recv_seq_changed) = cb.receiver.recv_seq_no.watch();
+            let (_, recv_state_changed) = cb.receiver.state.watch();
+            let (_, send_state_changed) = cb.sender.state.watch();
+            let (_, send_base_changed) = cb.sender.base_seq_no.watch();
+            let (_, unsent_seq_changed) = cb.sender.unsent_seq_no.watch();
+            let (_, win_sz_changed) = cb.sender.window_size.watch();
+            if (cb.receiver.is_readable(), cb.sender.is_writable()) != readiness {
+                return;
+            }
+            futures::pin_mut!(recv_base_changed);
+            futures::pin_mut!(recv_seq_changed);
+            futures::pin_mut!(recv_state_changed);
+            futures::pin_mut!(send_state_changed);
+            futures::pin_mut!(send_base_changed);
+            futures::pin_mut!(unsent_seq_changed);
+            futures::pin_mut!(win_sz_changed);
+            futures::select_biased! {
+                _ = recv_base_changed => (),
+                _ = recv_seq_changed => (),
+                _ = recv_state_changed => (),
+                _ = send_state_changed => (),
+                _ = send_base_changed => (),
+                _ = unsent_seq_changed => (),
+                _ = win_sz_changed => (),
+            }
+        }
+    }
+
+    /// Returns a future that completes once the connection has fully closed.
+    pub fn wait_closed(&self) -> impl Future<Output = ()> {
+        let cb = self.cb.clone();
+        async move {
+            loop {
+                let (closed, closed_changed) = cb.closed.watch();
+                if closed {
+                    return;
+                }
+                closed_changed.await;
+            }
+        }
+    }
+
+    pub fn snd_una(&self) -> SeqNumber {
+        self.cb.sender.base_seq_no.get()
+    }
+
+    pub fn snd_nxt(&self) -> SeqNumber {
+        self.cb.snd_nxt()
+    }
+
+    pub fn unacked_segments(&self) -> usize {
+        self.cb.sender.send_buffer.borrow().unacked_segments()
+    }
+
+    pub fn cc_phase(&self) -> CcPhase {
+        self.cb.sender.congestion_ctrl.phase()
+    }
+
+    pub fn rcv_nxt(&self) -> SeqNumber {
+        self.cb.receiver.recv_seq_no.get()
+    }
+
     pub fn remote_mss(&self) -> usize {
         self.cb.remote_mss()
     }
 
+    pub fn effective_send_window(&self) -> u32 {
+        self.cb.effective_send_window()
+    }
+
     pub fn current_rto(&self) -> Duration {
         self.cb.current_rto()
     }
 
+    pub fn path_mtu(&self) -> usize {
+        self.cb.path_mtu()
+    }
+
+    pub fn update_path_mtu(&self, seq_num: SeqNumber, path_mtu: usize) -> Result<(), Fail> {
+        self.cb.update_path_mtu(seq_num, path_mtu)
+    }
+
     pub fn endpoints(&self) -> (ipv4::Endpoint, ipv4::Endpoint) {
         (self.cb.local, self.cb.remote)
     }
+
+    pub fn time_wait_deadline(&self) -> Option<Instant> {
+        self.cb.time_wait_deadline()
+    }
+
+    pub fn retransmit_deadline(&self) -> Option<Instant> {
+        self.cb.sender.retransmit_deadline.get()
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.cb.id
+    }
+
+    pub fn rejected_segments(&self) -> u64 {
+        self.cb.rejected_segments()
+    }
+
+    pub fn counters(&self) -> ConnectionCounters {
+        self.cb.counters()
+    }
+
+    pub fn reset_counters(&self) -> ConnectionCounters {
+        self.cb.reset_counters()
+    }
+
+    pub fn take_error(&self) -> Option<Fail> {
+        self.cb.take_error()
+    }
+
+    pub fn set_tos(&self, tos: u8) {
+        self.cb.set_tos(tos)
+    }
+
+    pub fn set_quickack(&self, quickack: bool) {
+        self.cb.set_quickack(quickack)
+    }
+
+    pub fn set_rcvlowat(&self, bytes: usize) {
+        self.cb.set_rcvlowat(bytes)
+    }
 }
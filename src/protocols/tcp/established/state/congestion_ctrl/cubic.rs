@@ -3,13 +3,13 @@
 
 use super::super::sender::Sender;
 use super::{
-    CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
+    CcPhase, CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
     SlowStartCongestionAvoidance,
 };
 use crate::runtime::Runtime;
 use crate::{
     collections::watched::{WatchFuture, WatchedValue},
-    protocols::tcp::SeqNumber,
+    protocols::tcp::{constants::MIN_MSS, SeqNumber},
 };
 use std::{
     cell::Cell,
@@ -24,11 +24,14 @@ use std::{
 pub struct Cubic {
     pub mss: u32, // Just for convenience, otherwise we have `as u32` or `.try_into().unwrap()` scattered everywhere...
     // Slow Start / Congestion Avoidance State
-    pub ca_start: Cell<Instant>, // The time we started the current congestion avoidance
+    pub ca_start: Cell<Option<Instant>>, // The time we started the current congestion avoidance, set when we first enter it
+    pub app_limited_since: Cell<Option<Instant>>, // When the application stopped sending enough to use cwnd, if it has
     pub cwnd: WatchedValue<u32>, // Congestion window: Maximum number of bytes that may be in flight ot prevent congestion
     pub fast_convergence: bool, // Should we employ the fast convergence algorithm (Only recommended if there are multiple CUBIC streams on the same network, in which case we'll cede capacity to new ones faster)
+    pub slow_start_after_idle: bool, // Should we fully reset the controller after the connection has been idle for `idle_reset_threshold`
+    pub idle_reset_threshold: Duration, // How long the connection must be idle before we go back into slow start
     pub initial_cwnd: u32, // The initial value of cwnd, which gets used if the connection ever resets
-    pub last_send_time: Cell<Instant>, // The moment at which we last sent data
+    pub last_send_time: Cell<Option<Instant>>, // The moment at which we last sent data, if we have
     pub last_congestion_was_rto: Cell<bool>, // A flag for whether the last congestion event was detected by RTO
     pub retransmitted_packets_in_flight: Cell<u32>, // A flag for if there is currently a retransmitted packet in flight
     pub rtt_at_last_send: Cell<Duration>,           // The RTT at the moment we last sent data
@@ -51,7 +54,8 @@ impl<RT: Runtime> CongestionControl<RT> for Cubic {
         seq_no: SeqNumber,
         options: Option<Options>,
     ) -> Box<dyn CongestionControl<RT>> {
-        let mss: u32 = mss.try_into().unwrap();
+        // Anything smaller than the minimum MSS would make for a uselessly small cwnd.
+        let mss: u32 = max(mss, MIN_MSS).try_into().unwrap();
         // The initial value of cwnd is set according to RFC5681, section 3.1, page 7
         let initial_cwnd = match mss {
             0..=1095 => 4 * mss,
@@ -61,15 +65,23 @@ impl<RT: Runtime> CongestionControl<RT> for Cubic {
 
         let options: Options = options.unwrap_or_default();
         let fast_convergence = options.get_bool("fast_convergence").unwrap_or(true);
+        let slow_start_after_idle = options.get_bool("slow_start_after_idle").unwrap_or(true);
+        let idle_reset_threshold = options
+            .get_float("idle_reset_threshold")
+            .map(Duration::from_secs_f64)
+            .unwrap_or(Self::DEFAULT_IDLE_RESET_THRESHOLD);
 
         Box::new(Self {
             mss,
             // Slow Start / Congestion Avoidance State
-            ca_start: Cell::new(Instant::now()), // record the start time of the congestion avoidance period
+            ca_start: Cell::new(None), // recorded when we first get into congestion avoidance
+            app_limited_since: Cell::new(None),
             cwnd: WatchedValue::new(initial_cwnd),
             fast_convergence,
+            slow_start_after_idle,
+            idle_reset_threshold,
             initial_cwnd,
-            last_send_time: Cell::new(Instant::now()),
+            last_send_time: Cell::new(None),
             retransmitted_packets_in_flight: Cell::new(0),
             rtt_at_last_send: Cell::new(Duration::new(1, 0)), // The default RTT is 1 sec
             ssthresh: Cell::new(u32::MAX), // According to RFC5681 ssthresh should be initialised 'arbitrarily high'
@@ -85,6 +97,18 @@ impl<RT: Runtime> CongestionControl<RT> for Cubic {
             limited_transmit_cwnd_increase: WatchedValue::new(0),
         })
     }
+
+    fn phase(&self) -> CcPhase {
+        if self.in_fast_recovery.get() {
+            CcPhase::FastRecovery
+        } else if self.cwnd.get() >= self.ssthresh.get() {
+            CcPhase::CongestionAvoidance
+        } else if self.last_congestion_was_rto.get() {
+            CcPhase::RtoRecovery
+        } else {
+            CcPhase::SlowStart
+        }
+    }
 }
 
 impl Cubic {
@@ -94,6 +118,23 @@ impl Cubic {
 
     const DUP_ACK_THRESHOLD: u32 = 3;
 
+    const DEFAULT_IDLE_RESET_THRESHOLD: Duration = Duration::from_secs(1);
+
+    fn reset(&self) {
+        // Put the controller back into the state it was in when the connection was established,
+        // so we slow start from the initial window again.
+        self.cwnd.set(self.initial_cwnd);
+        self.ssthresh.set(u32::MAX);
+        self.w_max.set(0);
+        self.ca_start.set(None);
+        self.app_limited_since.set(None);
+        self.last_congestion_was_rto.set(false);
+        self.retransmitted_packets_in_flight.set(0);
+        self.duplicate_ack_count.set(0);
+        self.in_fast_recovery.set(false);
+        self.limited_transmit_cwnd_increase.set_without_notify(0);
+    }
+
     fn fast_convergence(&self) {
         // The fast convergence algorithm assumes that w_max and cwnd are stored in units of mss, so we do this
         // integer division to prevent it being applied too often
@@ -160,6 +201,7 @@ impl Cubic {
         &self,
         sender: &Sender<RT>,
         ack_seq_no: SeqNumber,
+        now: Instant,
     ) {
         let bytes_outstanding = sender.sent_seq_no.get() - sender.base_seq_no.get();
         let bytes_acknowledged = ack_seq_no - sender.base_seq_no.get();
@@ -172,7 +214,7 @@ impl Cubic {
                 max(bytes_outstanding.0, mss) + mss,
             ));
             // Record the time we go back into congestion avoidance
-            self.ca_start.set(Instant::now());
+            self.ca_start.set(Some(now));
             // Record that we didn't enter CA from a timeout
             self.last_congestion_was_rto.set(false);
             self.in_fast_recovery.set(false);
@@ -212,7 +254,36 @@ impl Cubic {
         w_max * bc + ((3. * (1. - bc) / (1. + bc)) * t / rtt)
     }
 
-    fn on_ack_received_ss_ca<RT: Runtime>(&self, sender: &Sender<RT>, ack_seq_no: SeqNumber) {
+    /// Whether the application is sending too little to make use of cwnd: nothing is waiting to be
+    /// sent and less than half of cwnd is in flight (RFC 7661, section 4.3).
+    fn is_app_limited<RT: Runtime>(&self, sender: &Sender<RT>) -> bool {
+        let Wrapping(in_flight) = sender.sent_seq_no.get() - sender.base_seq_no.get();
+        sender.unsent_seq_no.get() == sender.sent_seq_no.get() && in_flight < self.cwnd.get() / 2
+    }
+
+    fn on_ack_received_ss_ca<RT: Runtime>(
+        &self,
+        sender: &Sender<RT>,
+        ack_seq_no: SeqNumber,
+        now: Instant,
+    ) {
+        // ACKs for data that didn't fill cwnd say nothing about whether the network could take
+        // more, so cwnd stays where it is until the application uses it again. The time spent
+        // application-limited doesn't count towards the cubic curve either, or cwnd would jump
+        // ahead as soon as we were cwnd-limited again.
+        if self.is_app_limited(sender) {
+            if self.app_limited_since.get().is_none() {
+                self.app_limited_since.set(Some(now));
+            }
+            return;
+        }
+        if let Some(since) = self.app_limited_since.take() {
+            if let Some(ca_start) = self.ca_start.get() {
+                self.ca_start
+                    .set(Some(ca_start + now.duration_since(since)));
+            }
+        }
+
         let bytes_acknowledged = ack_seq_no - sender.base_seq_no.get();
         let mss = self.mss;
         let cwnd = self.cwnd.get();
@@ -223,7 +294,9 @@ impl Cubic {
             self.cwnd.modify(|c| c + min(bytes_acknowledged.0, mss));
         } else {
             // Congestion avoidance
-            let t = self.ca_start.get().elapsed().as_secs_f32();
+            let ca_start = self.ca_start.get().unwrap_or(now);
+            self.ca_start.set(Some(ca_start));
+            let t = now.duration_since(ca_start).as_secs_f32();
             let rtt = sender.current_rto().as_secs_f32();
             let mss_f32 = mss as f32;
             let normalised_w_max = self.w_max.get() as f32 / mss_f32;
@@ -285,18 +358,24 @@ impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for Cubic {
         self.cwnd.watch()
     }
 
-    fn on_cwnd_check_before_send(&self, _sender: &Sender<RT>) {
-        let long_time_since_send =
-            Instant::now().duration_since(self.last_send_time.get()) > self.rtt_at_last_send.get();
-        if long_time_since_send {
+    fn on_cwnd_check_before_send(&self, _sender: &Sender<RT>, now: Instant) {
+        let last_send_time = match self.last_send_time.get() {
+            Some(t) => t,
+            // We haven't sent anything yet, so cwnd is still where it started.
+            None => return,
+        };
+        let time_since_send = now.duration_since(last_send_time);
+        if self.slow_start_after_idle && time_since_send > self.idle_reset_threshold {
+            self.reset();
+        } else if time_since_send > self.rtt_at_last_send.get() {
             let restart_window = min(self.initial_cwnd, self.cwnd.get());
             self.cwnd.set(restart_window);
             self.limited_transmit_cwnd_increase.set_without_notify(0);
         }
     }
 
-    fn on_send(&self, sender: &Sender<RT>, num_bytes_sent: u32) {
-        self.last_send_time.set(Instant::now());
+    fn on_send(&self, sender: &Sender<RT>, num_bytes_sent: u32, now: Instant) {
+        self.last_send_time.set(Some(now));
         self.rtt_at_last_send.set(sender.current_rto());
         self.limited_transmit_cwnd_increase.set_without_notify(
             self.limited_transmit_cwnd_increase
@@ -305,7 +384,7 @@ impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for Cubic {
         );
     }
 
-    fn on_ack_received(&self, sender: &Sender<RT>, ack_seq_no: SeqNumber) {
+    fn on_ack_received(&self, sender: &Sender<RT>, ack_seq_no: SeqNumber, now: Instant) {
         let bytes_acknowledged = ack_seq_no - sender.base_seq_no.get();
         if bytes_acknowledged.0 == 0 {
             // ACK is a duplicate
@@ -319,9 +398,9 @@ impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for Cubic {
 
             if self.in_fast_recovery.get() {
                 // Fast Recovery response to new data
-                self.on_ack_received_fast_recovery(sender, ack_seq_no);
+                self.on_ack_received_fast_recovery(sender, ack_seq_no, now);
             } else {
-                self.on_ack_received_ss_ca(sender, ack_seq_no);
+                self.on_ack_received_ss_ca(sender, ack_seq_no, now);
             }
             // Used to handle dup ACKs after timeout
             self.prev_ack_seq_no.set(ack_seq_no);
@@ -368,3 +447,196 @@ impl<RT: Runtime> LimitedTransmit<RT> for Cubic {
         self.limited_transmit_cwnd_increase.watch()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory_budget::MemoryBudget, protocols::tcp::established::state::rto::RtoCalculator,
+        test_helpers::TestRuntime,
+    };
+
+    #[test]
+    fn test_mss_clamped_to_minimum() {
+        let cc: Box<dyn CongestionControl<TestRuntime>> = Cubic::new(64, Wrapping(0), None);
+        assert_eq!(cc.get_cwnd(), 4 * MIN_MSS as u32);
+    }
+
+    #[test]
+    fn test_slow_start_after_idle() {
+        let mut options = Options::default();
+        options.insert_float("idle_reset_threshold".to_string(), 10.0);
+        let sender = Sender::<TestRuntime>::new(
+            Wrapping(0),
+            65536,
+            0,
+            1000,
+            false,
+            RtoCalculator::default(),
+            Cubic::new,
+            Some(options),
+            MemoryBudget::default(),
+        );
+        let cc = &sender.congestion_ctrl;
+        let initial_cwnd = cc.get_cwnd();
+        let now = Instant::now();
+
+        // Shrink cwnd below its initial value with a timeout.
+        cc.on_send(&sender, 0, now);
+        cc.on_rto(&sender);
+        assert!(cc.get_cwnd() < initial_cwnd);
+
+        // Idling for less than the threshold doesn't bring cwnd back up.
+        let reduced_cwnd = cc.get_cwnd();
+        cc.on_cwnd_check_before_send(&sender, now + Duration::from_secs(9));
+        assert_eq!(cc.get_cwnd(), reduced_cwnd);
+
+        // Idle past the threshold and check that we're back to the initial window.
+        cc.on_cwnd_check_before_send(&sender, now + Duration::from_secs(11));
+        assert_eq!(cc.get_cwnd(), initial_cwnd);
+    }
+
+    /// Runs a connection through a timeout and then ACKs a window's worth of segments every RTT,
+    /// returning cwnd at the end of each RTT.
+    fn cwnd_curve(start: Instant) -> Vec<u32> {
+        let mss = 1000;
+        let sender = Sender::<TestRuntime>::new(
+            Wrapping(0),
+            1 << 30,
+            0,
+            mss,
+            false,
+            RtoCalculator::default(),
+            Cubic::new,
+            None,
+            MemoryBudget::default(),
+        );
+        let cc = &sender.congestion_ctrl;
+        let mut now = start;
+        // Keep plenty of data in flight, so we're never application-limited.
+        sender.sent_seq_no.set(Wrapping(1 << 30));
+        sender.unsent_seq_no.set(Wrapping(1 << 30));
+
+        // Grow cwnd in slow start, then time out so that we come back through congestion
+        // avoidance.
+        let mut ack_seq_no = Wrapping(0);
+        for _ in 0..16 {
+            ack_seq_no += Wrapping(mss as u32);
+            cc.on_ack_received(&sender, ack_seq_no, now);
+            sender.base_seq_no.set(ack_seq_no);
+        }
+        cc.on_send(&sender, 0, now);
+        cc.on_rto(&sender);
+
+        let mut curve = vec![];
+        for _ in 0..20 {
+            now += Duration::from_millis(100);
+            for _ in 0..(cc.get_cwnd() / mss as u32) {
+                ack_seq_no += Wrapping(mss as u32);
+                cc.on_ack_received(&sender, ack_seq_no, now);
+                sender.base_seq_no.set(ack_seq_no);
+            }
+            curve.push(cc.get_cwnd());
+        }
+        curve
+    }
+
+    #[test]
+    fn test_cwnd_growth_is_deterministic() {
+        let start = Instant::now();
+        let curve = cwnd_curve(start);
+
+        // The curve only depends on the clock we pass in, not on when the test runs.
+        assert_eq!(curve, cwnd_curve(start + Duration::from_secs(3600)));
+
+        // cwnd grows back past where slow start hands over to congestion avoidance.
+        assert!(curve.windows(2).all(|w| w[0] <= w[1]));
+        assert!(*curve.last().unwrap() > 14 * 1000);
+    }
+
+    #[test]
+    fn test_cwnd_frozen_while_app_limited() {
+        let mss = 1000;
+        let sender = Sender::<TestRuntime>::new(
+            Wrapping(0),
+            1 << 30,
+            0,
+            mss,
+            false,
+            RtoCalculator::default(),
+            Cubic::new,
+            None,
+            MemoryBudget::default(),
+        );
+        let cc = &sender.congestion_ctrl;
+        let initial_cwnd = cc.get_cwnd();
+        let mut now = Instant::now();
+
+        // The application sends one segment at a time, well short of what cwnd allows.
+        let mut seq_no = Wrapping(0);
+        for _ in 0..100 {
+            seq_no += Wrapping(mss as u32);
+            sender.sent_seq_no.set(seq_no);
+            sender.unsent_seq_no.set(seq_no);
+            cc.on_ack_received(&sender, seq_no, now);
+            sender.base_seq_no.set(seq_no);
+            now += Duration::from_millis(100);
+        }
+        assert_eq!(cc.get_cwnd(), initial_cwnd);
+
+        // Once it fills cwnd, cwnd grows again.
+        sender.sent_seq_no.set(seq_no + Wrapping(initial_cwnd));
+        sender.unsent_seq_no.set(seq_no + Wrapping(initial_cwnd));
+        cc.on_ack_received(&sender, seq_no + Wrapping(mss as u32), now);
+        assert_eq!(cc.get_cwnd(), initial_cwnd + mss as u32);
+    }
+
+    #[test]
+    fn test_phase_transitions() {
+        let mss = 1000;
+        let sender = Sender::<TestRuntime>::new(
+            Wrapping(0),
+            1 << 30,
+            0,
+            mss,
+            false,
+            RtoCalculator::default(),
+            Cubic::new,
+            None,
+            MemoryBudget::default(),
+        );
+        let cc = &sender.congestion_ctrl;
+        let now = Instant::now();
+        let sent_seq_no = Wrapping(1 << 20);
+        sender.sent_seq_no.set(sent_seq_no);
+        sender.unsent_seq_no.set(sent_seq_no);
+
+        let mut ack_seq_no = Wrapping(0);
+        for _ in 0..16 {
+            ack_seq_no += Wrapping(mss as u32);
+            cc.on_ack_received(&sender, ack_seq_no, now);
+            sender.base_seq_no.set(ack_seq_no);
+            assert_eq!(cc.phase(), CcPhase::SlowStart);
+        }
+
+        // A segment goes missing and the peer keeps ACKing up to it.
+        for _ in 0..3 {
+            cc.on_ack_received(&sender, ack_seq_no, now);
+        }
+        assert_eq!(cc.phase(), CcPhase::FastRecovery);
+
+        // We carry on sending through recovery, until the retransmission fills the hole and
+        // everything we'd sent when the loss was detected is ACKed.
+        sender.sent_seq_no.set(sent_seq_no + Wrapping(1 << 20));
+        sender.unsent_seq_no.set(sent_seq_no + Wrapping(1 << 20));
+        ack_seq_no = sent_seq_no + Wrapping(mss as u32);
+        cc.on_ack_received(&sender, ack_seq_no, now);
+        sender.base_seq_no.set(ack_seq_no);
+        assert_eq!(cc.phase(), CcPhase::CongestionAvoidance);
+        assert_eq!(cc.get_cwnd(), (20000. * Cubic::BETA_CUBIC) as u32);
+
+        // A timeout drops cwnd to a single segment to slow start back up from.
+        cc.on_rto(&sender);
+        assert_eq!(cc.phase(), CcPhase::RtoRecovery);
+    }
+}
@@ -3,17 +3,32 @@
 
 use super::sender::Sender;
 use crate::{collections::watched::WatchFuture, protocols::tcp::SeqNumber, runtime::Runtime};
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Instant};
 
 mod cubic;
+mod new_reno;
 mod none;
 mod options;
 pub use self::{
     cubic::Cubic,
+    new_reno::NewReno,
     none::None,
     options::{OptionValue, Options},
 };
 
+/// Which part of its algorithm a congestion controller is running.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CcPhase {
+    /// cwnd is below ssthresh and grows by a segment per ACK.
+    SlowStart,
+    /// cwnd has reached ssthresh and grows more slowly, per the algorithm.
+    CongestionAvoidance,
+    /// Recovering from a loss detected by duplicate ACKs.
+    FastRecovery,
+    /// Slow starting back up after a loss detected by the retransmission timer.
+    RtoRecovery,
+}
+
 pub trait SlowStartCongestionAvoidance<RT: Runtime> {
     fn get_cwnd(&self) -> u32 {
         u32::MAX
@@ -22,16 +37,19 @@ pub trait SlowStartCongestionAvoidance<RT: Runtime> {
         (u32::MAX, WatchFuture::Pending)
     }
 
+    // Hooks that depend on time are passed the runtime's clock rather than reading the system
+    // clock, so that tests can drive them deterministically.
+
     // Called immediately before the cwnd check is performed before data is sent
-    fn on_cwnd_check_before_send(&self, _sender: &Sender<RT>) {}
+    fn on_cwnd_check_before_send(&self, _sender: &Sender<RT>, _now: Instant) {}
 
-    fn on_ack_received(&self, _sender: &Sender<RT>, _ack_seq_no: SeqNumber) {}
+    fn on_ack_received(&self, _sender: &Sender<RT>, _ack_seq_no: SeqNumber, _now: Instant) {}
 
     // Called immediately before retransmit after RTO
     fn on_rto(&self, _sender: &Sender<RT>) {}
 
     // Called immediately before a segment is sent for the 1st time
-    fn on_send(&self, _sender: &Sender<RT>, _num_sent_bytes: u32) {}
+    fn on_send(&self, _sender: &Sender<RT>, _num_sent_bytes: u32, _now: Instant) {}
 }
 
 pub trait FastRetransmitRecovery<RT: Runtime>
@@ -75,7 +93,14 @@ pub trait CongestionControl<RT: Runtime>:
     ) -> Box<dyn CongestionControl<RT>>
     where
         Self: Sized;
+
+    fn phase(&self) -> CcPhase;
 }
 
 pub type CongestionControlConstructor<T> =
     fn(usize, SeqNumber, Option<options::Options>) -> Box<dyn CongestionControl<T>>;
+
+/// A congestion control algorithm chosen for a particular socket, along with the options to
+/// construct it with. Sockets without one use the algorithm from the stack's TCP options.
+pub type CongestionControlSelection<T> =
+    (CongestionControlConstructor<T>, Option<options::Options>);
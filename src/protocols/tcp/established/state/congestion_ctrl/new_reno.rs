@@ -0,0 +1,393 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::super::sender::Sender;
+use super::{
+    CcPhase, CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
+    SlowStartCongestionAvoidance,
+};
+use crate::runtime::Runtime;
+use crate::{
+    collections::watched::{WatchFuture, WatchedValue},
+    protocols::tcp::{constants::MIN_MSS, seq_gt, seq_lt, SeqNumber},
+};
+use std::{
+    cell::Cell,
+    cmp::{max, min},
+    convert::TryInto,
+    fmt::Debug,
+    num::Wrapping,
+    time::{Duration, Instant},
+};
+
+/// Standard TCP congestion control: slow start and AIMD congestion avoidance (RFC 5681), with the
+/// NewReno modification to fast recovery (RFC 6582). This backs off more gently than CUBIC grows,
+/// which suits high-latency paths.
+#[derive(Debug)]
+pub struct NewReno {
+    pub mss: u32,
+    // Slow Start / Congestion Avoidance State
+    pub cwnd: WatchedValue<u32>, // Congestion window: Maximum number of bytes that may be in flight to prevent congestion
+    pub initial_cwnd: u32,       // The initial value of cwnd, which we restart from after idling
+    pub ssthresh: Cell<u32>, // The size of cwnd at which we will change from using slow start to congestion avoidance
+    pub last_send_time: Cell<Option<Instant>>, // The moment at which we last sent data, if we have
+    pub rto_at_last_send: Cell<Duration>, // The RTO at the moment we last sent data
+    pub last_congestion_was_rto: Cell<bool>, // A flag for whether the last congestion event was detected by RTO
+
+    // Fast Recovery / Fast Retransmit State
+    pub duplicate_ack_count: Cell<u32>, // The number of consecutive duplicate ACKs we've received
+    pub fast_retransmit_now: WatchedValue<bool>, // Flag to cause the retransmitter to retransmit a segment now
+    pub in_fast_recovery: Cell<bool>, // Are we currently in the `fast recovery` algorithm
+    pub recover: Cell<SeqNumber>,     // SND.NXT when we last entered fast recovery
+
+    pub limited_transmit_cwnd_increase: WatchedValue<u32>, // The amount by which cwnd should be increased due to the limited transit algorithm
+}
+
+impl<RT: Runtime> CongestionControl<RT> for NewReno {
+    fn new(
+        mss: usize,
+        seq_no: SeqNumber,
+        _options: Option<Options>,
+    ) -> Box<dyn CongestionControl<RT>> {
+        // Anything smaller than the minimum MSS would make for a uselessly small cwnd.
+        let mss: u32 = max(mss, MIN_MSS).try_into().unwrap();
+        // The initial value of cwnd is set according to RFC5681, section 3.1, page 7
+        let initial_cwnd = match mss {
+            0..=1095 => 4 * mss,
+            1096..=2190 => 3 * mss,
+            _ => 2 * mss,
+        };
+
+        Box::new(Self {
+            mss,
+            cwnd: WatchedValue::new(initial_cwnd),
+            initial_cwnd,
+            ssthresh: Cell::new(u32::MAX), // According to RFC5681 ssthresh should be initialised 'arbitrarily high'
+            last_send_time: Cell::new(None),
+            rto_at_last_send: Cell::new(Duration::new(1, 0)),
+            last_congestion_was_rto: Cell::new(false),
+
+            duplicate_ack_count: Cell::new(0),
+            fast_retransmit_now: WatchedValue::new(false),
+            in_fast_recovery: Cell::new(false),
+            recover: Cell::new(seq_no), // Recover set to initial send sequence number according to RFC6582
+
+            limited_transmit_cwnd_increase: WatchedValue::new(0),
+        })
+    }
+
+    fn phase(&self) -> CcPhase {
+        if self.in_fast_recovery.get() {
+            CcPhase::FastRecovery
+        } else if self.cwnd.get() >= self.ssthresh.get() {
+            CcPhase::CongestionAvoidance
+        } else if self.last_congestion_was_rto.get() {
+            CcPhase::RtoRecovery
+        } else {
+            CcPhase::SlowStart
+        }
+    }
+}
+
+impl NewReno {
+    const DUP_ACK_THRESHOLD: u32 = 3;
+
+    /// RFC5681, equation 4: half the data in flight, but no less than two segments.
+    fn reduced_ssthresh<RT: Runtime>(&self, sender: &Sender<RT>) -> u32 {
+        let Wrapping(flight_size) = sender.sent_seq_no.get() - sender.base_seq_no.get();
+        max(flight_size / 2, 2 * self.mss)
+    }
+
+    fn on_dup_ack_received<RT: Runtime>(&self, sender: &Sender<RT>, ack_seq_no: SeqNumber) {
+        let duplicate_ack_count = self.duplicate_ack_count.get() + 1;
+        self.duplicate_ack_count.set(duplicate_ack_count);
+
+        if self.in_fast_recovery.get() {
+            // Each further duplicate ACK means another segment has left the network.
+            self.cwnd.modify(|c| c + self.mss);
+        } else if duplicate_ack_count < Self::DUP_ACK_THRESHOLD {
+            // Limited transmit (RFC3042): send a new segment for each of the first two duplicates.
+            self.limited_transmit_cwnd_increase
+                .modify(|ltci| ltci + self.mss);
+        } else if duplicate_ack_count == Self::DUP_ACK_THRESHOLD
+            && seq_gt(ack_seq_no - Wrapping(1), self.recover.get())
+        {
+            // Only enter fast recovery if the duplicates cover more than the last recovery did
+            // (RFC6582, section 3.2, step 2).
+            let ssthresh = self.reduced_ssthresh(sender);
+            self.ssthresh.set(ssthresh);
+            self.recover.set(sender.sent_seq_no.get());
+            self.cwnd.set(ssthresh + Self::DUP_ACK_THRESHOLD * self.mss);
+            self.limited_transmit_cwnd_increase.set_without_notify(0);
+            self.last_congestion_was_rto.set(false);
+            self.in_fast_recovery.set(true);
+            self.fast_retransmit_now.set(true);
+        }
+    }
+
+    fn on_ack_received_fast_recovery<RT: Runtime>(
+        &self,
+        sender: &Sender<RT>,
+        ack_seq_no: SeqNumber,
+    ) {
+        let Wrapping(bytes_acknowledged) = ack_seq_no - sender.base_seq_no.get();
+        if !seq_lt(ack_seq_no, self.recover.get()) {
+            // Full acknowledgement: deflate the window (RFC6582, section 3.2, step 3).
+            let Wrapping(flight_size) = sender.sent_seq_no.get() - ack_seq_no;
+            self.cwnd.set(min(
+                self.ssthresh.get(),
+                max(flight_size, self.mss) + self.mss,
+            ));
+            self.in_fast_recovery.set(false);
+        } else {
+            // Partial acknowledgement: the next hole is lost too, so retransmit it straight away
+            // and deflate the window by the amount of new data acknowledged.
+            self.fast_retransmit_now.set(true);
+            let deflated = self.cwnd.get().saturating_sub(bytes_acknowledged);
+            if bytes_acknowledged >= self.mss {
+                self.cwnd.set(deflated + self.mss);
+            } else {
+                self.cwnd.set(deflated);
+            }
+        }
+    }
+
+    fn on_ack_received_ss_ca<RT: Runtime>(&self, sender: &Sender<RT>, ack_seq_no: SeqNumber) {
+        let Wrapping(bytes_acknowledged) = ack_seq_no - sender.base_seq_no.get();
+        let cwnd = self.cwnd.get();
+        if cwnd < self.ssthresh.get() {
+            // Slow start
+            self.cwnd.modify(|c| c + min(bytes_acknowledged, self.mss));
+        } else {
+            // Congestion avoidance: about one segment per RTT.
+            let increase = max((self.mss as u64 * self.mss as u64 / cwnd as u64) as u32, 1);
+            self.cwnd.modify(|c| c.saturating_add(increase));
+        }
+    }
+}
+
+impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for NewReno {
+    fn get_cwnd(&self) -> u32 {
+        self.cwnd.get()
+    }
+    fn watch_cwnd(&self) -> (u32, WatchFuture<'_, u32>) {
+        self.cwnd.watch()
+    }
+
+    fn on_cwnd_check_before_send(&self, _sender: &Sender<RT>, now: Instant) {
+        // Restart from at most the initial window after idling for an RTO (RFC5681, section 4.1).
+        if let Some(last_send_time) = self.last_send_time.get() {
+            if now.duration_since(last_send_time) > self.rto_at_last_send.get() {
+                self.cwnd.set(min(self.initial_cwnd, self.cwnd.get()));
+                self.limited_transmit_cwnd_increase.set_without_notify(0);
+            }
+        }
+    }
+
+    fn on_send(&self, sender: &Sender<RT>, num_bytes_sent: u32, now: Instant) {
+        self.last_send_time.set(Some(now));
+        self.rto_at_last_send.set(sender.current_rto());
+        self.limited_transmit_cwnd_increase.set_without_notify(
+            self.limited_transmit_cwnd_increase
+                .get()
+                .saturating_sub(num_bytes_sent),
+        );
+    }
+
+    fn on_ack_received(&self, sender: &Sender<RT>, ack_seq_no: SeqNumber, _now: Instant) {
+        let bytes_acknowledged = ack_seq_no - sender.base_seq_no.get();
+        if bytes_acknowledged.0 == 0 {
+            self.on_dup_ack_received(sender, ack_seq_no);
+        } else {
+            self.duplicate_ack_count.set(0);
+            self.limited_transmit_cwnd_increase.set_without_notify(0);
+            if self.in_fast_recovery.get() {
+                self.on_ack_received_fast_recovery(sender, ack_seq_no);
+            } else {
+                self.on_ack_received_ss_ca(sender, ack_seq_no);
+            }
+        }
+    }
+
+    fn on_rto(&self, sender: &Sender<RT>) {
+        // Fall back to slow start from the loss window (RFC5681, section 3.1). Only shrink
+        // ssthresh for the first timeout, while the flight size still reflects the window.
+        if self.cwnd.get() > self.mss {
+            self.ssthresh.set(self.reduced_ssthresh(sender));
+        }
+        self.cwnd.set(self.mss);
+        self.duplicate_ack_count.set(0);
+        self.in_fast_recovery.set(false);
+        self.recover.set(sender.sent_seq_no.get());
+        self.last_congestion_was_rto.set(true);
+    }
+}
+
+impl<RT: Runtime> FastRetransmitRecovery<RT> for NewReno {
+    fn get_duplicate_ack_count(&self) -> u32 {
+        self.duplicate_ack_count.get()
+    }
+
+    fn get_retransmit_now_flag(&self) -> bool {
+        self.fast_retransmit_now.get()
+    }
+    fn watch_retransmit_now_flag(&self) -> (bool, WatchFuture<'_, bool>) {
+        self.fast_retransmit_now.watch()
+    }
+
+    fn on_fast_retransmit(&self, _sender: &Sender<RT>) {
+        self.fast_retransmit_now.set_without_notify(false);
+    }
+}
+
+impl<RT: Runtime> LimitedTransmit<RT> for NewReno {
+    fn get_limited_transmit_cwnd_increase(&self) -> u32 {
+        self.limited_transmit_cwnd_increase.get()
+    }
+    fn watch_limited_transmit_cwnd_increase(&self) -> (u32, WatchFuture<'_, u32>) {
+        self.limited_transmit_cwnd_increase.watch()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory_budget::MemoryBudget, protocols::tcp::established::state::rto::RtoCalculator,
+        test_helpers::TestRuntime,
+    };
+
+    fn new_sender(mss: usize) -> Sender<TestRuntime> {
+        Sender::<TestRuntime>::new(
+            Wrapping(0),
+            1 << 30,
+            0,
+            mss,
+            false,
+            RtoCalculator::default(),
+            NewReno::new,
+            None,
+            MemoryBudget::default(),
+        )
+    }
+
+    /// ACKs `num_bytes` more data, as the sender would on receiving the ACK.
+    fn ack(sender: &Sender<TestRuntime>, num_bytes: u32, now: Instant) {
+        let ack_seq_no = sender.base_seq_no.get() + Wrapping(num_bytes);
+        sender
+            .congestion_ctrl
+            .on_ack_received(sender, ack_seq_no, now);
+        sender.base_seq_no.set(ack_seq_no);
+    }
+
+    #[test]
+    fn test_mss_clamped_to_minimum() {
+        let cc: Box<dyn CongestionControl<TestRuntime>> = NewReno::new(64, Wrapping(0), None);
+        assert_eq!(cc.get_cwnd(), 4 * MIN_MSS as u32);
+    }
+
+    #[test]
+    fn test_slow_start_then_congestion_avoidance() {
+        let now = Instant::now();
+        let sender = new_sender(1000);
+        let cc = &sender.congestion_ctrl;
+        sender.sent_seq_no.set(Wrapping(100_000));
+
+        // Slow start grows cwnd by a segment per ACK...
+        ack(&sender, 1000, now);
+        assert_eq!(cc.get_cwnd(), 5000);
+
+        // ...until a timeout sets ssthresh to half the flight size and cwnd to one segment.
+        cc.on_rto(&sender);
+        assert_eq!(cc.get_cwnd(), 1000);
+        ack(&sender, 1000, now);
+        ack(&sender, 1000, now);
+        assert_eq!(cc.get_cwnd(), 3000);
+
+        // Above ssthresh, a window's worth of ACKs grows cwnd by about one segment.
+        let sender = new_sender(1000);
+        let cc = &sender.congestion_ctrl;
+        sender.sent_seq_no.set(Wrapping(8000));
+        cc.on_rto(&sender);
+        while cc.get_cwnd() < 4000 {
+            ack(&sender, 1000, now);
+        }
+        let cwnd = cc.get_cwnd();
+        for _ in 0..(cwnd / 1000) {
+            ack(&sender, 1000, now);
+        }
+        assert!(cc.get_cwnd() > cwnd);
+        assert!(cc.get_cwnd() <= cwnd + 1000);
+    }
+
+    #[test]
+    fn test_phase() {
+        let now = Instant::now();
+        let sender = new_sender(1000);
+        let cc = &sender.congestion_ctrl;
+        sender.sent_seq_no.set(Wrapping(8000));
+        assert_eq!(cc.phase(), CcPhase::SlowStart);
+
+        // After a timeout we slow start back up to ssthresh, and then carry on in congestion
+        // avoidance.
+        cc.on_rto(&sender);
+        assert_eq!(cc.phase(), CcPhase::RtoRecovery);
+        while cc.get_cwnd() < 4000 {
+            ack(&sender, 1000, now);
+        }
+        assert_eq!(cc.phase(), CcPhase::CongestionAvoidance);
+
+        // Once everything outstanding at the timeout is ACKed, duplicate ACKs can set off fast
+        // recovery, which then counts as the last congestion event. Slow starting after it isn't
+        // recovering from a timeout.
+        ack(&sender, 6000, now);
+        sender.sent_seq_no.set(Wrapping(20_000));
+        for _ in 0..3 {
+            ack(&sender, 0, now);
+        }
+        assert_eq!(cc.phase(), CcPhase::FastRecovery);
+        ack(&sender, 11_000, now);
+        assert!(cc.get_cwnd() < 5500);
+        assert_eq!(cc.phase(), CcPhase::SlowStart);
+    }
+
+    #[test]
+    fn test_fast_retransmit() {
+        let now = Instant::now();
+        let sender = new_sender(1000);
+        let cc = &sender.congestion_ctrl;
+        sender.sent_seq_no.set(Wrapping(10_000));
+        ack(&sender, 1000, now);
+        let cwnd = cc.get_cwnd();
+
+        // The first two duplicates only let limited transmit send new data.
+        ack(&sender, 0, now);
+        ack(&sender, 0, now);
+        assert_eq!(cc.get_limited_transmit_cwnd_increase(), 2000);
+        assert!(!cc.get_retransmit_now_flag());
+        assert_eq!(cc.get_cwnd(), cwnd);
+
+        // The third sets off a fast retransmit, halving the 9000 bytes in flight.
+        ack(&sender, 0, now);
+        assert!(cc.get_retransmit_now_flag());
+        cc.on_fast_retransmit(&sender);
+        assert_eq!(cc.get_duplicate_ack_count(), 3);
+        assert_eq!(cc.get_cwnd(), 4500 + 3000);
+
+        // Further duplicates inflate the window.
+        ack(&sender, 0, now);
+        assert_eq!(cc.get_cwnd(), 4500 + 4000);
+
+        // A partial ACK retransmits the next hole and deflates the window.
+        ack(&sender, 2000, now);
+        assert!(cc.get_retransmit_now_flag());
+        cc.on_fast_retransmit(&sender);
+        assert_eq!(cc.get_cwnd(), 4500 + 4000 - 2000 + 1000);
+
+        // Once everything outstanding at the loss is ACKed, we're back to ssthresh.
+        sender.sent_seq_no.set(Wrapping(12_000));
+        ack(&sender, 7000, now);
+        assert!(!cc.get_retransmit_now_flag());
+        assert_eq!(cc.get_cwnd(), 3000);
+    }
+}
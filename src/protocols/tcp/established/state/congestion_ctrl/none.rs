@@ -2,7 +2,7 @@
 // Licensed under the MIT license.
 
 use super::{
-    CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
+    CcPhase, CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
     SlowStartCongestionAvoidance,
 };
 use crate::{protocols::tcp::SeqNumber, runtime::Runtime};
@@ -20,6 +20,11 @@ impl<RT: Runtime> CongestionControl<RT> for None {
     ) -> Box<dyn CongestionControl<RT>> {
         Box::new(Self {})
     }
+
+    fn phase(&self) -> CcPhase {
+        // cwnd never shrinks, so there's nothing to recover from.
+        CcPhase::CongestionAvoidance
+    }
 }
 
 impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for None {}
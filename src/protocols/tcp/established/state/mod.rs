@@ -2,12 +2,21 @@
 // Licensed under the MIT license.
 
 pub mod congestion_ctrl;
+pub mod counters;
 pub mod receiver;
-mod rto;
+pub mod recv_buffer;
+pub mod rto;
+pub mod send_buffer;
 pub mod sender;
+mod window_tuner;
 
-use self::{receiver::Receiver, sender::Sender};
+use self::{
+    counters::ConnectionCounters,
+    receiver::{Receiver, ReceiverState},
+    sender::{Sender, SenderState},
+};
 use crate::{
+    collections::watched::WatchedValue,
     fail::Fail,
     protocols::{
         arp,
@@ -16,64 +25,297 @@ use crate::{
             MacAddress,
         },
         ipv4,
-        ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
-        tcp::segment::{TcpHeader, TcpSegment},
+        ipv4::datagram::{Ipv4Header, Ipv4Protocol2, IPV4_HEADER_SIZE},
+        tcp::{
+            authentication::{AuthState, AO_MAC_LEN},
+            constants::MAX_SACK_BLOCKS,
+            segment::{
+                TcpHeader, TcpOptions2, TcpSegment, MAX_TCP_HEADER_SIZE, MIN_TCP_HEADER_SIZE,
+            },
+            seq_gt, seq_lt,
+            timestamps::{self, TimestampState},
+            ConnectionId, SeqNumber,
+        },
     },
     runtime::Runtime,
 };
-use std::time::Duration;
+use std::{
+    cell::Cell,
+    cmp,
+    net::Shutdown,
+    num::Wrapping,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
 /// Transmission control block for representing our TCP connection.
 pub struct ControlBlock<RT: Runtime> {
+    /// Stable identifier for this connection, for correlating log messages.
+    pub id: ConnectionId,
     pub local: ipv4::Endpoint,
     pub remote: ipv4::Endpoint,
 
     pub rt: RT,
     pub arp: arp::Peer<RT>,
 
+    /// The IPv4 TOS byte (DSCP and ECN) stamped on each outgoing segment.
+    pub tos: Cell<u8>,
+
+    /// The path MTU from the last "fragmentation needed" error we believed, if we've had one.
+    pub path_mtu: Cell<Option<usize>>,
+
     /// The sender end of our connection.
     pub sender: Sender<RT>,
     /// The receiver end of our connection.
     pub receiver: Receiver<RT>,
+
+    /// When we'll leave TIME-WAIT, if we're in it.
+    pub time_wait_deadline: WatchedValue<Option<Instant>>,
+
+    /// When we last heard from the peer, which keep-alive probing counts idle time from.
+    pub last_received: WatchedValue<Instant>,
+
+    /// Number of segments dropped because they failed the sequence number acceptability test.
+    pub rejected_segments: Cell<u64>,
+
+    /// Bytes and segments sent and received since the connection was established, or since the
+    /// counters were last reset.
+    pub counters: Cell<ConnectionCounters>,
+
+    /// The error that terminated the connection, until someone collects it.
+    pub error: Cell<Option<Fail>>,
+
+    /// Set once the connection's background work has finished, i.e. it has fully closed or been
+    /// reset.
+    pub closed: WatchedValue<bool>,
+
+    /// TCP-AO keys for this connection, if it's authenticated.
+    pub auth: Option<AuthState>,
+
+    /// Whether both sides advertised SACK-permitted on the handshake, so we send SACK blocks and
+    /// act on the peer's.
+    pub sack_permitted: bool,
+
+    /// Timestamp state, if both sides advertised the timestamps option on the handshake.
+    pub timestamps: Option<TimestampState>,
 }
 
 impl<RT: Runtime> ControlBlock<RT> {
     pub fn receive(&self, header: &TcpHeader, data: RT::Buf) {
-        debug!("Receiving {} bytes + {:?}", data.len(), header);
+        debug!("{}: Receiving {} bytes + {:?}", self.id, data.len(), header);
         let now = self.rt.now();
+        self.update_counters(|c| {
+            c.segments_received += 1;
+            c.bytes_received += data.len() as u64;
+        });
+        let timestamp = timestamps::parse(header);
+        if let Some(ref ts) = self.timestamps {
+            // Once timestamps are negotiated, every segment but a RST must carry one (RFC 7323,
+            // section 3.2). We let pure ACKs off, since a peer with `ts_on_pure_ack` turned off
+            // leaves it out of those.
+            let pure_ack = data.is_empty() && !header.syn && !header.fin;
+            if timestamp.is_none() && !header.rst && !pure_ack {
+                warn!("Dropping segment without timestamp {:?}", header);
+                return;
+            }
+            // PAWS (RFC 7323, section 5.3): a segment older than the last one we took the
+            // timestamp from is an old duplicate, even if its sequence number looks acceptable.
+            if let Some((tsval, _)) = timestamp {
+                if ts.is_old(tsval) && !header.rst {
+                    self.rejected_segments.set(self.rejected_segments.get() + 1);
+                    warn!("Dropping segment that failed PAWS {:?}", header);
+                    self.send_ack();
+                    return;
+                }
+            }
+        }
+        self.last_received.set(now);
+        // An echoed timestamp measures the RTT even for retransmitted data, where Karn's
+        // algorithm would otherwise leave us without a sample.
+        let rtt = match (&self.timestamps, timestamp) {
+            (Some(ts), Some((_, tsecr))) if header.ack && tsecr != 0 => Some(ts.rtt(tsecr, now)),
+            _ => None,
+        };
+        if !self.is_acceptable(header, data.len()) {
+            self.rejected_segments.set(self.rejected_segments.get() + 1);
+            if header.rst {
+                return;
+            }
+            // If our window is closed we still have to process ACKs and window updates, or we'd
+            // never find out that the other side has freed up space (RFC 793, page 69).
+            if self.receiver.window_size() == 0 && header.ack {
+                if let Err(e) = self.sender.remote_ack(header.ack_num, rtt, now) {
+                    warn!("Ignoring remote ack for {:?}: {:?}", header, e);
+                }
+                if let Err(e) = self.sender.update_remote_window(header.window_size as u16) {
+                    warn!("Invalid window size update for {:?}: {:?}", header, e);
+                }
+            }
+            warn!("Dropping unacceptable segment {:?}", header);
+            self.send_ack();
+            return;
+        }
+        // An ACK for something we haven't sent yet gets an ACK in reply, and the segment is
+        // dropped (RFC 793, page 72).
+        if header.ack && seq_gt(header.ack_num, self.snd_nxt()) {
+            warn!("Dropping ACK for unsent data {:?}", header);
+            self.send_ack();
+            return;
+        }
+        // Remember the timestamp to echo, as long as the segment doesn't start past what we've
+        // acknowledged (RFC 7323, section 4.3).
+        if let (Some(ts), Some((tsval, _))) = (&self.timestamps, timestamp) {
+            if !seq_gt(header.seq_num, self.receiver.ack_seq_no.get()) {
+                ts.set_recent(tsval);
+            }
+        }
         if header.syn {
             warn!("Ignoring duplicate SYN on established connection");
         }
         if header.rst {
             self.sender.receive_rst();
-        }
-        if header.fin {
-            self.receiver.receive_fin();
+            self.error.set(Some(Fail::ConnectionReset {}));
         }
         if header.ack {
-            if let Err(e) = self.sender.remote_ack(header.ack_num, now) {
+            if let Err(e) = self.sender.remote_ack(header.ack_num, rtt, now) {
                 warn!("Ignoring remote ack for {:?}: {:?}", header, e);
             }
+            if self.sack_permitted {
+                for option in header.iter_options() {
+                    if let TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks } = option {
+                        for sack in &sacks[..*num_sacks] {
+                            self.sender.remote_sack(sack.begin, sack.end);
+                        }
+                    }
+                }
+            }
         }
         if let Err(e) = self.sender.update_remote_window(header.window_size as u16) {
             warn!("Invalid window size update for {:?}: {:?}", header, e);
         }
+        // The FIN comes after the segment's data, so we take the data first.
+        let fin_seq_no = header.seq_num + Wrapping(data.len() as u32);
         if !data.is_empty() {
             if let Err(e) = self.receiver.receive_data(header.seq_num, data, now) {
                 warn!("Ignoring remote data for {:?}: {:?}", header, e);
             }
         }
+        if header.fin {
+            self.receiver.receive_fin(fin_seq_no, now);
+        }
+    }
+
+    /// The sequence number acceptability test from RFC 793, section 3.3: a segment is acceptable
+    /// if any part of it falls within our receive window.
+    fn is_acceptable(&self, header: &TcpHeader, data_len: usize) -> bool {
+        let fin_received = self.receiver.state.get() != ReceiverState::Open;
+        let recv_seq_no = self.receiver.recv_seq_no.get();
+
+        // A retransmitted FIN sits just left of our window, but we need to see it so we can ACK it
+        // again and restart TIME-WAIT.
+        if fin_received && header.fin && header.seq_num + Wrapping(data_len as u32) == recv_seq_no {
+            return true;
+        }
+
+        // RCV.NXT covers the FIN too, once we've received it.
+        let rcv_nxt = if fin_received {
+            recv_seq_no + Wrapping(1)
+        } else {
+            recv_seq_no
+        };
+        let rcv_wnd = self.receiver.window_size();
+        let seg_len = data_len as u32 + header.syn as u32 + header.fin as u32;
+        let in_window = |seq_no: SeqNumber| (seq_no - rcv_nxt).0 < rcv_wnd;
+
+        match (seg_len, rcv_wnd) {
+            (0, 0) => header.seq_num == rcv_nxt,
+            (0, _) => in_window(header.seq_num),
+            (_, 0) => false,
+            (_, _) => {
+                in_window(header.seq_num) || in_window(header.seq_num + Wrapping(seg_len - 1))
+            }
+        }
+    }
+
+    /// Immediately send a pure ACK for everything we've received so far.
+    fn send_ack(&self) {
+        let remote_link_addr = match self.arp.try_query(self.remote.address()) {
+            Some(r) => r,
+            None => {
+                warn!("Not sending ACK for unacceptable segment: no ARP entry for remote");
+                return;
+            }
+        };
+        self.emit(self.ack_header(), RT::Buf::empty(), remote_link_addr);
+    }
+
+    /// Sends a keep-alive probe: a pure ACK for a sequence number the peer has already seen, which
+    /// it has to answer with an ACK of its own (RFC 1122, section 4.2.3.6).
+    pub fn send_keepalive(&self, remote_link_addr: MacAddress) {
+        let mut header = self.ack_header();
+        header.seq_num -= Wrapping(1);
+        self.emit(header, RT::Buf::empty(), remote_link_addr);
+    }
+
+    /// A header acknowledging everything we've received so far.
+    fn ack_header(&self) -> TcpHeader {
+        let mut header = self.tcp_header();
+        header.ack = true;
+        header.ack_num = match self.receiver.state.get() {
+            ReceiverState::AckdFin => self.receiver.recv_seq_no.get() + Wrapping(1),
+            _ => self.receiver.recv_seq_no.get(),
+        };
+        header
     }
 
     pub fn close(&self) -> Result<(), Fail> {
         self.sender.close()
     }
 
+    pub fn poll_push(&self, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        self.sender
+            .poll_push(self.rt.tcp_options().send_buffer_size, ctx)
+    }
+
+    /// Shuts down one or both directions of the connection. Shutting down writing sends our FIN
+    /// once the data already pushed has gone out, while the peer can keep sending to us.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Fail> {
+        if how != Shutdown::Write {
+            self.receiver.shutdown();
+        }
+        if how != Shutdown::Read {
+            self.sender.close()?;
+        }
+        Ok(())
+    }
+
+    /// Gives up on a peer that has stopped responding: pushes and pops fail from now on, and the
+    /// error is kept for `take_error`.
+    pub fn time_out(&self) {
+        self.sender.time_out();
+        self.receiver.time_out();
+        self.error.set(Some(Fail::Timeout {}));
+    }
+
+    /// The sequence number of the next byte we'll send (SND.NXT), which moves past our FIN once
+    /// we've sent it.
+    pub fn snd_nxt(&self) -> SeqNumber {
+        let sent_seq_no = self.sender.sent_seq_no.get();
+        match self.sender.state.get() {
+            SenderState::SentFin | SenderState::FinAckd => sent_seq_no + Wrapping(1),
+            _ => sent_seq_no,
+        }
+    }
+
     /// Fetch a TCP header filling out various values based on our current state.
     pub fn tcp_header(&self) -> TcpHeader {
         let mut header = TcpHeader::new(self.local.port, self.remote.port);
         header.window_size = self.receiver.hdr_window_size();
 
+        // Segments without data of their own (e.g. pure ACKs) go out at SND.NXT. Data segments
+        // overwrite this with their own sequence number.
+        header.seq_num = self.snd_nxt();
+
         // Check if we have acknowledged all bytes that we have received. If not, piggy back an ACK
         // on this message.
         if let Some(ack_seq_no) = self.receiver.current_ack() {
@@ -84,24 +326,66 @@ impl<RT: Runtime> ControlBlock<RT> {
     }
 
     /// Transmit this message to our connected peer.
-    pub fn emit(&self, header: TcpHeader, data: RT::Buf, remote_link_addr: MacAddress) {
+    pub fn emit(&self, mut header: TcpHeader, data: RT::Buf, remote_link_addr: MacAddress) {
+        let tcp_options = self.rt.tcp_options();
+        let pure_ack = data.is_empty() && !header.syn && !header.fin && !header.rst;
+        if let Some(ref ts) = self.timestamps {
+            if !pure_ack || tcp_options.ts_on_pure_ack {
+                header.push_option(ts.option(self.rt.now()));
+            }
+        }
         if header.ack {
             self.receiver.update_ack_sent(header.ack_num);
+            if self.sack_permitted {
+                self.push_sack_option(&mut header);
+            }
         }
+        let signer = self.auth.as_ref().map(|auth| auth.sign(&mut header));
 
-        debug!("Sending {} bytes + {:?}", data.len(), header);
+        debug!("{}: Sending {} bytes + {:?}", self.id, data.len(), header);
+        self.update_counters(|c| {
+            c.segments_sent += 1;
+            c.bytes_sent += data.len() as u64;
+        });
+        let mut ipv4_hdr = Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp);
+        let tos = self.tos.get();
+        ipv4_hdr.dscp = tos >> 2;
+        ipv4_hdr.ecn = tos & 3;
         let segment = TcpSegment {
             ethernet2_hdr: Ethernet2Header {
                 dst_addr: remote_link_addr,
                 src_addr: self.rt.local_link_addr(),
                 ether_type: EtherType2::Ipv4,
             },
-            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
+            ipv4_hdr,
             tcp_hdr: header,
             data,
-            tx_checksum_offload: self.rt.tcp_options().tx_checksum_offload,
+            tx_checksum_offload: tcp_options.tx_checksum_offload,
+            signer,
         };
-        self.rt.transmit(segment);
+        self.arp.transmit(segment);
+    }
+
+    /// Reports the out-of-order data we're holding in a SACK option, if there is any.
+    fn push_sack_option(&self, header: &mut TcpHeader) {
+        let blocks = self.receiver.sack_blocks();
+        if blocks.is_empty() {
+            return;
+        }
+        // Fit as many blocks as we can around the options already on the header, and the TCP-AO
+        // option that signing will add.
+        let mut room = MAX_TCP_HEADER_SIZE - header.compute_size();
+        if self.auth.is_some() {
+            room -= 4 + AO_MAC_LEN;
+        }
+        let max_blocks = cmp::min(room.saturating_sub(2) / 8, MAX_SACK_BLOCKS);
+        let num_sacks = cmp::min(blocks.len(), max_blocks);
+        if num_sacks == 0 {
+            return;
+        }
+        let mut sacks = [blocks[0]; MAX_SACK_BLOCKS];
+        sacks[..num_sacks].copy_from_slice(&blocks[..num_sacks]);
+        header.push_option(TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks });
     }
 
     pub fn remote_mss(&self) -> usize {
@@ -111,4 +395,206 @@ impl<RT: Runtime> ControlBlock<RT> {
     pub fn current_rto(&self) -> Duration {
         self.sender.current_rto()
     }
+
+    /// Our send window (SND.WND) in bytes: the window field of the peer's last segment, scaled by
+    /// the shift it negotiated on the handshake.
+    pub fn effective_send_window(&self) -> u32 {
+        self.sender.window_size.get()
+    }
+
+    pub fn path_mtu(&self) -> usize {
+        self.path_mtu
+            .get()
+            .unwrap_or_else(|| self.sender.remote_mss() + IPV4_HEADER_SIZE + MIN_TCP_HEADER_SIZE)
+    }
+
+    /// Handles a "fragmentation needed" error for the segment starting at `seq_num`. We only
+    /// believe errors about data that's actually in flight, so that an off-path attacker can't
+    /// easily shrink our segments (RFC 5927).
+    pub fn update_path_mtu(&self, seq_num: SeqNumber, path_mtu: usize) -> Result<(), Fail> {
+        if seq_lt(seq_num, self.sender.base_seq_no.get())
+            || !seq_lt(seq_num, self.sender.sent_seq_no.get())
+        {
+            return Err(Fail::Ignored {
+                details: "Fragmentation needed for data not in flight",
+            });
+        }
+        // The path MTU only comes down this way (RFC 1191, section 3).
+        if path_mtu < self.path_mtu() {
+            self.path_mtu.set(Some(path_mtu));
+        }
+        let mss = path_mtu.saturating_sub(IPV4_HEADER_SIZE + MIN_TCP_HEADER_SIZE);
+        self.sender.reduce_mss(mss);
+        Ok(())
+    }
+
+    pub fn time_wait_deadline(&self) -> Option<Instant> {
+        self.time_wait_deadline.get()
+    }
+
+    pub fn rejected_segments(&self) -> u64 {
+        self.rejected_segments.get()
+    }
+
+    pub fn counters(&self) -> ConnectionCounters {
+        self.counters.get()
+    }
+
+    /// Zeroes the traffic counters, returning what they were.
+    pub fn reset_counters(&self) -> ConnectionCounters {
+        self.counters.take()
+    }
+
+    /// Returns the error that terminated the connection, if any, and clears it (like
+    /// `getsockopt(SO_ERROR)`).
+    pub fn take_error(&self) -> Option<Fail> {
+        self.error.take()
+    }
+
+    pub fn update_counters(&self, f: impl FnOnce(&mut ConnectionCounters)) {
+        let mut counters = self.counters.get();
+        f(&mut counters);
+        self.counters.set(counters);
+    }
+
+    pub fn set_tos(&self, tos: u8) {
+        self.tos.set(tos);
+    }
+
+    pub fn set_quickack(&self, quickack: bool) {
+        self.receiver.quickack.set(quickack);
+    }
+
+    /// Like Linux, we treat a low-watermark of zero as one.
+    pub fn set_rcvlowat(&self, bytes: usize) {
+        self.receiver.rcvlowat.set(cmp::max(bytes, 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        counters::ConnectionCounters, receiver::Receiver, rto::RtoCalculator, sender::Sender,
+        ControlBlock,
+    };
+    use crate::{
+        collections::{
+            bytes::{Bytes, BytesMut},
+            watched::WatchedValue,
+        },
+        memory_budget::MemoryBudget,
+        protocols::{
+            arp, ip, ipv4,
+            tcp::{congestion_ctrl as cc, segment::TcpHeader, ConnectionId},
+        },
+        runtime::RuntimeBuf,
+        test_helpers::{self, DecodedFrame, TestRuntime, L4},
+    };
+    use must_let::must_let;
+    use std::{
+        cell::Cell,
+        collections::HashMap,
+        convert::TryFrom,
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    fn new_control_block(
+        rt: &TestRuntime,
+        local_isn: u32,
+        remote_isn: u32,
+        now: Instant,
+    ) -> ControlBlock<TestRuntime> {
+        let mut initial_values = HashMap::new();
+        initial_values.insert(test_helpers::BOB_IPV4, test_helpers::BOB_MAC);
+        let arp_options = arp::Options::new(
+            Duration::from_secs(600),
+            Duration::from_secs(1),
+            2,
+            initial_values,
+            false,
+        );
+        let memory = MemoryBudget::default();
+        ControlBlock {
+            id: ConnectionId::next(),
+            local: ipv4::Endpoint::new(
+                test_helpers::ALICE_IPV4,
+                ip::Port::try_from(54321).unwrap(),
+            ),
+            remote: ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap()),
+            rt: rt.clone(),
+            arp: arp::Peer::new(now, rt.clone(), arp_options).unwrap(),
+            tos: Cell::new(0),
+            path_mtu: Cell::new(None),
+            sender: Sender::new(
+                Wrapping(local_isn),
+                0xffff,
+                0,
+                1024,
+                false,
+                RtoCalculator::default(),
+                cc::Cubic::new,
+                None,
+                memory.clone(),
+            ),
+            receiver: Receiver::new(
+                Wrapping(remote_isn),
+                0xffff,
+                0,
+                2,
+                Duration::from_millis(40),
+                None,
+                memory,
+            ),
+            time_wait_deadline: WatchedValue::new(None),
+            last_received: WatchedValue::new(now),
+            rejected_segments: Cell::new(0),
+            counters: Cell::new(ConnectionCounters::default()),
+            error: Cell::new(None),
+            closed: WatchedValue::new(false),
+            auth: None,
+            sack_permitted: false,
+            timestamps: None,
+        }
+    }
+
+    #[test]
+    fn test_ack_for_unsent_data_near_wraparound() {
+        let now = Instant::now();
+        let rt = TestRuntime::new(
+            "alice",
+            now,
+            test_helpers::ALICE_MAC,
+            test_helpers::ALICE_IPV4,
+        );
+        let cb = new_control_block(&rt, u32::MAX - 4, 1000, now);
+
+        // Send 10 bytes, which takes our sequence numbers across the wrap boundary.
+        let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+        cb.sender.send(buf, &cb).unwrap();
+        rt.pop_frame();
+        let sent_seq_no = cb.sender.sent_seq_no.get();
+        assert_eq!(sent_seq_no, Wrapping(5));
+
+        // An ACK just past what we've sent is unacceptable: we mustn't advance past it, and we
+        // tell the other side where we really are.
+        let mut header = TcpHeader::new(cb.remote.port, cb.local.port);
+        header.seq_num = Wrapping(1000);
+        header.ack = true;
+        header.ack_num = sent_seq_no + Wrapping(3);
+        header.window_size = 0xffff;
+        cb.receive(&header, Bytes::empty());
+        assert_eq!(cb.sender.base_seq_no.get(), Wrapping(u32::MAX - 4));
+
+        must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(ack_hdr), .. } = test_helpers::decode_frame(&rt.pop_frame()));
+        assert!(ack_hdr.ack);
+        assert_eq!(ack_hdr.seq_num, sent_seq_no);
+        assert_eq!(ack_hdr.ack_num, Wrapping(1000));
+
+        // The real ACK is still accepted afterwards.
+        header.ack_num = sent_seq_no;
+        cb.receive(&header, Bytes::empty());
+        assert_eq!(cb.sender.base_seq_no.get(), sent_seq_no);
+        assert!(rt.try_pop_frame().is_none());
+    }
 }
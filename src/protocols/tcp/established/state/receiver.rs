@@ -1,19 +1,24 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+use super::{recv_buffer::RecvBuffer, window_tuner::WindowTuner};
 use crate::{
-    collections::watched::WatchedValue, fail::Fail, protocols::tcp::SeqNumber, runtime::Runtime,
+    collections::watched::WatchedValue,
+    fail::Fail,
+    memory_budget::MemoryBudget,
+    protocols::tcp::{segment::SelectiveAcknowlegement, seq_gt, seq_lt, SeqNumber},
+    runtime::{Runtime, RuntimeBuf},
 };
 use std::{
-    cell::RefCell,
-    collections::{BTreeMap, VecDeque},
+    cell::{Cell, RefCell},
+    cmp,
     convert::TryInto,
     num::Wrapping,
     task::{Context, Poll, Waker},
     time::{Duration, Instant},
 };
 
-const RECV_QUEUE_SZ: usize = 2048;
+/// Most separate ranges of out-of-order data we hold on to at once.
 const MAX_OUT_OF_ORDER: usize = 16;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -42,40 +47,96 @@ pub struct Receiver<RT: Runtime> {
     // the old `ack_seq_no` until we send them an ACK (see the diagram in sender.rs).
     //
     pub base_seq_no: WatchedValue<SeqNumber>,
-    pub recv_queue: RefCell<VecDeque<RT::Buf>>,
+    /// The data from `base_seq_no` on, including out-of-order segments held until the gap in
+    /// front of them is filled.
+    recv_buffer: RefCell<RecvBuffer>,
     /// Running counter of ack sequence number we have sent to peer.
     pub ack_seq_no: WatchedValue<SeqNumber>,
     /// Our sequence number based on how much data we have sent.
     pub recv_seq_no: WatchedValue<SeqNumber>,
 
     pub ack_deadline: WatchedValue<Option<Instant>>,
-
-    pub max_window_size: u32,
+    /// ACK every in-order segment immediately instead of delaying the ACK (TCP_QUICKACK).
+    pub quickack: Cell<bool>,
+    /// `pop` waits until at least this many bytes are buffered, unless the peer has closed its
+    /// side (SO_RCVLOWAT).
+    pub rcvlowat: Cell<usize>,
+    /// Send an ACK once this many segments have arrived since the last one we sent.
+    pub delack_segs: usize,
+    /// Otherwise, send one this long after the first segment it covers arrived.
+    pub ack_delay: Duration,
+    unacked_segments: Cell<usize>,
+
+    pub max_window_size: Cell<u32>,
     pub window_scale: u32,
+    /// Grows `max_window_size` to suit the path, if receive window auto-tuning is on.
+    window_tuner: Option<WindowTuner>,
+
+    /// Shared accounting for the bytes sitting in `recv_buffer`.
+    memory: MemoryBudget,
 
     waker: RefCell<Option<Waker>>,
-    out_of_order: RefCell<BTreeMap<SeqNumber, RT::Buf>>,
+    /// Set once the connection has timed out, so that `pop` fails instead of waiting for data
+    /// that will never arrive.
+    timed_out: Cell<bool>,
+    /// Set once the application has shut down its reading side, after which `pop` reports end of
+    /// stream as soon as the buffered data is gone.
+    shut_down: Cell<bool>,
+    /// Sequence number of a FIN that arrived ahead of data we're still missing. We only take it
+    /// once everything before it has arrived.
+    pending_fin: Cell<Option<SeqNumber>>,
+    /// Where the last out-of-order segment we stored starts, so we can report its SACK block
+    /// first.
+    last_out_of_order: Cell<Option<SeqNumber>>,
 }
 
 impl<RT: Runtime> Receiver<RT> {
-    pub fn new(seq_no: SeqNumber, max_window_size: u32, window_scale: u32) -> Self {
+    pub fn new(
+        seq_no: SeqNumber,
+        max_window_size: u32,
+        window_scale: u32,
+        delack_segs: usize,
+        ack_delay: Duration,
+        rcvbuf_max: Option<u32>,
+        memory: MemoryBudget,
+    ) -> Self {
+        // We can't advertise a window any bigger than the window scale allows.
+        let window_tuner = rcvbuf_max
+            .map(|max| WindowTuner::new(cmp::min(max, (u16::MAX as u32) << window_scale)));
         Self {
             state: WatchedValue::new(ReceiverState::Open),
             base_seq_no: WatchedValue::new(seq_no),
-            recv_queue: RefCell::new(VecDeque::with_capacity(RECV_QUEUE_SZ)),
+            recv_buffer: RefCell::new(RecvBuffer::new(seq_no, max_window_size as usize)),
             ack_seq_no: WatchedValue::new(seq_no),
             recv_seq_no: WatchedValue::new(seq_no),
             ack_deadline: WatchedValue::new(None),
-            max_window_size,
+            quickack: Cell::new(false),
+            rcvlowat: Cell::new(1),
+            delack_segs,
+            ack_delay,
+            unacked_segments: Cell::new(0),
+            max_window_size: Cell::new(max_window_size),
             window_scale,
+            window_tuner,
+            memory,
             waker: RefCell::new(None),
-            out_of_order: RefCell::new(BTreeMap::new()),
+            timed_out: Cell::new(false),
+            shut_down: Cell::new(false),
+            pending_fin: Cell::new(None),
+            last_out_of_order: Cell::new(None),
         }
     }
 
-    pub fn hdr_window_size(&self) -> u16 {
+    /// Space left in our receive window (RCV.WND), in bytes. We never offer more than we could
+    /// buffer without going over the memory budget.
+    pub fn window_size(&self) -> u32 {
         let Wrapping(bytes_outstanding) = self.recv_seq_no.get() - self.base_seq_no.get();
-        let window_size = self.max_window_size - bytes_outstanding;
+        let available = cmp::min(self.memory.available(), u32::MAX as usize) as u32;
+        cmp::min(self.max_window_size.get() - bytes_outstanding, available)
+    }
+
+    pub fn hdr_window_size(&self) -> u16 {
+        let window_size = self.window_size();
         let hdr_window_size = (window_size >> self.window_scale)
             .try_into()
             .expect("Window size overflow");
@@ -115,80 +176,165 @@ impl<RT: Runtime> Receiver<RT> {
             assert_eq!(ack_seq, self.recv_seq_no.get());
         }
         self.ack_deadline.set(None);
+        self.unacked_segments.set(0);
         self.ack_seq_no.set(ack_seq);
     }
 
+    /// Whether a `pop` would return immediately, either with data or with EOF.
+    pub fn is_readable(&self) -> bool {
+        let Wrapping(buffered) = self.recv_seq_no.get() - self.base_seq_no.get();
+        !self.is_open()
+            || self.timed_out.get()
+            || (buffered > 0 && buffered as usize >= self.rcvlowat.get())
+    }
+
+    /// Returns the next chunk of in-order data without consuming it, or an empty buffer at the end
+    /// of the stream.
     pub fn peek(&self) -> Result<RT::Buf, Fail> {
         if self.base_seq_no.get() == self.recv_seq_no.get() {
-            if self.state.get() != ReceiverState::Open {
-                return Err(Fail::ResourceNotFound {
-                    details: "Receiver closed",
-                });
+            if !self.is_open() {
+                return Ok(RT::Buf::empty());
             }
             return Err(Fail::ResourceExhausted {
                 details: "No available data",
             });
         }
 
-        let segment = self
-            .recv_queue
-            .borrow_mut()
-            .front()
-            .expect("recv_seq > base_seq without data in queue?")
-            .clone();
-
-        Ok(segment)
+        let recv_buffer = self.recv_buffer.borrow();
+        let data = recv_buffer.peek(recv_buffer.available());
+        Ok(RT::Buf::from_slice(&data[..]))
     }
 
+    /// Consumes all of the in-order data, if there is any yet. At the end of the stream it returns
+    /// an empty buffer.
     pub fn recv(&self) -> Result<Option<RT::Buf>, Fail> {
         if self.base_seq_no.get() == self.recv_seq_no.get() {
-            if self.state.get() != ReceiverState::Open {
-                return Err(Fail::ResourceNotFound {
-                    details: "Receiver closed",
-                });
+            if self.timed_out.get() {
+                return Err(Fail::Timeout {});
+            }
+            if !self.is_open() {
+                return Ok(Some(RT::Buf::empty()));
             }
             return Ok(None);
         }
 
-        let segment = self
-            .recv_queue
-            .borrow_mut()
-            .pop_front()
-            .expect("recv_seq > base_seq without data in queue?");
-        self.base_seq_no
-            .modify(|b| b + Wrapping(segment.len() as u32));
-
-        Ok(Some(segment))
+        Ok(Some(self.read()))
     }
 
+    /// We don't look at PSH. Without a receive low-watermark, in-order data is handed to the
+    /// application as soon as it arrives, pushed or not, which is all PSH asks of a receiver
+    /// (RFC 1122, section 4.2.2.2). With one, a `pop` waits for at least that many bytes (or EOF)
+    /// even if a segment had PSH set, as with `SO_RCVLOWAT` on Linux. Either way, a `pop` hands
+    /// back everything buffered in order in one go. Once the stream has ended and everything
+    /// before the end has been handed back, we return an empty buffer, which data never is.
     pub fn poll_recv(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
-        if self.base_seq_no.get() == self.recv_seq_no.get() {
-            if self.state.get() != ReceiverState::Open {
-                return Poll::Ready(Err(Fail::ResourceNotFound {
-                    details: "Receiver closed",
-                }));
-            }
+        let Wrapping(buffered) = self.recv_seq_no.get() - self.base_seq_no.get();
+        let open = self.is_open();
+        if buffered == 0 && self.timed_out.get() {
+            return Poll::Ready(Err(Fail::Timeout {}));
+        }
+        if buffered == 0 && !open {
+            return Poll::Ready(Ok(RT::Buf::empty()));
+        }
+        if buffered == 0 || (open && (buffered as usize) < self.rcvlowat.get()) {
             *self.waker.borrow_mut() = Some(ctx.waker().clone());
             return Poll::Pending;
         }
 
-        let segment = self
-            .recv_queue
-            .borrow_mut()
-            .pop_front()
-            .expect("recv_seq > base_seq without data in queue?");
-        self.base_seq_no
-            .modify(|b| b + Wrapping(segment.len() as u32));
+        Poll::Ready(Ok(self.read()))
+    }
+
+    /// Takes everything buffered in order out of the receive buffer.
+    fn read(&self) -> RT::Buf {
+        let mut recv_buffer = self.recv_buffer.borrow_mut();
+        let data = recv_buffer.read(recv_buffer.available());
+        assert!(
+            !data.is_empty(),
+            "recv_seq > base_seq without data in buffer?"
+        );
+        self.base_seq_no.modify(|b| b + Wrapping(data.len() as u32));
+        self.memory.release(data.len());
+        RT::Buf::from_slice(&data[..])
+    }
+
+    /// The contiguous ranges of out-of-order data we're holding, to report as SACK blocks. The
+    /// block containing the most recently received segment comes first, as RFC 2018 requires,
+    /// and the rest follow in sequence order.
+    pub fn sack_blocks(&self) -> Vec<SelectiveAcknowlegement> {
+        let mut blocks: Vec<SelectiveAcknowlegement> = self
+            .recv_buffer
+            .borrow()
+            .out_of_order()
+            .map(|(begin, end)| SelectiveAcknowlegement { begin, end })
+            .collect();
+        if let Some(last) = self.last_out_of_order.get() {
+            if let Some(i) = blocks
+                .iter()
+                .position(|b| !seq_lt(last, b.begin) && seq_lt(last, b.end))
+            {
+                let block = blocks.remove(i);
+                blocks.insert(0, block);
+            }
+        }
+        blocks
+    }
+
+    /// Fails any pending `pop` once the data we've already received has been read.
+    pub fn time_out(&self) {
+        self.timed_out.set(true);
+        if let Some(w) = self.waker.borrow_mut().take() {
+            w.wake()
+        }
+    }
 
-        Poll::Ready(Ok(segment))
+    /// Shuts down our reading side (SHUT_RD): data that's already buffered can still be popped,
+    /// but after that `pop` reports end of stream.
+    pub fn shutdown(&self) {
+        self.shut_down.set(true);
+        if let Some(w) = self.waker.borrow_mut().take() {
+            w.wake()
+        }
     }
 
-    pub fn receive_fin(&self) {
+    /// Whether the application may still see more data, i.e. neither the peer's FIN nor our own
+    /// shutdown has ended the stream.
+    fn is_open(&self) -> bool {
+        self.state.get() == ReceiverState::Open && !self.shut_down.get()
+    }
+
+    /// Takes a FIN with sequence number `seq_no`, i.e. the one just past the data in its segment.
+    /// If data before it is still missing, we hold on to the FIN until that data arrives, since
+    /// taking it now would end the stream early.
+    pub fn receive_fin(&self, seq_no: SeqNumber, now: Instant) {
+        let recv_seq_no = self.recv_seq_no.get();
+        if self.state.get() == ReceiverState::Open && seq_gt(seq_no, recv_seq_no) {
+            debug!(
+                "Holding FIN at {} until data from {} arrives",
+                seq_no, recv_seq_no
+            );
+            self.pending_fin.set(Some(seq_no));
+            self.ack_deadline.set(Some(now));
+            return;
+        }
+        if self.state.get() == ReceiverState::Open && seq_lt(seq_no, recv_seq_no) {
+            warn!("Ignoring FIN at {} inside data we've received", seq_no);
+            return;
+        }
         // Even if we've already ACKd the FIN, we need to resend the ACK if we receive another FIN.
+        self.pending_fin.set(None);
         self.state.set(ReceiverState::ReceivedFin);
+        // A `pop` held back by the low-watermark returns whatever is left once the peer closes.
+        if let Some(w) = self.waker.borrow_mut().take() {
+            w.wake()
+        }
     }
 
-    pub fn receive_data(&self, seq_no: SeqNumber, buf: RT::Buf, now: Instant) -> Result<(), Fail> {
+    pub fn receive_data(
+        &self,
+        seq_no: SeqNumber,
+        mut buf: RT::Buf,
+        now: Instant,
+    ) -> Result<(), Fail> {
         if self.state.get() != ReceiverState::Open {
             return Err(Fail::ResourceNotFound {
                 details: "Receiver closed",
@@ -196,82 +342,272 @@ impl<RT: Runtime> Receiver<RT> {
         }
 
         let recv_seq_no = self.recv_seq_no.get();
-        if seq_no > recv_seq_no {
-            let mut out_of_order = self.out_of_order.borrow_mut();
-            if !out_of_order.contains_key(&seq_no) {
-                while out_of_order.len() > MAX_OUT_OF_ORDER {
-                    let (&key, _) = out_of_order.iter().rev().next().unwrap();
-                    out_of_order.remove(&key);
-                }
-                out_of_order.insert(seq_no, buf);
+        if seq_gt(seq_no, recv_seq_no) {
+            // A gap means a segment may have been lost, so we ACK right away rather than delaying
+            // it, to get the sender's loss recovery going (RFC 5681, section 4.2).
+            self.ack_deadline.set(Some(now));
+            // Only hold on to what will fit in the window once the gap is filled.
+            let window_end = self.base_seq_no.get() + Wrapping(self.max_window_size.get());
+            if seq_gt(seq_no + Wrapping(buf.len() as u32), window_end) {
                 return Err(Fail::Ignored {
-                    details: "Out of order segment (reordered)",
+                    details: "Out of order segment (outside receive window)",
                 });
             }
-        }
-        if seq_no < recv_seq_no {
+            // Segments held for reassembly count against the memory budget like any other buffered
+            // data. If there's no room, the sender will retransmit once the gap is filled.
+            if self.memory.try_reserve(buf.len()).is_err() {
+                return Err(Fail::ResourceExhausted {
+                    details: "Out of order segment (buffer memory limit)",
+                });
+            }
+            let mut recv_buffer = self.recv_buffer.borrow_mut();
+            let held = recv_buffer.buffered();
+            let result = recv_buffer.insert(seq_no, &buf[..]);
+            // Only charge for what we didn't already have.
+            self.memory
+                .release(held + buf.len() - recv_buffer.buffered());
+            result?;
+            if recv_buffer.buffered() == held {
+                return Err(Fail::Ignored {
+                    details: "Out of order segment (duplicate)",
+                });
+            }
+            // Give up on the data furthest ahead to keep the number of holes bounded.
+            while recv_buffer.out_of_order().count() > MAX_OUT_OF_ORDER {
+                self.memory.release(recv_buffer.evict_last());
+            }
+            self.last_out_of_order.set(Some(seq_no));
             return Err(Fail::Ignored {
-                details: "Out of order segment (duplicate)",
+                details: "Out of order segment (reordered)",
             });
         }
-
-        let unread_bytes = self
-            .recv_queue
-            .borrow()
-            .iter()
-            .map(|b| b.len())
-            .sum::<usize>();
-        if unread_bytes + buf.len() > self.max_window_size as usize {
+        if seq_lt(seq_no, recv_seq_no) {
             return Err(Fail::Ignored {
-                details: "Full receive window",
+                details: "Out of order segment (duplicate)",
             });
         }
 
-        self.recv_seq_no.modify(|r| r + Wrapping(buf.len() as u32));
-        self.recv_queue.borrow_mut().push_back(buf);
+        // Buffer as much of the segment as our window and the memory budget have room for. The
+        // rest is dropped for the sender to retransmit once the application has drained the
+        // buffer, and we ACK straight away so it finds out our window has closed.
+        let Wrapping(unread_bytes) = recv_seq_no - self.base_seq_no.get();
+        let room = cmp::min(
+            self.max_window_size.get().saturating_sub(unread_bytes) as usize,
+            self.memory.available(),
+        );
+        let truncated = buf.len() > room;
+        if truncated {
+            if room == 0 {
+                self.ack_deadline.set(Some(now));
+                return Err(Fail::ResourceExhausted {
+                    details: "Receive buffer full",
+                });
+            }
+            debug!(
+                "Dropping {} bytes past the end of the receive buffer",
+                buf.len() - room
+            );
+            buf.trim(buf.len() - room);
+        }
+        {
+            // Filling a gap also makes the out-of-order data behind it readable, which has
+            // already been charged to the memory budget.
+            let mut recv_buffer = self.recv_buffer.borrow_mut();
+            let held = recv_buffer.buffered();
+            recv_buffer.insert(seq_no, &buf[..])?;
+            self.memory.reserve(recv_buffer.buffered() - held);
+            let new_recv_seq_no = recv_buffer.next_seq_no();
+            if new_recv_seq_no != seq_no + Wrapping(buf.len() as u32) {
+                info!("Recovered out-of-order data up to {}", new_recv_seq_no);
+            }
+            self.recv_seq_no.set(new_recv_seq_no);
+        }
         if let Some(w) = self.waker.borrow_mut().take() {
             w.wake()
         }
+        if let Some(ref tuner) = self.window_tuner {
+            let max_window_size = self.max_window_size.get();
+            if let Some(new_size) = tuner.on_receive(now, self.recv_seq_no.get(), max_window_size) {
+                debug!("Growing receive window {} -> {}", max_window_size, new_size);
+                self.max_window_size.set(new_size);
+                self.recv_buffer.borrow_mut().grow(new_size as usize);
+            }
+        }
 
         // TODO: How do we handle when the other side is in PERSIST state here?
-        if self.ack_deadline.get().is_none() {
-            // TODO: Configure this value (and also maybe just have an RT pointer here.)
-            self.ack_deadline
-                .set(Some(now + Duration::from_millis(500)));
+        let unacked_segments = self.unacked_segments.get() + 1;
+        self.unacked_segments.set(unacked_segments);
+        if truncated || self.quickack.get() || unacked_segments >= self.delack_segs {
+            self.ack_deadline.set(Some(now));
+        } else if self.ack_deadline.get().is_none() {
+            self.ack_deadline.set(Some(now + self.ack_delay));
         }
 
-        let new_recv_seq_no = self.recv_seq_no.get();
-        let old_data = {
-            let mut out_of_order = self.out_of_order.borrow_mut();
-            out_of_order.remove(&new_recv_seq_no)
-        };
-        if let Some(old_data) = old_data {
-            info!("Recovering out-of-order packet at {}", new_recv_seq_no);
-            if let Err(e) = self.receive_data(new_recv_seq_no, old_data, now) {
-                info!("Failed to recover out-of-order packet: {:?}", e);
-            }
+        // The data we were missing before a held FIN has all arrived.
+        if self.state.get() == ReceiverState::Open
+            && self.pending_fin.get() == Some(self.recv_seq_no.get())
+        {
+            self.receive_fin(self.recv_seq_no.get(), now);
         }
 
         Ok(())
     }
 }
 
+impl<RT: Runtime> Drop for Receiver<RT> {
+    fn drop(&mut self) {
+        self.memory.release(self.recv_buffer.borrow().buffered());
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Receiver;
+    use super::{Receiver, MAX_OUT_OF_ORDER};
     use crate::collections::bytes::BytesMut;
     use crate::fail::Fail;
+    use crate::memory_budget::MemoryBudget;
     use crate::test_helpers::TestRuntime;
     use must_let::must_let;
-    use std::{num::Wrapping, time::Instant};
+    use std::{
+        cmp,
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
 
     #[test]
     fn test_out_of_order() {
         let now = Instant::now();
-        let receiver = Receiver::<TestRuntime>::new(Wrapping(0), 65536, 0);
+        let receiver = Receiver::<TestRuntime>::new(
+            Wrapping(0),
+            65536,
+            0,
+            2,
+            Duration::from_millis(40),
+            None,
+            MemoryBudget::default(),
+        );
+        let buf = BytesMut::zeroed(16).freeze();
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(16), buf.clone(), now));
+        // The gap gets ACKed straight away.
+        assert_eq!(receiver.ack_deadline.get(), Some(now));
+        must_let!(let Ok(..) = receiver.receive_data(Wrapping(0), buf.clone(), now));
+        assert_eq!(receiver.recv_seq_no.get(), Wrapping(32));
+
+        // However many holes the segments arriving past a gap leave, we hold on to no more than
+        // MAX_OUT_OF_ORDER ranges of them.
+        for i in 0..MAX_OUT_OF_ORDER + 4 {
+            let seq_no = Wrapping(48 + 32 * i as u32);
+            must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(seq_no, buf.clone(), now));
+            assert!(receiver.sack_blocks().len() <= MAX_OUT_OF_ORDER);
+        }
+        assert_eq!(receiver.sack_blocks().len(), MAX_OUT_OF_ORDER);
+    }
+
+    #[test]
+    fn test_out_of_order_memory() {
+        let now = Instant::now();
+        let memory = MemoryBudget::new(Some(40));
+        let receiver = Receiver::<TestRuntime>::new(
+            Wrapping(0),
+            65536,
+            0,
+            2,
+            Duration::from_millis(40),
+            None,
+            memory.clone(),
+        );
         let buf = BytesMut::zeroed(16).freeze();
+
+        // Data held for reassembly is charged to the budget, and a segment that doesn't fit in
+        // what's left isn't held.
         must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(16), buf.clone(), now));
+        assert_eq!(memory.used(), 16);
+        must_let!(let Err(Fail::ResourceExhausted { .. }) = receiver.receive_data(Wrapping(64), BytesMut::zeroed(32).freeze(), now));
+        assert_eq!(receiver.sack_blocks().len(), 1);
+        assert_eq!(memory.used(), 16);
+
+        // Filling the gap makes the held segment readable without charging it twice.
         must_let!(let Ok(..) = receiver.receive_data(Wrapping(0), buf.clone(), now));
-        assert_eq!(receiver.recv_seq_no.get(), Wrapping(32))
+        assert_eq!(receiver.recv_seq_no.get(), Wrapping(32));
+        assert_eq!(memory.used(), 32);
+
+        // Whatever the connection still holds is released when it goes away.
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(40), BytesMut::zeroed(8).freeze(), now));
+        assert_eq!(memory.used(), 40);
+        drop(receiver);
+        assert_eq!(memory.used(), 0);
+    }
+
+    #[test]
+    fn test_out_of_order_outside_window() {
+        let now = Instant::now();
+        let receiver = Receiver::<TestRuntime>::new(
+            Wrapping(0),
+            64,
+            0,
+            2,
+            Duration::from_millis(40),
+            None,
+            MemoryBudget::default(),
+        );
+        let buf = BytesMut::zeroed(16).freeze();
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(48), buf.clone(), now));
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(56), buf.clone(), now));
+        assert_eq!(receiver.sack_blocks().len(), 1);
+
+        // Sequence numbers compare modulo 2^32, so a segment just past a wrap is still ahead.
+        let receiver = Receiver::<TestRuntime>::new(
+            Wrapping(u32::MAX - 7),
+            64,
+            0,
+            2,
+            Duration::from_millis(40),
+            None,
+            MemoryBudget::default(),
+        );
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(8), buf.clone(), now));
+        must_let!(let Ok(..) = receiver.receive_data(Wrapping(u32::MAX - 7), buf.clone(), now));
+        assert_eq!(receiver.recv_seq_no.get(), Wrapping(24));
+    }
+
+    #[test]
+    fn test_window_auto_tuning() {
+        // A path whose bandwidth-delay product is well beyond the initial window.
+        const BDP: u32 = 1 << 20;
+        const SEGMENT_SIZE: u32 = 1024;
+        let rtt = Duration::from_millis(50);
+        let mut now = Instant::now();
+        let receiver = Receiver::<TestRuntime>::new(
+            Wrapping(0),
+            65536,
+            7,
+            2,
+            Duration::from_millis(40),
+            Some(4 << 20),
+            MemoryBudget::default(),
+        );
+
+        // Each RTT, the sender fills whatever window we advertised, up to what the path can carry,
+        // and the application reads it all straight away.
+        let mut windows = vec![];
+        for _ in 0..16 {
+            let window = receiver.window_size();
+            windows.push(window);
+            for _ in 0..cmp::min(window, BDP) / SEGMENT_SIZE {
+                let buf = BytesMut::zeroed(SEGMENT_SIZE as usize).freeze();
+                receiver
+                    .receive_data(receiver.recv_seq_no.get(), buf, now)
+                    .unwrap();
+                receiver.recv().unwrap();
+            }
+            now += rtt;
+        }
+
+        // The window grows over several RTTs until it's twice the BDP, and then stays there.
+        assert_eq!(windows[0], 65536);
+        assert!(windows.windows(2).all(|w| w[0] <= w[1]));
+        windows.dedup();
+        assert!(windows.len() > 3);
+        assert_eq!(*windows.last().unwrap(), 2 * BDP);
     }
 }
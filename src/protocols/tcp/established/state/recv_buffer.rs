@@ -0,0 +1,279 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    collections::bytes::{Bytes, BytesMut},
+    fail::Fail,
+    protocols::tcp::SeqNumber,
+    runtime::RuntimeBuf,
+};
+use std::{cmp, num::Wrapping};
+
+/// Receive buffer for a TCP connection, stored as a contiguous ring of `capacity` bytes.
+///
+//                 base_seq_no          base_seq_no + available       base_seq_no + capacity
+//                      v                          v                            v
+// ... -----------------|--------------------------|-----[ooo]----[ooo]---------| (unavailable)
+//         read                 readable                      window
+//
+// Out-of-order segments ("ooo" above) are copied straight into their place in the ring, and
+// become readable once the gap in front of them is filled.
+#[derive(Debug)]
+pub struct RecvBuffer {
+    buf: BytesMut,
+    /// Index into `buf` of the byte at `base_seq_no`.
+    head: usize,
+    /// Sequence number of the first unread byte.
+    base_seq_no: SeqNumber,
+    /// Number of in-order bytes that can be read starting at `base_seq_no`.
+    available: usize,
+    /// Sorted, non-overlapping `[start, end)` ranges of out-of-order data, as offsets from
+    /// `base_seq_no`. Every range starts after `available`.
+    out_of_order: Vec<(usize, usize)>,
+}
+
+impl RecvBuffer {
+    pub fn new(seq_no: SeqNumber, capacity: usize) -> Self {
+        assert!(capacity > 0, "Receive buffer must have room for data");
+        Self {
+            buf: BytesMut::zeroed(capacity),
+            head: 0,
+            base_seq_no: seq_no,
+            available: 0,
+            out_of_order: Vec::new(),
+        }
+    }
+
+    /// Number of in-order bytes ready to be read.
+    pub fn available(&self) -> usize {
+        self.available
+    }
+
+    /// Free space after the last in-order byte, i.e. the receive window we can advertise.
+    pub fn window(&self) -> usize {
+        self.buf.len() - self.available
+    }
+
+    /// Sequence number of the next in-order byte we expect (RCV.NXT).
+    pub fn next_seq_no(&self) -> SeqNumber {
+        self.base_seq_no + Wrapping(self.available as u32)
+    }
+
+    /// Number of bytes held, in order or not.
+    pub fn buffered(&self) -> usize {
+        let out_of_order: usize = self.out_of_order.iter().map(|(s, e)| e - s).sum();
+        self.available + out_of_order
+    }
+
+    /// The `[begin, end)` sequence number ranges of the out-of-order data we're holding, in
+    /// sequence order.
+    pub fn out_of_order(&self) -> impl Iterator<Item = (SeqNumber, SeqNumber)> + '_ {
+        self.out_of_order.iter().map(move |&(s, e)| {
+            (
+                self.base_seq_no + Wrapping(s as u32),
+                self.base_seq_no + Wrapping(e as u32),
+            )
+        })
+    }
+
+    /// Drops the out-of-order range furthest ahead, returning how many bytes it held.
+    pub fn evict_last(&mut self) -> usize {
+        self.out_of_order.pop().map(|(s, e)| e - s).unwrap_or(0)
+    }
+
+    /// Grows the ring to `capacity` bytes, keeping everything it holds.
+    pub fn grow(&mut self, capacity: usize) {
+        let old_capacity = self.buf.len();
+        assert!(capacity >= old_capacity);
+        let mut buf = BytesMut::zeroed(capacity);
+        let first = old_capacity - self.head;
+        buf[..first].copy_from_slice(&self.buf[self.head..]);
+        buf[first..old_capacity].copy_from_slice(&self.buf[..self.head]);
+        self.buf = buf;
+        self.head = 0;
+    }
+
+    /// Stores the segment starting at `seq_no`, trimming anything we've already received or that
+    /// falls outside of the window.
+    pub fn insert(&mut self, seq_no: SeqNumber, data: &[u8]) -> Result<(), Fail> {
+        let capacity = self.buf.len();
+        let Wrapping(offset) = seq_no - self.base_seq_no;
+
+        // Sequence numbers "behind" `base_seq_no` show up as huge offsets.
+        let (offset, data) = if offset > i32::MAX as u32 {
+            let duplicate = (u32::MAX - offset + 1) as usize;
+            if duplicate >= data.len() {
+                return Err(Fail::Ignored {
+                    details: "Duplicate segment",
+                });
+            }
+            (0, &data[duplicate..])
+        } else {
+            (offset as usize, data)
+        };
+        if offset >= capacity {
+            return Err(Fail::Ignored {
+                details: "Segment outside of receive window",
+            });
+        }
+        let end = cmp::min(offset + data.len(), capacity);
+        if end <= self.available {
+            return Err(Fail::Ignored {
+                details: "Duplicate segment",
+            });
+        }
+
+        // Copy the data into the ring, which may wrap around the end of `buf`.
+        let data = &data[..(end - offset)];
+        let start_ix = (self.head + offset) % capacity;
+        let first = cmp::min(data.len(), capacity - start_ix);
+        self.buf[start_ix..(start_ix + first)].copy_from_slice(&data[..first]);
+        self.buf[..(data.len() - first)].copy_from_slice(&data[first..]);
+
+        self.add_range(offset, end);
+        Ok(())
+    }
+
+    /// Returns up to `max_bytes` of in-order data without consuming it.
+    pub fn peek(&self, max_bytes: usize) -> Bytes {
+        let n = cmp::min(max_bytes, self.available);
+        if n == 0 {
+            return Bytes::empty();
+        }
+        let capacity = self.buf.len();
+        let mut out = BytesMut::zeroed(n);
+        let first = cmp::min(n, capacity - self.head);
+        out[..first].copy_from_slice(&self.buf[self.head..(self.head + first)]);
+        out[first..].copy_from_slice(&self.buf[..(n - first)]);
+        out.freeze()
+    }
+
+    /// Reads up to `max_bytes` of in-order data.
+    pub fn read(&mut self, max_bytes: usize) -> Bytes {
+        let out = self.peek(max_bytes);
+        let n = out.len();
+        if n == 0 {
+            return out;
+        }
+        let capacity = self.buf.len();
+        self.head = (self.head + n) % capacity;
+        self.base_seq_no += Wrapping(n as u32);
+        self.available -= n;
+        for range in self.out_of_order.iter_mut() {
+            range.0 -= n;
+            range.1 -= n;
+        }
+        out
+    }
+
+    fn add_range(&mut self, start: usize, end: usize) {
+        if start <= self.available {
+            self.available = cmp::max(self.available, end);
+        } else {
+            let ix = self.out_of_order.partition_point(|&(s, _)| s < start);
+            self.out_of_order.insert(ix, (start, end));
+        }
+
+        // Coalesce overlapping ranges and fold any that now touch the in-order data into it.
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.out_of_order.len());
+        for &(s, e) in self.out_of_order.iter() {
+            if s <= self.available {
+                self.available = cmp::max(self.available, e);
+                continue;
+            }
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = cmp::max(last.1, e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.out_of_order = merged;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecvBuffer;
+    use crate::fail::Fail;
+    use must_let::must_let;
+    use std::num::Wrapping;
+
+    #[test]
+    fn test_out_of_order_insert() {
+        let mut buf = RecvBuffer::new(Wrapping(0), 64);
+        buf.insert(Wrapping(8), &[1; 8]).unwrap();
+        assert_eq!(buf.available(), 0);
+        assert_eq!(buf.window(), 64);
+
+        buf.insert(Wrapping(24), &[3; 8]).unwrap();
+        buf.insert(Wrapping(0), &[0; 8]).unwrap();
+        assert_eq!(buf.available(), 16);
+        assert_eq!(buf.next_seq_no(), Wrapping(16));
+
+        // Filling the last gap makes everything readable.
+        buf.insert(Wrapping(16), &[2; 8]).unwrap();
+        assert_eq!(buf.available(), 32);
+        let data = buf.read(64);
+        assert_eq!(&data[..8], &[0; 8]);
+        assert_eq!(&data[8..16], &[1; 8]);
+        assert_eq!(&data[16..24], &[2; 8]);
+        assert_eq!(&data[24..], &[3; 8]);
+
+        must_let!(let Err(Fail::Ignored { .. }) = buf.insert(Wrapping(0), &[0; 8]));
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        // Start close to the end of the sequence space so the data straddles the wrap.
+        let isn = Wrapping(u32::MAX - 3);
+        let mut buf = RecvBuffer::new(isn, 16);
+        buf.insert(isn + Wrapping(8), &[2; 4]).unwrap();
+        buf.insert(isn, &[1; 8]).unwrap();
+        assert_eq!(buf.available(), 12);
+        assert_eq!(buf.next_seq_no(), Wrapping(8));
+
+        // Consume most of the ring so the next segment wraps around the end of the storage.
+        assert_eq!(buf.read(12).len(), 12);
+        buf.insert(Wrapping(8), &[3; 10]).unwrap();
+        let data = buf.read(16);
+        assert_eq!(&data[..], &[3; 10]);
+
+        // Data overlapping what we've already read is trimmed.
+        buf.insert(Wrapping(14), &[4; 8]).unwrap();
+        assert_eq!(&buf.read(16)[..], &[4; 4]);
+    }
+
+    #[test]
+    fn test_partial_read() {
+        let mut buf = RecvBuffer::new(Wrapping(0), 16);
+        buf.insert(Wrapping(0), &[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(buf.window(), 10);
+
+        assert_eq!(&buf.read(4)[..], &[1, 2, 3, 4]);
+        assert_eq!(buf.available(), 2);
+        assert_eq!(buf.window(), 14);
+
+        // Anything past the window is dropped.
+        buf.insert(Wrapping(6), &[7; 20]).unwrap();
+        assert_eq!(buf.available(), 16);
+        assert_eq!(&buf.read(3)[..], &[5, 6, 7]);
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut buf = RecvBuffer::new(Wrapping(0), 16);
+        buf.insert(Wrapping(0), &[1; 12]).unwrap();
+        assert_eq!(buf.read(8).len(), 8);
+
+        // Data that wraps around the end of the ring stays in order, along with out-of-order data.
+        buf.insert(Wrapping(12), &[2; 8]).unwrap();
+        buf.insert(Wrapping(22), &[3; 2]).unwrap();
+        buf.grow(32);
+        assert_eq!(buf.window(), 20);
+        buf.insert(Wrapping(20), &[4; 2]).unwrap();
+        let data = buf.read(32);
+        assert_eq!(&data[..4], &[1; 4]);
+        assert_eq!(&data[4..12], &[2; 8]);
+        assert_eq!(&data[12..14], &[4; 2]);
+        assert_eq!(&data[14..], &[3; 2]);
+    }
+}
@@ -1,6 +1,7 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+use crate::protocols::tcp::constants::{DEFAULT_RTO_MAX, DEFAULT_RTO_MIN, INITIAL_RTO};
 use float_duration::FloatDuration;
 use std::{cmp, time::Duration};
 
@@ -11,18 +12,28 @@ pub struct RtoCalculator {
     rttvar: f64,
     rto: f64,
 
+    /// Bounds the RTO is clamped to, in seconds.
+    lbound: f64,
+    ubound: f64,
+
     received_sample: bool,
 }
 
 impl RtoCalculator {
-    pub fn new() -> Self {
-        Self {
-            srtt: 1.0,
+    pub fn new(initial: Duration, min: Duration, max: Duration) -> Self {
+        assert!(min <= max);
+        let mut calculator = Self {
+            srtt: initial.as_secs_f64(),
             rttvar: 0.0,
-            rto: 1.0,
+            rto: 0.0,
+
+            lbound: min.as_secs_f64(),
+            ubound: max.as_secs_f64(),
 
             received_sample: false,
-        }
+        };
+        calculator.update_rto(initial.as_secs_f64());
+        calculator
     }
 
     pub fn add_sample(&mut self, rtt: Duration) {
@@ -50,14 +61,12 @@ impl RtoCalculator {
     }
 
     fn update_rto(&mut self, new_rto: f64) {
-        const UBOUND_SEC: f64 = 60.0f64;
-        const LBOUND_SEC: f64 = 0.100f64;
         self.rto = match (
-            new_rto.partial_cmp(&LBOUND_SEC),
-            new_rto.partial_cmp(&UBOUND_SEC),
+            new_rto.partial_cmp(&self.lbound),
+            new_rto.partial_cmp(&self.ubound),
         ) {
-            (Some(cmp::Ordering::Less), _) => LBOUND_SEC,
-            (_, Some(cmp::Ordering::Greater)) => UBOUND_SEC,
+            (Some(cmp::Ordering::Less), _) => self.lbound,
+            (_, Some(cmp::Ordering::Greater)) => self.ubound,
             (None, _) | (_, None) => panic!("NaN RTO: {:?}", new_rto),
             _ => new_rto,
         };
@@ -71,3 +80,50 @@ impl RtoCalculator {
         FloatDuration::seconds(self.rto).to_std().unwrap()
     }
 }
+
+impl Default for RtoCalculator {
+    fn default() -> Self {
+        Self::new(INITIAL_RTO, DEFAULT_RTO_MIN, DEFAULT_RTO_MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rto_bounds() {
+        let min = Duration::from_millis(10);
+        let max = Duration::from_secs(2);
+        let mut rto = RtoCalculator::new(Duration::from_millis(500), min, max);
+        assert_eq!(rto.estimate(), Duration::from_millis(500));
+
+        // Steady sub-millisecond RTTs on a LAN bring it down to the floor, but no further.
+        for _ in 0..100 {
+            rto.add_sample(Duration::from_micros(200));
+            assert!(rto.estimate() >= min);
+        }
+        assert_eq!(rto.estimate(), min);
+
+        // Backing off after repeated timeouts stops at the ceiling.
+        for _ in 0..10 {
+            rto.record_failure();
+            assert!(rto.estimate() <= max);
+        }
+        assert_eq!(rto.estimate(), max);
+
+        // So does a huge sample.
+        rto.add_sample(Duration::from_secs(30));
+        assert_eq!(rto.estimate(), max);
+    }
+
+    #[test]
+    fn test_initial_rto_clamped() {
+        let rto = RtoCalculator::new(
+            Duration::from_secs(1),
+            Duration::from_millis(10),
+            Duration::from_millis(200),
+        );
+        assert_eq!(rto.estimate(), Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    fail::Fail,
+    protocols::tcp::{seq_gt, seq_lt, SeqNumber},
+    runtime::RuntimeBuf,
+};
+use std::{cmp, collections::VecDeque, num::Wrapping, time::Instant};
+
+struct UnackedSegment<T> {
+    bytes: T,
+    // Set to `None` on retransmission to implement Karn's algorithm.
+    initial_tx: Option<Instant>,
+    /// The peer has told us it's holding this segment in a SACK block.
+    sacked: bool,
+}
+
+/// Outgoing byte stream for a TCP connection, split into the data we've sent but that hasn't been
+/// acknowledged yet and the data the application has pushed that we haven't sent.
+///
+//               base_seq_no                sent_seq_no             unsent_seq_no
+//                    v                          v                        v
+// ... ---------------|--------------------------|------------------------| (unavailable)
+//       acknowledged         unacknowledged               unsent
+//
+pub struct SendBuffer<T: RuntimeBuf> {
+    base_seq_no: SeqNumber,
+    unacked: VecDeque<UnackedSegment<T>>,
+    unacked_bytes: usize,
+    unsent: VecDeque<T>,
+    unsent_bytes: usize,
+
+    /// Hold back sub-MSS segments while there's unacknowledged data in flight (RFC 896).
+    nagle: bool,
+}
+
+impl<T: RuntimeBuf> SendBuffer<T> {
+    pub fn new(seq_no: SeqNumber) -> Self {
+        Self {
+            base_seq_no: seq_no,
+            unacked: VecDeque::new(),
+            unacked_bytes: 0,
+            unsent: VecDeque::new(),
+            unsent_bytes: 0,
+            nagle: false,
+        }
+    }
+
+    pub fn set_nagle(&mut self, nagle: bool) {
+        self.nagle = nagle;
+    }
+
+    pub fn nagle(&self) -> bool {
+        self.nagle
+    }
+
+    /// Sequence number of the first unacknowledged byte (SND.UNA).
+    pub fn base_seq_no(&self) -> SeqNumber {
+        self.base_seq_no
+    }
+
+    /// Sequence number of the next byte we'll send (SND.NXT).
+    pub fn sent_seq_no(&self) -> SeqNumber {
+        self.base_seq_no + Wrapping(self.unacked_bytes as u32)
+    }
+
+    /// Sequence number one past the last byte the application has pushed.
+    pub fn unsent_seq_no(&self) -> SeqNumber {
+        self.sent_seq_no() + Wrapping(self.unsent_bytes as u32)
+    }
+
+    pub fn unacked_bytes(&self) -> usize {
+        self.unacked_bytes
+    }
+
+    pub fn unsent_bytes(&self) -> usize {
+        self.unsent_bytes
+    }
+
+    /// Number of segments we've sent that haven't been acknowledged yet.
+    pub fn unacked_segments(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Appends `buf` to the end of the unsent data.
+    pub fn enqueue(&mut self, buf: T) {
+        if buf.is_empty() {
+            return;
+        }
+        self.unsent_bytes += buf.len();
+        self.unsent.push_back(buf);
+    }
+
+    /// Moves the next segment of at most `min(mss, window)` bytes from the unsent to the
+    /// unacknowledged region and returns it, or `None` if there's nothing we should send right now.
+    pub fn next_segment(&mut self, mss: usize, window: usize, now: Instant) -> Option<T> {
+        let max_bytes = cmp::min(mss, window);
+        if max_bytes == 0 {
+            return None;
+        }
+        let front_len = self.unsent.front()?.len();
+
+        // Nagle only holds back segments that are short because we've run out of data, not
+        // because the window is small.
+        if self.nagle
+            && !self.unacked.is_empty()
+            && self.unsent_bytes < mss
+            && self.unsent_bytes <= max_bytes
+        {
+            return None;
+        }
+
+        let mut buf = self.unsent.pop_front().unwrap();
+        if front_len > max_bytes {
+            let mut rest = buf.clone();
+            rest.adjust(max_bytes);
+            buf.trim(front_len - max_bytes);
+            self.unsent.push_front(rest);
+        } else if front_len < max_bytes && !self.unsent.is_empty() {
+            // Coalesce small writes into one segment, which is what Nagle holds them back for.
+            // TODO: Use a scatter/gather array instead of copying.
+            let mut data = buf.to_vec();
+            while data.len() < max_bytes {
+                let mut next = match self.unsent.pop_front() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let n = cmp::min(max_bytes - data.len(), next.len());
+                data.extend_from_slice(&next[..n]);
+                if n < next.len() {
+                    next.adjust(n);
+                    self.unsent.push_front(next);
+                }
+            }
+            buf = T::from_slice(&data);
+        }
+
+        self.unsent_bytes -= buf.len();
+        self.unacked_bytes += buf.len();
+        self.unacked.push_back(UnackedSegment {
+            bytes: buf.clone(),
+            initial_tx: Some(now),
+            sacked: false,
+        });
+        Some(buf)
+    }
+
+    /// Drops everything before `ack_seq_no` from the unacknowledged region. ACKs don't need to
+    /// fall on segment boundaries. Returns the initial transmission time of the newest segment
+    /// that's now fully acknowledged and was never retransmitted, for RTT estimation.
+    pub fn ack(&mut self, ack_seq_no: SeqNumber) -> Result<Option<Instant>, Fail> {
+        let Wrapping(bytes_acknowledged) = ack_seq_no - self.base_seq_no;
+        let mut remaining = bytes_acknowledged as usize;
+        if remaining > self.unacked_bytes {
+            return Err(Fail::Ignored {
+                details: "ACK is outside of send window",
+            });
+        }
+        self.base_seq_no = ack_seq_no;
+        self.unacked_bytes -= remaining;
+
+        let mut initial_tx = None;
+        while remaining > 0 {
+            let front = self
+                .unacked
+                .front_mut()
+                .expect("Unacknowledged bytes without segments?");
+            if front.bytes.len() > remaining {
+                front.bytes.adjust(remaining);
+                break;
+            }
+            remaining -= front.bytes.len();
+            if let Some(t) = self.unacked.pop_front().unwrap().initial_tx {
+                initial_tx = Some(t);
+            }
+        }
+        Ok(initial_tx)
+    }
+
+    /// Returns the segment to retransmit from the front of the unacknowledged region, with
+    /// `mss` bytes at most. Small segments are repacketized with the data that follows them so
+    /// we retransmit full-sized segments where we can.
+    pub fn retransmit_front(&mut self, mss: usize) -> Option<T> {
+        let mut bytes = self.unacked.pop_front()?.bytes;
+        if bytes.len() > mss {
+            let mut rest = bytes.clone();
+            rest.adjust(mss);
+            bytes.trim(rest.len());
+            self.unacked.push_front(UnackedSegment {
+                bytes: rest,
+                initial_tx: None,
+                sacked: false,
+            });
+        } else if bytes.len() < mss && !self.unacked.is_empty() {
+            let mut data = bytes.to_vec();
+            while data.len() < mss {
+                // Data the peer already holds stays where it is.
+                let next = match self.unacked.front() {
+                    Some(s) if !s.sacked => self.unacked.pop_front().unwrap(),
+                    _ => break,
+                };
+                let n = cmp::min(mss - data.len(), next.bytes.len());
+                data.extend_from_slice(&next.bytes[..n]);
+                if n < next.bytes.len() {
+                    let mut rest = next.bytes;
+                    rest.adjust(n);
+                    self.unacked.push_front(UnackedSegment {
+                        bytes: rest,
+                        initial_tx: None,
+                        sacked: false,
+                    });
+                }
+            }
+            bytes = T::from_slice(&data);
+        }
+        self.unacked.push_front(UnackedSegment {
+            bytes: bytes.clone(),
+            initial_tx: None,
+            sacked: false,
+        });
+        Some(bytes)
+    }
+
+    /// Marks the unacknowledged segments that fall entirely within the SACK block `[begin, end)`
+    /// as held by the peer, so that we don't retransmit them (RFC 2018).
+    pub fn sack(&mut self, begin: SeqNumber, end: SeqNumber) {
+        let mut seq_no = self.base_seq_no;
+        for segment in self.unacked.iter_mut() {
+            let segment_end = seq_no + Wrapping(segment.bytes.len() as u32);
+            if !seq_lt(seq_no, begin) && !seq_gt(segment_end, end) {
+                segment.sacked = true;
+            }
+            seq_no = segment_end;
+        }
+    }
+
+    /// Returns the holes the peer's SACK blocks have revealed past the front of the
+    /// unacknowledged region, i.e. the segments that haven't been SACKed but that come before one
+    /// that has, along with their sequence numbers. Their data is retransmitted as is.
+    pub fn retransmit_holes(&mut self) -> Vec<(SeqNumber, T)> {
+        let last_sacked = match self.unacked.iter().rposition(|s| s.sacked) {
+            Some(i) => i,
+            None => return vec![],
+        };
+        let mut holes = vec![];
+        let mut seq_no = self.base_seq_no;
+        for (i, segment) in self.unacked.iter_mut().enumerate().take(last_sacked) {
+            if i > 0 && !segment.sacked {
+                segment.initial_tx = None;
+                holes.push((seq_no, segment.bytes.clone()));
+            }
+            seq_no += Wrapping(segment.bytes.len() as u32);
+        }
+        holes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SendBuffer;
+    use crate::{collections::bytes::Bytes, fail::Fail, runtime::RuntimeBuf};
+    use must_let::must_let;
+    use std::{
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    fn buf(len: usize, fill: u8) -> Bytes {
+        Bytes::from_slice(&vec![fill; len])
+    }
+
+    #[test]
+    fn test_enqueue_segment_ack() {
+        let now = Instant::now();
+        let mut sb = SendBuffer::new(Wrapping(100));
+        sb.enqueue(buf(10, 1));
+        sb.enqueue(buf(4, 2));
+        assert_eq!(sb.unsent_bytes(), 14);
+        assert_eq!(sb.unsent_seq_no(), Wrapping(114));
+
+        // The first buffer is split at the MSS.
+        assert_eq!(&sb.next_segment(8, 100, now).unwrap()[..], &[1; 8]);
+        // The window limits the next segment to the rest of the first buffer.
+        assert_eq!(&sb.next_segment(8, 2, now).unwrap()[..], &[1; 2]);
+        assert!(sb.next_segment(8, 0, now).is_none());
+        assert_eq!(sb.sent_seq_no(), Wrapping(110));
+        assert_eq!(sb.unacked_bytes(), 10);
+
+        // A partial ACK in the middle of the first segment leaves the rest of it in flight.
+        let later = now + Duration::from_millis(5);
+        assert_eq!(sb.ack(Wrapping(104)).unwrap(), None);
+        assert_eq!(sb.unacked_bytes(), 6);
+        assert_eq!(sb.ack(Wrapping(110)).unwrap(), Some(now));
+        assert_eq!(sb.base_seq_no(), Wrapping(110));
+
+        assert_eq!(&sb.next_segment(8, 100, later).unwrap()[..], &[2; 4]);
+        must_let!(let Err(Fail::Ignored { .. }) = sb.ack(Wrapping(115)));
+        assert_eq!(sb.ack(Wrapping(114)).unwrap(), Some(later));
+        assert_eq!(sb.unacked_bytes() + sb.unsent_bytes(), 0);
+    }
+
+    #[test]
+    fn test_nagle_holding() {
+        let now = Instant::now();
+        let mut sb = SendBuffer::new(Wrapping(0));
+        sb.set_nagle(true);
+
+        // Nothing in flight, so a small segment goes straight out.
+        sb.enqueue(buf(2, 1));
+        assert_eq!(sb.next_segment(8, 100, now).unwrap().len(), 2);
+
+        // With data in flight, small segments are held until we have a full MSS...
+        sb.enqueue(buf(3, 2));
+        assert!(sb.next_segment(8, 100, now).is_none());
+        sb.enqueue(buf(6, 3));
+        assert_eq!(
+            &sb.next_segment(8, 100, now).unwrap()[..],
+            &[2, 2, 2, 3, 3, 3, 3, 3][..]
+        );
+
+        // ...or until everything in flight is acknowledged.
+        sb.enqueue(buf(2, 4));
+        assert!(sb.next_segment(8, 100, now).is_none());
+        sb.ack(Wrapping(10)).unwrap();
+        assert_eq!(&sb.next_segment(8, 100, now).unwrap()[..], &[3, 4, 4][..]);
+
+        // Turning Nagle off sends small segments immediately.
+        sb.set_nagle(false);
+        sb.enqueue(buf(1, 5));
+        assert_eq!(&sb.next_segment(8, 100, now).unwrap()[..], &[5]);
+    }
+
+    #[test]
+    fn test_retransmit_repacketization() {
+        let now = Instant::now();
+        let mut sb = SendBuffer::new(Wrapping(0));
+        for i in 0..4 {
+            sb.enqueue(buf(3, i));
+            sb.next_segment(8, 100, now).unwrap();
+        }
+        assert!(sb.retransmit_front(8).is_some());
+
+        // The first three segments are coalesced, with the third one split at the MSS.
+        let mut expected = vec![0; 3];
+        expected.extend_from_slice(&[1; 3]);
+        expected.extend_from_slice(&[2; 2]);
+        assert_eq!(&sb.retransmit_front(8).unwrap()[..], &expected[..]);
+        assert_eq!(sb.unacked_bytes(), 12);
+
+        // Retransmitted data isn't used for RTT samples.
+        assert_eq!(sb.ack(Wrapping(8)).unwrap(), None);
+        assert_eq!(&sb.retransmit_front(8).unwrap()[..], &[2, 3, 3, 3]);
+
+        // An oversized segment is split on retransmission.
+        sb.ack(Wrapping(12)).unwrap();
+        sb.enqueue(buf(20, 5));
+        assert_eq!(sb.next_segment(20, 100, now).unwrap().len(), 20);
+        assert_eq!(&sb.retransmit_front(8).unwrap()[..], &[5; 8]);
+        assert_eq!(sb.unacked_bytes(), 20);
+        sb.ack(Wrapping(20)).unwrap();
+        assert_eq!(&sb.retransmit_front(8).unwrap()[..], &[5; 8]);
+        sb.ack(Wrapping(28)).unwrap();
+        assert_eq!(&sb.retransmit_front(8).unwrap()[..], &[5; 4]);
+        assert!(sb.ack(Wrapping(32)).is_ok());
+        assert!(sb.retransmit_front(8).is_none());
+    }
+
+    #[test]
+    fn test_sack_holes() {
+        let now = Instant::now();
+        let mut sb = SendBuffer::new(Wrapping(0));
+        for i in 0..5 {
+            sb.enqueue(buf(3, i));
+            sb.next_segment(8, 100, now).unwrap();
+        }
+
+        // The peer holds the third and fifth segments. The block covering only part of the
+        // fourth one doesn't count.
+        sb.sack(Wrapping(6), Wrapping(9));
+        sb.sack(Wrapping(9), Wrapping(11));
+        sb.sack(Wrapping(12), Wrapping(15));
+
+        // Coalescing stops short of the SACKed segment.
+        assert_eq!(&sb.retransmit_front(8).unwrap()[..], &[0, 0, 0, 1, 1, 1]);
+        let holes = sb.retransmit_holes();
+        assert_eq!(holes.len(), 1);
+        assert_eq!(holes[0].0, Wrapping(9));
+        assert_eq!(&holes[0].1[..], &[3; 3]);
+
+        // Nothing is left to fill once the cumulative ACK passes the holes.
+        sb.ack(Wrapping(12)).unwrap();
+        assert!(sb.retransmit_holes().is_empty());
+        assert_eq!(sb.ack(Wrapping(15)).unwrap(), Some(now));
+    }
+}
@@ -1,29 +1,25 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::{congestion_ctrl as cc, rto::RtoCalculator};
+use super::{congestion_ctrl as cc, rto::RtoCalculator, send_buffer::SendBuffer};
 use crate::{
     collections::watched::WatchedValue,
     fail::Fail,
-    protocols::tcp::SeqNumber,
+    memory_budget::MemoryBudget,
+    protocols::tcp::{constants::MIN_MSS, seq_gt, seq_lt, SeqNumber},
     runtime::{Runtime, RuntimeBuf},
 };
 use std::{
     boxed::Box,
-    cell::RefCell,
-    collections::VecDeque,
+    cell::{Cell, RefCell},
+    cmp,
     convert::TryInto,
-    fmt,
+    fmt, mem,
     num::Wrapping,
+    task::{Context, Poll, Waker},
     time::{Duration, Instant},
 };
 
-pub struct UnackedSegment<RT: Runtime> {
-    pub bytes: RT::Buf,
-    // Set to `None` on retransmission to implement Karn's algorithm.
-    pub initial_tx: Option<Instant>,
-}
-
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SenderState {
     Open,
@@ -33,6 +29,9 @@ pub enum SenderState {
     /// The FIN we previously sent has been acknowledged by by the other side.
     FinAckd,
     Reset,
+    /// We gave up on the peer, either because the head of the send buffer went longer than the
+    /// send stall timeout without moving or because it didn't answer our keep-alive probes.
+    TimedOut,
 }
 
 pub struct Sender<RT: Runtime> {
@@ -47,22 +46,30 @@ pub struct Sender<RT: Runtime> {
     // ... ---------------|-------------------------|----------------------| (unavailable)
     //       acknowledged        unacknowledged     ^        unsent
     //
+    // The data itself lives in `send_buffer`, which these sequence numbers mirror so that the
+    // background tasks can watch them.
     pub base_seq_no: WatchedValue<SeqNumber>,
-    pub unacked_queue: RefCell<VecDeque<UnackedSegment<RT>>>,
     pub sent_seq_no: WatchedValue<SeqNumber>,
-    pub unsent_queue: RefCell<VecDeque<RT::Buf>>,
     pub unsent_seq_no: WatchedValue<SeqNumber>,
+    pub send_buffer: RefCell<SendBuffer<RT::Buf>>,
 
     pub window_size: WatchedValue<u32>,
     // RFC 1323: Number of bits to shift advertised window, defaults to zero.
     pub window_scale: u8,
 
-    pub mss: usize,
+    /// Largest segment we send, which PMTUD can shrink after the handshake.
+    pub mss: Cell<usize>,
 
     pub retransmit_deadline: WatchedValue<Option<Instant>>,
     pub rto: RefCell<RtoCalculator>,
 
     pub congestion_ctrl: Box<dyn cc::CongestionControl<RT>>,
+
+    /// Shared accounting for the bytes sitting in `send_buffer`.
+    memory: MemoryBudget,
+
+    /// Pushes waiting for the send buffer to drain below its high-watermark.
+    push_wakers: RefCell<Vec<Waker>>,
 }
 
 impl<RT: Runtime> fmt::Debug for Sender<RT> {
@@ -73,7 +80,7 @@ impl<RT: Runtime> fmt::Debug for Sender<RT> {
             .field("unsent_seq_no", &self.unsent_seq_no)
             .field("window_size", &self.window_size)
             .field("window_scale", &self.window_scale)
-            .field("mss", &self.mss)
+            .field("mss", &self.mss.get())
             .field("retransmit_deadline", &self.retransmit_deadline)
             .field("rto", &self.rto)
             .finish()
@@ -86,38 +93,60 @@ impl<RT: Runtime> Sender<RT> {
         window_size: u32,
         window_scale: u8,
         mss: usize,
+        nagle: bool,
+        rto: RtoCalculator,
         cc_constructor: cc::CongestionControlConstructor<RT>,
         congestion_control_options: Option<cc::Options>,
+        memory: MemoryBudget,
     ) -> Self {
+        let mut send_buffer = SendBuffer::new(seq_no);
+        send_buffer.set_nagle(nagle);
         Self {
             state: WatchedValue::new(SenderState::Open),
 
             base_seq_no: WatchedValue::new(seq_no),
-            unacked_queue: RefCell::new(VecDeque::new()),
             sent_seq_no: WatchedValue::new(seq_no),
-            unsent_queue: RefCell::new(VecDeque::new()),
             unsent_seq_no: WatchedValue::new(seq_no),
+            send_buffer: RefCell::new(send_buffer),
 
             window_size: WatchedValue::new(window_size),
             window_scale,
-            mss,
+            mss: Cell::new(mss),
 
             retransmit_deadline: WatchedValue::new(None),
-            rto: RefCell::new(RtoCalculator::new()),
+            rto: RefCell::new(rto),
 
             congestion_ctrl: cc_constructor(mss, seq_no, congestion_control_options),
+            memory,
+            push_wakers: RefCell::new(vec![]),
         }
     }
 
     pub fn send(&self, buf: RT::Buf, cb: &super::ControlBlock<RT>) -> Result<(), Fail> {
-        if self.state.get() != SenderState::Open {
-            return Err(Fail::Ignored {
-                details: "Sender closed",
-            });
+        // Queueing data behind our FIN (or on a dead connection) would mean it never gets sent.
+        match self.state.get() {
+            SenderState::Open => (),
+            SenderState::Closed | SenderState::SentFin | SenderState::FinAckd => {
+                return Err(Fail::BrokenPipe {})
+            }
+            SenderState::Reset => return Err(Fail::ConnectionReset {}),
+            SenderState::TimedOut => return Err(Fail::Timeout {}),
         }
         let buf_len: u32 = buf.len().try_into().map_err(|_| Fail::Ignored {
             details: "Buffer too large",
         })?;
+        // Turn the push away if the queue is already over its high-watermark, rather than relying
+        // on the caller to wait for earlier pushes: one that never does would grow it without
+        // bound.
+        if let Some(high_watermark) = cb.rt.tcp_options().send_buffer_size {
+            let Wrapping(queued) = self.unsent_seq_no.get() - self.base_seq_no.get();
+            if queued as usize > high_watermark {
+                return Err(Fail::ResourceExhausted {
+                    details: "Send buffer full",
+                });
+            }
+        }
+        self.memory.try_reserve(buf.len())?;
 
         let win_sz = self.window_size.get();
         let base_seq = self.base_seq_no.get();
@@ -128,56 +157,124 @@ impl<RT: Runtime> Sender<RT> {
         let in_flight_after_send = sent_data + buf_len;
 
         // Before we get cwnd for the check, we prompt it to shrink it if the connection has been idle
-        self.congestion_ctrl.on_cwnd_check_before_send(&self);
+        self.congestion_ctrl
+            .on_cwnd_check_before_send(&self, cb.rt.now());
         let cwnd = self.congestion_ctrl.get_cwnd();
         // The limited transmit algorithm can increase the effective size of cwnd by up to 2MSS
         let effective_cwnd = cwnd + self.congestion_ctrl.get_limited_transmit_cwnd_increase();
 
-        if win_sz > 0 && win_sz >= in_flight_after_send && effective_cwnd >= in_flight_after_send {
+        // Don't let new data jump ahead of data that's already waiting to be sent.
+        let unsent_seq = self.unsent_seq_no.get();
+        if unsent_seq == sent_seq
+            && win_sz > 0
+            && win_sz >= in_flight_after_send
+            && effective_cwnd >= in_flight_after_send
+        {
             if let Some(remote_link_addr) = cb.arp.try_query(cb.remote.address()) {
-                // This hook is primarily intended to record the last time we sent data, so we can later tell if the connection has been idle
-                self.congestion_ctrl.on_send(&self, sent_data);
-
-                let mut header = cb.tcp_header();
-                header.seq_num = sent_seq;
-                cb.emit(header, buf.clone(), remote_link_addr);
-
-                self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
-                self.sent_seq_no.modify(|s| s + Wrapping(buf_len));
-                let unacked_segment = UnackedSegment {
-                    bytes: buf,
-                    initial_tx: Some(cb.rt.now()),
+                let segment = {
+                    let mut send_buffer = self.send_buffer.borrow_mut();
+                    send_buffer.enqueue(buf);
+                    let window = cmp::min(win_sz, effective_cwnd) - sent_data;
+                    send_buffer.next_segment(self.mss.get(), window as usize, cb.rt.now())
                 };
-                self.unacked_queue.borrow_mut().push_back(unacked_segment);
-                if self.retransmit_deadline.get().is_none() {
-                    let rto = self.rto.borrow().estimate();
-                    self.retransmit_deadline.set(Some(cb.rt.now() + rto));
+                self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
+
+                // Whatever doesn't fit in this segment is left for the background sender.
+                if let Some(segment) = segment {
+                    // This hook is primarily intended to record the last time we sent data, so we can later tell if the connection has been idle
+                    self.congestion_ctrl.on_send(&self, sent_data, cb.rt.now());
+
+                    let mut header = cb.tcp_header();
+                    header.seq_num = sent_seq;
+                    let segment_len = segment.len() as u32;
+                    cb.emit(header, segment, remote_link_addr);
+
+                    self.sent_seq_no.modify(|s| s + Wrapping(segment_len));
+                    if self.retransmit_deadline.get().is_none() {
+                        let rto = self.rto.borrow().estimate();
+                        self.retransmit_deadline.set(Some(cb.rt.now() + rto));
+                    }
                 }
                 return Ok(());
             }
         }
         // Slow path: Delegating sending the data to background processing.
-        self.unsent_queue.borrow_mut().push_back(buf);
+        self.send_buffer.borrow_mut().enqueue(buf);
         self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
 
         Ok(())
     }
 
+    /// Whether we're still accepting data and haven't already queued up more than the remote
+    /// window can take.
+    pub fn is_writable(&self) -> bool {
+        let Wrapping(queued) = self.unsent_seq_no.get() - self.base_seq_no.get();
+        self.state.get() == SenderState::Open && queued < self.window_size.get()
+    }
+
+    /// Resolves once no more than `high_watermark` bytes are waiting in the send buffer, counting
+    /// both unsent and unacknowledged data, or straight away if there's no high-watermark.
+    pub fn poll_push(
+        &self,
+        high_watermark: Option<usize>,
+        ctx: &mut Context,
+    ) -> Poll<Result<(), Fail>> {
+        match self.state.get() {
+            SenderState::Reset => return Poll::Ready(Err(Fail::ConnectionReset {})),
+            SenderState::TimedOut => return Poll::Ready(Err(Fail::Timeout {})),
+            _ => (),
+        }
+        let Wrapping(queued) = self.unsent_seq_no.get() - self.base_seq_no.get();
+        match high_watermark {
+            Some(high_watermark) if queued as usize > high_watermark => {
+                let mut push_wakers = self.push_wakers.borrow_mut();
+                if !push_wakers.iter().any(|w| w.will_wake(ctx.waker())) {
+                    push_wakers.push(ctx.waker().clone());
+                }
+                Poll::Pending
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn wake_pushers(&self) {
+        let push_wakers = mem::take(&mut *self.push_wakers.borrow_mut());
+        for w in push_wakers {
+            w.wake()
+        }
+    }
+
     pub fn close(&self) -> Result<(), Fail> {
         if self.state.get() != SenderState::Open {
             return Err(Fail::Ignored {
                 details: "Sender closed",
             });
         }
+        // No more data is coming for Nagle to coalesce with whatever it's holding back, so that
+        // goes out now instead of waiting for the data in flight to be ACKed.
+        self.send_buffer.borrow_mut().set_nagle(false);
         self.state.set(SenderState::Closed);
         Ok(())
     }
 
     pub fn receive_rst(&self) {
         self.state.set(SenderState::Reset);
+        self.wake_pushers();
+    }
+
+    pub fn time_out(&self) {
+        self.state.set(SenderState::TimedOut);
+        self.wake_pushers();
     }
 
-    pub fn remote_ack(&self, ack_seq_no: SeqNumber, now: Instant) -> Result<(), Fail> {
+    /// Handles an ACK from the peer, where `rtt` is the round trip time measured from its echoed
+    /// timestamp, if it had one.
+    pub fn remote_ack(
+        &self,
+        ack_seq_no: SeqNumber,
+        rtt: Option<Duration>,
+        now: Instant,
+    ) -> Result<(), Fail> {
         if self.state.get() == SenderState::SentFin
             && ack_seq_no == self.base_seq_no.get() + Wrapping(1)
         {
@@ -190,16 +287,19 @@ impl<RT: Runtime> Sender<RT> {
         let base_seq_no = self.base_seq_no.get();
         let sent_seq_no = self.sent_seq_no.get();
 
-        let bytes_outstanding = sent_seq_no - base_seq_no;
-        let bytes_acknowledged = ack_seq_no - base_seq_no;
-
-        if bytes_acknowledged > bytes_outstanding {
+        if seq_lt(ack_seq_no, base_seq_no) {
+            return Err(Fail::Ignored {
+                details: "ACK for already acknowledged data",
+            });
+        }
+        if seq_gt(ack_seq_no, sent_seq_no) {
             return Err(Fail::Ignored {
                 details: "ACK is outside of send window",
             });
         }
+        let bytes_acknowledged = ack_seq_no - base_seq_no;
 
-        self.congestion_ctrl.on_ack_received(&self, ack_seq_no);
+        self.congestion_ctrl.on_ack_received(&self, ack_seq_no, now);
         if bytes_acknowledged == Wrapping(0) {
             return Ok(());
         }
@@ -213,27 +313,15 @@ impl<RT: Runtime> Sender<RT> {
             self.retransmit_deadline.set(Some(deadline));
         }
 
-        // TODO: Do acks need to be on segment boundaries? How does this interact with repacketization?
-        let mut bytes_remaining = bytes_acknowledged.0 as usize;
-        while let Some(segment) = self.unacked_queue.borrow_mut().pop_front() {
-            if segment.bytes.len() > bytes_remaining {
-                // TODO: We need to close the connection in this case.
-                return Err(Fail::Ignored {
-                    details: "ACK isn't on segment boundary",
-                });
-            }
-            bytes_remaining -= segment.bytes.len();
-
-            // Add sample for RTO if not a retransmission
-            // TODO: TCP timestamp support.
-            if let Some(initial_tx) = segment.initial_tx {
-                self.rto.borrow_mut().add_sample(now - initial_tx);
-            }
-            if bytes_remaining == 0 {
-                break;
-            }
+        // Add sample for RTO if not a retransmission, unless the ACK echoed a timestamp that gives
+        // us the RTT directly.
+        let initial_tx = self.send_buffer.borrow_mut().ack(ack_seq_no)?;
+        if let Some(rtt) = rtt.or_else(|| initial_tx.map(|initial_tx| now - initial_tx)) {
+            self.rto.borrow_mut().add_sample(rtt);
         }
+        self.memory.release(bytes_acknowledged.0 as usize);
         self.base_seq_no.modify(|b| b + bytes_acknowledged);
+        self.wake_pushers();
         let new_base_seq_no = self.base_seq_no.get();
         if new_base_seq_no < base_seq_no {
             // We've wrapped around, and so we need to do some bookkeeping
@@ -243,36 +331,24 @@ impl<RT: Runtime> Sender<RT> {
         Ok(())
     }
 
-    pub fn pop_one_unsent_byte(&self) -> Option<RT::Buf> {
-        let mut queue = self.unsent_queue.borrow_mut();
-
-        let buf = queue.front_mut()?;
-        let mut cloned_buf = buf.clone();
-        let buf_len = buf.len();
-
-        // Pop one byte off the buf still in the queue and all but one of the bytes on our clone.
-        buf.adjust(1);
-        cloned_buf.trim(buf_len - 1);
-
-        Some(cloned_buf)
+    /// Records a SACK block from the peer, ignoring any that don't cover data in flight.
+    pub fn remote_sack(&self, begin: SeqNumber, end: SeqNumber) {
+        if seq_lt(begin, self.base_seq_no.get()) || seq_gt(end, self.sent_seq_no.get()) {
+            warn!(
+                "Ignoring SACK block outside of send window: {}..{}",
+                begin, end
+            );
+            return;
+        }
+        self.send_buffer.borrow_mut().sack(begin, end);
     }
 
-    pub fn pop_unsent(&self, max_bytes: usize) -> Option<RT::Buf> {
-        // TODO: Use a scatter/gather array to coalesce multiple buffers into a single segment.
-        let mut unsent_queue = self.unsent_queue.borrow_mut();
-        let mut buf = unsent_queue.pop_front()?;
-        let buf_len = buf.len();
-
-        if buf_len > max_bytes {
-            let mut cloned_buf = buf.clone();
-
-            buf.adjust(max_bytes);
-            cloned_buf.trim(buf_len - max_bytes);
-
-            unsent_queue.push_front(buf);
-            buf = cloned_buf;
-        }
-        Some(buf)
+    /// Moves the next segment out of the send buffer, stamping it with the current time for RTT
+    /// estimation.
+    pub fn pop_unsent(&self, max_bytes: usize, now: Instant) -> Option<RT::Buf> {
+        self.send_buffer
+            .borrow_mut()
+            .next_segment(self.mss.get(), max_bytes, now)
     }
 
     pub fn update_remote_window(&self, window_size_hdr: u16) -> Result<(), Fail> {
@@ -299,10 +375,28 @@ impl<RT: Runtime> Sender<RT> {
     }
 
     pub fn remote_mss(&self) -> usize {
-        self.mss
+        self.mss.get()
+    }
+
+    /// Lowers the MSS to `mss` (but never below the minimum every host must accept), if it's
+    /// higher.
+    pub fn reduce_mss(&self, mss: usize) {
+        let mss = cmp::max(mss, MIN_MSS);
+        if mss < self.mss.get() {
+            info!("Reducing MSS {} -> {}", self.mss.get(), mss);
+            self.mss.set(mss);
+        }
     }
 
     pub fn current_rto(&self) -> Duration {
         self.rto.borrow().estimate()
     }
 }
+
+impl<RT: Runtime> Drop for Sender<RT> {
+    fn drop(&mut self) {
+        let send_buffer = self.send_buffer.borrow();
+        self.memory
+            .release(send_buffer.unacked_bytes() + send_buffer.unsent_bytes());
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::tcp::{seq_lt, SeqNumber};
+use std::{
+    cell::Cell,
+    cmp,
+    num::Wrapping,
+    time::{Duration, Instant},
+};
+
+/// Receive window auto-tuning, along the lines of Linux's dynamic right-sizing. The amount of data
+/// that arrives in one RTT is a lower bound on the path's bandwidth-delay product, so we grow the
+/// window to twice that, which leaves the sender room to speed up further.
+///
+/// A connection that only receives never gets RTT samples from its sender, so we estimate the RTT
+/// on the receive side by timing how long it takes for a window's worth of data to arrive.
+#[derive(Debug)]
+pub struct WindowTuner {
+    max_window_size: u32,
+
+    rtt: Cell<Option<Duration>>,
+    /// When we started timing the RTT, and the sequence number that ends the measurement.
+    rtt_mark: Cell<Option<(Instant, SeqNumber)>>,
+
+    /// When the current delivery rate sample started, and RCV.NXT at that point.
+    sample_start: Cell<Option<(Instant, SeqNumber)>>,
+}
+
+impl WindowTuner {
+    pub fn new(max_window_size: u32) -> Self {
+        Self {
+            max_window_size,
+            rtt: Cell::new(None),
+            rtt_mark: Cell::new(None),
+            sample_start: Cell::new(None),
+        }
+    }
+
+    /// Called whenever in-order data advances RCV.NXT to `recv_seq_no`. Returns the size the
+    /// window should grow to, if it should grow.
+    pub fn on_receive(
+        &self,
+        now: Instant,
+        recv_seq_no: SeqNumber,
+        window_size: u32,
+    ) -> Option<u32> {
+        self.measure_rtt(now, recv_seq_no, window_size);
+        let rtt = self.rtt.get()?;
+
+        let (start, start_seq_no) = match self.sample_start.get() {
+            Some(sample_start) => sample_start,
+            None => {
+                self.sample_start.set(Some((now, recv_seq_no)));
+                return None;
+            }
+        };
+        if now.duration_since(start) < rtt {
+            return None;
+        }
+        self.sample_start.set(Some((now, recv_seq_no)));
+
+        let Wrapping(delivered) = recv_seq_no - start_seq_no;
+        let target = cmp::min(delivered.saturating_mul(2), self.max_window_size);
+        if target > window_size {
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    fn measure_rtt(&self, now: Instant, recv_seq_no: SeqNumber, window_size: u32) {
+        if let Some((start, end_seq_no)) = self.rtt_mark.get() {
+            if seq_lt(recv_seq_no, end_seq_no) {
+                return;
+            }
+            // A sender that doesn't fill the window makes this overestimate the RTT, so we keep
+            // the smallest sample we've seen.
+            let sample = now.duration_since(start);
+            if sample > Duration::from_secs(0) {
+                let rtt = self.rtt.get().map_or(sample, |rtt| cmp::min(rtt, sample));
+                self.rtt.set(Some(rtt));
+            }
+        }
+        self.rtt_mark
+            .set(Some((now, recv_seq_no + Wrapping(window_size))));
+    }
+}
@@ -1,7 +1,11 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+mod accept_filter;
 mod active_open;
+mod authentication;
+mod connection_id;
+mod connection_table;
 pub mod constants;
 mod established;
 mod isn_generator;
@@ -10,6 +14,7 @@ mod options;
 mod passive_open;
 pub mod peer;
 pub mod segment;
+mod timestamps;
 
 #[cfg(test)]
 mod tests;
@@ -18,4 +23,25 @@ use std::num::Wrapping;
 
 pub type SeqNumber = Wrapping<u32>;
 
-pub use self::{established::state::congestion_ctrl, options::TcpOptions as Options, peer::Peer};
+/// Whether `a` comes before `b` in sequence number space, which wraps around (RFC 1982).
+pub fn seq_lt(a: SeqNumber, b: SeqNumber) -> bool {
+    ((a - b).0 as i32) < 0
+}
+
+/// Whether `a` comes after `b` in sequence number space, which wraps around (RFC 1982).
+pub fn seq_gt(a: SeqNumber, b: SeqNumber) -> bool {
+    seq_lt(b, a)
+}
+
+pub use self::{
+    accept_filter::{AllowList, DeniedAction},
+    authentication::{MacAlgorithm, MasterKeyTuple},
+    connection_id::ConnectionId,
+    constants::{DEFAULT_MSS, INITIAL_RTO, MIN_MSS},
+    established::state::{
+        congestion_ctrl, counters::ConnectionCounters, recv_buffer::RecvBuffer,
+        send_buffer::SendBuffer,
+    },
+    options::TcpOptions as Options,
+    peer::Peer,
+};
@@ -162,10 +162,12 @@ impl<RT: Runtime> Future for AcceptFuture<RT> {
     }
 }
 
+/// Resolves once the pushed data has been queued and, if the send buffer has a high-watermark, the
+/// peer has acknowledged enough of what's queued to bring it back under that mark.
 pub struct PushFuture<RT: Runtime> {
     pub fd: FileDescriptor,
     pub err: Option<Fail>,
-    pub _marker: std::marker::PhantomData<RT>,
+    pub inner: Rc<RefCell<Inner<RT>>>,
 }
 
 impl<RT: Runtime> fmt::Debug for PushFuture<RT> {
@@ -177,14 +179,21 @@ impl<RT: Runtime> fmt::Debug for PushFuture<RT> {
 impl<RT: Runtime> Future for PushFuture<RT> {
     type Output = Result<(), Fail>;
 
-    fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<Self::Output> {
-        match self.get_mut().err.take() {
-            None => Poll::Ready(Ok(())),
-            Some(e) => Poll::Ready(Err(e)),
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        if let Some(e) = self_.err.take() {
+            return Poll::Ready(Err(e));
         }
+        let peer = Peer {
+            inner: self_.inner.clone(),
+        };
+        peer.poll_push(self_.fd, ctx)
     }
 }
 
+/// Resolves to the next chunk of in-order data. Once the peer has sent its FIN and everything
+/// before it has been popped, it resolves to an empty buffer instead, which marks the end of the
+/// stream; data never comes back empty.
 pub struct PopFuture<RT: Runtime> {
     pub fd: FileDescriptor,
     pub inner: Rc<RefCell<Inner<RT>>>,
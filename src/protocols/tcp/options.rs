@@ -1,26 +1,94 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 use crate::{
-    protocols::tcp::{
-        constants::{DEFAULT_MSS, MAX_MSS, MIN_MSS},
-        established::state::congestion_ctrl::{self as cc, CongestionControl},
+    fail::Fail,
+    protocols::{
+        ipv4::datagram::IPV4_HEADER_SIZE,
+        tcp::{
+            authentication::MasterKeyTuple,
+            constants::{
+                DEFAULT_MSL, DEFAULT_MSS, DEFAULT_RTO_MAX, DEFAULT_RTO_MIN, INITIAL_RTO, MAX_MSS,
+                MIN_MSS,
+            },
+            established::state::congestion_ctrl::{self as cc, CongestionControl},
+            segment::MIN_TCP_HEADER_SIZE,
+        },
     },
     runtime::Runtime,
 };
-use std::time::Duration;
+use std::{cmp, time::Duration};
 
 pub use crate::protocols::tcp::established::state::congestion_ctrl::CongestionControlConstructor;
 
 #[derive(Clone, Debug)]
 pub struct TcpOptions<RT: Runtime> {
+    /// Longest we hold back the ACK for in-order data, hoping to piggyback it on a reply or
+    /// cover more segments with it.
+    pub ack_delay_timeout: Duration,
     pub advertised_mss: usize,
+    /// Master key tuples for the TCP Authentication Option. Connections with a peer that any of
+    /// these match are authenticated with them.
+    pub authentication_keys: Vec<MasterKeyTuple>,
     pub congestion_ctrl_type: CongestionControlConstructor<RT>,
     pub congestion_ctrl_options: Option<cc::Options>,
+    /// Number of in-order segments we receive before sending an ACK, rather than waiting for the
+    /// delayed ACK timer.
+    pub delack_segs: usize,
     pub handshake_retries: usize,
     pub handshake_timeout: Duration,
+    /// Send keep-alive probes once a connection has heard nothing from the peer for this long
+    /// (TCP_KEEPIDLE). Keep-alives are off unless this is set.
+    pub keepalive_time: Option<Duration>,
+    /// Time between keep-alive probes that go unanswered (TCP_KEEPINTVL).
+    pub keepalive_intvl: Duration,
+    /// Number of unanswered keep-alive probes after which we give up on the connection
+    /// (TCP_KEEPCNT).
+    pub keepalive_probes: usize,
+    /// Wait in TIME-WAIT after an active close, so that delayed duplicates from the old
+    /// connection can't be mistaken for part of a new one with the same endpoints. Turning this
+    /// off frees the endpoints as soon as the final ACK is sent.
+    pub linger_in_time_wait: bool,
+    /// Most segments we send back-to-back before letting the scheduler run something else.
+    pub maxburst: usize,
+    /// Maximum segment lifetime: how long we assume a segment can survive in the network. We
+    /// stay in TIME-WAIT for twice this.
+    pub msl: Duration,
+    /// Send small segments straight away, rather than holding them back with Nagle's algorithm
+    /// while there's unacknowledged data in flight (TCP_NODELAY).
+    pub nodelay: bool,
+    /// Grow the receive window past `receive_window_size` to suit the path's bandwidth-delay
+    /// product, up to `rcvbuf_max` bytes.
+    pub rcvbuf_auto: bool,
+    pub rcvbuf_max: u32,
     pub receive_window_size: u16,
     pub retries: usize,
+    /// RTO before we have an RTT measurement.
+    pub rto_initial: Duration,
+    /// Ceiling on the RTO, however far it backs off.
+    pub rto_max: Duration,
+    /// Floor on the RTO. RFC 6298 asks for a second, which is far longer than a LAN needs to
+    /// recover from a loss, so low-latency deployments may want to lower it.
+    pub rto_min: Duration,
+    /// Advertise selective acknowledgments (RFC 2018) on the handshake. Connections only use them
+    /// if the peer advertises them too.
+    pub sack: bool,
+    /// High-watermark on the bytes a connection keeps queued, sent or not, before the peer
+    /// acknowledges them. A push made while the queue is at or under it is accepted even if it
+    /// takes the queue past it, but its future doesn't resolve until ACKs bring the queue back
+    /// down. Pushes made while the queue is over it fail with `Fail::ResourceExhausted`. `None`
+    /// leaves the send buffer unbounded.
+    pub send_buffer_size: Option<usize>,
+    /// Give up on a connection once the oldest data in its send buffer has gone this long without
+    /// being acknowledged, e.g. because the peer's window never reopens.
+    pub send_stall_timeout: Option<Duration>,
+    /// Advertise the timestamps option (RFC 7323) on the handshake, which connections use for RTT
+    /// measurement and PAWS if the peer advertises it too.
+    pub timestamps: bool,
     pub trailing_ack_delay: Duration,
+    /// Put the timestamps option on pure ACKs too, as RFC 7323 requires. Turning this off saves
+    /// 12 bytes per ACK on a constrained reverse path, at the cost of fewer RTT samples for the
+    /// peer.
+    pub ts_on_pure_ack: bool,
     pub window_scale: u8,
     pub rx_checksum_offload: bool,
     pub tx_checksum_offload: bool,
@@ -29,14 +97,34 @@ pub struct TcpOptions<RT: Runtime> {
 impl<RT: Runtime> Default for TcpOptions<RT> {
     fn default() -> Self {
         TcpOptions {
+            ack_delay_timeout: Duration::from_millis(40),
             advertised_mss: DEFAULT_MSS,
+            authentication_keys: Vec::new(),
             congestion_ctrl_type: cc::Cubic::new,
             congestion_ctrl_options: None,
+            delack_segs: 2,
             handshake_retries: 5,
             handshake_timeout: Duration::from_secs(3),
+            keepalive_time: None,
+            keepalive_intvl: Duration::from_secs(75),
+            keepalive_probes: 9,
+            linger_in_time_wait: true,
+            maxburst: 10,
+            msl: DEFAULT_MSL,
+            nodelay: false,
+            rcvbuf_auto: false,
+            rcvbuf_max: 6 << 20,
             receive_window_size: 0xffff,
             retries: 5,
+            rto_initial: INITIAL_RTO,
+            rto_max: DEFAULT_RTO_MAX,
+            rto_min: DEFAULT_RTO_MIN,
+            sack: true,
+            send_buffer_size: None,
+            send_stall_timeout: None,
+            timestamps: false,
             trailing_ack_delay: Duration::from_micros(1),
+            ts_on_pure_ack: true,
             window_scale: 0,
             rx_checksum_offload: false,
             tx_checksum_offload: false,
@@ -45,6 +133,24 @@ impl<RT: Runtime> Default for TcpOptions<RT> {
 }
 
 impl<RT: Runtime> TcpOptions<RT> {
+    /// Checks the constraints between options that the individual setters can't, since they can
+    /// be set in any order. Connections are only set up with options that pass.
+    pub fn validate(&self) -> Result<(), Fail> {
+        if self.rto_min > self.rto_max {
+            return Err(Fail::Invalid {
+                details: "rto_min is greater than rto_max",
+            });
+        }
+        Ok(())
+    }
+
+    pub fn ack_delay_timeout(mut self, value: Duration) -> Self {
+        // RFC 1122, section 4.2.3.2: the delay must be less than 0.5 seconds.
+        assert!(value < Duration::from_millis(500));
+        self.ack_delay_timeout = value;
+        self
+    }
+
     pub fn advertised_mss(mut self, value: usize) -> Self {
         assert!(value >= MIN_MSS);
         assert!(value <= MAX_MSS);
@@ -52,6 +158,24 @@ impl<RT: Runtime> TcpOptions<RT> {
         self
     }
 
+    /// The MSS option to put on our SYN. If the runtime knows its link MTU, that's whatever fits
+    /// after the IPv4 and TCP headers (options aren't counted, per RFC 6691), so the peer never
+    /// sends us segments that would be fragmented on our link. Otherwise it's `advertised_mss`.
+    pub fn syn_mss(&self, mtu: Option<usize>) -> usize {
+        match mtu {
+            Some(mtu) => {
+                let mss = mtu.saturating_sub(IPV4_HEADER_SIZE + MIN_TCP_HEADER_SIZE);
+                cmp::min(cmp::max(mss, MIN_MSS), MAX_MSS)
+            }
+            None => self.advertised_mss,
+        }
+    }
+
+    pub fn authentication_key(mut self, value: MasterKeyTuple) -> Self {
+        self.authentication_keys.push(value);
+        self
+    }
+
     pub fn congestion_ctrl_type(mut self, value: CongestionControlConstructor<RT>) -> Self {
         self.congestion_ctrl_type = value;
         self
@@ -62,6 +186,12 @@ impl<RT: Runtime> TcpOptions<RT> {
         self
     }
 
+    pub fn delack_segs(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.delack_segs = value;
+        self
+    }
+
     pub fn handshake_retries(mut self, value: usize) -> Self {
         assert!(value > 0);
         self.handshake_retries = value;
@@ -74,6 +204,57 @@ impl<RT: Runtime> TcpOptions<RT> {
         self
     }
 
+    pub fn keepalive_time(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.keepalive_time = Some(value);
+        self
+    }
+
+    pub fn keepalive_intvl(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.keepalive_intvl = value;
+        self
+    }
+
+    pub fn keepalive_probes(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.keepalive_probes = value;
+        self
+    }
+
+    pub fn linger_in_time_wait(mut self, value: bool) -> Self {
+        self.linger_in_time_wait = value;
+        self
+    }
+
+    pub fn maxburst(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.maxburst = value;
+        self
+    }
+
+    pub fn msl(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.msl = value;
+        self
+    }
+
+    pub fn nodelay(mut self, value: bool) -> Self {
+        self.nodelay = value;
+        self
+    }
+
+    pub fn rcvbuf_auto(mut self, value: bool) -> Self {
+        self.rcvbuf_auto = value;
+        self
+    }
+
+    pub fn rcvbuf_max(mut self, value: u32) -> Self {
+        assert!(value > 0);
+        self.rcvbuf_max = value;
+        self
+    }
+
     pub fn receive_window_size(mut self, value: u16) -> Self {
         assert!(value > 0);
         self.receive_window_size = value;
@@ -86,11 +267,56 @@ impl<RT: Runtime> TcpOptions<RT> {
         self
     }
 
+    pub fn rto_initial(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.rto_initial = value;
+        self
+    }
+
+    pub fn rto_max(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.rto_max = value;
+        self
+    }
+
+    pub fn rto_min(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.rto_min = value;
+        self
+    }
+
+    pub fn sack(mut self, value: bool) -> Self {
+        self.sack = value;
+        self
+    }
+
+    pub fn send_buffer_size(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.send_buffer_size = Some(value);
+        self
+    }
+
+    pub fn send_stall_timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.send_stall_timeout = Some(value);
+        self
+    }
+
+    pub fn timestamps(mut self, value: bool) -> Self {
+        self.timestamps = value;
+        self
+    }
+
     pub fn trailing_ack_delay(mut self, value: Duration) -> Self {
         self.trailing_ack_delay = value;
         self
     }
 
+    pub fn ts_on_pure_ack(mut self, value: bool) -> Self {
+        self.ts_on_pure_ack = value;
+        self
+    }
+
     pub fn window_scale(mut self, value: u8) -> Self {
         self.window_scale = value;
         self
@@ -2,20 +2,28 @@
 // Licensed under the MIT license.
 
 use super::{
+    accept_filter::AllowList,
     constants::FALLBACK_MSS,
-    established::state::{receiver::Receiver, sender::Sender, ControlBlock},
+    established::state::{
+        congestion_ctrl as cc, counters::ConnectionCounters, receiver::Receiver,
+        rto::RtoCalculator, sender::Sender, ControlBlock,
+    },
     isn_generator::IsnGenerator,
 };
 use crate::{
+    collections::watched::WatchedValue,
     fail::Fail,
+    memory_budget::MemoryBudget,
     protocols::{
         arp,
         ethernet2::frame::{EtherType2, Ethernet2Header},
         ipv4,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
         tcp::{
+            authentication::AuthState,
             segment::{TcpHeader, TcpOptions2, TcpSegment},
-            SeqNumber,
+            timestamps::{self, TimestampState},
+            ConnectionId, SeqNumber,
         },
     },
     runtime::Runtime,
@@ -24,14 +32,14 @@ use crate::{
 };
 use std::collections::{HashMap, HashSet};
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::VecDeque,
     convert::TryInto,
     future::Future,
     num::Wrapping,
     rc::Rc,
     task::{Context, Poll, Waker},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 struct InflightAccept {
@@ -40,6 +48,12 @@ struct InflightAccept {
     header_window_size: u16,
     remote_window_scale: Option<u8>,
     mss: usize,
+    sack_permitted: bool,
+    /// Origin of the connection's timestamp clock.
+    ts_base: Instant,
+    /// TSval from the SYN, if both sides advertised timestamps.
+    remote_tsval: Option<u32>,
+    auth: Option<AuthState>,
 
     #[allow(unused)]
     handle: SchedulerHandle,
@@ -49,12 +63,15 @@ struct ReadySockets<RT: Runtime> {
     ready: VecDeque<Result<ControlBlock<RT>, Fail>>,
     endpoints: HashSet<ipv4::Endpoint>,
     waker: Option<Waker>,
+    /// Mirrors `ready.len()` so readiness watchers can wait on it.
+    len: Rc<WatchedValue<usize>>,
 }
 
 impl<RT: Runtime> ReadySockets<RT> {
     fn push_ok(&mut self, cb: ControlBlock<RT>) {
         assert!(self.endpoints.insert(cb.remote));
         self.ready.push_back(Ok(cb));
+        self.len.set(self.ready.len());
         if let Some(w) = self.waker.take() {
             w.wake()
         }
@@ -62,6 +79,7 @@ impl<RT: Runtime> ReadySockets<RT> {
 
     fn push_err(&mut self, err: Fail) {
         self.ready.push_back(Err(err));
+        self.len.set(self.ready.len());
         if let Some(w) = self.waker.take() {
             w.wake()
         }
@@ -75,6 +93,7 @@ impl<RT: Runtime> ReadySockets<RT> {
                 return Poll::Pending;
             }
         };
+        self.len.set(self.ready.len());
         if let Ok(ref cb) = r {
             assert!(self.endpoints.remove(&cb.remote));
         }
@@ -92,18 +111,28 @@ pub struct PassiveSocket<RT: Runtime> {
 
     max_backlog: usize,
     isn_generator: IsnGenerator,
+    accept_filter: Option<AllowList>,
 
-    local: ipv4::Endpoint,
     rt: RT,
     arp: arp::Peer<RT>,
+    memory: MemoryBudget,
+    /// Algorithm chosen for this socket, which every connection accepted from it inherits.
+    congestion_ctrl: Option<cc::CongestionControlSelection<RT>>,
 }
 
 impl<RT: Runtime> PassiveSocket<RT> {
-    pub fn new(local: ipv4::Endpoint, max_backlog: usize, rt: RT, arp: arp::Peer<RT>) -> Self {
+    pub fn new(
+        max_backlog: usize,
+        rt: RT,
+        arp: arp::Peer<RT>,
+        memory: MemoryBudget,
+        congestion_ctrl: Option<cc::CongestionControlSelection<RT>>,
+    ) -> Self {
         let ready = ReadySockets {
             ready: VecDeque::new(),
             endpoints: HashSet::new(),
             waker: None,
+            len: Rc::new(WatchedValue::new(0)),
         };
         let ready = Rc::new(RefCell::new(ready));
         let nonce = rt.rng_gen();
@@ -112,9 +141,11 @@ impl<RT: Runtime> PassiveSocket<RT> {
             ready,
             max_backlog,
             isn_generator: IsnGenerator::new(nonce),
-            local,
+            accept_filter: None,
             rt,
             arp,
+            memory,
+            congestion_ctrl,
         }
     }
 
@@ -122,7 +153,45 @@ impl<RT: Runtime> PassiveSocket<RT> {
         self.ready.borrow_mut().poll(ctx)
     }
 
-    pub fn receive(&mut self, ip_header: &Ipv4Header, header: &TcpHeader) -> Result<(), Fail> {
+    /// Number of completed connections waiting to be `accept`ed.
+    pub fn accept_queue_len(&self) -> usize {
+        self.ready.borrow().len()
+    }
+
+    /// Returns a future that completes once the accept queue becomes empty or non-empty.
+    pub fn readiness_changed(&self) -> impl Future<Output = ()> {
+        let len = self.ready.borrow().len.clone();
+        let readable = len.get() > 0;
+        async move {
+            let (current, changed) = len.watch();
+            if (current > 0) == readable {
+                changed.await;
+            }
+        }
+    }
+
+    pub fn backlog(&self) -> usize {
+        self.max_backlog
+    }
+
+    pub fn accept_filter(&self) -> Option<&AllowList> {
+        self.accept_filter.as_ref()
+    }
+
+    pub fn set_accept_filter(&mut self, filter: AllowList) {
+        self.accept_filter = Some(filter);
+    }
+
+    /// Handles a segment for this listener, where `segment` holds its raw TCP header and data.
+    pub fn receive(
+        &mut self,
+        ip_header: &Ipv4Header,
+        header: &TcpHeader,
+        segment: &[u8],
+    ) -> Result<(), Fail> {
+        // The listener may be bound to the wildcard address, but each connection takes the
+        // address its SYN was sent to.
+        let local = ipv4::Endpoint::new(ip_header.dst_addr, header.dst_port);
         let remote = ipv4::Endpoint::new(ip_header.src_addr, header.src_port);
         if self.ready.borrow().endpoints.contains(&remote) {
             // TODO: What should we do if a packet shows up for a connection that hasn't been
@@ -146,8 +215,14 @@ impl<RT: Runtime> PassiveSocket<RT> {
                 header_window_size,
                 remote_window_scale,
                 mss,
+                sack_permitted,
+                ts_base,
+                remote_tsval,
                 ..
             } = self.inflight.get(&remote).unwrap();
+            if let Some(ref auth) = self.inflight[&remote].auth {
+                auth.verify(header, segment)?;
+            }
             if header.ack_num != local_isn + Wrapping(1) {
                 return Err(Fail::Malformed {
                     details: "Invalid SYN+ACK seq num",
@@ -176,27 +251,59 @@ impl<RT: Runtime> PassiveSocket<RT> {
                 local_window_scale, remote_window_scale
             );
 
+            let (cc_constructor, cc_options) = self.congestion_ctrl.clone().unwrap_or((
+                tcp_options.congestion_ctrl_type,
+                tcp_options.congestion_ctrl_options,
+            ));
             let sender = Sender::new(
                 local_isn + Wrapping(1),
                 remote_window_size,
                 remote_window_scale,
                 mss,
-                tcp_options.congestion_ctrl_type,
-                tcp_options.congestion_ctrl_options,
+                !tcp_options.nodelay,
+                RtoCalculator::new(
+                    tcp_options.rto_initial,
+                    tcp_options.rto_min,
+                    tcp_options.rto_max,
+                ),
+                cc_constructor,
+                cc_options,
+                self.memory.clone(),
             );
             let receiver = Receiver::new(
                 remote_isn + Wrapping(1),
                 local_window_size,
                 local_window_scale,
+                tcp_options.delack_segs,
+                tcp_options.ack_delay_timeout,
+                tcp_options.rcvbuf_auto.then(|| tcp_options.rcvbuf_max),
+                self.memory.clone(),
             );
-            self.inflight.remove(&remote);
+            // Echo the ACK's timestamp from now on, or the SYN's if the ACK lacks one.
+            let timestamps = remote_tsval.map(|syn_tsval| {
+                let tsval = timestamps::parse(header).map_or(syn_tsval, |(tsval, _)| tsval);
+                TimestampState::new(ts_base, tsval)
+            });
+            let auth = self.inflight.remove(&remote).and_then(|accept| accept.auth);
             let cb = ControlBlock {
-                local: self.local,
+                id: ConnectionId::next(),
+                local,
                 remote,
                 rt: self.rt.clone(),
                 arp: self.arp.clone(),
+                tos: Cell::new(0),
+                path_mtu: Cell::new(None),
                 sender,
                 receiver,
+                time_wait_deadline: WatchedValue::new(None),
+                last_received: WatchedValue::new(self.rt.now()),
+                rejected_segments: Cell::new(0),
+                counters: Cell::new(ConnectionCounters::default()),
+                error: Cell::new(None),
+                closed: WatchedValue::new(false),
+                auth,
+                sack_permitted,
+                timestamps,
             };
             self.ready.borrow_mut().push_ok(cb);
             return Ok(());
@@ -213,21 +320,31 @@ impl<RT: Runtime> PassiveSocket<RT> {
             // TODO: Should we send a RST here?
             return Err(Fail::ConnectionRefused {});
         }
-        let local_isn = self.isn_generator.generate(&self.local, &remote);
         let remote_isn = header.seq_num;
-        let future = Self::background(
-            local_isn,
+        let tcp_options = self.rt.tcp_options();
+        // The peer signed its SYN without knowing our ISN, so zero takes its place in the keys.
+        let syn_auth = AuthState::new(
+            &tcp_options.authentication_keys,
+            local,
+            remote,
+            Wrapping(0),
             remote_isn,
-            self.local,
+        );
+        if let Some(ref syn_auth) = syn_auth {
+            syn_auth.verify(header, segment)?;
+        }
+        let local_isn = self.isn_generator.generate(&local, &remote);
+        let auth = AuthState::new(
+            &tcp_options.authentication_keys,
+            local,
             remote,
-            self.rt.clone(),
-            self.arp.clone(),
-            self.ready.clone(),
+            local_isn,
+            remote_isn,
         );
-        let handle = self.rt.spawn(future);
-
         let mut remote_window_scale = None;
         let mut mss = FALLBACK_MSS;
+        let mut sack_permitted = false;
+        let mut remote_tsval = None;
         for option in header.iter_options() {
             match option {
                 TcpOptions2::WindowScale(w) => {
@@ -238,15 +355,44 @@ impl<RT: Runtime> PassiveSocket<RT> {
                     info!("Received advertised MSS: {}", m);
                     mss = *m as usize;
                 }
+                TcpOptions2::SelectiveAcknowlegementPermitted => {
+                    info!("Received SACK permitted");
+                    sack_permitted = tcp_options.sack;
+                }
+                TcpOptions2::Timestamp {
+                    sender_timestamp, ..
+                } if tcp_options.timestamps => {
+                    info!("Received timestamp: {}", sender_timestamp);
+                    remote_tsval = Some(*sender_timestamp);
+                }
                 _ => continue,
             }
         }
+
+        let ts_base = self.rt.now();
+        let future = Self::background(
+            local_isn,
+            remote_isn,
+            local,
+            remote,
+            sack_permitted,
+            remote_tsval.map(|tsval| (ts_base, tsval)),
+            self.rt.clone(),
+            self.arp.clone(),
+            self.ready.clone(),
+        );
+        let handle = self.rt.spawn(future);
+
         let accept = InflightAccept {
             local_isn,
             remote_isn,
             header_window_size: header.window_size,
             remote_window_scale,
             mss,
+            sack_permitted,
+            ts_base,
+            remote_tsval,
+            auth,
             handle,
         };
         self.inflight.insert(remote, accept);
@@ -258,11 +404,20 @@ impl<RT: Runtime> PassiveSocket<RT> {
         remote_isn: SeqNumber,
         local: ipv4::Endpoint,
         remote: ipv4::Endpoint,
+        sack_permitted: bool,
+        remote_timestamp: Option<(Instant, u32)>,
         rt: RT,
         arp: arp::Peer<RT>,
         ready: Rc<RefCell<ReadySockets<RT>>>,
     ) -> impl Future<Output = ()> {
         let tcp_options = rt.tcp_options();
+        let auth = AuthState::new(
+            &tcp_options.authentication_keys,
+            local,
+            remote,
+            local_isn,
+            remote_isn,
+        );
         let handshake_retries = 3usize;
         let handshake_timeout = Duration::from_secs(5);
 
@@ -282,13 +437,29 @@ impl<RT: Runtime> PassiveSocket<RT> {
                 tcp_hdr.ack_num = remote_isn + Wrapping(1);
                 tcp_hdr.window_size = tcp_options.receive_window_size;
 
-                let mss = tcp_options.advertised_mss as u16;
+                let mss = tcp_options.syn_mss(rt.mtu()) as u16;
                 tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
                 info!("Advertising MSS: {}", mss);
 
                 tcp_hdr.push_option(TcpOptions2::WindowScale(tcp_options.window_scale));
                 info!("Advertising window scale: {}", tcp_options.window_scale);
 
+                // We only offer SACK back to peers that offered it to us (RFC 2018).
+                if sack_permitted {
+                    tcp_hdr.push_option(TcpOptions2::SelectiveAcknowlegementPermitted);
+                    info!("Advertising SACK permitted");
+                }
+
+                // Likewise for timestamps (RFC 7323), where we echo the SYN's TSval.
+                if let Some((ts_base, remote_tsval)) = remote_timestamp {
+                    tcp_hdr.push_option(TcpOptions2::Timestamp {
+                        sender_timestamp: timestamps::tsval(ts_base, rt.now()),
+                        echo_timestamp: remote_tsval,
+                    });
+                    info!("Advertising timestamps");
+                }
+
+                let signer = auth.as_ref().map(|auth| auth.sign(&mut tcp_hdr));
                 debug!("Sending SYN+ACK: {:?}", tcp_hdr);
                 let segment = TcpSegment {
                     ethernet2_hdr: Ethernet2Header {
@@ -300,8 +471,9 @@ impl<RT: Runtime> PassiveSocket<RT> {
                     tcp_hdr,
                     data: RT::Buf::empty(),
                     tx_checksum_offload: tcp_options.tx_checksum_offload,
+                    signer,
                 };
-                rt.transmit(segment);
+                arp.transmit(segment);
                 rt.wait(handshake_timeout).await;
             }
             ready.borrow_mut().push_err(Fail::Timeout {});
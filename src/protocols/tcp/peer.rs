@@ -2,34 +2,44 @@
 // Licensed under the MIT license.
 
 use super::{
-    active_open::ActiveOpenSocket, established::EstablishedSocket, isn_generator::IsnGenerator,
+    accept_filter::{AllowList, DeniedAction},
+    active_open::ActiveOpenSocket,
+    connection_table::{ConnectionTable, FourTuple, ListenerTable},
+    established::EstablishedSocket,
+    isn_generator::IsnGenerator,
     passive_open::PassiveSocket,
 };
 use crate::{
     fail::Fail,
     file_table::{File, FileDescriptor, FileTable},
+    memory_budget::MemoryBudget,
     protocols::{
         arp,
         ethernet2::frame::{EtherType2, Ethernet2Header},
+        icmpv4::FragmentationNeeded,
         ip,
         ip::port::EphemeralPorts,
         ipv4,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
         tcp::{
+            congestion_ctrl as cc,
             operations::{AcceptFuture, ConnectFuture, ConnectFutureState, PopFuture, PushFuture},
             segment::{TcpHeader, TcpSegment},
+            ConnectionCounters, ConnectionId, SeqNumber,
         },
     },
     runtime::Runtime,
     runtime::RuntimeBuf,
 };
-use futures::channel::mpsc;
+use futures::{channel::mpsc, future::LocalBoxFuture, FutureExt};
 use std::collections::HashMap;
 use std::{
     cell::RefCell,
+    net::Shutdown,
+    num::Wrapping,
     rc::Rc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 pub struct Peer<RT: Runtime> {
@@ -37,9 +47,15 @@ pub struct Peer<RT: Runtime> {
 }
 
 impl<RT: Runtime> Peer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Self {
+    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable, memory: MemoryBudget) -> Self {
         let (tx, _rx) = mpsc::unbounded();
-        let inner = Rc::new(RefCell::new(Inner::new(rt.clone(), arp, file_table, tx)));
+        let inner = Rc::new(RefCell::new(Inner::new(
+            rt.clone(),
+            arp,
+            file_table,
+            memory,
+            tx,
+        )));
         Self { inner }
     }
 
@@ -75,6 +91,19 @@ impl<RT: Runtime> Peer<RT> {
         self.inner.borrow_mut().receive(ip_header, buf)
     }
 
+    /// Shrinks the segments of the connection a "fragmentation needed" error is about to fit the
+    /// path MTU it reports.
+    pub fn receive_fragmentation_needed(&self, error: FragmentationNeeded) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let key = FourTuple::new(error.local, error.remote);
+        match inner.established.get(&key) {
+            Some(s) => s.update_path_mtu(error.seq_num, error.path_mtu() as usize),
+            None => Err(Fail::Ignored {
+                details: "Fragmentation needed for unknown connection",
+            }),
+        }
+    }
+
     pub fn listen(&self, fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
         let local = match inner.sockets.get_mut(&fd) {
@@ -85,6 +114,7 @@ impl<RT: Runtime> Peer<RT> {
                 })
             }
         };
+        inner.rt.tcp_options().validate()?;
         // TODO: Should this move to bind?
         if inner.passive.contains_key(&local) {
             return Err(Fail::ResourceBusy {
@@ -92,7 +122,14 @@ impl<RT: Runtime> Peer<RT> {
             });
         }
 
-        let socket = PassiveSocket::new(local, backlog, inner.rt.clone(), inner.arp.clone());
+        let congestion_ctrl = inner.congestion_ctrl.remove(&fd);
+        let socket = PassiveSocket::new(
+            backlog,
+            inner.rt.clone(),
+            inner.arp.clone(),
+            inner.memory.clone(),
+            congestion_ctrl,
+        );
         assert!(inner.passive.insert(local, socket).is_none());
         inner.sockets.insert(fd, Socket::Listening { local });
         Ok(())
@@ -126,7 +163,7 @@ impl<RT: Runtime> Peer<RT> {
         };
         let fd = inner.file_table.alloc(File::TcpSocket);
         let established = EstablishedSocket::new(cb, fd, inner.dead_socket_tx.clone());
-        let key = (established.cb.local, established.cb.remote);
+        let key = FourTuple::new(established.cb.local, established.cb.remote);
 
         let socket = Socket::Established {
             local: established.cb.local,
@@ -138,6 +175,153 @@ impl<RT: Runtime> Peer<RT> {
         Poll::Ready(Ok(fd))
     }
 
+    /// Closes the listening socket `fd` so that new SYNs for its endpoint get a RST, but leaves the
+    /// connections already accepted from it open. Connections that completed the handshake but
+    /// weren't accepted yet are dropped along with the listener. The returned future completes
+    /// once all of the accepted connections have closed.
+    pub fn drain(&self, fd: FileDescriptor) -> Result<LocalBoxFuture<'static, ()>, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let local = match inner.sockets.get(&fd) {
+            Some(Socket::Listening { local }) => *local,
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not listening",
+                })
+            }
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        inner.passive.remove(&local);
+        inner.sockets.remove(&fd);
+        inner.file_table.free(fd);
+
+        // Listening ports are never handed out as ephemeral ports, so every established
+        // connection on the listener's endpoint was accepted from it.
+        let closed = inner
+            .established
+            .values()
+            .filter(|s| {
+                s.cb.local.port == local.port
+                    && (local.addr.is_unspecified() || s.cb.local.addr == local.addr)
+            })
+            .map(|s| s.wait_closed())
+            .collect::<Vec<_>>();
+        Ok(async move {
+            for c in closed {
+                c.await;
+            }
+        }
+        .boxed_local())
+    }
+
+    /// Returns the number of completed connections on the listening socket `fd` that haven't
+    /// been `accept`ed yet.
+    pub fn accept_queue_len(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Listening { local }) => Ok(inner
+                .passive
+                .get(local)
+                .expect("sockets/local inconsistency")
+                .accept_queue_len()),
+            Some(..) => Err(Fail::Malformed {
+                details: "Socket not listening",
+            }),
+            None => Err(Fail::Malformed { details: "Bad FD" }),
+        }
+    }
+
+    /// Restricts which source addresses may connect to the listening socket `fd`.
+    pub fn set_accept_filter(&self, fd: FileDescriptor, filter: AllowList) -> Result<(), Fail> {
+        let mut inner_ = self.inner.borrow_mut();
+        let inner = &mut *inner_;
+        match inner.sockets.get(&fd) {
+            Some(Socket::Listening { local }) => {
+                inner
+                    .passive
+                    .get_mut(local)
+                    .expect("sockets/local inconsistency")
+                    .set_accept_filter(filter);
+                Ok(())
+            }
+            Some(..) => Err(Fail::Malformed {
+                details: "Socket not listening",
+            }),
+            None => Err(Fail::Malformed { details: "Bad FD" }),
+        }
+    }
+
+    /// Returns the backlog the listening socket `fd` was created with.
+    pub fn backlog(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Listening { local }) => Ok(inner
+                .passive
+                .get(local)
+                .expect("sockets/local inconsistency")
+                .backlog()),
+            Some(..) => Err(Fail::Malformed {
+                details: "Socket not listening",
+            }),
+            None => Err(Fail::Malformed { details: "Bad FD" }),
+        }
+    }
+
+    /// Whether `fd` has data (or EOF) ready to be popped, or for a listening socket, a connection
+    /// ready to be accepted.
+    pub fn is_readable(&self, fd: FileDescriptor) -> bool {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => inner
+                .established
+                .get(&FourTuple::new(*local, *remote))
+                .map(|s| s.is_readable())
+                .unwrap_or(false),
+            Some(Socket::Listening { local }) => {
+                inner
+                    .passive
+                    .get(local)
+                    .expect("sockets/local inconsistency")
+                    .accept_queue_len()
+                    > 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `fd` is an established connection with room to push more data.
+    pub fn is_writable(&self, fd: FileDescriptor) -> bool {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => inner
+                .established
+                .get(&FourTuple::new(*local, *remote))
+                .map(|s| s.is_writable())
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Returns a future that completes once the readiness of `fd` may have changed, or `None` if
+    /// `fd` is neither established nor listening.
+    pub fn readiness_changed(&self, fd: FileDescriptor) -> Option<LocalBoxFuture<'static, ()>> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => inner
+                .established
+                .get(&FourTuple::new(*local, *remote))
+                .map(|s| s.readiness_changed().boxed_local()),
+            Some(Socket::Listening { local }) => Some(
+                inner
+                    .passive
+                    .get(local)
+                    .expect("sockets/local inconsistency")
+                    .readiness_changed()
+                    .boxed_local(),
+            ),
+            _ => None,
+        }
+    }
+
     pub fn accept(&self, fd: FileDescriptor) -> AcceptFuture<RT> {
         AcceptFuture {
             fd,
@@ -149,28 +333,49 @@ impl<RT: Runtime> Peer<RT> {
         let mut inner = self.inner.borrow_mut();
 
         let r = try {
-            match inner.sockets.get_mut(&fd) {
-                Some(Socket::Inactive { .. }) => (),
+            let bound = match inner.sockets.get(&fd) {
+                Some(Socket::Inactive { local }) => *local,
                 _ => Err(Fail::Malformed {
                     details: "Invalid file descriptor",
                 })?,
-            }
+            };
+            inner.rt.tcp_options().validate()?;
 
-            // TODO: We need to free these!
-            let local_port = inner.ephemeral_ports.alloc()?;
-            let local = ipv4::Endpoint::new(inner.rt.local_ipv4_addr(), local_port);
+            // Connect from the bound endpoint if there is one, or else from an ephemeral port.
+            let local = match bound {
+                Some(local) if local.addr.is_unspecified() => {
+                    ipv4::Endpoint::new(inner.rt.local_ipv4_addr(), local.port)
+                }
+                Some(local) => local,
+                // TODO: We need to free these!
+                None => {
+                    let local_port = inner.ephemeral_ports.alloc()?;
+                    ipv4::Endpoint::new(inner.rt.local_ipv4_addr(), local_port)
+                }
+            };
+            // We'd be sending the SYN to ourselves, which we don't loop back, so the handshake
+            // could only ever time out.
+            if local == remote {
+                if bound.is_none() {
+                    inner.ephemeral_ports.free(local.port);
+                }
+                Err(Fail::ConnectionRefused {})?
+            }
 
             let socket = Socket::Connecting { local, remote };
             inner.sockets.insert(fd, socket);
 
             let local_isn = inner.isn_generator.generate(&local, &remote);
-            let key = (local, remote);
+            let key = FourTuple::new(local, remote);
+            let congestion_ctrl = inner.congestion_ctrl.remove(&fd);
             let socket = ActiveOpenSocket::new(
                 local_isn,
                 local,
                 remote,
                 inner.rt.clone(),
                 inner.arp.clone(),
+                inner.memory.clone(),
+                congestion_ctrl,
             );
             assert!(inner.connecting.insert(key, socket).is_none());
             fd
@@ -186,70 +391,35 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
-    pub fn peek(&self, fd: FileDescriptor) -> Result<RT::Buf, Fail> {
-        let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+    /// Runs `f` on the connection on `fd`, failing if `fd` isn't an established connection.
+    fn with_established<R>(
+        &self,
+        fd: FileDescriptor,
+        f: impl FnOnce(&EstablishedSocket<RT>) -> Result<R, Fail>,
+    ) -> Result<R, Fail> {
+        let inner = self.inner.borrow();
+        let not_established = Fail::Malformed {
+            details: "Socket not established",
         };
-        match inner.established.get(&key) {
-            Some(ref s) => s.peek(),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
-            }),
-        }
+        f(inner.established_socket(fd, not_established)?)
+    }
+
+    pub fn peek(&self, fd: FileDescriptor) -> Result<RT::Buf, Fail> {
+        self.with_established(fd, |s| s.peek())
     }
 
     pub fn recv(&self, fd: FileDescriptor) -> Result<Option<RT::Buf>, Fail> {
-        let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Recv: Socket not established",
-                })
-            }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => s.recv(),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
-            }),
-        }
+        self.with_established(fd, |s| s.recv())
     }
 
     pub fn poll_recv(&self, fd: FileDescriptor, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
-        let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(Socket::Connecting { .. }) => {
-                return Poll::Ready(Err(Fail::Malformed {
-                    details: "pool_recv(): socket connecting",
-                }))
-            }
-            Some(Socket::Inactive { .. }) => {
-                return Poll::Ready(Err(Fail::Malformed {
-                    details: "pool_recv(): socket inactive",
-                }))
-            }
-            Some(Socket::Listening { .. }) => {
-                return Poll::Ready(Err(Fail::Malformed {
-                    details: "pool_recv(): socket listening",
-                }))
-            }
-            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
+        let inner = self.inner.borrow();
+        let not_established = Fail::Malformed {
+            details: "Socket not established",
         };
-        match inner.established.get(&key) {
-            Some(ref s) => s.poll_recv(ctx),
-            None => Poll::Ready(Err(Fail::Malformed {
-                details: "Socket not established",
-            })),
+        match inner.established_socket(fd, not_established) {
+            Ok(s) => s.poll_recv(ctx),
+            Err(e) => Poll::Ready(Err(e)),
         }
     }
 
@@ -261,7 +431,15 @@ impl<RT: Runtime> Peer<RT> {
         PushFuture {
             fd,
             err,
-            _marker: std::marker::PhantomData,
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn poll_push(&self, fd: FileDescriptor, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        let inner = self.inner.borrow();
+        match inner.established_socket(fd, Fail::NotConnected {}) {
+            Ok(s) => s.poll_push(ctx),
+            Err(e) => Poll::Ready(Err(e)),
         }
     }
 
@@ -273,38 +451,16 @@ impl<RT: Runtime> Peer<RT> {
     }
 
     fn send(&self, fd: FileDescriptor, buf: RT::Buf) -> Result<(), Fail> {
-        let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => s.send(buf),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
-            }),
-        }
+        let inner = self.inner.borrow();
+        inner
+            .established_socket(fd, Fail::NotConnected {})?
+            .send(buf)
     }
 
     pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
-        let inner = self.inner.borrow_mut();
+        let inner = self.inner.borrow();
         match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => {
-                let key = (*local, *remote);
-                match inner.established.get(&key) {
-                    Some(ref s) => s.close()?,
-                    None => {
-                        return Err(Fail::Malformed {
-                            details: "Socket not established",
-                        })
-                    }
-                }
-            }
+            Some(Socket::Established { .. }) => self.with_established(fd, |s| s.close())?,
             Some(..) => {
                 // TODO: Implement close for listening sockets.
                 // unimplemented!();
@@ -314,61 +470,202 @@ impl<RT: Runtime> Peer<RT> {
         Ok(())
     }
 
-    pub fn remote_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+    /// Shuts down reading, writing or both on a connection, without giving up the socket.
+    pub fn shutdown(&self, fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
         let inner = self.inner.borrow();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
+        inner
+            .established_socket(fd, Fail::NotConnected {})?
+            .shutdown(how)
+    }
+
+    pub fn remote_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        self.with_established(fd, |s| Ok(s.remote_mss()))
+    }
+
+    pub fn current_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
+        self.with_established(fd, |s| Ok(s.current_rto()))
+    }
+
+    pub fn endpoints(&self, fd: FileDescriptor) -> Result<(ipv4::Endpoint, ipv4::Endpoint), Fail> {
+        self.with_established(fd, |s| Ok(s.endpoints()))
+    }
+
+    pub fn time_wait_deadline(&self, fd: FileDescriptor) -> Result<Option<Instant>, Fail> {
+        self.with_established(fd, |s| Ok(s.time_wait_deadline()))
+    }
+
+    /// When the retransmission timer will next fire, if there's data in flight.
+    pub fn retransmit_deadline(&self, fd: FileDescriptor) -> Result<Option<Instant>, Fail> {
+        self.with_established(fd, |s| Ok(s.retransmit_deadline()))
+    }
+
+    /// Pulls the retransmission timer in to now, so the next time the scheduler runs it takes the
+    /// timeout path without tests having to advance the clock.
+    #[cfg(test)]
+    pub fn force_rto(&self, fd: FileDescriptor) -> Result<(), Fail> {
+        self.with_established(fd, |s| {
+            if s.retransmit_deadline().is_none() {
+                return Err(Fail::Ignored {
+                    details: "Nothing to retransmit",
+                });
             }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.remote_mss()),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
+            s.cb.sender.retransmit_deadline.set(Some(s.cb.rt.now()));
+            Ok(())
+        })
+    }
+
+    /// Overrides the window the peer last advertised, so tests can drive the sender into states
+    /// like a zero window without crafting segments.
+    #[cfg(test)]
+    pub fn set_peer_window(&self, fd: FileDescriptor, window_size: u32) -> Result<(), Fail> {
+        self.with_established(fd, |s| {
+            s.cb.sender.window_size.set(window_size);
+            Ok(())
+        })
+    }
+
+    /// Stable identifier of the connection on `fd`, which unlike the file descriptor itself is
+    /// never reused.
+    pub fn connection_id(&self, fd: FileDescriptor) -> Result<ConnectionId, Fail> {
+        self.with_established(fd, |s| Ok(s.connection_id()))
+    }
+
+    /// Path MTU of an established connection: the one PMTUD last found, or until it has found one,
+    /// what the MSS the peer advertised implies. We never send segments smaller than `MIN_MSS`, so
+    /// PMTUD can take this below what we actually send.
+    pub fn path_mtu(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        self.with_established(fd, |s| Ok(s.path_mtu()))
+    }
+
+    /// Number of segments on an established connection that failed the sequence number
+    /// acceptability test and were dropped.
+    pub fn rejected_segments(&self, fd: FileDescriptor) -> Result<u64, Fail> {
+        self.with_established(fd, |s| Ok(s.rejected_segments()))
+    }
+
+    pub fn counters(&self, fd: FileDescriptor) -> Result<ConnectionCounters, Fail> {
+        self.with_established(fd, |s| Ok(s.counters()))
+    }
+
+    /// How many bytes the peer's last advertised window lets us have in flight, with its window
+    /// scale applied.
+    pub fn effective_send_window(&self, fd: FileDescriptor) -> Result<u32, Fail> {
+        self.with_established(fd, |s| Ok(s.effective_send_window()))
+    }
+
+    /// The oldest sequence number we've sent that hasn't been acknowledged yet (SND.UNA).
+    pub fn snd_una(&self, fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.with_established(fd, |s| Ok(s.snd_una()))
+    }
+
+    /// The sequence number of the next byte we'll send (SND.NXT).
+    pub fn snd_nxt(&self, fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.with_established(fd, |s| Ok(s.snd_nxt()))
+    }
+
+    /// The number of segments we've sent that haven't been acknowledged yet.
+    pub fn unacked_segments(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        self.with_established(fd, |s| Ok(s.unacked_segments()))
+    }
+
+    /// Which phase the connection's congestion controller is in.
+    pub fn cc_phase(&self, fd: FileDescriptor) -> Result<cc::CcPhase, Fail> {
+        self.with_established(fd, |s| Ok(s.cc_phase()))
+    }
+
+    /// The sequence number of the next byte we expect to receive (RCV.NXT).
+    pub fn rcv_nxt(&self, fd: FileDescriptor) -> Result<SeqNumber, Fail> {
+        self.with_established(fd, |s| Ok(s.rcv_nxt()))
+    }
+
+    /// Zero the traffic counters of an established connection, returning their values from just
+    /// before the reset.
+    pub fn reset_counters(&self, fd: FileDescriptor) -> Result<ConnectionCounters, Fail> {
+        self.with_established(fd, |s| Ok(s.reset_counters()))
+    }
+
+    /// Returns and clears the error that terminated an established connection (the equivalent of
+    /// `getsockopt(SO_ERROR)`). This lets an application find out that the connection died even
+    /// if it had no operation pending at the time.
+    pub fn take_error(&self, fd: FileDescriptor) -> Result<Option<Fail>, Fail> {
+        self.with_established(fd, |s| Ok(s.take_error()))
+    }
+
+    /// Chooses the congestion control algorithm for a socket that hasn't connected or listened
+    /// yet, instead of the one in the stack's TCP options (the equivalent of
+    /// `setsockopt(TCP_CONGESTION)`). Connections accepted on a listening socket inherit its choice.
+    pub fn set_congestion_ctrl(
+        &self,
+        fd: FileDescriptor,
+        constructor: cc::CongestionControlConstructor<RT>,
+        options: Option<cc::Options>,
+    ) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Inactive { .. }) => {
+                inner.congestion_ctrl.insert(fd, (constructor, options));
+                Ok(())
+            }
+            Some(..) => Err(Fail::Malformed {
+                details: "Socket already connected or listening",
             }),
+            None => Err(Fail::Malformed { details: "Bad FD" }),
         }
     }
 
-    pub fn current_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
-        let inner = self.inner.borrow();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.current_rto()),
+    /// Describes the congestion controller of the connection on `fd`, so tests can tell which
+    /// algorithm it's running.
+    #[cfg(test)]
+    pub fn congestion_ctrl_debug(&self, fd: FileDescriptor) -> Result<String, Fail> {
+        self.with_established(fd, |s| Ok(format!("{:?}", s.cb.sender.congestion_ctrl)))
+    }
+
+    /// Set the IPv4 TOS byte used for subsequent segments on an established connection (the
+    /// equivalent of `setsockopt(IP_TOS)`).
+    pub fn set_tos(&self, fd: FileDescriptor, tos: u8) -> Result<(), Fail> {
+        self.with_established(fd, |s| {
+            s.set_tos(tos);
+            Ok(())
+        })
+    }
+
+    /// Enable or disable immediate ACKs on an established connection (the equivalent of
+    /// `setsockopt(TCP_QUICKACK)`).
+    pub fn set_quickack(&self, fd: FileDescriptor, quickack: bool) -> Result<(), Fail> {
+        self.with_established(fd, |s| {
+            s.set_quickack(quickack);
+            Ok(())
+        })
+    }
+
+    /// Set the number of bytes a `pop` on an established connection waits for before returning
+    /// (the equivalent of `setsockopt(SO_RCVLOWAT)`).
+    pub fn set_rcvlowat(&self, fd: FileDescriptor, bytes: usize) -> Result<(), Fail> {
+        self.with_established(fd, |s| {
+            s.set_rcvlowat(bytes);
+            Ok(())
+        })
+    }
+
+    /// Asks the peer of a TCP-AO connection to start signing its segments with the key we know as
+    /// `recv_id`, by advertising it as the RNextKeyID.
+    pub fn set_rnext_key_id(&self, fd: FileDescriptor, recv_id: u8) -> Result<(), Fail> {
+        self.with_established(fd, |s| match s.cb.auth {
+            Some(ref auth) => auth.set_rnext_key_id(recv_id),
             None => Err(Fail::Malformed {
-                details: "Socket not established",
+                details: "Connection isn't using TCP-AO",
             }),
-        }
+        })
     }
 
-    pub fn endpoints(&self, fd: FileDescriptor) -> Result<(ipv4::Endpoint, ipv4::Endpoint), Fail> {
-        let inner = self.inner.borrow();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.endpoints()),
+    /// KeyID of the TCP-AO key we're currently signing segments on `fd` with.
+    pub fn current_key_id(&self, fd: FileDescriptor) -> Result<u8, Fail> {
+        self.with_established(fd, |s| match s.cb.auth {
+            Some(ref auth) => Ok(auth.current_key_id()),
             None => Err(Fail::Malformed {
-                details: "Socket not established",
+                details: "Connection isn't using TCP-AO",
             }),
-        }
+        })
     }
 }
 
@@ -398,12 +695,17 @@ pub struct Inner<RT: Runtime> {
     // FD -> local port
     sockets: HashMap<FileDescriptor, Socket>,
 
-    passive: HashMap<ipv4::Endpoint, PassiveSocket<RT>>,
-    connecting: HashMap<(ipv4::Endpoint, ipv4::Endpoint), ActiveOpenSocket<RT>>,
-    established: HashMap<(ipv4::Endpoint, ipv4::Endpoint), EstablishedSocket<RT>>,
+    passive: ListenerTable<PassiveSocket<RT>>,
+    connecting: ConnectionTable<ActiveOpenSocket<RT>>,
+    established: ConnectionTable<EstablishedSocket<RT>>,
+
+    /// Congestion control algorithms chosen for sockets that haven't connected or listened yet.
+    congestion_ctrl: HashMap<FileDescriptor, cc::CongestionControlSelection<RT>>,
 
     rt: RT,
     arp: arp::Peer<RT>,
+    /// Buffer memory shared with every other socket on this stack.
+    memory: MemoryBudget,
 
     dead_socket_tx: mpsc::UnboundedSender<FileDescriptor>,
 }
@@ -413,6 +715,7 @@ impl<RT: Runtime> Inner<RT> {
         rt: RT,
         arp: arp::Peer<RT>,
         file_table: FileTable,
+        memory: MemoryBudget,
         dead_socket_tx: mpsc::UnboundedSender<FileDescriptor>,
     ) -> Self {
         Self {
@@ -420,17 +723,36 @@ impl<RT: Runtime> Inner<RT> {
             file_table,
             ephemeral_ports: EphemeralPorts::new(&rt),
             sockets: HashMap::new(),
-            passive: HashMap::new(),
-            connecting: HashMap::new(),
-            established: HashMap::new(),
+            passive: ListenerTable::new(),
+            connecting: ConnectionTable::new(),
+            established: ConnectionTable::new(),
+            congestion_ctrl: HashMap::new(),
             rt,
             arp,
+            memory,
             dead_socket_tx,
         }
     }
 
+    /// Looks up the connection on `fd`, failing with `not_established` if `fd` is some other kind
+    /// of socket.
+    fn established_socket(
+        &self,
+        fd: FileDescriptor,
+        not_established: Fail,
+    ) -> Result<&EstablishedSocket<RT>, Fail> {
+        let key = match self.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => FourTuple::new(*local, *remote),
+            Some(..) => return Err(not_established),
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        self.established.get(&key).ok_or(not_established)
+    }
+
     fn receive(&mut self, ip_hdr: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
         let tcp_options = self.rt.tcp_options();
+        // Hold on to the raw segment for checking TCP-AO MACs.
+        let segment = buf.clone();
         let (tcp_hdr, data) = TcpHeader::parse(ip_hdr, buf, tcp_options.rx_checksum_offload)?;
         debug!("TCP received {:?}", tcp_hdr);
         let local = ipv4::Endpoint::new(ip_hdr.dst_addr, tcp_hdr.dst_port);
@@ -442,31 +764,91 @@ impl<RT: Runtime> Inner<RT> {
                 details: "Invalid address type",
             });
         }
-        let key = (local, remote);
-
+        let key = FourTuple::new(local, remote);
+
+        // A connection that closed without lingering in TIME-WAIT doesn't hold on to its
+        // endpoints, so a new SYN for them starts over with the listener. Anything else for a
+        // CLOSED connection is treated as if the connection never existed (RFC 793, page 65).
+        let closed = self
+            .established
+            .get(&key)
+            .map_or(false, |s| s.cb.closed.get());
+        if closed && tcp_hdr.syn && !tcp_hdr.ack {
+            debug!("Dropping closed connection for new SYN: {:?}", key);
+            self.established.remove(&key);
+            for socket in self.sockets.values_mut() {
+                if matches!(socket, Socket::Established { local: l, remote: r } if FourTuple::new(*l, *r) == key)
+                {
+                    *socket = Socket::Inactive { local: None };
+                }
+            }
+        } else if closed {
+            return self.reject_segment(&tcp_hdr, data.len(), &local, &remote);
+        }
         if let Some(s) = self.established.get(&key) {
             debug!("Routing to established connection: {:?}", key);
+            if let Some(ref auth) = s.cb.auth {
+                auth.verify(&tcp_hdr, &segment[..])?;
+            }
             s.receive(&tcp_hdr, data);
             return Ok(());
         }
         if let Some(s) = self.connecting.get_mut(&key) {
             debug!("Routing to connecting connection: {:?}", key);
-            s.receive(&tcp_hdr);
+            s.receive(&tcp_hdr, &segment[..]);
             return Ok(());
         }
-        let (local, _) = key;
-        if let Some(s) = self.passive.get_mut(&local) {
-            debug!("Routing to passive connection: {:?}", local);
-            return s.receive(ip_hdr, &tcp_hdr);
+        let listener = match self.passive.lookup(&local) {
+            Some(listener) => listener,
+            // The packet isn't for an open port; send a RST segment.
+            None => return self.reject_segment(&tcp_hdr, data.len(), &local, &remote),
+        };
+        let denied_action = match self.passive.get(&listener).and_then(|s| s.accept_filter()) {
+            Some(filter) if tcp_hdr.syn && !tcp_hdr.ack && !filter.allows(remote.addr) => {
+                Some(filter.denied_action())
+            }
+            _ => None,
+        };
+        if let Some(action) = denied_action {
+            debug!("Accept filter denied SYN from {:?}", remote);
+            if action == DeniedAction::Reset {
+                self.send_rst(&tcp_hdr, data.len(), &local, &remote)?;
+            }
+            return Ok(());
         }
+        debug!("Routing to passive connection: {:?}", listener);
+        self.passive
+            .get_mut(&listener)
+            .unwrap()
+            .receive(ip_hdr, &tcp_hdr, &segment[..])
+    }
 
-        // The packet isn't for an open port; send a RST segment.
+    /// Answers a segment that doesn't belong to any connection with a RST, unless it's a RST
+    /// itself.
+    fn reject_segment(
+        &mut self,
+        tcp_hdr: &TcpHeader,
+        data_len: usize,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+    ) -> Result<(), Fail> {
+        if tcp_hdr.rst {
+            debug!("Dropping RST for {:?}, {:?}", local, remote);
+            return Ok(());
+        }
         debug!("Sending RST for {:?}, {:?}", local, remote);
-        self.send_rst(&local, &remote)?;
-        Ok(())
+        self.send_rst(tcp_hdr, data_len, local, remote)
     }
 
-    fn send_rst(&mut self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> Result<(), Fail> {
+    /// Sends a RST in reply to `tcp_hdr`, with sequence numbers the sender will accept (RFC 793,
+    /// page 36).
+    fn send_rst(
+        &mut self,
+        tcp_hdr: &TcpHeader,
+        data_len: usize,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+    ) -> Result<(), Fail> {
         // TODO: Make this work pending on ARP resolution if needed.
         let remote_link_addr = self
             .arp
@@ -475,8 +857,18 @@ impl<RT: Runtime> Inner<RT> {
                 details: "RST destination not in ARP cache",
             })?;
 
-        let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
-        tcp_hdr.rst = true;
+        // If the segment has an ACK, the RST goes out at that sequence number. Otherwise it
+        // acknowledges the segment instead, which is how the sender of a SYN knows the RST is for
+        // it.
+        let mut rst_hdr = TcpHeader::new(local.port, remote.port);
+        rst_hdr.rst = true;
+        if tcp_hdr.ack {
+            rst_hdr.seq_num = tcp_hdr.ack_num;
+        } else {
+            let seg_len = data_len as u32 + tcp_hdr.syn as u32 + tcp_hdr.fin as u32;
+            rst_hdr.ack = true;
+            rst_hdr.ack_num = tcp_hdr.seq_num + Wrapping(seg_len);
+        }
 
         let segment = TcpSegment {
             ethernet2_hdr: Ethernet2Header {
@@ -485,11 +877,12 @@ impl<RT: Runtime> Inner<RT> {
                 ether_type: EtherType2::Ipv4,
             },
             ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
-            tcp_hdr,
+            tcp_hdr: rst_hdr,
             data: RT::Buf::empty(),
             tx_checksum_offload: self.rt.tcp_options().tx_checksum_offload,
+            signer: None,
         };
-        self.rt.transmit(segment);
+        self.arp.transmit(segment);
 
         Ok(())
     }
@@ -500,7 +893,7 @@ impl<RT: Runtime> Inner<RT> {
         context: &mut Context,
     ) -> Poll<Result<(), Fail>> {
         let key = match self.sockets.get(&fd) {
-            Some(Socket::Connecting { local, remote }) => (*local, *remote),
+            Some(Socket::Connecting { local, remote }) => FourTuple::new(*local, *remote),
             Some(..) => {
                 return Poll::Ready(Err(Fail::Malformed {
                     details: "Socket not connecting",
@@ -528,7 +921,7 @@ impl<RT: Runtime> Inner<RT> {
         let cb = result?;
         let socket = EstablishedSocket::new(cb, fd, self.dead_socket_tx.clone());
         assert!(self.established.insert(key, socket).is_none());
-        let (local, remote) = key;
+        let FourTuple { local, remote } = key;
         self.sockets
             .insert(fd, Socket::Established { local, remote });
 
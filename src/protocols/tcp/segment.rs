@@ -6,7 +6,10 @@ use crate::{
         ethernet2::frame::Ethernet2Header,
         ip,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
-        tcp::SeqNumber,
+        tcp::{
+            authentication::{SegmentSigner, AO_MAC_LEN, AO_OPTION_KIND},
+            SeqNumber,
+        },
     },
     runtime::PacketBuf,
     runtime::RuntimeBuf,
@@ -14,7 +17,7 @@ use crate::{
 use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt};
 use std::{
     convert::{TryFrom, TryInto},
-    io::Cursor,
+    io::{Cursor, Read},
     num::Wrapping,
 };
 
@@ -29,6 +32,8 @@ pub struct TcpSegment<T: RuntimeBuf> {
     pub data: T,
 
     pub tx_checksum_offload: bool,
+    /// Fills in the MAC of the segment's TCP-AO option, if it has one.
+    pub signer: Option<SegmentSigner>,
 }
 
 impl<T: RuntimeBuf> PacketBuf<T> for TcpSegment<T> {
@@ -64,6 +69,7 @@ impl<T: RuntimeBuf> PacketBuf<T> for TcpSegment<T> {
             &self.ipv4_hdr,
             &self.data[..],
             self.tx_checksum_offload,
+            self.signer.as_ref(),
         );
     }
 
@@ -92,6 +98,11 @@ pub enum TcpOptions2 {
         sender_timestamp: u32,
         echo_timestamp: u32,
     },
+    Authentication {
+        key_id: u8,
+        rnext_key_id: u8,
+        mac: [u8; AO_MAC_LEN],
+    },
 }
 
 impl TcpOptions2 {
@@ -104,6 +115,7 @@ impl TcpOptions2 {
             SelectiveAcknowlegementPermitted => 2,
             SelectiveAcknowlegement { num_sacks, .. } => 2 + 8 * num_sacks,
             Timestamp { .. } => 10,
+            Authentication { .. } => 4 + AO_MAC_LEN,
         }
     }
 
@@ -156,6 +168,18 @@ impl TcpOptions2 {
                 NetworkEndian::write_u32(&mut buf[6..10], *echo_timestamp);
                 10
             }
+            Authentication {
+                key_id,
+                rnext_key_id,
+                mac,
+            } => {
+                buf[0] = AO_OPTION_KIND;
+                buf[1] = (4 + AO_MAC_LEN) as u8;
+                buf[2] = *key_id;
+                buf[3] = *rnext_key_id;
+                buf[4..(4 + AO_MAC_LEN)].copy_from_slice(mac);
+                4 + AO_MAC_LEN
+            }
         }
     }
 }
@@ -278,9 +302,12 @@ impl TcpHeader {
         let mut num_options = 0;
         let mut option_list = [TcpOptions2::NoOperation; MAX_TCP_OPTIONS];
 
+        // Options only ever come from the region between the fixed header and the data offset, so
+        // any data that follows (e.g. on a SYN) is never mistaken for options.
         if data_offset > MIN_TCP_HEADER_SIZE {
+            let options_len = data_offset - MIN_TCP_HEADER_SIZE;
             let mut option_rdr = Cursor::new(&hdr_buf[MIN_TCP_HEADER_SIZE..data_offset]);
-            while (option_rdr.position() as usize) < data_offset - MIN_TCP_HEADER_SIZE {
+            while (option_rdr.position() as usize) < options_len {
                 let option_kind = option_rdr.read_u8()?;
                 let option = match option_kind {
                     0 => break,
@@ -348,10 +375,34 @@ impl TcpHeader {
                             echo_timestamp,
                         }
                     }
+                    AO_OPTION_KIND => {
+                        let option_length = option_rdr.read_u8()?;
+                        if option_length as usize != 4 + AO_MAC_LEN {
+                            return Err(Fail::Malformed {
+                                details: "Invalid TCP-AO size",
+                            });
+                        }
+                        let key_id = option_rdr.read_u8()?;
+                        let rnext_key_id = option_rdr.read_u8()?;
+                        let mut mac = [0; AO_MAC_LEN];
+                        option_rdr.read_exact(&mut mac)?;
+                        TcpOptions2::Authentication {
+                            key_id,
+                            rnext_key_id,
+                            mac,
+                        }
+                    }
                     _ => {
-                        return Err(Fail::Malformed {
-                            details: "Invalid TCP option",
-                        })
+                        // RFC 1122 4.2.2.5: Skip over options we don't understand.
+                        let option_length = option_rdr.read_u8()? as u64;
+                        let option_end = option_rdr.position() + option_length - 2;
+                        if option_length < 2 || option_end > options_len as u64 {
+                            return Err(Fail::Malformed {
+                                details: "Invalid TCP option length",
+                            });
+                        }
+                        option_rdr.set_position(option_end);
+                        continue;
                     }
                 };
                 if num_options >= option_list.len() {
@@ -394,6 +445,7 @@ impl TcpHeader {
         ipv4_hdr: &Ipv4Header,
         data: &[u8],
         tx_checksum_offload: bool,
+        signer: Option<&SegmentSigner>,
     ) {
         let fixed_buf: &mut [u8; MIN_TCP_HEADER_SIZE] =
             (&mut buf[..MIN_TCP_HEADER_SIZE]).try_into().unwrap();
@@ -439,7 +491,11 @@ impl TcpHeader {
         NetworkEndian::write_u16(&mut fixed_buf[18..20], self.urgent_pointer);
 
         let mut cur_pos = MIN_TCP_HEADER_SIZE;
+        let mut mac_offset = None;
         for i in 0..self.num_options {
+            if let TcpOptions2::Authentication { .. } = self.option_list[i] {
+                mac_offset = Some(cur_pos + 4);
+            }
             let bytes_written = self.option_list[i].serialize(&mut buf[cur_pos..]);
             cur_pos += bytes_written;
         }
@@ -453,6 +509,18 @@ impl TcpHeader {
             *byte = 0;
         }
 
+        // The MAC covers everything but the checksum, which in turn covers the MAC.
+        if let (Some(signer), Some(mac_offset)) = (signer, mac_offset) {
+            let mac = signer.sign(
+                ipv4_hdr.src_addr,
+                ipv4_hdr.dst_addr,
+                &buf[..],
+                mac_offset,
+                data,
+            );
+            buf[mac_offset..(mac_offset + AO_MAC_LEN)].copy_from_slice(&mac);
+        }
+
         // Alright, we've fully filled out the header, time to compute the checksum.
         if !tx_checksum_offload {
             let checksum = tcp_checksum(ipv4_hdr, &buf[..], data);
@@ -476,6 +544,12 @@ impl TcpHeader {
         size.wrapping_add(3) & !0x3
     }
 
+    /// The window this header advertises in bytes, once the sender's window scale `shift` is
+    /// applied to the 16-bit window field (RFC 7323, section 2.3).
+    pub fn scaled_window(&self, shift: u8) -> u32 {
+        (self.window_size as u32) << shift
+    }
+
     pub fn iter_options(&self) -> impl Iterator<Item = &TcpOptions2> {
         (0..self.num_options).map(move |i| &self.option_list[i])
     }
@@ -564,3 +638,169 @@ fn tcp_checksum(ipv4_header: &Ipv4Header, header: &[u8], data: &[u8]) -> u16 {
     }
     !state as u16
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{SelectiveAcknowlegement, TcpHeader, TcpOptions2, MIN_TCP_HEADER_SIZE};
+    use crate::{
+        collections::bytes::Bytes,
+        fail::Fail,
+        protocols::{
+            ip,
+            ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
+        },
+        runtime::RuntimeBuf,
+        test_helpers,
+    };
+    use must_let::must_let;
+    use std::{convert::TryFrom, num::Wrapping};
+
+    fn ipv4_header() -> Ipv4Header {
+        Ipv4Header::new(
+            test_helpers::ALICE_IPV4,
+            test_helpers::BOB_IPV4,
+            Ipv4Protocol2::Tcp,
+        )
+    }
+
+    fn serialize(header: &TcpHeader, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; header.compute_size()];
+        header.serialize(&mut buf, &ipv4_header(), data, false, None);
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn test_parse_syn_with_options_and_data() {
+        let src_port = ip::Port::try_from(54321).unwrap();
+        let dst_port = ip::Port::try_from(80).unwrap();
+        let mut header = TcpHeader::new(src_port, dst_port);
+        header.syn = true;
+        header.seq_num = Wrapping(1000);
+        header.push_option(TcpOptions2::MaximumSegmentSize(1460));
+        header.push_option(TcpOptions2::WindowScale(7));
+        let data = b"hello, world";
+
+        // 20 byte fixed header + 4 (MSS) + 3 (window scale) + 1 (end of options), padded to 28.
+        let buf = serialize(&header, data);
+        assert_eq!(buf[12] >> 4, 7);
+
+        let (parsed, payload) =
+            TcpHeader::parse(&ipv4_header(), Bytes::from_slice(&buf), false).unwrap();
+        assert!(parsed.syn);
+        assert_eq!(parsed.seq_num, Wrapping(1000));
+        let options: Vec<_> = parsed.iter_options().collect();
+        assert_eq!(options.len(), 2);
+        must_let!(let TcpOptions2::MaximumSegmentSize(1460) = options[0]);
+        must_let!(let TcpOptions2::WindowScale(7) = options[1]);
+        assert_eq!(&payload[..], &data[..]);
+    }
+
+    #[test]
+    fn test_parse_skips_unknown_options() {
+        let src_port = ip::Port::try_from(54321).unwrap();
+        let dst_port = ip::Port::try_from(80).unwrap();
+        let mut header = TcpHeader::new(src_port, dst_port);
+        header.syn = true;
+        header.push_option(TcpOptions2::MaximumSegmentSize(1460));
+        let data = [0xaa; 4];
+        let mut buf = serialize(&header, &data);
+
+        // Swap the end of options marker and padding for a (kind 34) fast open cookie request,
+        // which we don't understand, and bump the data offset to match.
+        buf.truncate(MIN_TCP_HEADER_SIZE + 4);
+        buf.extend_from_slice(&[34, 2, 1, 1]);
+        buf[12] = 7 << 4;
+        buf.extend_from_slice(&data);
+        buf[16] = 0;
+        buf[17] = 0;
+        let checksum = super::tcp_checksum(&ipv4_header(), &buf[..28], &data);
+        buf[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+        let (parsed, payload) =
+            TcpHeader::parse(&ipv4_header(), Bytes::from_slice(&buf), false).unwrap();
+        let options: Vec<_> = parsed.iter_options().collect();
+        assert_eq!(options.len(), 1);
+        must_let!(let TcpOptions2::MaximumSegmentSize(1460) = options[0]);
+        assert_eq!(&payload[..], &data[..]);
+    }
+
+    #[test]
+    fn test_round_trip_with_options() {
+        let src_port = ip::Port::try_from(54321).unwrap();
+        let dst_port = ip::Port::try_from(80).unwrap();
+        let mut header = TcpHeader::new(src_port, dst_port);
+        header.seq_num = Wrapping(0xdead_beef);
+        header.ack_num = Wrapping(0x1234_5678);
+        header.ack = true;
+        header.psh = true;
+        header.urg = true;
+        header.window_size = 0x4321;
+        header.urgent_pointer = 3;
+        header.push_option(TcpOptions2::Timestamp {
+            sender_timestamp: 100,
+            echo_timestamp: 200,
+        });
+        let sack = SelectiveAcknowlegement {
+            begin: Wrapping(1000),
+            end: Wrapping(2000),
+        };
+        header.push_option(TcpOptions2::SelectiveAcknowlegement {
+            num_sacks: 2,
+            sacks: [
+                sack,
+                SelectiveAcknowlegement {
+                    begin: Wrapping(3000),
+                    end: Wrapping(4000),
+                },
+                sack,
+                sack,
+            ],
+        });
+        let data = b"round trip";
+
+        let buf = serialize(&header, data);
+        let (parsed, payload) =
+            TcpHeader::parse(&ipv4_header(), Bytes::from_slice(&buf), false).unwrap();
+        assert_eq!(parsed.src_port, src_port);
+        assert_eq!(parsed.dst_port, dst_port);
+        assert_eq!(parsed.seq_num, header.seq_num);
+        assert_eq!(parsed.ack_num, header.ack_num);
+        assert!(parsed.ack && parsed.psh && parsed.urg);
+        assert!(!parsed.syn && !parsed.fin && !parsed.rst);
+        assert_eq!(parsed.window_size, 0x4321);
+        assert_eq!(parsed.urgent_pointer, 3);
+        assert_eq!(&payload[..], &data[..]);
+
+        let options: Vec<_> = parsed.iter_options().collect();
+        assert_eq!(options.len(), 2);
+        must_let!(let TcpOptions2::Timestamp { sender_timestamp: 100, echo_timestamp: 200 } = options[0]);
+        must_let!(let TcpOptions2::SelectiveAcknowlegement { num_sacks: 2, sacks } = options[1]);
+        assert_eq!(
+            (sacks[0].begin, sacks[0].end),
+            (Wrapping(1000), Wrapping(2000))
+        );
+        assert_eq!(
+            (sacks[1].begin, sacks[1].end),
+            (Wrapping(3000), Wrapping(4000))
+        );
+
+        // Serializing the parsed header gives back the same bytes.
+        assert_eq!(serialize(&parsed, data), buf);
+
+        // The checksum covers the payload.
+        let mut corrupted = buf.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        must_let!(let Err(Fail::Malformed { .. }) = TcpHeader::parse(&ipv4_header(), Bytes::from_slice(&corrupted), false));
+    }
+
+    #[test]
+    fn test_scaled_window() {
+        let src_port = ip::Port::try_from(54321).unwrap();
+        let dst_port = ip::Port::try_from(80).unwrap();
+        let mut header = TcpHeader::new(src_port, dst_port);
+        header.window_size = 0xffff;
+        assert_eq!(header.scaled_window(0), 0xffff);
+        assert_eq!(header.scaled_window(14), 0xffff << 14);
+    }
+}
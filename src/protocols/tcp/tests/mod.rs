@@ -2,17 +2,36 @@
 // Licensed under the MIT license.
 
 use crate::{
-    collections::bytes::BytesMut,
-    protocols::{ip, ipv4},
-    runtime::Runtime,
-    test_helpers,
+    collections::bytes::{Bytes, BytesMut},
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ethernet2::frame::{serialize_frame, EtherType2, Ethernet2Header},
+        icmpv4::{Icmpv4Header, Icmpv4Message, Icmpv4Type2},
+        ip, ipv4,
+        ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
+        tcp::{
+            self,
+            congestion_ctrl::{self as cc, CongestionControl},
+            segment::{TcpHeader, TcpOptions2, TcpSegment},
+            AllowList, MacAlgorithm, MasterKeyTuple,
+        },
+    },
+    runtime::{PacketBuf, Runtime, RuntimeBuf},
+    test_helpers::{self, DecodedFrame, L4},
 };
-use futures::task::noop_waker_ref;
+use futures::task::{noop_waker_ref, waker, ArcWake};
 use must_let::must_let;
 use std::{
     convert::TryFrom,
     future::Future,
+    net::{Ipv4Addr, Shutdown},
+    num::Wrapping,
     pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
     time::{Duration, Instant},
 };
@@ -89,6 +108,2739 @@ fn test_connect() {
     bob.rt().poll_scheduler();
 }
 
+#[test]
+fn test_handshake_helper() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // The connection is ready for data straight away.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    let (hdr, data) = parse_tcp_frame(frame.clone());
+    assert_eq!(hdr.dst_port, listen_port);
+    assert_eq!(data, buf);
+
+    bob.receive(frame).unwrap();
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_change_tos_mid_flow() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    // Establish the connection between the two peers.
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+
+    // The first burst goes out with the default DSCP of zero.
+    let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+    assert_eq!(ipv4_hdr.dscp, 0);
+    assert_eq!(ipv4_hdr.ecn, 0);
+
+    // Mark the rest of the flow as low priority (CS1).
+    alice.tcp_set_tos(alice_fd, 0x20).unwrap();
+
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+    assert_eq!(ipv4_hdr.dscp, 0x20 >> 2);
+    assert_eq!(ipv4_hdr.ecn, 0);
+}
+
+#[test]
+fn test_per_socket_congestion_ctrl() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 2).unwrap();
+
+    // The first connection picks NewReno, the second keeps the stack's default (Cubic).
+    let reno_fd = alice.tcp_socket();
+    alice
+        .tcp_set_congestion_ctrl(reno_fd, cc::NewReno::new, None)
+        .unwrap();
+    let cubic_fd = alice.tcp_socket();
+
+    for &fd in &[reno_fd, cubic_fd] {
+        test_helpers::connect(&mut alice, fd, &mut bob, listen_fd, listen_addr);
+    }
+
+    let reno = alice.tcp_congestion_ctrl_debug(reno_fd).unwrap();
+    let cubic = alice.tcp_congestion_ctrl_debug(cubic_fd).unwrap();
+    assert!(reno.starts_with("NewReno"), "{}", reno);
+    assert!(cubic.starts_with("Cubic"), "{}", cubic);
+
+    // It's too late to choose once the connection is up.
+    assert!(alice
+        .tcp_set_congestion_ctrl(cubic_fd, cc::NewReno::new, None)
+        .is_err());
+}
+
+#[test]
+fn test_accept_queue_len() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 4).unwrap();
+    assert_eq!(bob.tcp_backlog(listen_fd).unwrap(), 4);
+    assert_eq!(bob.tcp_accept_queue_len(listen_fd).unwrap(), 0);
+
+    // Complete two handshakes without accepting either connection.
+    let mut connect_futures = vec![];
+    for _ in 0..2 {
+        let alice_fd = alice.tcp_socket();
+        connect_futures.push(alice.tcp_connect(alice_fd, listen_addr));
+
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+
+    assert_eq!(bob.tcp_accept_queue_len(listen_fd).unwrap(), 2);
+}
+
+#[test]
+fn test_duplicate_fin_in_time_wait() {
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // Alice closes first, so she's the one that ends up in TIME-WAIT.
+    alice.close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // Bob ACKs Alice's FIN and then sends his own.
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    bob.close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    let bob_fin = bob.rt().pop_frame();
+
+    alice.receive(bob_fin.clone()).unwrap();
+    alice.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(fin_ack), .. } = test_helpers::decode_frame(&alice.rt().pop_frame()));
+    assert!(fin_ack.ack);
+    let deadline = alice.tcp_time_wait_deadline(alice_fd).unwrap().unwrap();
+
+    // Pretend Alice's ACK was lost, so Bob retransmits his FIN.
+    now += Duration::from_secs(1);
+    alice.rt().advance_clock(now);
+    alice.receive(bob_fin).unwrap();
+    alice.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(dup_fin_ack), .. } = test_helpers::decode_frame(&alice.rt().pop_frame()));
+    assert!(dup_fin_ack.ack);
+    assert_eq!(dup_fin_ack.ack_num, fin_ack.ack_num);
+
+    // The TIME-WAIT timer restarts from when the duplicate FIN arrived.
+    assert_eq!(
+        alice.tcp_time_wait_deadline(alice_fd).unwrap(),
+        Some(deadline + Duration::from_secs(1))
+    );
+}
+
+#[test]
+fn test_accept_filter() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Only accept connections from 10.0.0.0/8, which doesn't include Alice.
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let filter = AllowList::default().allow(Ipv4Addr::new(10, 0, 0, 0), 8);
+    bob.tcp_set_accept_filter(listen_fd, filter).unwrap();
+
+    let alice_fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    // Bob drops Alice's SYN without answering it.
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().try_pop_frame().is_none());
+}
+
+#[test]
+fn test_quickack() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    bob.tcp_set_quickack(bob_fd, true).unwrap();
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(data_hdr), .. } = test_helpers::decode_frame(&frame));
+
+    // Bob ACKs the segment without the clock moving past the delayed ACK timeout.
+    bob.receive(frame).unwrap();
+    bob.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(ack_hdr), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert!(ack_hdr.ack);
+    assert_eq!(ack_hdr.ack_num, data_hdr.seq_num + Wrapping(32));
+}
+
+#[test]
+fn test_rcvlowat() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    bob.tcp_set_rcvlowat(bob_fd, 100).unwrap();
+    let mut pop_future = bob.tcp_pop(bob_fd);
+
+    // 50 bytes aren't enough to complete the pop.
+    let buf = BytesMut::from(&vec![0x5a; 50][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert!(!bob.is_readable(bob_fd));
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    // Another 60 take us over the watermark, and the pop returns all of it.
+    let buf = BytesMut::from(&vec![0xa5; 60][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert!(bob.is_readable(bob_fd));
+    must_let!(let Poll::Ready(Ok(received_buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received_buf.len(), 110);
+    assert_eq!(&received_buf[..50], &[0x5a; 50][..]);
+    assert_eq!(&received_buf[50..], &[0xa5; 60][..]);
+}
+
+#[test]
+fn test_readable_writable() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    // A connecting socket is neither readable nor writable.
+    alice.rt().poll_scheduler();
+    assert!(!alice.is_readable(alice_fd));
+    assert!(!alice.is_writable(alice_fd));
+
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // The listening socket is readable once a connection is waiting to be accepted.
+    assert!(bob.is_readable(listen_fd));
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    assert!(!bob.is_readable(listen_fd));
+
+    // An established socket with send space is writable, but has nothing to read yet.
+    assert!(alice.is_writable(alice_fd));
+    assert!(!alice.is_readable(alice_fd));
+    assert!(!bob.is_readable(bob_fd));
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // Buffered data makes Bob's socket readable until it's popped.
+    assert!(bob.is_readable(bob_fd));
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert!(!bob.is_readable(bob_fd));
+}
+
+#[test]
+fn test_unacceptable_segment_is_acked() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(data_hdr), .. } = test_helpers::decode_frame(&frame));
+
+    bob.receive(frame.clone()).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().try_pop_frame().is_none());
+    assert_eq!(bob.tcp_rejected_segments(bob_fd).unwrap(), 0);
+
+    // The duplicate lies entirely to the left of Bob's window, so it's dropped and Bob ACKs
+    // right away instead of waiting for the delayed ACK timer.
+    bob.receive(frame).unwrap();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(ack_hdr), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert!(ack_hdr.ack);
+    assert_eq!(ack_hdr.ack_num, data_hdr.seq_num + Wrapping(32));
+    assert_eq!(bob.tcp_rejected_segments(bob_fd).unwrap(), 1);
+
+    // Only one copy of the data made it into the receive buffer.
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+    assert!(!bob.is_readable(bob_fd));
+}
+
+#[test]
+fn test_global_memory_limit() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    bob.set_memory_limit(Some(1000));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 2).unwrap();
+
+    let mut connections = vec![];
+    for _ in 0..2 {
+        let alice_fd = alice.tcp_socket();
+        let bob_fd = test_helpers::connect(&mut alice, alice_fd, &mut bob, listen_fd, listen_addr);
+        connections.push((alice_fd, bob_fd));
+    }
+    let (alice_fd1, bob_fd1) = connections[0];
+    let (alice_fd2, bob_fd2) = connections[1];
+
+    // Fill up Bob's receive buffer on the first connection.
+    let buf = BytesMut::from(&vec![0x5a; 600][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd1, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.memory_used(), 600);
+
+    // The second connection only has 400 bytes of the shared budget left, so it buffers that
+    // much of another 600 and drops the rest.
+    let mut write_future = alice.tcp_push(alice_fd2, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.memory_used(), 1000);
+    assert!(bob.is_readable(bob_fd2));
+
+    // Pushes draw from the same budget.
+    let small_buf = BytesMut::from(&vec![0xa5; 10][..]).freeze();
+    let mut write_future = bob.tcp_push(bob_fd2, small_buf.clone());
+    must_let!(let Poll::Ready(Err(Fail::ResourceExhausted { .. })) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+
+    // Once the application drains the first connection, there's room again, and Bob advertises
+    // no more window than the budget has room for.
+    let mut pop_future = bob.tcp_pop(bob_fd1);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+    assert_eq!(bob.memory_used(), 400);
+    let mut write_future = bob.tcp_push(bob_fd2, small_buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(hdr), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert_eq!(bob.memory_used(), 410);
+    assert!((hdr.window_size as usize) << 2 <= 1000 - 410);
+}
+
+#[test]
+fn test_receive_buffer_memory_limit() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // Alice was offered a much bigger window on the handshake than Bob's memory now allows for.
+    bob.set_memory_limit(Some(1000));
+    let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    let mut write_future = alice.tcp_push(alice_fd, BytesMut::from(&data[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let mut data_seq = None;
+    while let Some(frame) = alice.rt().try_pop_frame() {
+        let (hdr, _) = parse_tcp_frame(frame.clone());
+        data_seq.get_or_insert(hdr.seq_num);
+        bob.receive(frame).unwrap();
+    }
+    let data_seq = data_seq.unwrap();
+
+    // Bob buffers what he can, and tells Alice straight away that his window has closed.
+    assert_eq!(bob.memory_used(), 1000);
+    bob.rt().poll_scheduler();
+    let mut acks = 0;
+    while let Some(frame) = bob.rt().try_pop_frame() {
+        let (hdr, _) = parse_tcp_frame(frame);
+        assert_eq!(hdr.window_size, 0);
+        assert_eq!(hdr.ack_num, data_seq + Wrapping(1000));
+        acks += 1;
+    }
+    assert!(acks > 0);
+
+    // The transfer resumes as the application drains the buffer, and nothing gets lost or
+    // corrupted along the way.
+    let mut received = vec![];
+    for _ in 0..20 {
+        loop {
+            let mut pop_future = bob.tcp_pop(bob_fd);
+            match Future::poll(Pin::new(&mut pop_future), &mut ctx) {
+                Poll::Ready(Ok(buf)) => received.extend_from_slice(&buf[..]),
+                Poll::Ready(Err(e)) => panic!("pop failed: {:?}", e),
+                Poll::Pending => break,
+            }
+        }
+        if received.len() == data.len() {
+            break;
+        }
+        now += Duration::from_secs(1);
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        while let Some(frame) = alice.rt().try_pop_frame() {
+            bob.receive(frame).unwrap();
+        }
+        bob.rt().advance_clock(now);
+        bob.rt().poll_scheduler();
+        while let Some(frame) = bob.rt().try_pop_frame() {
+            alice.receive(frame).unwrap();
+        }
+    }
+    assert_eq!(received, data);
+    assert_eq!(bob.memory_used(), 0);
+}
+
+#[test]
+fn test_connection_ids_are_unique() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    let mut ids = vec![];
+    for _ in 0..2 {
+        let alice_fd = alice.tcp_socket();
+        let bob_fd = test_helpers::connect(&mut alice, alice_fd, &mut bob, listen_fd, listen_addr);
+
+        let alice_id = alice.tcp_connection_id(alice_fd).unwrap();
+        let bob_id = bob.tcp_connection_id(bob_fd).unwrap();
+        // The ID doesn't change over the lifetime of the connection.
+        assert_eq!(alice.tcp_connection_id(alice_fd).unwrap(), alice_id);
+
+        alice.close(alice_fd).unwrap();
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+
+        ids.push(alice_id);
+        ids.push(bob_id);
+    }
+
+    // Each end of each connection got its own ID, and later connections get later IDs.
+    let mut unique = ids.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), 4);
+    assert!(ids[2] > ids[0]);
+    assert!(ids[3] > ids[1]);
+}
+
+#[test]
+fn test_demux_with_wildcard_listener() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    // Bob listens on port 80 of his address and on port 81 of every address. A wildcard listener
+    // on port 80 coexists with the specific one, which takes precedence.
+    let port_80 = ip::Port::try_from(80).unwrap();
+    let port_81 = ip::Port::try_from(81).unwrap();
+    let mut listen_fds = vec![];
+    for &addr in &[
+        ipv4::Endpoint::new(test_helpers::BOB_IPV4, port_80),
+        ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port_80),
+        ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port_81),
+    ] {
+        let fd = bob.tcp_socket();
+        bob.tcp_bind(fd, addr).unwrap();
+        bob.tcp_listen(fd, 8).unwrap();
+        listen_fds.push(fd);
+    }
+
+    let mut connections = vec![];
+    for i in 0..8u8 {
+        let (port, listen_fd) = if i % 2 == 0 {
+            (port_80, listen_fds[0])
+        } else {
+            (port_81, listen_fds[2])
+        };
+        let remote = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+        let mut accept_future = bob.tcp_accept(listen_fd);
+        let alice_fd = alice.tcp_socket();
+        let mut connect_future = alice.tcp_connect(alice_fd, remote);
+
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+        let syn_ack = bob.rt().pop_frame();
+        // Connections accepted by the wildcard listener still answer from Bob's address.
+        must_let!(let DecodedFrame::Ipv4 { header, .. } = test_helpers::decode_frame(&syn_ack));
+        assert_eq!(header.src_addr, test_helpers::BOB_IPV4);
+        alice.receive(syn_ack).unwrap();
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+
+        must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+        connections.push((alice_fd, bob_fd, i));
+    }
+
+    // Nothing was routed to the shadowed wildcard listener on port 80.
+    let mut accept_future = bob.tcp_accept(listen_fds[1]);
+    assert!(Future::poll(Pin::new(&mut accept_future), &mut ctx).is_pending());
+
+    // Every segment reaches the connection it belongs to.
+    for &(alice_fd, _, i) in connections.iter().rev() {
+        let buf = BytesMut::from(&vec![i; 16][..]).freeze();
+        let mut push_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+    for &(_, bob_fd, i) in &connections {
+        let mut pop_future = bob.tcp_pop(bob_fd);
+        must_let!(let Poll::Ready(Ok(buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        assert_eq!(&buf[..], &vec![i; 16][..]);
+    }
+}
+
+#[test]
+fn test_delack_segs() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    bob.rt()
+        .set_tcp_options(bob.rt().tcp_options().delack_segs(4));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut first_seq_no = None;
+    for i in 0..4 {
+        let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        let frame = alice.rt().pop_frame();
+        must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(data_hdr), .. } = test_helpers::decode_frame(&frame));
+        first_seq_no.get_or_insert(data_hdr.seq_num);
+
+        bob.receive(frame).unwrap();
+        bob.rt().poll_scheduler();
+        if i < 3 {
+            // Bob holds off on ACKing until the fourth segment arrives.
+            assert!(bob.rt().try_pop_frame().is_none());
+        }
+    }
+
+    // A single ACK covers all four segments.
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(ack_hdr), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert!(ack_hdr.ack);
+    assert_eq!(ack_hdr.ack_num, first_seq_no.unwrap() + Wrapping(4 * 32));
+    assert!(bob.rt().try_pop_frame().is_none());
+}
+
+/// Connects Alice to Bob and has Alice send `count` 32 byte segments, returning Bob (without
+/// having polled his scheduler since they arrived) and the sequence number of the first.
+fn send_segments_to_bob(now: Instant, count: usize) -> (test_helpers::TestEngine, tcp::SeqNumber) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut first_seq_no = None;
+    for _ in 0..count {
+        let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        let frame = alice.rt().pop_frame();
+        let (data_hdr, _) = parse_tcp_frame(frame.clone());
+        first_seq_no.get_or_insert(data_hdr.seq_num);
+        bob.receive(frame).unwrap();
+    }
+    (bob, first_seq_no.unwrap())
+}
+
+#[test]
+fn test_delayed_ack_covers_two_segments() {
+    let now = Instant::now();
+    let (bob, seq_no) = send_segments_to_bob(now, 2);
+
+    // The second segment sends the ACK without waiting for the timer.
+    bob.rt().poll_scheduler();
+    let (ack_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert!(ack_hdr.ack);
+    assert_eq!(ack_hdr.ack_num, seq_no + Wrapping(2 * 32));
+    assert!(bob.rt().try_pop_frame().is_none());
+
+    bob.rt()
+        .advance_clock(now + bob.rt().tcp_options().ack_delay_timeout);
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().try_pop_frame().is_none());
+}
+
+#[test]
+fn test_delayed_ack_timer() {
+    let now = Instant::now();
+    let (bob, seq_no) = send_segments_to_bob(now, 1);
+    let ack_delay = bob.rt().tcp_options().ack_delay_timeout;
+
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().try_pop_frame().is_none());
+    bob.rt()
+        .advance_clock(now + ack_delay - Duration::from_millis(1));
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().try_pop_frame().is_none());
+
+    // A lone segment gets its ACK once the timer fires.
+    bob.rt().advance_clock(now + ack_delay);
+    bob.rt().poll_scheduler();
+    let (ack_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert!(ack_hdr.ack);
+    assert_eq!(ack_hdr.ack_num, seq_no + Wrapping(32));
+}
+
+/// Has Alice push three 10 byte buffers in quick succession, delivering everything she sends to
+/// Bob and his ACKs back to her, and returns the payload length of each segment she sent.
+fn small_write_segments(nodelay: bool) -> Vec<usize> {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    alice
+        .rt()
+        .set_tcp_options(alice.rt().tcp_options().nodelay(nodelay));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    for i in 0..3 {
+        let buf = BytesMut::from(&vec![i; 10][..]).freeze();
+        let mut write_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    }
+
+    let mut lengths = vec![];
+    let mut later = now;
+    while lengths.iter().sum::<usize>() < 30 {
+        alice.rt().poll_scheduler();
+        while let Some(frame) = alice.rt().try_pop_frame() {
+            lengths.push(parse_tcp_frame(frame.clone()).1.len());
+            bob.receive(frame).unwrap();
+        }
+        later += bob.rt().tcp_options().ack_delay_timeout;
+        bob.rt().advance_clock(later);
+        bob.rt().poll_scheduler();
+        while let Some(frame) = bob.rt().try_pop_frame() {
+            alice.receive(frame).unwrap();
+        }
+    }
+    lengths
+}
+
+#[test]
+fn test_nagle_coalesces_small_writes() {
+    // The first write goes straight out, and the other two wait for its ACK.
+    assert_eq!(small_write_segments(false), vec![10, 20]);
+}
+
+#[test]
+fn test_close_flushes_nagle() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    alice
+        .rt()
+        .set_tcp_options(alice.rt().tcp_options().nodelay(false));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // The first write goes straight out, and Nagle holds back the second one...
+    for i in 0..2 {
+        let buf = BytesMut::from(&vec![i; 10][..]).freeze();
+        let mut write_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    }
+    alice.rt().poll_scheduler();
+    let (first_hdr, first_data) = parse_tcp_frame(alice.rt().pop_frame());
+    assert_eq!(first_data.len(), 10);
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    // ...until we close, which sends it right before the FIN without waiting for an ACK.
+    alice.close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let (last_hdr, last_data) = parse_tcp_frame(alice.rt().pop_frame());
+    assert_eq!(&last_data[..], &[1; 10][..]);
+    assert_eq!(last_hdr.seq_num, first_hdr.seq_num + Wrapping(10));
+    alice.rt().poll_scheduler();
+    let (fin_hdr, _) = parse_tcp_frame(alice.rt().pop_frame());
+    assert!(fin_hdr.fin);
+    assert_eq!(fin_hdr.seq_num, last_hdr.seq_num + Wrapping(10));
+}
+
+#[test]
+fn test_nodelay() {
+    assert_eq!(small_write_segments(true), vec![10, 10, 10]);
+}
+
+/// Tests that we never have more in flight than the window the peer last advertised.
+#[test]
+fn test_send_within_peer_window() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    bob.rt().set_tcp_options(
+        bob.rt()
+            .tcp_options()
+            .receive_window_size(1000)
+            .window_scale(0),
+    );
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    bob.tcp_set_quickack(bob_fd, true).unwrap();
+
+    // Alice has far more to send than Bob's window, and congestion control would let her send
+    // it all.
+    for _ in 0..10 {
+        let buf = BytesMut::from(&vec![0x5a; 500][..]).freeze();
+        let mut push_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+
+    // Bob only reads part of what arrives each time, so his window never fully reopens.
+    let mut window = 1000;
+    let mut delivered = 0;
+    while delivered < 5000 {
+        alice.rt().poll_scheduler();
+        let Wrapping(in_flight) =
+            alice.tcp_snd_nxt(alice_fd).unwrap() - alice.tcp_snd_una(alice_fd).unwrap();
+        assert!(in_flight > 0);
+        assert!(in_flight <= window);
+        while let Some(frame) = alice.rt().try_pop_frame() {
+            let (_, data) = parse_tcp_frame(frame.clone());
+            delivered += data.len();
+            bob.receive(frame).unwrap();
+        }
+
+        let mut pop_future = bob.tcp_pop(bob_fd);
+        must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        bob.rt().poll_scheduler();
+        while let Some(ack) = bob.rt().try_pop_frame() {
+            let (ack_hdr, _) = parse_tcp_frame(ack.clone());
+            window = ack_hdr.window_size as u32;
+            alice.receive(ack).unwrap();
+        }
+        assert!(window < 1000);
+    }
+    assert_eq!(delivered, 5000);
+}
+
+#[test]
+fn test_zero_window_arms_persist_timer() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // Pretend Bob has closed his window.
+    alice.tcp_set_peer_window(alice_fd, 0).unwrap();
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+
+    // Alice goes into PERSIST and sends a one-byte window probe...
+    alice.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(probe_hdr), .. } = test_helpers::decode_frame(&alice.rt().pop_frame()));
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    // ...which she repeats when the persist timer fires, backing off each time.
+    for &backoff in &[1, 2, 4] {
+        now += Duration::from_millis(backoff * 1000 - 1);
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        assert!(alice.rt().try_pop_frame().is_none());
+
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(hdr), .. } = test_helpers::decode_frame(&alice.rt().pop_frame()));
+        assert_eq!(hdr.seq_num, probe_hdr.seq_num);
+    }
+}
+
+#[test]
+fn test_send_stall_timeout() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let stall_timeout = Duration::from_secs(10);
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    alice
+        .rt()
+        .set_tcp_options(alice.rt().tcp_options().send_stall_timeout(stall_timeout));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // Bob's window never opens, so none of Alice's window probes get her data ACKed.
+    alice.tcp_set_peer_window(alice_fd, 0).unwrap();
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+
+    alice
+        .rt()
+        .advance_clock(now + stall_timeout - Duration::from_millis(1));
+    alice.rt().poll_scheduler();
+    let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+
+    alice.rt().advance_clock(now + stall_timeout);
+    alice.rt().poll_scheduler();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    must_let!(let Ok(Some(Fail::Timeout {})) = alice.tcp_take_error(alice_fd));
+}
+
+#[test]
+fn test_keepalive() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let keepalive_time = Duration::from_secs(10);
+    let keepalive_intvl = Duration::from_secs(1);
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    alice.rt().set_tcp_options(
+        alice
+            .rt()
+            .tcp_options()
+            .keepalive_time(keepalive_time)
+            .keepalive_intvl(keepalive_intvl)
+            .keepalive_probes(3),
+    );
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+
+    // Nothing is sent until the connection has been idle for the keep-alive time.
+    alice
+        .rt()
+        .advance_clock(now + keepalive_time - Duration::from_millis(1));
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    // Bob answers the first probe, which starts the idle period over.
+    now += keepalive_time;
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    let probe = alice.rt().pop_frame();
+    let (probe_hdr, probe_data) = parse_tcp_frame(probe.clone());
+    assert!(probe_hdr.ack);
+    assert!(probe_data.is_empty());
+    bob.receive(probe).unwrap();
+    let ack = bob.rt().pop_frame();
+    let (ack_hdr, _) = parse_tcp_frame(ack.clone());
+    assert_eq!(ack_hdr.ack_num, probe_hdr.seq_num + Wrapping(1));
+    alice.receive(ack).unwrap();
+
+    // Then Bob stops responding, and Alice gives up once all her probes go unanswered.
+    now += keepalive_time;
+    for _ in 0..3 {
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        let (probe_hdr, _) = parse_tcp_frame(alice.rt().pop_frame());
+        assert!(probe_hdr.ack);
+        assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+        now += keepalive_intvl;
+    }
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    must_let!(let Ok(Some(Fail::Timeout {})) = alice.tcp_take_error(alice_fd));
+}
+
+#[test]
+fn test_tcp_ao_sha256() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let master_key = b"correct horse battery staple";
+    let alice_mkt = MasterKeyTuple::new(1, 2, MacAlgorithm::HmacSha256, master_key)
+        .remote(test_helpers::BOB_IPV4);
+    let bob_mkt = MasterKeyTuple::new(2, 1, MacAlgorithm::HmacSha256, master_key)
+        .remote(test_helpers::ALICE_IPV4);
+    alice
+        .rt()
+        .set_tcp_options(tcp::Options::default().authentication_key(alice_mkt));
+    let bob_options = tcp::Options::default().authentication_key(bob_mkt);
+    bob.rt().set_tcp_options(bob_options.clone());
+
+    // Every segment should carry a TCP-AO option signed with the sender's key.
+    let has_ao = |frame: &[u8], expected_key_id: u8| {
+        must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(hdr), .. } = test_helpers::decode_frame(frame));
+        hdr.iter_options().any(|option| {
+            matches!(option, TcpOptions2::Authentication { key_id, .. } if *key_id == expected_key_id)
+        })
+    };
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    assert!(has_ao(&syn, 1));
+    bob.receive(syn).unwrap();
+
+    bob.rt().poll_scheduler();
+    let syn_ack = bob.rt().pop_frame();
+    assert!(has_ao(&syn_ack, 2));
+    alice.receive(syn_ack).unwrap();
+
+    alice.rt().poll_scheduler();
+    let ack = alice.rt().pop_frame();
+    assert!(has_ao(&ack, 1));
+    bob.receive(ack).unwrap();
+
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    assert_eq!(alice.tcp_current_key_id(alice_fd).unwrap(), 1);
+    assert_eq!(bob.tcp_current_key_id(bob_fd).unwrap(), 2);
+
+    // Data gets through once Bob has verified its MAC.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    assert!(has_ao(&frame, 1));
+    bob.receive(frame).unwrap();
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received_buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received_buf, buf);
+
+    // Skip Bob's checksum validation, so it's the MAC that catches a corrupted payload.
+    let mut bob_options = bob_options;
+    bob_options.rx_checksum_offload = true;
+    bob.rt().set_tcp_options(bob_options);
+
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let mut corrupted = alice.rt().pop_frame().to_vec();
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    assert!(bob
+        .receive(BytesMut::from(&corrupted[..]).freeze())
+        .is_err());
+    assert!(!bob.is_readable(bob_fd));
+}
+
+#[test]
+fn test_default_mss() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    // Neither side configures an MSS, so both advertise the default.
+    alice.rt().set_tcp_options(tcp::Options::default());
+    bob.rt().set_tcp_options(tcp::Options::default());
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    assert_eq!(alice.tcp_mss(alice_fd).unwrap(), tcp::DEFAULT_MSS);
+    assert_eq!(bob.tcp_mss(bob_fd).unwrap(), tcp::DEFAULT_MSS);
+    assert_eq!(alice.tcp_rto(alice_fd).unwrap(), tcp::INITIAL_RTO);
+}
+
+#[test]
+fn test_mss_from_link_mtu() {
+    let advertised_mss = |frame: Bytes| {
+        let (hdr, _) = parse_tcp_frame(frame);
+        hdr.iter_options()
+            .find_map(|option| match option {
+                TcpOptions2::MaximumSegmentSize(mss) => Some(*mss),
+                _ => None,
+            })
+            .unwrap()
+    };
+    for &(mtu, mss) in &[(1500, 1460), (9000, 8960)] {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice2(now);
+        let mut bob = test_helpers::new_bob2(now);
+        alice.rt().set_mtu(mtu);
+        bob.rt().set_mtu(mtu);
+
+        let listen_port = ip::Port::try_from(80).unwrap();
+        let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+        let listen_fd = bob.tcp_socket();
+        bob.tcp_bind(listen_fd, listen_addr).unwrap();
+        bob.tcp_listen(listen_fd, 1).unwrap();
+
+        // Both the SYN and the SYN+ACK advertise what fits in the link MTU, whatever the
+        // configured MSS.
+        let alice_fd = alice.tcp_socket();
+        let _connect_future = alice.tcp_connect(alice_fd, listen_addr);
+        alice.rt().poll_scheduler();
+        let syn = alice.rt().pop_frame();
+        assert_eq!(advertised_mss(syn.clone()), mss);
+        bob.receive(syn).unwrap();
+        bob.rt().poll_scheduler();
+        assert_eq!(advertised_mss(bob.rt().pop_frame()), mss);
+    }
+}
+
+#[test]
+fn test_rto_options() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    alice.rt().set_tcp_options(
+        alice
+            .rt()
+            .tcp_options()
+            .rto_initial(Duration::from_millis(250))
+            .rto_min(Duration::from_millis(10))
+            .rto_max(Duration::from_secs(2)),
+    );
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    assert_eq!(alice.tcp_rto(alice_fd).unwrap(), Duration::from_millis(250));
+}
+
+#[test]
+fn test_rto_min_above_max_rejected() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    let bad_options = |options: tcp::Options<_>| {
+        options
+            .rto_min(Duration::from_secs(2))
+            .rto_max(Duration::from_secs(1))
+    };
+    alice
+        .rt()
+        .set_tcp_options(bad_options(alice.rt().tcp_options()));
+    bob.rt()
+        .set_tcp_options(bad_options(bob.rt().tcp_options()));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Neither end gets as far as setting up a connection with the bad bounds.
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    must_let!(let Err(Fail::Invalid { .. }) = bob.tcp_listen(listen_fd, 1));
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    must_let!(let Poll::Ready(Err(Fail::Invalid { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+}
+
+#[test]
+fn test_reset_counters() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 100][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // The reset hands back everything counted so far...
+    let before = alice.tcp_reset_counters(alice_fd).unwrap();
+    assert_eq!(before.bytes_sent, 100);
+    assert_eq!(before.segments_sent, 1);
+    assert_eq!(bob.tcp_counters(bob_fd).unwrap().bytes_received, 100);
+
+    // ...and only what comes after it is counted from then on.
+    let buf = BytesMut::from(&vec![0x5a; 50][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    let after = alice.tcp_counters(alice_fd).unwrap();
+    assert_eq!(after.bytes_sent, 50);
+    assert_eq!(after.segments_sent, 1);
+    assert_eq!(after.retransmits, 0);
+    assert_eq!(bob.tcp_counters(bob_fd).unwrap().bytes_received, 150);
+}
+
+#[test]
+fn test_connect_to_self_is_refused() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+
+    let port = ip::Port::try_from(80).unwrap();
+    let addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port);
+
+    let fd = alice.tcp_socket();
+    alice.tcp_bind(fd, addr).unwrap();
+    let mut connect_future = alice.tcp_connect(fd, addr);
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Nothing went out on the wire.
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+}
+
+#[test]
+fn test_connect_to_own_ephemeral_port_is_refused() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+
+    // A twin of Alice with the same RNG seed shows which ephemeral port Alice will pick.
+    let mut twin = test_helpers::new_alice2(now);
+    let twin_fd = twin.tcp_socket();
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let _twin_connect_future = twin.tcp_connect(twin_fd, bob_addr);
+    twin.rt().poll_scheduler();
+    let (syn_hdr, _) = parse_tcp_frame(twin.rt().pop_frame());
+    let addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, syn_hdr.src_port);
+
+    let fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(fd, addr);
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    // The port went back to the pool, so the next connection gets it.
+    let fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(fd, bob_addr);
+    alice.rt().poll_scheduler();
+    let (syn_hdr, _) = parse_tcp_frame(alice.rt().pop_frame());
+    assert_eq!(syn_hdr.src_port, addr.port);
+}
+
+#[test]
+fn test_connect_refused_by_rst() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    // Nothing is listening on Bob's port.
+    let port = ip::Port::try_from(80).unwrap();
+    let addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+
+    let fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(fd, addr);
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    let (syn_hdr, _) = parse_tcp_frame(syn.clone());
+    assert!(syn_hdr.syn);
+
+    // A RST that doesn't acknowledge the SYN is ignored...
+    let mut tcp_hdr = TcpHeader::new(syn_hdr.dst_port, syn_hdr.src_port);
+    tcp_hdr.rst = true;
+    tcp_hdr.ack = true;
+    tcp_hdr.ack_num = syn_hdr.seq_num;
+    let segment = TcpSegment {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: test_helpers::ALICE_MAC,
+            src_addr: test_helpers::BOB_MAC,
+            ether_type: EtherType2::Ipv4,
+        },
+        ipv4_hdr: Ipv4Header::new(
+            test_helpers::BOB_IPV4,
+            test_helpers::ALICE_IPV4,
+            Ipv4Protocol2::Tcp,
+        ),
+        tcp_hdr,
+        data: Bytes::empty(),
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    alice
+        .receive(serialize_frame(segment, false).freeze())
+        .unwrap();
+    assert!(Future::poll(Pin::new(&mut connect_future), &mut ctx).is_pending());
+
+    // ...but Bob's answer to the SYN itself refuses the connection.
+    bob.receive(syn).unwrap();
+    let rst = bob.rt().pop_frame();
+    let (rst_hdr, _) = parse_tcp_frame(rst.clone());
+    assert!(rst_hdr.rst);
+    assert_eq!(rst_hdr.ack_num, syn_hdr.seq_num + Wrapping(1));
+    alice.receive(rst).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+}
+
+/// Tests that a SYN+ACK that doesn't acknowledge our SYN is reset, without completing or failing
+/// the connect.
+#[test]
+fn test_syn_ack_with_wrong_ack() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    let (syn_hdr, _) = parse_tcp_frame(syn.clone());
+
+    let mut tcp_hdr = TcpHeader::new(syn_hdr.dst_port, syn_hdr.src_port);
+    tcp_hdr.syn = true;
+    tcp_hdr.seq_num = Wrapping(5000);
+    tcp_hdr.ack = true;
+    tcp_hdr.ack_num = syn_hdr.seq_num + Wrapping(100);
+    tcp_hdr.window_size = 1024;
+    let segment = TcpSegment {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: test_helpers::ALICE_MAC,
+            src_addr: test_helpers::BOB_MAC,
+            ether_type: EtherType2::Ipv4,
+        },
+        ipv4_hdr: Ipv4Header::new(
+            test_helpers::BOB_IPV4,
+            test_helpers::ALICE_IPV4,
+            Ipv4Protocol2::Tcp,
+        ),
+        tcp_hdr,
+        data: Bytes::empty(),
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    alice
+        .receive(serialize_frame(segment, false).freeze())
+        .unwrap();
+
+    // The RST goes out at the sequence number the bogus SYN+ACK acknowledged...
+    let (rst_hdr, _) = parse_tcp_frame(alice.rt().pop_frame());
+    assert!(rst_hdr.rst);
+    assert!(!rst_hdr.ack);
+    assert_eq!(rst_hdr.seq_num, syn_hdr.seq_num + Wrapping(100));
+    assert!(Future::poll(Pin::new(&mut connect_future), &mut ctx).is_pending());
+
+    // ...and the real SYN+ACK still completes the handshake.
+    bob.receive(syn).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+}
+
+/// Tests that segments for a port nobody is listening on are answered with a RST that the sender
+/// will accept, and that RSTs themselves go unanswered.
+#[test]
+fn test_rst_for_unlistened_port() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob2(now);
+
+    let segment_to_bob = |tcp_hdr: TcpHeader, data: Bytes| {
+        let segment = TcpSegment {
+            ethernet2_hdr: Ethernet2Header {
+                dst_addr: test_helpers::BOB_MAC,
+                src_addr: test_helpers::ALICE_MAC,
+                ether_type: EtherType2::Ipv4,
+            },
+            ipv4_hdr: Ipv4Header::new(
+                test_helpers::ALICE_IPV4,
+                test_helpers::BOB_IPV4,
+                Ipv4Protocol2::Tcp,
+            ),
+            tcp_hdr,
+            data,
+            tx_checksum_offload: false,
+            signer: None,
+        };
+        serialize_frame(segment, false).freeze()
+    };
+    let src_port = ip::Port::try_from(50000).unwrap();
+    let dst_port = ip::Port::try_from(80).unwrap();
+
+    // A SYN is refused with a RST that acknowledges it.
+    let mut syn_hdr = TcpHeader::new(src_port, dst_port);
+    syn_hdr.syn = true;
+    syn_hdr.seq_num = Wrapping(1000);
+    bob.receive(segment_to_bob(syn_hdr, Bytes::empty()))
+        .unwrap();
+    let (rst_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert!(rst_hdr.rst);
+    assert!(rst_hdr.ack);
+    assert_eq!(rst_hdr.seq_num, Wrapping(0));
+    assert_eq!(rst_hdr.ack_num, Wrapping(1001));
+    assert_eq!(rst_hdr.src_port, dst_port);
+    assert_eq!(rst_hdr.dst_port, src_port);
+
+    // Data without an ACK is acknowledged in full.
+    let mut data_hdr = TcpHeader::new(src_port, dst_port);
+    data_hdr.seq_num = Wrapping(2000);
+    bob.receive(segment_to_bob(data_hdr, Bytes::from_slice(&[7; 10])))
+        .unwrap();
+    let (rst_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert!(rst_hdr.rst);
+    assert!(rst_hdr.ack);
+    assert_eq!(rst_hdr.ack_num, Wrapping(2010));
+
+    // A segment with an ACK gets a RST at the sequence number it acknowledged, and no ACK.
+    let mut ack_hdr = TcpHeader::new(src_port, dst_port);
+    ack_hdr.seq_num = Wrapping(3000);
+    ack_hdr.ack = true;
+    ack_hdr.ack_num = Wrapping(4000);
+    bob.receive(segment_to_bob(ack_hdr, Bytes::from_slice(&[7; 10])))
+        .unwrap();
+    let (rst_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert!(rst_hdr.rst);
+    assert!(!rst_hdr.ack);
+    assert_eq!(rst_hdr.seq_num, Wrapping(4000));
+
+    // A RST is never answered with another.
+    let mut rst_hdr = TcpHeader::new(src_port, dst_port);
+    rst_hdr.rst = true;
+    rst_hdr.seq_num = Wrapping(5000);
+    bob.receive(segment_to_bob(rst_hdr, Bytes::empty()))
+        .unwrap();
+    assert!(bob.rt().try_pop_frame().is_none());
+}
+
+#[test]
+fn test_connect_timeout() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+    let handshake_timeout = Duration::from_secs(1);
+    alice.rt().set_tcp_options(
+        alice
+            .rt()
+            .tcp_options()
+            .handshake_retries(2)
+            .handshake_timeout(handshake_timeout),
+    );
+
+    let port = ip::Port::try_from(80).unwrap();
+    let addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+
+    let fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(fd, addr);
+
+    // Bob never answers, so Alice sends a SYN for each retry...
+    for _ in 0..2 {
+        alice.rt().poll_scheduler();
+        let (syn_hdr, _) = parse_tcp_frame(alice.rt().pop_frame());
+        assert!(syn_hdr.syn);
+        assert!(Future::poll(Pin::new(&mut connect_future), &mut ctx).is_pending());
+        now += handshake_timeout;
+        alice.rt().advance_clock(now);
+    }
+
+    // ...and gives up once the last one times out.
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+}
+
+#[test]
+fn test_reset_recorded_without_pending_operation() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    assert_eq!(alice.tcp_take_error(alice_fd).unwrap(), None);
+
+    // Turn a segment from Bob into an in-window RST.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = bob.tcp_push(bob_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    let frame = bob.rt().pop_frame();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(frame.clone()).unwrap();
+    must_let!(let DecodedFrame::Ipv4 { header: ipv4_hdr, payload: L4::Tcp(mut tcp_hdr) } = test_helpers::decode_frame(&frame));
+    tcp_hdr.rst = true;
+    let segment = TcpSegment {
+        ethernet2_hdr,
+        ipv4_hdr,
+        tcp_hdr,
+        data: Bytes::empty(),
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    let mut rst_frame = vec![0u8; segment.header_size()];
+    segment.write_header(&mut rst_frame[..]);
+
+    // Alice has nothing outstanding on the connection when the RST arrives...
+    alice.receive(Bytes::from_slice(&rst_frame)).unwrap();
+    alice.rt().poll_scheduler();
+
+    // ...but she can still find out what happened, once.
+    assert_eq!(
+        alice.tcp_take_error(alice_fd).unwrap(),
+        Some(Fail::ConnectionReset {})
+    );
+    assert_eq!(alice.tcp_take_error(alice_fd).unwrap(), None);
+}
+
+/// Rebuilds a data segment sent by the stack with PSH set.
+fn set_psh(frame: Bytes) -> Bytes {
+    let (ethernet2_hdr, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+    let (mut tcp_hdr, data) = parse_tcp_frame(frame);
+    tcp_hdr.psh = true;
+    let segment = TcpSegment {
+        ethernet2_hdr,
+        ipv4_hdr,
+        tcp_hdr,
+        data: data.clone(),
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    let mut psh_frame = vec![0u8; segment.header_size()];
+    segment.write_header(&mut psh_frame[..]);
+    psh_frame.extend_from_slice(&data[..]);
+    Bytes::from_slice(&psh_frame)
+}
+
+/// Tests that a pending pop completes as soon as a small PSH segment arrives, but that PSH doesn't
+/// override a receive low-watermark.
+#[test]
+fn test_pop_returns_on_push() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+
+    // Bob sends a single byte, which we mark PSH on its way over.
+    let data = Bytes::from_slice(b"y");
+    let mut write_future = bob.tcp_push(bob_fd, data.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    alice.receive(set_psh(bob.rt().pop_frame())).unwrap();
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, data);
+
+    // With a low-watermark of two bytes, another PSH byte isn't enough.
+    alice.tcp_set_rcvlowat(alice_fd, 2).unwrap();
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    let mut write_future = bob.tcp_push(bob_fd, Bytes::from_slice(b"z"));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    alice.receive(set_psh(bob.rt().pop_frame())).unwrap();
+    assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+}
+
+/// Tests that a bare ACK is zero-padded to the minimum Ethernet frame size, unless padding is
+/// left to the NIC.
+#[test]
+fn test_short_frames_padded() {
+    let now = Instant::now();
+
+    for &pad in &[true, false] {
+        let mut alice = test_helpers::new_alice2(now);
+        let mut bob = test_helpers::new_bob2(now);
+        alice.rt().set_pad_frames(pad);
+
+        let listen_port = ip::Port::try_from(80).unwrap();
+        let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+        let listen_fd = bob.tcp_socket();
+        bob.tcp_bind(listen_fd, listen_addr).unwrap();
+        bob.tcp_listen(listen_fd, 1).unwrap();
+        let _accept_future = bob.tcp_accept(listen_fd);
+
+        let alice_fd = alice.tcp_socket();
+        let _connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+        alice.rt().poll_scheduler();
+
+        // Alice's half of the handshake ends with a bare ACK.
+        let frame = alice.rt().pop_frame();
+        must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(tcp_hdr), .. } = test_helpers::decode_frame(&frame));
+        assert!(tcp_hdr.ack && !tcp_hdr.syn);
+        let unpadded_len = 14 + u16::from_be_bytes([frame[16], frame[17]]) as usize;
+        assert!(unpadded_len < 60);
+        if pad {
+            assert_eq!(frame.len(), 60);
+            assert!(frame[unpadded_len..].iter().all(|&b| b == 0));
+        } else {
+            assert_eq!(frame.len(), unpadded_len);
+        }
+        bob.receive(frame).unwrap();
+    }
+}
+
+/// Builds the "fragmentation needed" error Carrie, a router, sends back when `frame` runs into a
+/// link with an MTU of `next_hop_mtu`.
+fn fragmentation_needed(frame: &Bytes, next_hop_mtu: u16) -> Bytes {
+    let (ethernet2_hdr, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(payload).unwrap();
+    let quoted = Bytes::from_slice(&frame[14..(14 + 20 + 8)]);
+    let error = Icmpv4Message::with_data(
+        Ethernet2Header::new(
+            ethernet2_hdr.src_addr,
+            test_helpers::CARRIE_MAC,
+            EtherType2::Ipv4,
+        ),
+        Ipv4Header::new(
+            test_helpers::CARRIE_IPV4,
+            ipv4_hdr.src_addr,
+            Ipv4Protocol2::Icmpv4,
+        ),
+        Icmpv4Header::new(Icmpv4Type2::DestinationUnreachable { next_hop_mtu }, 4),
+        quoted,
+    );
+    serialize_frame(error, false).freeze()
+}
+
+/// Tests that a "fragmentation needed" error for a segment in flight brings the connection's path
+/// MTU down to the one it reports.
+#[test]
+fn test_path_mtu_discovery() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (_, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    assert!(bob.tcp_path_mtu(bob_fd).unwrap() > 1280);
+
+    // A segment from Bob runs into a link with a 1280-byte MTU on its way to Alice, and the router
+    // in front of it sends back the start of the segment.
+    let buf = BytesMut::from(&vec![0x5a; 100][..]).freeze();
+    let mut write_future = bob.tcp_push(bob_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    let frame = bob.rt().pop_frame();
+    bob.receive(fragmentation_needed(&frame, 1280)).unwrap();
+    assert_eq!(bob.tcp_path_mtu(bob_fd).unwrap(), 1280);
+
+    // A path MTU too small for the smallest MSS we'll use is still reported as it is, and a larger
+    // one later doesn't raise it again.
+    bob.receive(fragmentation_needed(&frame, 300)).unwrap();
+    assert_eq!(bob.tcp_path_mtu(bob_fd).unwrap(), 300);
+    assert_eq!(bob.tcp_mss(bob_fd).unwrap(), tcp::MIN_MSS);
+    bob.receive(fragmentation_needed(&frame, 1280)).unwrap();
+    assert_eq!(bob.tcp_path_mtu(bob_fd).unwrap(), 300);
+}
+
+/// Tests that when a router doesn't report the next-hop MTU, we take the path MTU down to the
+/// plateau below the size of the datagram that was too big.
+#[test]
+fn test_path_mtu_plateau() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (_, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 1400][..]).freeze();
+    let mut write_future = bob.tcp_push(bob_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    let frame = bob.rt().pop_frame();
+    bob.receive(fragmentation_needed(&frame, 0)).unwrap();
+    assert_eq!(bob.tcp_path_mtu(bob_fd).unwrap(), 1006);
+    assert_eq!(bob.tcp_mss(bob_fd).unwrap(), 1006 - 40);
+}
+
+/// Tests that a retransmission after PMTUD has lowered the MSS is repacketized to the new MSS.
+#[test]
+fn test_retransmit_after_mss_reduction() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let mut frames = vec![];
+    for i in 0..3 {
+        let buf = BytesMut::from(&vec![i; 1000][..]).freeze();
+        let mut write_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        frames.push(alice.rt().pop_frame());
+    }
+    let (first_hdr, _) = parse_tcp_frame(frames[0].clone());
+
+    // The first segment runs into a link with a 1280-byte MTU.
+    alice
+        .receive(fragmentation_needed(&frames[0], 1280))
+        .unwrap();
+    let mss = alice.tcp_mss(alice_fd).unwrap();
+    assert_eq!(mss, 1280 - 40);
+
+    // The retransmission fills the new MSS with the first segment and the start of the second.
+    alice.tcp_force_rto(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let (hdr, data) = parse_tcp_frame(alice.rt().pop_frame());
+    assert_eq!(hdr.seq_num, first_hdr.seq_num);
+    assert_eq!(data.len(), mss);
+    assert_eq!(&data[..1000], &[0; 1000][..]);
+    assert_eq!(&data[1000..], &vec![1; mss - 1000][..]);
+}
+
+#[test]
+fn test_drain() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 2).unwrap();
+
+    let mut fds = vec![];
+    for _ in 0..2 {
+        let alice_fd = alice.tcp_socket();
+        let bob_fd = test_helpers::connect(&mut alice, alice_fd, &mut bob, listen_fd, listen_addr);
+        fds.push((alice_fd, bob_fd));
+    }
+
+    let mut drain_future = bob.tcp_drain(listen_fd).unwrap();
+    assert!(Future::poll(Pin::new(&mut drain_future), &mut ctx).is_pending());
+
+    // New connections are refused.
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let rst = bob.rt().pop_frame();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(rst_hdr), .. } = test_helpers::decode_frame(&rst));
+    assert!(rst_hdr.rst);
+    alice.receive(rst).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // The drain only completes once both of the accepted connections have closed.
+    for (i, (alice_fd, bob_fd)) in fds.into_iter().enumerate() {
+        alice.close(alice_fd).unwrap();
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+
+        bob.close(bob_fd).unwrap();
+        bob.rt().poll_scheduler();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+
+        let drained = Future::poll(Pin::new(&mut drain_future), &mut ctx).is_ready();
+        assert_eq!(drained, i == 1);
+    }
+}
+
+#[test]
+fn test_sequence_numbers() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    bob.tcp_set_quickack(bob_fd, true).unwrap();
+
+    let snd_una = alice.tcp_snd_una(alice_fd).unwrap();
+    assert_eq!(alice.tcp_snd_nxt(alice_fd).unwrap(), snd_una);
+    assert_eq!(bob.tcp_rcv_nxt(bob_fd).unwrap(), snd_una);
+
+    let buf = BytesMut::from(&vec![0x5a; 100][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+
+    // The segment is in flight until Bob's ACK comes back.
+    assert_eq!(alice.tcp_snd_una(alice_fd).unwrap(), snd_una);
+    assert_eq!(
+        alice.tcp_snd_nxt(alice_fd).unwrap() - snd_una,
+        Wrapping(100)
+    );
+
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.tcp_rcv_nxt(bob_fd).unwrap(), snd_una + Wrapping(100));
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(
+        alice.tcp_snd_una(alice_fd).unwrap(),
+        snd_una + Wrapping(100)
+    );
+    assert_eq!(
+        alice.tcp_snd_nxt(alice_fd).unwrap(),
+        alice.tcp_snd_una(alice_fd).unwrap()
+    );
+}
+
+#[test]
+fn test_unacked_segments() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    bob.tcp_set_quickack(bob_fd, true).unwrap();
+    assert_eq!(alice.tcp_unacked_segments(alice_fd).unwrap(), 0);
+
+    // Each push goes out as a segment of its own.
+    let mut segments = vec![];
+    for _ in 0..3 {
+        let buf = BytesMut::from(&vec![0x5a; 100][..]).freeze();
+        let mut write_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        segments.push(alice.rt().pop_frame());
+    }
+    assert_eq!(alice.tcp_unacked_segments(alice_fd).unwrap(), 3);
+
+    // An ACK for the first segment only releases that one...
+    let mut segments = segments.into_iter();
+    bob.receive(segments.next().unwrap()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.tcp_unacked_segments(alice_fd).unwrap(), 2);
+
+    // ...and acknowledging the rest empties the queue.
+    for segment in segments {
+        bob.receive(segment).unwrap();
+    }
+    bob.rt().poll_scheduler();
+    while let Some(ack) = bob.rt().try_pop_frame() {
+        alice.receive(ack).unwrap();
+    }
+    assert_eq!(alice.tcp_unacked_segments(alice_fd).unwrap(), 0);
+}
+
+#[test]
+fn test_no_linger_in_time_wait() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    bob.rt()
+        .set_tcp_options(bob.rt().tcp_options().linger_in_time_wait(false));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    bob.receive(syn.clone()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Bob closes first, which would normally leave him in TIME-WAIT.
+    bob.close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    alice.close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(fin_ack), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert!(fin_ack.ack);
+    assert_eq!(bob.tcp_time_wait_deadline(bob_fd).unwrap(), None);
+
+    // A SYN from the same endpoint is answered by the listener straight away.
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(syn_hdr), .. } = test_helpers::decode_frame(&syn));
+    bob.receive(syn).unwrap();
+    bob.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(syn_ack), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert!(syn_ack.syn);
+    assert!(syn_ack.ack);
+    assert_eq!(syn_ack.ack_num, syn_hdr.seq_num + Wrapping(1));
+}
+
+#[test]
+fn test_time_wait_holds_endpoints() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let msl = Duration::from_secs(5);
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    bob.rt().set_tcp_options(bob.rt().tcp_options().msl(msl));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    bob.receive(syn.clone()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Bob closes first, so he's the one that ends up in TIME-WAIT.
+    bob.close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    alice.close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(fin_ack), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert!(fin_ack.ack);
+    let deadline = bob.tcp_time_wait_deadline(bob_fd).unwrap().unwrap();
+    assert_eq!(deadline, now + 2 * msl);
+
+    // Until TIME-WAIT is over, a new SYN for the same endpoints goes to the old connection
+    // rather than the listener, so it doesn't start a new one.
+    bob.rt().advance_clock(deadline - Duration::from_millis(1));
+    bob.rt().poll_scheduler();
+    bob.receive(syn.clone()).unwrap();
+    bob.rt().poll_scheduler();
+    while let Some(frame) = bob.rt().try_pop_frame() {
+        let (hdr, _) = parse_tcp_frame(frame);
+        assert!(!hdr.syn);
+    }
+
+    // Afterwards the endpoints are free again.
+    bob.rt().advance_clock(deadline);
+    bob.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(syn_hdr), .. } = test_helpers::decode_frame(&syn));
+    bob.receive(syn).unwrap();
+    bob.rt().poll_scheduler();
+    let (syn_ack, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert!(syn_ack.syn);
+    assert!(syn_ack.ack);
+    assert_eq!(syn_ack.ack_num, syn_hdr.seq_num + Wrapping(1));
+}
+
+/// Tests that a segment for a connection that has fully closed is answered with a RST.
+#[test]
+fn test_segment_for_closed_connection() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    bob.rt()
+        .set_tcp_options(bob.rt().tcp_options().linger_in_time_wait(false));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // Hang on to a data segment from Alice so we can replay it later.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let data = alice.rt().pop_frame();
+    bob.receive(data.clone()).unwrap();
+
+    // Bob closes first and, without TIME-WAIT, goes straight to CLOSED.
+    bob.close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    alice.close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(fin_ack), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert!(fin_ack.ack);
+    assert!(bob.rt().try_pop_frame().is_none());
+
+    // The stray segment gets a RST...
+    bob.receive(data.clone()).unwrap();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(rst_hdr), .. } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert!(rst_hdr.rst);
+
+    // ...but a stray RST doesn't.
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(data.clone()).unwrap();
+    must_let!(let DecodedFrame::Ipv4 { header: ipv4_hdr, payload: L4::Tcp(mut tcp_hdr) } = test_helpers::decode_frame(&data));
+    tcp_hdr.rst = true;
+    let segment = TcpSegment {
+        ethernet2_hdr,
+        ipv4_hdr,
+        tcp_hdr,
+        data: Bytes::empty(),
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    let mut rst_frame = vec![0u8; segment.header_size()];
+    segment.write_header(&mut rst_frame[..]);
+    bob.receive(Bytes::from_slice(&rst_frame)).unwrap();
+    assert!(bob.rt().try_pop_frame().is_none());
+}
+
+/// Tests that pushing fails once our side of the connection has been closed.
+#[test]
+fn test_push_after_close() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    let alice_fd = alice.tcp_socket();
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Err(Fail::NotConnected {})) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    test_helpers::connect(&mut alice, alice_fd, &mut bob, listen_fd, listen_addr);
+
+    // Closing shuts down the send half, so there's nowhere for more data to go, both before...
+    alice.close(alice_fd).unwrap();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Err(Fail::BrokenPipe {})) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    // ...and after our FIN has gone out.
+    alice.rt().poll_scheduler();
+    must_let!(let DecodedFrame::Ipv4 { payload: L4::Tcp(fin), .. } = test_helpers::decode_frame(&alice.rt().pop_frame()));
+    assert!(fin.fin);
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Err(Fail::BrokenPipe {})) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+}
+
+/// Tests that pushing on a connection the other side has reset reports the reset.
+#[test]
+fn test_push_after_reset() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // Turn a segment from Bob into an in-window RST.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = bob.tcp_push(bob_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    let frame = bob.rt().pop_frame();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(frame.clone()).unwrap();
+    must_let!(let DecodedFrame::Ipv4 { header: ipv4_hdr, payload: L4::Tcp(mut tcp_hdr) } = test_helpers::decode_frame(&frame));
+    tcp_hdr.rst = true;
+    let segment = TcpSegment {
+        ethernet2_hdr,
+        ipv4_hdr,
+        tcp_hdr,
+        data: Bytes::empty(),
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    let mut rst_frame = vec![0u8; segment.header_size()];
+    segment.write_header(&mut rst_frame[..]);
+
+    alice.receive(Bytes::from_slice(&rst_frame)).unwrap();
+    alice.rt().poll_scheduler();
+
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Err(Fail::ConnectionReset {})) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+}
+
+/// Tests that a pop waiting for data completes with end of stream when the peer closes.
+#[test]
+fn test_pop_eof() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // Bob drains what Alice sent, and his next pop waits for more.
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+
+    // Alice's FIN ends the stream, for that pop and any after it.
+    alice.close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let fin = alice.rt().pop_frame();
+    let (fin_hdr, _) = parse_tcp_frame(fin.clone());
+    assert!(fin_hdr.fin);
+    bob.receive(fin).unwrap();
+    must_let!(let Poll::Ready(Ok(eof)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert!(eof.is_empty());
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(eof)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert!(eof.is_empty());
+}
+
+/// Tests that data carried on a FIN segment is delivered before the end of stream.
+#[test]
+fn test_fin_with_data() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+
+    // Set FIN on Alice's data segment, as a sender closing right after a write might.
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(frame.clone()).unwrap();
+    must_let!(let DecodedFrame::Ipv4 { header: ipv4_hdr, .. } = test_helpers::decode_frame(&frame));
+    let (hdr, data) = parse_tcp_frame(frame);
+    let mut tcp_hdr = TcpHeader::new(hdr.src_port, hdr.dst_port);
+    tcp_hdr.seq_num = hdr.seq_num;
+    tcp_hdr.ack = true;
+    tcp_hdr.ack_num = hdr.ack_num;
+    tcp_hdr.window_size = hdr.window_size;
+    tcp_hdr.fin = true;
+    let segment = TcpSegment {
+        ethernet2_hdr,
+        ipv4_hdr,
+        tcp_hdr,
+        data,
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    bob.receive(serialize_frame(segment, false).freeze())
+        .unwrap();
+    assert_eq!(bob.tcp_rcv_nxt(bob_fd).unwrap(), hdr.seq_num + Wrapping(10));
+
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(eof)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert!(eof.is_empty());
+}
+
+/// Tests that a FIN arriving ahead of data we're missing doesn't end the stream until the data
+/// has arrived.
+#[test]
+fn test_out_of_order_fin() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let data = alice.rt().pop_frame();
+    alice.close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let fin = alice.rt().pop_frame();
+    let (fin_hdr, _) = parse_tcp_frame(fin.clone());
+    assert!(fin_hdr.fin);
+
+    // The FIN overtakes the data, so Bob holds on to it and his pop keeps waiting.
+    let rcv_nxt = bob.tcp_rcv_nxt(bob_fd).unwrap();
+    bob.receive(fin).unwrap();
+    assert_eq!(bob.tcp_rcv_nxt(bob_fd).unwrap(), rcv_nxt);
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+
+    // Once the data arrives, Bob takes the FIN too: the data is popped and then the stream ends.
+    bob.receive(data).unwrap();
+    assert_eq!(bob.tcp_rcv_nxt(bob_fd).unwrap(), rcv_nxt + Wrapping(10));
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(eof)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert!(eof.is_empty());
+}
+
+/// Tests that shutting down writing sends a FIN but leaves the connection open for the peer's data.
+#[test]
+fn test_shutdown_write() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    alice.shutdown(alice_fd, Shutdown::Write).unwrap();
+    alice.rt().poll_scheduler();
+    let fin = alice.rt().pop_frame();
+    let (fin_hdr, _) = parse_tcp_frame(fin.clone());
+    assert!(fin_hdr.fin);
+    bob.receive(fin).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+
+    // Bob sees the end of Alice's stream...
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(eof)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert!(eof.is_empty());
+
+    // ...but can still send to her.
+    let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    let mut push_future = bob.tcp_push(bob_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+
+    // Once Alice shuts down reading too, her pops report the end of the stream.
+    alice.shutdown(alice_fd, Shutdown::Read).unwrap();
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    must_let!(let Poll::Ready(Ok(eof)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert!(eof.is_empty());
+}
+
+#[test]
+fn test_effective_send_window() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    bob.rt()
+        .set_tcp_options(bob.rt().tcp_options().window_scale(7));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    // Turn a segment from Bob into a pure ACK advertising a window field of 1000.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut write_future = bob.tcp_push(bob_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    let frame = bob.rt().pop_frame();
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(frame.clone()).unwrap();
+    must_let!(let DecodedFrame::Ipv4 { header: ipv4_hdr, payload: L4::Tcp(mut tcp_hdr) } = test_helpers::decode_frame(&frame));
+    tcp_hdr.window_size = 1000;
+    assert_eq!(tcp_hdr.scaled_window(7), 128000);
+    let segment = TcpSegment {
+        ethernet2_hdr,
+        ipv4_hdr,
+        tcp_hdr,
+        data: Bytes::empty(),
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    let mut ack_frame = vec![0u8; segment.header_size()];
+    segment.write_header(&mut ack_frame[..]);
+    alice.receive(Bytes::from_slice(&ack_frame)).unwrap();
+
+    assert_eq!(alice.tcp_effective_send_window(alice_fd).unwrap(), 128000);
+}
+
+/// Splits a TCP frame into its header and payload.
+fn parse_tcp_frame(frame: Bytes) -> (TcpHeader, Bytes) {
+    let (_, payload) = Ethernet2Header::parse(frame).unwrap();
+    let (ipv4_hdr, payload) = Ipv4Header::parse(payload).unwrap();
+    TcpHeader::parse(&ipv4_hdr, payload, false).unwrap()
+}
+
+/// Has Alice send four 10 byte segments to Bob, dropping the second, and lets her retransmission
+/// timer fire once Bob has ACKed what he got. Returns Bob's ACK and everything Alice
+/// retransmitted, along with the sequence number of the first segment.
+fn drop_second_of_four(bob_sack: bool) -> (tcp::SeqNumber, TcpHeader, Vec<(TcpHeader, Bytes)>) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    bob.rt()
+        .set_tcp_options(bob.rt().tcp_options().sack(bob_sack));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+
+    let mut frames = vec![];
+    for i in 0..4 {
+        let buf = BytesMut::from(&vec![i; 10][..]).freeze();
+        let mut write_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        frames.push(alice.rt().pop_frame());
+    }
+    let (first_hdr, _) = parse_tcp_frame(frames[0].clone());
+    for (i, frame) in frames.into_iter().enumerate() {
+        if i != 1 {
+            bob.receive(frame).unwrap();
+        }
+    }
+
+    // Bob's delayed ACK only covers the first segment.
+    bob.rt().advance_clock(now + Duration::from_millis(500));
+    bob.rt().poll_scheduler();
+    let ack_frame = bob.rt().pop_frame();
+    let (ack_hdr, _) = parse_tcp_frame(ack_frame.clone());
+    assert_eq!(ack_hdr.ack_num, first_hdr.seq_num + Wrapping(10));
+    alice.receive(ack_frame).unwrap();
+
+    alice.rt().advance_clock(now + tcp::INITIAL_RTO);
+    alice.rt().poll_scheduler();
+    let mut retransmitted = vec![];
+    while let Some(frame) = alice.rt().try_pop_frame() {
+        retransmitted.push(parse_tcp_frame(frame));
+    }
+    (first_hdr.seq_num, ack_hdr, retransmitted)
+}
+
+#[test]
+fn test_retransmit_deadline() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    assert_eq!(alice.tcp_retransmit_deadline(alice_fd).unwrap(), None);
+    must_let!(let Err(Fail::Ignored { .. }) = alice.tcp_force_rto(alice_fd));
+
+    let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let (first_hdr, _) = parse_tcp_frame(alice.rt().pop_frame());
+
+    // The timer fires at its deadline...
+    let deadline = alice.tcp_retransmit_deadline(alice_fd).unwrap().unwrap();
+    assert_eq!(deadline, now + tcp::INITIAL_RTO);
+    alice.rt().advance_clock(deadline);
+    alice.rt().poll_scheduler();
+    let (hdr, data) = parse_tcp_frame(alice.rt().pop_frame());
+    assert_eq!(hdr.seq_num, first_hdr.seq_num);
+    assert_eq!(data.len(), 10);
+    assert!(alice.tcp_retransmit_deadline(alice_fd).unwrap().unwrap() > deadline);
+
+    // ...or whenever a test forces it to.
+    alice.tcp_force_rto(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let (hdr, _) = parse_tcp_frame(alice.rt().pop_frame());
+    assert_eq!(hdr.seq_num, first_hdr.seq_num);
+}
+
+#[test]
+fn test_sack_retransmits_only_missing_segment() {
+    let (seq_num, ack_hdr, retransmitted) = drop_second_of_four(true);
+
+    // Bob reports the third and fourth segments in a single block.
+    let mut sack_blocks = ack_hdr.iter_options().filter_map(|option| match option {
+        TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks } => {
+            Some(sacks[..*num_sacks].to_vec())
+        }
+        _ => None,
+    });
+    let blocks = sack_blocks.next().expect("ACK without SACK blocks");
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].begin, seq_num + Wrapping(20));
+    assert_eq!(blocks[0].end, seq_num + Wrapping(40));
+
+    assert_eq!(retransmitted.len(), 1);
+    let (hdr, data) = &retransmitted[0];
+    assert_eq!(hdr.seq_num, seq_num + Wrapping(10));
+    assert_eq!(&data[..], &[1; 10][..]);
+}
+
+#[test]
+fn test_no_sack_unless_both_sides_advertise_it() {
+    let (seq_num, ack_hdr, retransmitted) = drop_second_of_four(false);
+    assert!(!ack_hdr
+        .iter_options()
+        .any(|option| matches!(option, TcpOptions2::SelectiveAcknowlegement { .. })));
+
+    // Without SACK, Alice repacketizes everything after the first segment.
+    assert_eq!(retransmitted.len(), 1);
+    let (hdr, data) = &retransmitted[0];
+    assert_eq!(hdr.seq_num, seq_num + Wrapping(10));
+    assert_eq!(data.len(), 30);
+}
+
+/// Connects Alice to Bob with timestamps enabled on both sides, returning each side's socket.
+fn connect_with_timestamps(
+    now: Instant,
+) -> (
+    test_helpers::TestEngine,
+    FileDescriptor,
+    test_helpers::TestEngine,
+    FileDescriptor,
+) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    alice
+        .rt()
+        .set_tcp_options(alice.rt().tcp_options().timestamps(true));
+    bob.rt()
+        .set_tcp_options(bob.rt().tcp_options().timestamps(true));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    // Every segment of the handshake carries the option.
+    let has_timestamp = |frame: &Bytes| {
+        let (hdr, _) = parse_tcp_frame(frame.clone());
+        hdr.iter_options()
+            .any(|option| matches!(option, TcpOptions2::Timestamp { .. }))
+    };
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    assert!(has_timestamp(&syn));
+    bob.receive(syn).unwrap();
+    bob.rt().poll_scheduler();
+    let syn_ack = bob.rt().pop_frame();
+    assert!(has_timestamp(&syn_ack));
+    alice.receive(syn_ack).unwrap();
+    alice.rt().poll_scheduler();
+    let ack = alice.rt().pop_frame();
+    assert!(has_timestamp(&ack));
+    bob.receive(ack).unwrap();
+
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    (alice, alice_fd, bob, bob_fd)
+}
+
+#[test]
+fn test_timestamps_measure_rtt_of_retransmission() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let (mut alice, alice_fd, mut bob, _) = connect_with_timestamps(now);
+
+    // Alice's first transmission is lost, so Karn's algorithm wouldn't let her take a sample
+    // from the ACK for the retransmission.
+    let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    alice.rt().pop_frame();
+
+    alice.rt().advance_clock(now + tcp::INITIAL_RTO);
+    alice.rt().poll_scheduler();
+    let retransmission = alice.rt().pop_frame();
+    let backed_off_rto = alice.tcp_rto(alice_fd).unwrap();
+    assert!(backed_off_rto > tcp::INITIAL_RTO);
+
+    bob.rt().advance_clock(now + tcp::INITIAL_RTO);
+    bob.receive(retransmission).unwrap();
+    bob.rt()
+        .advance_clock(now + tcp::INITIAL_RTO + Duration::from_millis(500));
+    bob.rt().poll_scheduler();
+    let ack = bob.rt().pop_frame();
+
+    // The ACK echoes the retransmission's timestamp, which measures a 200ms RTT. That sample
+    // replaces the backed-off RTO, though the default floor keeps it from going below a second.
+    alice
+        .rt()
+        .advance_clock(now + tcp::INITIAL_RTO + Duration::from_millis(200));
+    alice.receive(ack).unwrap();
+    assert!(alice.tcp_rto(alice_fd).unwrap() < backed_off_rto);
+}
+
+#[test]
+fn test_no_timestamp_on_pure_ack() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let (mut alice, alice_fd, mut bob, bob_fd) = connect_with_timestamps(now);
+    bob.rt()
+        .set_tcp_options(bob.rt().tcp_options().ts_on_pure_ack(false));
+    bob.tcp_set_quickack(bob_fd, true).unwrap();
+
+    // Bob's ACK for Alice's data leaves the option out, and Alice takes it all the same.
+    let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    let mut write_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let ack = bob.rt().pop_frame();
+    let (ack_hdr, ack_data) = parse_tcp_frame(ack.clone());
+    assert!(ack_data.is_empty());
+    assert!(tcp::timestamps::parse(&ack_hdr).is_none());
+    alice.receive(ack).unwrap();
+    assert_eq!(
+        alice.tcp_snd_una(alice_fd).unwrap(),
+        alice.tcp_snd_nxt(alice_fd).unwrap()
+    );
+
+    // His data still carries it.
+    let mut write_future = bob.tcp_push(bob_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    let (data_hdr, data) = parse_tcp_frame(bob.rt().pop_frame());
+    assert_eq!(data.len(), 10);
+    assert!(tcp::timestamps::parse(&data_hdr).is_some());
+}
+
+#[test]
+fn test_paws_rejects_old_timestamp() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let (mut alice, alice_fd, mut bob, bob_fd) = connect_with_timestamps(now);
+
+    let mut frames = vec![];
+    for i in 0..2 {
+        alice
+            .rt()
+            .advance_clock(now + Duration::from_millis(1000 + 100 * i));
+        let buf = BytesMut::from(&vec![i as u8; 10][..]).freeze();
+        let mut write_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        frames.push(alice.rt().pop_frame());
+    }
+    bob.receive(frames[0].clone()).unwrap();
+    let rcv_nxt = bob.tcp_rcv_nxt(bob_fd).unwrap();
+
+    // Replay the second segment with a timestamp older than the first's. Its sequence number is
+    // in the window, but PAWS still throws it out.
+    let (ethernet2_hdr, _) = Ethernet2Header::parse(frames[1].clone()).unwrap();
+    must_let!(let DecodedFrame::Ipv4 { header: ipv4_hdr, .. } = test_helpers::decode_frame(&frames[1]));
+    let (hdr, data) = parse_tcp_frame(frames[1].clone());
+    must_let!(let Some((1100, tsecr)) = tcp::timestamps::parse(&hdr));
+    let mut tcp_hdr = TcpHeader::new(hdr.src_port, hdr.dst_port);
+    tcp_hdr.seq_num = hdr.seq_num;
+    tcp_hdr.ack = true;
+    tcp_hdr.ack_num = hdr.ack_num;
+    tcp_hdr.window_size = hdr.window_size;
+    tcp_hdr.push_option(TcpOptions2::Timestamp {
+        sender_timestamp: 500,
+        echo_timestamp: tsecr,
+    });
+    let segment = TcpSegment {
+        ethernet2_hdr,
+        ipv4_hdr,
+        tcp_hdr,
+        data,
+        tx_checksum_offload: false,
+        signer: None,
+    };
+    bob.receive(serialize_frame(segment, false).freeze())
+        .unwrap();
+    assert_eq!(bob.tcp_rejected_segments(bob_fd).unwrap(), 1);
+    assert_eq!(bob.tcp_rcv_nxt(bob_fd).unwrap(), rcv_nxt);
+
+    // Bob answers with an ACK for what he really has.
+    bob.rt().poll_scheduler();
+    let (ack_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert!(ack_hdr.ack);
+    assert_eq!(ack_hdr.ack_num, rcv_nxt);
+
+    // The genuine segment is still accepted.
+    bob.receive(frames[1].clone()).unwrap();
+    assert_eq!(bob.tcp_rcv_nxt(bob_fd).unwrap(), rcv_nxt + Wrapping(10));
+}
+
 // pub fn one_send_recv_round(
 //     ctx: &mut Context,
 //     buf: Bytes,
@@ -173,3 +2925,221 @@ fn test_connect() {
 //         bob_fd,
 //     );
 // }
+
+/// Records whether it has been woken.
+struct WakeFlag(AtomicBool);
+
+impl ArcWake for WakeFlag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Tests that pushes past the send buffer's high-watermark wait for ACKs to drain it, and that
+/// pushes made while it's over the mark are turned away.
+#[test]
+fn test_send_buffer_high_watermark() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    alice
+        .rt()
+        .set_tcp_options(alice.rt().tcp_options().send_buffer_size(1000));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    bob.tcp_set_quickack(bob_fd, true).unwrap();
+    let snd_una = alice.tcp_snd_una(alice_fd).unwrap();
+
+    // Both pushes are queued, since the queue is under the high-watermark when each is made, but
+    // their futures wait while Bob sits on what he's been sent.
+    let buf = BytesMut::from(&vec![0x5a; 500][..]).freeze();
+    let mut first_push = alice.tcp_push(alice_fd, buf);
+    let buf = BytesMut::from(&vec![0x5a; 1000][..]).freeze();
+    let mut second_push = alice.tcp_push(alice_fd, buf);
+    let first_flag = Arc::new(WakeFlag(AtomicBool::new(false)));
+    let first_waker = waker(first_flag.clone());
+    let second_flag = Arc::new(WakeFlag(AtomicBool::new(false)));
+    let second_waker = waker(second_flag.clone());
+    let mut first_ctx = Context::from_waker(&first_waker);
+    let mut second_ctx = Context::from_waker(&second_waker);
+    assert!(Future::poll(Pin::new(&mut first_push), &mut first_ctx).is_pending());
+    assert!(Future::poll(Pin::new(&mut second_push), &mut second_ctx).is_pending());
+
+    // Now the queue is over the mark, so a third push fails without being queued.
+    let buf = BytesMut::from(&vec![0x5a; 100][..]).freeze();
+    let mut third_push = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Err(Fail::ResourceExhausted { .. })) = Future::poll(Pin::new(&mut third_push), &mut first_ctx));
+
+    alice.rt().poll_scheduler();
+    let mut frames = vec![];
+    while let Some(frame) = alice.rt().try_pop_frame() {
+        frames.push(frame);
+    }
+    assert!(!frames.is_empty());
+    assert!(Future::poll(Pin::new(&mut second_push), &mut second_ctx).is_pending());
+
+    // Once Bob's ACKs come back, the queue has drained and both waiting pushes are woken and
+    // complete.
+    for frame in frames {
+        bob.receive(frame).unwrap();
+    }
+    bob.rt().poll_scheduler();
+    while let Some(ack) = bob.rt().try_pop_frame() {
+        alice.receive(ack).unwrap();
+    }
+    assert!(first_flag.0.load(Ordering::SeqCst));
+    assert!(second_flag.0.load(Ordering::SeqCst));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut first_push), &mut first_ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut second_push), &mut second_ctx));
+    assert_eq!(alice.tcp_snd_una(alice_fd), alice.tcp_snd_nxt(alice_fd));
+    assert_eq!(
+        alice.tcp_snd_nxt(alice_fd).unwrap(),
+        snd_una + Wrapping(1500)
+    );
+}
+
+/// Tests that the sender spreads a long burst over several scheduler polls.
+#[test]
+fn test_maxburst() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+    // Without congestion control, only Bob's window limits how much Alice can send at once.
+    alice.rt().set_tcp_options(
+        alice
+            .rt()
+            .tcp_options()
+            .congestion_ctrl_type(cc::None::new)
+            .maxburst(4),
+    );
+    bob.rt().set_tcp_options(
+        bob.rt()
+            .tcp_options()
+            .advertised_mss(tcp::constants::MIN_MSS),
+    );
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, _) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    let mss = alice.tcp_mss(alice_fd).unwrap();
+    assert_eq!(mss, tcp::constants::MIN_MSS);
+
+    // The push itself sends the first segment, leaving the rest to the background sender.
+    let buf = BytesMut::from(&vec![0x5a; 13 * mss][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let (_, data) = parse_tcp_frame(alice.rt().pop_frame());
+    assert_eq!(data.len(), mss);
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    for &expected in &[4, 4, 4] {
+        alice.rt().poll_scheduler();
+        let mut segments = 0;
+        while let Some(frame) = alice.rt().try_pop_frame() {
+            let (_, data) = parse_tcp_frame(frame);
+            assert_eq!(data.len(), mss);
+            segments += 1;
+        }
+        assert_eq!(segments, expected);
+    }
+    let Wrapping(in_flight) =
+        alice.tcp_snd_nxt(alice_fd).unwrap() - alice.tcp_snd_una(alice_fd).unwrap();
+    assert_eq!(in_flight as usize, 13 * mss);
+}
+
+/// Tests that reordered segments are held until the gap fills, and then delivered in order.
+#[test]
+fn test_reassembly() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    bob.tcp_set_quickack(bob_fd, true).unwrap();
+    bob.tcp_set_rcvlowat(bob_fd, 30).unwrap();
+
+    let mut frames = vec![];
+    for i in 0..3 {
+        let buf = BytesMut::from(&vec![i; 10][..]).freeze();
+        let mut push_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        frames.push(alice.rt().pop_frame());
+    }
+    let (first_hdr, _) = parse_tcp_frame(frames[0].clone());
+    let seq_num = first_hdr.seq_num;
+
+    // The first segment arrives, and then the third overtakes the second.
+    bob.receive(frames[0].clone()).unwrap();
+    bob.rt().poll_scheduler();
+    let (ack_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert_eq!(ack_hdr.ack_num, seq_num + Wrapping(10));
+    bob.receive(frames[2].clone()).unwrap();
+    bob.rt().poll_scheduler();
+    let (ack_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert_eq!(ack_hdr.ack_num, seq_num + Wrapping(10));
+    assert!(ack_hdr.iter_options().any(|option| match option {
+        TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks } => {
+            *num_sacks == 1
+                && sacks[0].begin == seq_num + Wrapping(20)
+                && sacks[0].end == seq_num + Wrapping(30)
+        }
+        _ => false,
+    }));
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+
+    // Once the second fills the gap, Bob ACKs all three and delivers them together.
+    bob.receive(frames[1].clone()).unwrap();
+    bob.rt().poll_scheduler();
+    let (ack_hdr, _) = parse_tcp_frame(bob.rt().pop_frame());
+    assert_eq!(ack_hdr.ack_num, seq_num + Wrapping(30));
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    let expected: Vec<u8> = (0..3).flat_map(|i| vec![i; 10]).collect();
+    assert_eq!(&received[..], &expected[..]);
+}
+
+#[test]
+fn test_cc_phase() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (alice_fd, bob_fd) = test_helpers::handshake(&mut alice, &mut bob, listen_addr);
+    assert_eq!(
+        alice.tcp_cc_phase(alice_fd).unwrap(),
+        cc::CcPhase::SlowStart
+    );
+
+    // A timeout puts the sender into RTO recovery.
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let buf = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    alice.rt().pop_frame();
+    alice.tcp_force_rto(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    alice.rt().pop_frame();
+    assert_eq!(
+        alice.tcp_cc_phase(alice_fd).unwrap(),
+        cc::CcPhase::RtoRecovery
+    );
+    assert_eq!(bob.tcp_cc_phase(bob_fd).unwrap(), cc::CcPhase::SlowStart);
+}
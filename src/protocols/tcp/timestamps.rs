@@ -0,0 +1,102 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! The TCP timestamps option (RFC 7323), which lets us measure the RTT of every acknowledged
+//! segment (retransmissions included) and protects against wrapped sequence numbers (PAWS).
+//!
+//! Each connection's timestamp clock counts milliseconds from when the connection was opened, so
+//! the values we put on the wire don't leak the runtime's clock.
+
+use super::segment::{TcpHeader, TcpOptions2};
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+/// Timestamp state for a connection that negotiated the option.
+#[derive(Debug)]
+pub struct TimestampState {
+    /// Origin of our timestamp clock.
+    base: Instant,
+    /// Most recent TSval from the peer that we'll echo back (TS.Recent).
+    recent: Cell<u32>,
+}
+
+impl TimestampState {
+    pub fn new(base: Instant, recent: u32) -> Self {
+        Self {
+            base,
+            recent: Cell::new(recent),
+        }
+    }
+
+    /// Reading of our timestamp clock at `now`.
+    pub fn tsval(&self, now: Instant) -> u32 {
+        tsval(self.base, now)
+    }
+
+    pub fn set_recent(&self, tsval: u32) {
+        self.recent.set(tsval);
+    }
+
+    /// Whether a segment carrying `tsval` fails the PAWS check, i.e. is older than TS.Recent.
+    pub fn is_old(&self, tsval: u32) -> bool {
+        (tsval.wrapping_sub(self.recent.get()) as i32) < 0
+    }
+
+    /// Round trip time of the segment whose TSval the peer echoed back in `tsecr`.
+    pub fn rtt(&self, tsecr: u32, now: Instant) -> Duration {
+        Duration::from_millis(self.tsval(now).wrapping_sub(tsecr) as u64)
+    }
+
+    /// The option to put on a segment sent at `now`.
+    pub fn option(&self, now: Instant) -> TcpOptions2 {
+        TcpOptions2::Timestamp {
+            sender_timestamp: self.tsval(now),
+            echo_timestamp: self.recent.get(),
+        }
+    }
+}
+
+/// Reading at `now` of a timestamp clock that started at `base`.
+pub fn tsval(base: Instant, now: Instant) -> u32 {
+    (now - base).as_millis() as u32
+}
+
+/// Returns the (TSval, TSecr) pair from `header`'s timestamps option, if it has one.
+pub fn parse(header: &TcpHeader) -> Option<(u32, u32)> {
+    header.iter_options().find_map(|option| match option {
+        TcpOptions2::Timestamp {
+            sender_timestamp,
+            echo_timestamp,
+        } => Some((*sender_timestamp, *echo_timestamp)),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paws_wraparound() {
+        let state = TimestampState::new(Instant::now(), 100);
+        assert!(state.is_old(99));
+        assert!(!state.is_old(100));
+        assert!(!state.is_old(101));
+
+        // Timestamps are compared modulo 2^32, like sequence numbers.
+        state.set_recent(u32::MAX - 10);
+        assert!(!state.is_old(5));
+        assert!(state.is_old(u32::MAX - 11));
+    }
+
+    #[test]
+    fn test_rtt() {
+        let base = Instant::now();
+        let state = TimestampState::new(base, 0);
+        let now = base + Duration::from_millis(1250);
+        assert_eq!(state.tsval(now), 1250);
+        assert_eq!(state.rtt(1000, now), Duration::from_millis(250));
+    }
+}
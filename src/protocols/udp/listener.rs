@@ -1,13 +1,23 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use crate::protocols::ipv4;
+use crate::{
+    collections::watched::WatchedValue, fail::Fail, memory_budget::MemoryBudget, protocols::ipv4,
+    runtime::RuntimeBuf,
+};
 
-use std::{collections::VecDeque, task::Waker};
+use std::{collections::VecDeque, net::Ipv4Addr, rc::Rc, task::Waker};
 
 pub struct Listener<T> {
-    buf: VecDeque<(Option<ipv4::Endpoint>, T)>,
+    /// Queued datagrams, along with the address each one was sent to.
+    buf: VecDeque<(Option<ipv4::Endpoint>, Ipv4Addr, T)>,
     waker: Option<Waker>,
+    /// Number of queued datagrams, which readiness watchers can wait on.
+    len: Rc<WatchedValue<usize>>,
+    /// Number of bytes queued, which are accounted for in `memory`.
+    bytes: usize,
+    /// Shared accounting for buffer memory.
+    memory: MemoryBudget,
 }
 
 //==============================================================================
@@ -15,20 +25,57 @@ pub struct Listener<T> {
 //==============================================================================
 
 /// Associate functions for [Listener].
-impl<T> Listener<T> {
+impl<T: RuntimeBuf> Listener<T> {
     /// Creates a new listener.
-    pub fn new(buf: VecDeque<(Option<ipv4::Endpoint>, T)>, waker: Option<Waker>) -> Self {
-        Self { buf, waker }
+    pub fn new(
+        buf: VecDeque<(Option<ipv4::Endpoint>, Ipv4Addr, T)>,
+        waker: Option<Waker>,
+        memory: MemoryBudget,
+    ) -> Self {
+        let len = Rc::new(WatchedValue::new(buf.len()));
+        let bytes = buf.iter().map(|(_, _, data)| data.len()).sum();
+        memory.reserve(bytes);
+        Self {
+            buf,
+            waker,
+            len,
+            bytes,
+            memory,
+        }
     }
 
-    /// Pushes data to the target listener.
-    pub fn push_data(&mut self, endpoint: Option<ipv4::Endpoint>, data: T) {
-        self.buf.push_back((endpoint, data));
+    /// Pushes data sent to `dst_addr` to the target listener, failing if there's no room left in
+    /// the memory budget.
+    pub fn push_data(
+        &mut self,
+        endpoint: Option<ipv4::Endpoint>,
+        dst_addr: Ipv4Addr,
+        data: T,
+    ) -> Result<(), Fail> {
+        self.memory.try_reserve(data.len())?;
+        self.bytes += data.len();
+        self.buf.push_back((endpoint, dst_addr, data));
+        self.len.set(self.buf.len());
+        Ok(())
     }
 
     /// Pops data from the target listener.
-    pub fn pop_data(&mut self) -> Option<(Option<ipv4::Endpoint>, T)> {
-        self.buf.pop_front()
+    pub fn pop_data(&mut self) -> Option<(Option<ipv4::Endpoint>, Ipv4Addr, T)> {
+        let data = self.buf.pop_front()?;
+        self.bytes -= data.2.len();
+        self.memory.release(data.2.len());
+        self.len.set(self.buf.len());
+        Some(data)
+    }
+
+    /// Checks whether the target listener has no data queued.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Returns the watched number of datagrams queued on the target listener.
+    pub fn watch_len(&self) -> Rc<WatchedValue<usize>> {
+        self.len.clone()
     }
 
     /// Takes the waker of the target listener.
@@ -47,12 +94,17 @@ impl<T> Listener<T> {
 //==============================================================================
 
 /// Default trait implementation for [Listener].
-impl<T> Default for Listener<T> {
+impl<T: RuntimeBuf> Default for Listener<T> {
     /// Creates a UDP socket with default values.
     fn default() -> Self {
-        Self {
-            buf: VecDeque::new(),
-            waker: None,
-        }
+        Self::new(VecDeque::new(), None, MemoryBudget::default())
+    }
+}
+
+/// Drop trait implementation for [Listener].
+impl<T> Drop for Listener<T> {
+    /// Returns the memory held by any unread datagrams to the budget.
+    fn drop(&mut self) {
+        self.memory.release(self.bytes);
     }
 }
@@ -13,6 +13,7 @@ mod tests;
 
 pub use datagram::UdpHeader;
 pub use operations::PopFuture as UdpPopFuture;
+pub use operations::PopWithInfoFuture as UdpPopWithInfoFuture;
 pub use operations::UdpOperation;
 pub use options::UdpOptions as Options;
 pub use peer::UdpPeer as Peer;
@@ -10,6 +10,7 @@ use crate::{operations::OperationResult, protocols::ipv4};
 use std::{
     cell::RefCell,
     future::Future,
+    net::Ipv4Addr,
     pin::Pin,
     rc::Rc,
     task::{Context, Poll},
@@ -27,6 +28,12 @@ pub struct PopFuture<RT: Runtime> {
     listener: Result<Rc<RefCell<Listener<RT::Buf>>>, Fail>,
 }
 
+/// Future for Pop Operation that also reports the address each datagram was sent to, like
+/// `recvmsg()` with `IP_PKTINFO`.
+pub struct PopWithInfoFuture<RT: Runtime> {
+    inner: PopFuture<RT>,
+}
+
 /// Operations on UDP Layer
 pub enum UdpOperation<RT: Runtime> {
     Connect(FileDescriptor, Result<(), Fail>),
@@ -67,19 +74,13 @@ impl<RT: Runtime> PopFuture<RT> {
     pub fn new(fd: FileDescriptor, listener: Result<Rc<RefCell<Listener<RT::Buf>>>, Fail>) -> Self {
         Self { fd, listener }
     }
-}
-
-//==============================================================================
-// Trait Implementations
-//==============================================================================
-
-/// Future trait implementation for [PopFuture].
-impl<RT: Runtime> Future for PopFuture<RT> {
-    type Output = Result<(Option<ipv4::Endpoint>, RT::Buf), Fail>;
 
-    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
-        let self_ = self.get_mut();
-        match self_.listener {
+    /// Polls for the next datagram, along with its source and destination addresses.
+    fn poll_datagram(
+        &mut self,
+        ctx: &mut Context,
+    ) -> Poll<Result<(Option<ipv4::Endpoint>, Ipv4Addr, RT::Buf), Fail>> {
+        match self.listener {
             Err(ref e) => Poll::Ready(Err(e.clone())),
             Ok(ref l) => {
                 let mut listener = l.borrow_mut();
@@ -94,6 +95,40 @@ impl<RT: Runtime> Future for PopFuture<RT> {
     }
 }
 
+/// Associate functions for [PopWithInfoFuture].
+impl<RT: Runtime> PopWithInfoFuture<RT> {
+    /// Creates a future for the pop operation.
+    pub fn new(fd: FileDescriptor, listener: Result<Rc<RefCell<Listener<RT::Buf>>>, Fail>) -> Self {
+        Self {
+            inner: PopFuture::new(fd, listener),
+        }
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+/// Future trait implementation for [PopFuture].
+impl<RT: Runtime> Future for PopFuture<RT> {
+    type Output = Result<(Option<ipv4::Endpoint>, RT::Buf), Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut()
+            .poll_datagram(ctx)
+            .map(|r| r.map(|(remote, _, buf)| (remote, buf)))
+    }
+}
+
+/// Future trait implementation for [PopWithInfoFuture].
+impl<RT: Runtime> Future for PopWithInfoFuture<RT> {
+    type Output = Result<(Option<ipv4::Endpoint>, Ipv4Addr, RT::Buf), Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().inner.poll_datagram(ctx)
+    }
+}
+
 /// Future trait implementation for [UdpOperation]
 impl<RT: Runtime> Future for UdpOperation<RT> {
     type Output = ();
@@ -12,6 +12,8 @@ pub struct UdpOptions {
     rx_checksum: bool,
     /// Enable checksum offload on sender side?
     tx_checksum: bool,
+    /// Deliver broadcast datagrams that we sent ourselves?
+    loop_back_multicast: bool,
 }
 
 //==============================================================================
@@ -25,9 +27,17 @@ impl UdpOptions {
         Self {
             rx_checksum,
             tx_checksum,
+            loop_back_multicast: false,
         }
     }
 
+    /// Sets whether broadcast datagrams that we sent ourselves should be delivered back to us
+    /// if the runtime loops them back (the equivalent of `IP_MULTICAST_LOOP`).
+    pub fn set_loop_back_multicast(mut self, loop_back_multicast: bool) -> Self {
+        self.loop_back_multicast = loop_back_multicast;
+        self
+    }
+
     /// Returns whether or not checksum offload on receiver side is enabled.
     pub fn rx_checksum(&self) -> bool {
         self.rx_checksum
@@ -37,6 +47,11 @@ impl UdpOptions {
     pub fn tx_checksum(&self) -> bool {
         self.tx_checksum
     }
+
+    /// Returns whether or not our own broadcast datagrams are delivered back to us.
+    pub fn loop_back_multicast(&self) -> bool {
+        self.loop_back_multicast
+    }
 }
 
 //==============================================================================
@@ -50,6 +65,7 @@ impl Default for UdpOptions {
         UdpOptions {
             rx_checksum: false,
             tx_checksum: false,
+            loop_back_multicast: false,
         }
     }
 }
@@ -69,10 +85,15 @@ mod tests {
         let options_default = UdpOptions::default();
         assert!(!options_default.rx_checksum());
         assert!(!options_default.tx_checksum());
+        assert!(!options_default.loop_back_multicast());
 
         // Custom options.
         let options_custom = UdpOptions::new(true, true);
         assert!(options_custom.rx_checksum());
         assert!(options_custom.tx_checksum());
+        assert!(!options_custom.loop_back_multicast());
+
+        let options_loop = UdpOptions::new(false, false).set_loop_back_multicast(true);
+        assert!(options_loop.loop_back_multicast());
     }
 }
@@ -4,16 +4,20 @@
 use super::{
     datagram::{UdpDatagram, UdpHeader},
     listener::Listener,
-    operations::PopFuture,
+    operations::{PopFuture, PopWithInfoFuture},
     socket::Socket,
 };
 
 use crate::{
     fail::Fail,
     file_table::{File, FileDescriptor, FileTable},
+    memory_budget::MemoryBudget,
     protocols::{
         arp,
-        ethernet2::frame::{EtherType2, Ethernet2Header},
+        ethernet2::{
+            frame::{EtherType2, Ethernet2Header},
+            MacAddress,
+        },
         ipv4,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
     },
@@ -23,7 +27,13 @@ use crate::{
 
 use futures::{channel::mpsc, stream::StreamExt};
 
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    future::Future,
+    rc::Rc,
+    time::Duration,
+};
 
 //==============================================================================
 // Constants & Structures
@@ -44,9 +54,12 @@ struct UdpPeerInner<RT: Runtime> {
     rt: RT,
     arp: arp::Peer<RT>,
     file_table: FileTable,
+    /// Buffer memory shared with every other socket on this stack.
+    memory: MemoryBudget,
 
     sockets: HashMap<FileDescriptor, Socket>,
     bound: HashMap<ipv4::Endpoint, Rc<RefCell<Listener<RT::Buf>>>>,
+    keepalives: HashMap<FileDescriptor, SchedulerHandle>,
 
     outgoing: OutgoingSender<RT::Buf>,
     #[allow(unused)]
@@ -68,6 +81,7 @@ impl<RT: Runtime> UdpPeerInner<RT> {
         rt: RT,
         arp: arp::Peer<RT>,
         file_table: FileTable,
+        memory: MemoryBudget,
         tx: OutgoingSender<RT::Buf>,
         handle: SchedulerHandle,
     ) -> Self {
@@ -75,23 +89,47 @@ impl<RT: Runtime> UdpPeerInner<RT> {
             rt,
             arp,
             file_table,
+            memory,
             sockets: HashMap::new(),
             bound: HashMap::new(),
+            keepalives: HashMap::new(),
             outgoing: tx,
             handle,
         }
     }
 
-    /// Sends a UDP packet.
+    /// Resolves the link address of `remote` if we can do so without waiting. A connected
+    /// `socket` remembers the link address of its remote endpoint until the ARP cache changes, so
+    /// that sending doesn't have to look it up every time.
+    fn try_resolve(&self, remote: ipv4::Endpoint, socket: Option<&Socket>) -> Option<MacAddress> {
+        // Broadcasts don't need resolving.
+        if remote.addr.is_broadcast() {
+            return Some(MacAddress::broadcast());
+        }
+        let socket = match socket {
+            Some(s) => s,
+            None => return self.arp.try_query(remote.addr),
+        };
+        let generation = self.arp.generation();
+        if let Some(link_addr) = socket.remote_link_addr(generation) {
+            return Some(link_addr);
+        }
+        let link_addr = self.arp.try_query(remote.addr)?;
+        socket.set_remote_link_addr(link_addr, generation);
+        Some(link_addr)
+    }
+
+    /// Sends a UDP packet, from `socket` if it's connected to `remote`.
     fn send_datagram(
         &self,
         buf: RT::Buf,
         local: Option<ipv4::Endpoint>,
         remote: ipv4::Endpoint,
+        socket: Option<&Socket>,
     ) -> Result<(), Fail> {
         // First, try to send the packet immediately. If we can't defer the
         // operation to the async path.
-        if let Some(link_addr) = self.arp.try_query(remote.addr) {
+        if let Some(link_addr) = self.try_resolve(remote, socket) {
             let datagram = UdpDatagram::new(
                 Ethernet2Header {
                     dst_addr: link_addr,
@@ -103,7 +141,7 @@ impl<RT: Runtime> UdpPeerInner<RT> {
                 buf,
                 self.rt.udp_options().tx_checksum(),
             );
-            self.rt.transmit(datagram);
+            self.arp.transmit(datagram);
         } else {
             self.outgoing.unbounded_send((local, remote, buf)).unwrap();
         }
@@ -114,11 +152,11 @@ impl<RT: Runtime> UdpPeerInner<RT> {
 /// Associate functions for [UdpPeer].
 impl<RT: Runtime> UdpPeer<RT> {
     /// Creates a Udp peer.
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Self {
+    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable, memory: MemoryBudget) -> Self {
         let (tx, rx) = mpsc::unbounded();
         let future = Self::background(rt.clone(), arp.clone(), rx);
         let handle = rt.spawn(future);
-        let inner = UdpPeerInner::new(rt, arp, file_table, tx, handle);
+        let inner = UdpPeerInner::new(rt, arp, file_table, memory, tx, handle);
         Self {
             inner: Rc::new(RefCell::new(inner)),
         }
@@ -126,24 +164,52 @@ impl<RT: Runtime> UdpPeer<RT> {
 
     async fn background(rt: RT, arp: arp::Peer<RT>, mut rx: OutgoingReceiver<RT::Buf>) {
         while let Some((local, remote, buf)) = rx.next().await {
-            let r: Result<_, Fail> = try {
-                let link_addr = arp.query(remote.addr).await?;
-                let datagram = UdpDatagram::new(
-                    Ethernet2Header {
-                        dst_addr: link_addr,
-                        src_addr: rt.local_link_addr(),
-                        ether_type: EtherType2::Ipv4,
-                    },
-                    Ipv4Header::new(rt.local_ipv4_addr(), remote.addr, Ipv4Protocol2::Udp),
-                    UdpHeader::new(local.map(|l| l.port), remote.port),
-                    buf,
-                    rt.udp_options().tx_checksum(),
-                );
-                rt.transmit(datagram);
-            };
-            if let Err(e) = r {
-                warn!("Failed to send UDP message: {:?}", e);
-            }
+            Self::send_resolved(&rt, &arp, local, remote, buf).await;
+        }
+    }
+
+    /// Sends a UDP packet once the remote link address has been resolved.
+    async fn send_resolved(
+        rt: &RT,
+        arp: &arp::Peer<RT>,
+        local: Option<ipv4::Endpoint>,
+        remote: ipv4::Endpoint,
+        buf: RT::Buf,
+    ) {
+        let r: Result<_, Fail> = try {
+            let link_addr = arp.query(remote.addr).await?;
+            let datagram = UdpDatagram::new(
+                Ethernet2Header {
+                    dst_addr: link_addr,
+                    src_addr: rt.local_link_addr(),
+                    ether_type: EtherType2::Ipv4,
+                },
+                Ipv4Header::new(rt.local_ipv4_addr(), remote.addr, Ipv4Protocol2::Udp),
+                UdpHeader::new(local.map(|l| l.port), remote.port),
+                buf,
+                rt.udp_options().tx_checksum(),
+            );
+            arp.transmit(datagram);
+        };
+        if let Err(e) = r {
+            warn!("Failed to send UDP message: {:?}", e);
+        }
+    }
+
+    /// Periodically sends `payload` to the remote end of a connected socket.
+    async fn keepalive(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        local: Option<ipv4::Endpoint>,
+        remote: ipv4::Endpoint,
+        interval: Duration,
+        payload: RT::Buf,
+    ) {
+        let mut deadline = rt.now() + interval;
+        loop {
+            rt.wait_until(deadline).await;
+            Self::send_resolved(&rt, &arp, local, remote, payload.clone()).await;
+            deadline += interval;
         }
     }
 
@@ -194,7 +260,7 @@ impl<RT: Runtime> UdpPeer<RT> {
         }
 
         // Register listener.
-        let listener = Listener::default();
+        let listener = Listener::new(VecDeque::new(), None, inner.memory.clone());
         if inner
             .bound
             .insert(addr, Rc::new(RefCell::new(listener)))
@@ -235,6 +301,8 @@ impl<RT: Runtime> UdpPeer<RT> {
             }
         };
 
+        inner.keepalives.remove(&fd);
+
         // Remove endpoint biding.
         if let Some(local) = socket.local() {
             if inner.bound.remove(&local).is_none() {
@@ -251,20 +319,42 @@ impl<RT: Runtime> UdpPeer<RT> {
     /// Consumes the payload from a buffer.
     pub fn receive(&self, ipv4_header: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
-        let (hdr, data) = UdpHeader::parse(ipv4_header, buf, inner.rt.udp_options().rx_checksum())?;
-        let local = ipv4::Endpoint::new(ipv4_header.dst_addr, hdr.dest_port());
+        let udp_options = inner.rt.udp_options();
+        let (hdr, data) = UdpHeader::parse(ipv4_header, buf, udp_options.rx_checksum())?;
+
+        // Broadcasts are delivered to whichever socket is bound to the port on our address.
+        // Depending on the runtime, our own broadcasts may come back to us too.
+        let local_addr = if ipv4_header.dst_addr.is_broadcast() {
+            let local_addr = inner.rt.local_ipv4_addr();
+            if ipv4_header.src_addr == local_addr && !udp_options.loop_back_multicast() {
+                return Err(Fail::Ignored {
+                    details: "Dropping our own broadcast",
+                });
+            }
+            local_addr
+        } else {
+            ipv4_header.dst_addr
+        };
+        let local = ipv4::Endpoint::new(local_addr, hdr.dest_port());
         let remote = hdr
             .src_port()
             .map(|p| ipv4::Endpoint::new(ipv4_header.src_addr, p));
 
-        // TODO: Send ICMPv4 error in this condition.
-        let listener = inner.bound.get_mut(&local).ok_or(Fail::Malformed {
-            details: "Port not bound",
-        })?;
+        // A datagram with a source port gets a "port unreachable" error back, which our caller
+        // sends when we refuse it.
+        let listener = match (inner.bound.get_mut(&local), remote) {
+            (Some(listener), _) => listener,
+            (None, Some(_)) => return Err(Fail::ConnectionRefused {}),
+            (None, None) => {
+                return Err(Fail::Malformed {
+                    details: "Port not bound",
+                })
+            }
+        };
 
         // Consume data and wakeup receiver.
         let mut l = listener.borrow_mut();
-        l.push_data(remote, data);
+        l.push_data(remote, ipv4_header.dst_addr, data)?;
         if let Some(w) = l.take_waker() {
             w.wake()
         }
@@ -272,12 +362,51 @@ impl<RT: Runtime> UdpPeer<RT> {
         Ok(())
     }
 
+    /// Lists the endpoints that sockets are currently bound to, in no particular order.
+    pub fn bound_endpoints(&self) -> Vec<ipv4::Endpoint> {
+        self.inner.borrow().bound.keys().copied().collect()
+    }
+
+    /// Checks whether a socket has datagrams waiting to be popped.
+    pub fn is_readable(&self, fd: FileDescriptor) -> bool {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd).and_then(|s| s.local()) {
+            Some(local) => inner
+                .bound
+                .get(&local)
+                .map(|l| !l.borrow().is_empty())
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Checks whether a socket is connected, so that data can be pushed to it.
+    pub fn is_writable(&self, fd: FileDescriptor) -> bool {
+        let inner = self.inner.borrow();
+        matches!(inner.sockets.get(&fd), Some(s) if s.local().is_some() && s.remote().is_some())
+    }
+
+    /// Returns a future that completes once the datagrams queued on a socket change, or `None` if
+    /// the socket isn't bound.
+    pub fn readiness_changed(&self, fd: FileDescriptor) -> Option<impl Future<Output = ()>> {
+        let inner = self.inner.borrow();
+        let local = inner.sockets.get(&fd)?.local()?;
+        let len = inner.bound.get(&local)?.borrow().watch_len();
+        let readable = len.get() > 0;
+        Some(async move {
+            let (current, changed) = len.watch();
+            if (current > 0) == readable {
+                changed.await;
+            }
+        })
+    }
+
     /// Pushes data to a socket.
     pub fn push(&self, fd: FileDescriptor, buf: RT::Buf) -> Result<(), Fail> {
         let inner = self.inner.borrow();
         match inner.sockets.get(&fd) {
             Some(s) if s.local().is_some() && s.remote().is_some() => {
-                inner.send_datagram(buf, s.local(), s.remote().unwrap())
+                inner.send_datagram(buf, s.local(), s.remote().unwrap(), Some(s))
             }
             Some(s) if s.local().is_some() => Err(Fail::BadFileDescriptor {}),
             Some(s) if s.remote().is_some() => Err(Fail::BadFileDescriptor {}),
@@ -287,6 +416,51 @@ impl<RT: Runtime> UdpPeer<RT> {
         }
     }
 
+    /// Enables (or, given `None`, disables) periodic heartbeats on a connected socket. Each
+    /// heartbeat sends the given payload to the socket's remote endpoint.
+    pub fn set_keepalive(
+        &self,
+        fd: FileDescriptor,
+        keepalive: Option<(Duration, RT::Buf)>,
+    ) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let (local, remote) = match inner.sockets.get(&fd) {
+            Some(s) if s.local().is_some() && s.remote().is_some() => {
+                (s.local(), s.remote().unwrap())
+            }
+            Some(..) => return Err(Fail::BadFileDescriptor {}),
+            None => {
+                return Err(Fail::Malformed {
+                    details: "Invalid file descriptor",
+                })
+            }
+        };
+        match keepalive {
+            // The heartbeat deadline would never move, so we'd send them back-to-back forever.
+            Some((interval, _)) if interval == Duration::new(0, 0) => {
+                return Err(Fail::Invalid {
+                    details: "keepalive interval",
+                })
+            }
+            Some((interval, payload)) => {
+                let future = Self::keepalive(
+                    inner.rt.clone(),
+                    inner.arp.clone(),
+                    local,
+                    remote,
+                    interval,
+                    payload,
+                );
+                let handle = inner.rt.spawn(future);
+                inner.keepalives.insert(fd, handle);
+            }
+            None => {
+                inner.keepalives.remove(&fd);
+            }
+        }
+        Ok(())
+    }
+
     pub fn pushto(&self, fd: FileDescriptor, buf: RT::Buf, to: ipv4::Endpoint) -> Result<(), Fail> {
         let inner = self.inner.borrow();
         let local = match inner.sockets.get(&fd) {
@@ -297,7 +471,7 @@ impl<RT: Runtime> UdpPeer<RT> {
                 })
             }
         };
-        inner.send_datagram(buf, local, to)
+        inner.send_datagram(buf, local, to, None)
     }
 
     /// Pops data from a socket.
@@ -316,4 +490,22 @@ impl<RT: Runtime> UdpPeer<RT> {
 
         PopFuture::new(fd, listener)
     }
+
+    /// Pops data from a socket, along with the address it was sent to. Unlike [pop](Self::pop),
+    /// this works on sockets that are bound but not connected, so that a server can tell which of
+    /// our addresses a request came in on.
+    pub fn pop_with_info(&self, fd: FileDescriptor) -> PopWithInfoFuture<RT> {
+        let inner = self.inner.borrow();
+        let listener = match inner.sockets.get(&fd) {
+            Some(s) => match s.local() {
+                Some(local) => Ok(inner.bound.get(&local).unwrap().clone()),
+                None => Err(Fail::BadFileDescriptor {}),
+            },
+            None => Err(Fail::Malformed {
+                details: "Invalid file descriptor",
+            }),
+        };
+
+        PopWithInfoFuture::new(fd, listener)
+    }
 }
@@ -1,7 +1,8 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use crate::protocols::ipv4;
+use crate::protocols::{ethernet2::MacAddress, ipv4};
+use std::cell::Cell;
 
 //==============================================================================
 // Constants & Structures
@@ -14,6 +15,8 @@ pub struct Socket {
     local: Option<ipv4::Endpoint>,
     /// Remote endpoint.
     remote: Option<ipv4::Endpoint>,
+    /// Link address of the remote endpoint, and the ARP cache generation we resolved it in.
+    remote_link_addr: Cell<Option<(MacAddress, u64)>>,
 }
 
 //==============================================================================
@@ -36,6 +39,20 @@ impl Socket {
 
     pub fn set_remote(&mut self, remote: Option<ipv4::Endpoint>) {
         self.remote = remote;
+        self.remote_link_addr.set(None);
+    }
+
+    /// Returns the link address of the remote endpoint, if we resolved it in the ARP cache's
+    /// current generation.
+    pub fn remote_link_addr(&self, arp_generation: u64) -> Option<MacAddress> {
+        match self.remote_link_addr.get() {
+            Some((link_addr, generation)) if generation == arp_generation => Some(link_addr),
+            _ => None,
+        }
+    }
+
+    pub fn set_remote_link_addr(&self, link_addr: MacAddress, arp_generation: u64) {
+        self.remote_link_addr.set(Some((link_addr, arp_generation)));
     }
 }
 
@@ -50,6 +67,7 @@ impl Default for Socket {
         Self {
             local: None,
             remote: None,
+            remote_link_addr: Cell::new(None),
         }
     }
 }
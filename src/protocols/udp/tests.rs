@@ -1,3 +1,335 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    collections::bytes::{Bytes, BytesMut},
+    engine::Engine,
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ethernet2::{frame::Ethernet2Header, MacAddress},
+        icmpv4::Icmpv4Type2,
+        ip, ipv4,
+        ipv4::datagram::Ipv4Header,
+        Protocol,
+    },
+    runtime::Runtime,
+    test_helpers::{self, DecodedFrame, FrameCorruption, TestEngine, TestRuntime, L4},
+};
+use futures::{
+    task::{noop_waker_ref, Context},
+    FutureExt,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    net::Ipv4Addr,
+    pin::Pin,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn decode_pushto() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+
+    let alice_port = ip::Port::try_from(54321).unwrap();
+    let bob_port = ip::Port::try_from(12345).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, alice_port);
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, bob_port);
+
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice.bind(alice_fd, alice_addr).unwrap();
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    alice.pushto(alice_fd, buf.clone(), bob_addr).unwrap();
+    alice.rt().poll_scheduler();
+
+    let frame = test_helpers::decode_frame(&alice.rt().pop_frame());
+    must_let!(let DecodedFrame::Ipv4 { header, payload: L4::Udp(udp_hdr, data) } = frame);
+    assert_eq!(header.src_addr, test_helpers::ALICE_IPV4);
+    assert_eq!(header.dst_addr, test_helpers::BOB_IPV4);
+    assert_eq!(udp_hdr.src_port(), Some(alice_port));
+    assert_eq!(udp_hdr.dest_port(), bob_port);
+    assert_eq!(data, buf);
+}
+
+#[test]
+fn keepalive() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+
+    let alice_port = ip::Port::try_from(54321).unwrap();
+    let bob_port = ip::Port::try_from(12345).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, alice_port);
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, bob_port);
+
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice.bind(alice_fd, alice_addr).unwrap();
+    alice.connect(alice_fd, bob_addr).unwrap();
+
+    let interval = Duration::from_millis(100);
+    let payload = BytesMut::from(&b"ping"[..]).freeze();
+    alice
+        .udp_keepalive(alice_fd, Some((interval, payload.clone())))
+        .unwrap();
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    for _ in 0..3 {
+        // Nothing goes out before the interval elapses...
+        now += interval / 2;
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        assert!(alice.rt().try_pop_frame().is_none());
+
+        // ...and exactly one heartbeat goes out once it does.
+        now += interval / 2;
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        let frame = test_helpers::decode_frame(&alice.rt().pop_frame());
+        must_let!(let DecodedFrame::Ipv4 { payload: L4::Udp(udp_hdr, data), .. } = frame);
+        assert_eq!(udp_hdr.dest_port(), bob_port);
+        assert_eq!(data, payload);
+        assert!(alice.rt().try_pop_frame().is_none());
+    }
+
+    // Disabling keepalive stops the heartbeats.
+    alice.udp_keepalive(alice_fd, None).unwrap();
+    now += interval * 2;
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    // A zero interval is rejected, rather than sending heartbeats back-to-back.
+    let payload = BytesMut::from(&b"ping"[..]).freeze();
+    must_let!(let Err(Fail::Invalid { .. }) = alice.udp_keepalive(alice_fd, Some((Duration::new(0, 0), payload))));
+}
+
+#[test]
+fn own_broadcast_not_looped_back() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+    let mut bob = test_helpers::new_bob2(now);
+
+    let port = ip::Port::try_from(5353).unwrap();
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice
+        .bind(
+            alice_fd,
+            ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port),
+        )
+        .unwrap();
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, ipv4::Endpoint::new(test_helpers::BOB_IPV4, port))
+        .unwrap();
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let broadcast_addr = ipv4::Endpoint::new(Ipv4Addr::BROADCAST, port);
+    alice.pushto(alice_fd, buf.clone(), broadcast_addr).unwrap();
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    let (eth_hdr, _) = Ethernet2Header::parse(frame.clone()).unwrap();
+    assert!(eth_hdr.dst_addr.is_broadcast());
+
+    // Other hosts on the segment get the datagram...
+    bob.receive(frame.clone()).unwrap();
+    assert!(bob.is_readable(bob_fd));
+
+    // ...but if it comes back to us, we don't deliver it to ourselves.
+    must_let!(let Err(Fail::Ignored { .. }) = alice.receive(frame));
+    assert!(!alice.is_readable(alice_fd));
+}
+
+#[test]
+fn bound_endpoints() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+
+    let addr1 = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(5000).unwrap());
+    let addr2 = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(5001).unwrap());
+    let fd1 = alice.socket(Protocol::Udp);
+    alice.bind(fd1, addr1).unwrap();
+    let fd2 = alice.socket(Protocol::Udp);
+    alice.bind(fd2, addr2).unwrap();
+
+    let bound = alice.udp_bound_endpoints();
+    assert_eq!(bound.len(), 2);
+    assert!(bound.contains(&addr1));
+    assert!(bound.contains(&addr2));
+
+    alice.close(fd1).unwrap();
+    assert_eq!(alice.udp_bound_endpoints(), vec![addr2]);
+}
+
+fn bob_with_udp_socket(now: Instant) -> (TestEngine, FileDescriptor, ipv4::Endpoint) {
+    let mut bob = test_helpers::new_bob2(now);
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(12345).unwrap());
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+    (bob, bob_fd, bob_addr)
+}
+
+fn alice_to_bob(bob_addr: ipv4::Endpoint, corruption: FrameCorruption) -> Bytes {
+    let alice_addr =
+        ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(54321).unwrap());
+    test_helpers::build_udp_frame(
+        (test_helpers::ALICE_MAC, alice_addr),
+        (test_helpers::BOB_MAC, bob_addr),
+        &[0x5a; 32],
+        corruption,
+    )
+}
+
+#[test]
+fn receive_built_frame() {
+    let now = Instant::now();
+    let (mut bob, bob_fd, bob_addr) = bob_with_udp_socket(now);
+
+    let frame = alice_to_bob(bob_addr, FrameCorruption::default());
+    bob.receive(frame).unwrap();
+    assert!(bob.is_readable(bob_fd));
+}
+
+#[test]
+fn reject_corrupted_frames() {
+    let now = Instant::now();
+    let (mut bob, bob_fd, bob_addr) = bob_with_udp_socket(now);
+
+    let corruptions = [
+        FrameCorruption {
+            bad_ipv4_checksum: true,
+            ..Default::default()
+        },
+        FrameCorruption {
+            bad_udp_checksum: true,
+            ..Default::default()
+        },
+        FrameCorruption {
+            bad_udp_length: true,
+            ..Default::default()
+        },
+        FrameCorruption {
+            truncate_udp_header: Some(0),
+            ..Default::default()
+        },
+        FrameCorruption {
+            truncate_udp_header: Some(7),
+            ..Default::default()
+        },
+    ];
+    for corruption in corruptions.iter() {
+        let frame = alice_to_bob(bob_addr, *corruption);
+        must_let!(let Err(Fail::Malformed { .. }) = bob.receive(frame));
+        assert!(!bob.is_readable(bob_fd));
+    }
+}
+
+#[test]
+fn connected_socket_skips_arp_lookup() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice2(now);
+
+    let alice_addr =
+        ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(54321).unwrap());
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(12345).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice.bind(alice_fd, alice_addr).unwrap();
+    alice.connect(alice_fd, bob_addr).unwrap();
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let lookups = alice.arp_lookups();
+    for _ in 0..10 {
+        alice.udp_push(alice_fd, buf.clone()).unwrap();
+        let (eth_hdr, _) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+        assert_eq!(eth_hdr.dst_addr, test_helpers::BOB_MAC);
+    }
+    assert_eq!(alice.arp_lookups(), lookups + 1);
+
+    // Bob shows up with a new NIC, which Alice learns about from his ARP request...
+    let new_bob_mac = test_helpers::CARRIE_MAC;
+    let new_bob = Engine::new(TestRuntime::new(
+        "bob",
+        now,
+        new_bob_mac,
+        test_helpers::BOB_IPV4,
+    ))
+    .unwrap();
+    let mut query = new_bob.arp_query(test_helpers::ALICE_IPV4).boxed_local();
+    assert!(Future::poll(query.as_mut(), &mut ctx).is_pending());
+    alice.receive(new_bob.rt().pop_frame()).unwrap();
+    must_let!(let DecodedFrame::Arp(_) = test_helpers::decode_frame(&alice.rt().pop_frame()));
+
+    // ...so the socket has to look his address up again.
+    alice.udp_push(alice_fd, buf).unwrap();
+    let (eth_hdr, _) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    assert_eq!(eth_hdr.dst_addr, new_bob_mac);
+    assert_eq!(alice.arp_lookups(), lookups + 2);
+}
+
+#[test]
+fn unbound_port_unreachable() {
+    let now = Instant::now();
+    let mut bob = test_helpers::new_bob2(now);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(12345).unwrap());
+    let frame = alice_to_bob(bob_addr, FrameCorruption::default());
+    must_let!(let Err(Fail::ConnectionRefused {}) = bob.receive(frame.clone()));
+    bob.rt().poll_scheduler();
+
+    must_let!(let DecodedFrame::Ipv4 { header, payload: L4::Icmp(icmpv4_hdr, data) } = test_helpers::decode_frame(&bob.rt().pop_frame()));
+    assert_eq!(header.src_addr, test_helpers::BOB_IPV4);
+    assert_eq!(header.dst_addr, test_helpers::ALICE_IPV4);
+    assert_eq!(
+        icmpv4_hdr.icmpv4_type,
+        Icmpv4Type2::DestinationUnreachable { next_hop_mtu: 0 }
+    );
+    assert_eq!(icmpv4_hdr.code, 3);
+
+    // The error quotes the datagram's IPv4 header and its UDP header.
+    let (_, datagram) = Ethernet2Header::parse(frame).unwrap();
+    let ipv4_hdr_size = Ipv4Header::parse(datagram.clone())
+        .unwrap()
+        .0
+        .compute_size();
+    assert_eq!(data.len(), ipv4_hdr_size + 8);
+    assert_eq!(&data[..], &datagram[..(ipv4_hdr_size + 8)]);
+    assert!(bob.rt().try_pop_frame().is_none());
+}
+
+#[test]
+fn pop_with_info_reports_local_address() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let (mut bob, bob_fd, bob_addr) = bob_with_udp_socket(now);
+
+    // One datagram is sent to Bob's own address, the other to the broadcast address.
+    let alice_addr =
+        ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(54321).unwrap());
+    let broadcast_addr = ipv4::Endpoint::new(Ipv4Addr::BROADCAST, bob_addr.port());
+    bob.receive(alice_to_bob(bob_addr, FrameCorruption::default()))
+        .unwrap();
+    bob.receive(test_helpers::build_udp_frame(
+        (test_helpers::ALICE_MAC, alice_addr),
+        (MacAddress::broadcast(), broadcast_addr),
+        &[0x5a; 32],
+        FrameCorruption::default(),
+    ))
+    .unwrap();
+
+    // Bob's socket isn't connected, but he can still tell them apart.
+    for &expected_addr in &[test_helpers::BOB_IPV4, Ipv4Addr::BROADCAST] {
+        let mut pop_future = bob.udp_pop_with_info(bob_fd).unwrap();
+        must_let!(let Poll::Ready(Ok((Some(remote), local_addr, data))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        assert_eq!(remote, alice_addr);
+        assert_eq!(local_addr, expected_addr);
+        assert_eq!(&data[..], &[0x5a; 32][..]);
+    }
+}
+
 // // Copyright (c) Microsoft Corporation.
 // // Licensed under the MIT license.
 
@@ -2,7 +2,7 @@
 // Licensed under the MIT license.
 use crate::{
     interop::dmtr_sgarray_t,
-    protocols::{arp, ethernet2::MacAddress, tcp, udp},
+    protocols::{arp, ethernet2::frame::serialize_frame, ethernet2::MacAddress, icmpv4, tcp, udp},
     scheduler::{Operation, Scheduler, SchedulerHandle},
 };
 use arrayvec::ArrayVec;
@@ -35,6 +35,26 @@ pub trait PacketBuf<T>: Sized {
     fn take_body(self) -> Option<T>;
 }
 
+/// A frame that's already been laid out by [Runtime::serialize], handed back to
+/// [Runtime::transmit] as is.
+struct SerializedFrame<T>(T);
+
+impl<T: RuntimeBuf> PacketBuf<T> for SerializedFrame<T> {
+    fn header_size(&self) -> usize {
+        0
+    }
+
+    fn write_header(&self, _buf: &mut [u8]) {}
+
+    fn body_size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn take_body(self) -> Option<T> {
+        Some(self.0)
+    }
+}
+
 /// Common interface that tranport layers should implement? E.g. DPDK and RDMA.
 pub trait Runtime: Clone + Unpin + 'static {
     type Buf: RuntimeBuf;
@@ -48,13 +68,43 @@ pub trait Runtime: Clone + Unpin + 'static {
 
     fn advance_clock(&self, now: Instant);
     fn transmit(&self, pkt: impl PacketBuf<Self::Buf>);
+    /// Lays `pkt` out as a complete frame, which the stack holds on to until it passes it to
+    /// `transmit_batch`. By default, short frames are padded to the Ethernet minimum.
+    fn serialize(&self, pkt: impl PacketBuf<Self::Buf>) -> Self::Buf {
+        Self::Buf::from_slice(&serialize_frame(pkt, true)[..])
+    }
+    /// Sends frames that the stack coalesced, together (e.g. in one burst to the device). The
+    /// stack only calls this when `coalesce_transmits` is on; by default each frame then goes out
+    /// through `transmit` on its own.
+    fn transmit_batch(&self, frames: Vec<Self::Buf>) {
+        if !self.coalesce_transmits() {
+            return;
+        }
+        for frame in frames {
+            self.transmit(SerializedFrame(frame));
+        }
+    }
+    /// Whether the stack should hold back everything it transmits within one LibOS poll and pass
+    /// it all to `transmit_batch` at the end, so that e.g. the ACKs for many connections go out in
+    /// a single batch.
+    fn coalesce_transmits(&self) -> bool {
+        false
+    }
     fn receive(&self) -> ArrayVec<Self::Buf, RECEIVE_BATCH_SIZE>;
 
     fn local_link_addr(&self) -> MacAddress;
     fn local_ipv4_addr(&self) -> Ipv4Addr;
+    /// The MTU of our link, if the runtime knows it. TCP derives the MSS it advertises from this.
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
     fn arp_options(&self) -> arp::Options;
     fn tcp_options(&self) -> tcp::Options<Self>;
     fn udp_options(&self) -> udp::Options;
+    /// Runtimes that don't need to tune ICMPv4 get the defaults.
+    fn icmpv4_options(&self) -> icmpv4::Options {
+        icmpv4::Options::default()
+    }
 
     fn wait(&self, duration: Duration) -> Self::WaitFuture;
     fn wait_until(&self, when: Instant) -> Self::WaitFuture;
@@ -136,6 +136,7 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
             slab: PinSlab::new(),
             pages: vec![],
             root_waker: SharedWaker::new(),
+            num_tasks: 0,
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
@@ -149,9 +150,16 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
         let (page, subpage_ix) = inner.page(key);
         assert!(!page.was_dropped(subpage_ix));
         page.clear(subpage_ix);
+        inner.num_tasks -= 1;
         inner.slab.remove_unpin(key as usize).unwrap()
     }
 
+    /// Returns the number of tasks held by the scheduler. Tasks whose handles have been dropped
+    /// count until the next [poll](Scheduler::poll) reaps them.
+    pub fn num_tasks(&self) -> usize {
+        self.inner.borrow().num_tasks
+    }
+
     /// Given the raw `key` representing this future return a proper handle.
     pub fn from_raw_handle(&self, key: u64) -> Option<SchedulerHandle> {
         let inner = self.inner.borrow();
@@ -184,12 +192,9 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
 
         // TODO rewrite this loop to use high-level iterators instead of indexes.
         // Iterate through all our pages finding the tasks that are ready to be polled again
-        // (notified) and dropped tasks which can be removed.
+        // (notified).
         for page_ix in 0..inner.pages.len() {
-            let (notified, dropped) = {
-                let page = &mut inner.pages[page_ix];
-                (page.take_notified(), page.take_dropped())
-            };
+            let notified = inner.pages[page_ix].take_notified();
             // Non-zero means at least one future in this page should be polled.
             if notified != 0 {
                 // Iterate through this page's bit vector polling the futures that are ready.
@@ -216,16 +221,8 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
                     }
                 }
             }
-            if dropped != 0 {
-                for subpage_ix in BitIter::from(dropped) {
-                    if subpage_ix != 0 {
-                        let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
-                        inner.slab.remove(ix);
-                        inner.pages[page_ix].clear(subpage_ix);
-                    }
-                }
-            }
         }
+        inner.reap_dropped();
     }
 }
 
@@ -237,6 +234,8 @@ struct Inner<F: Future<Output = ()> + Unpin> {
     /// The statuses are arranged in pages.
     pages: Vec<WakerPageRef>,
     root_waker: SharedWaker,
+    /// Number of tasks in the slab.
+    num_tasks: usize,
 }
 
 impl<F: Future<Output = ()> + Unpin> Inner<F> {
@@ -261,6 +260,31 @@ impl<F: Future<Output = ()> + Unpin> Inner<F> {
         }
         let (page, subpage_ix) = self.page(key as u64);
         page.initialize(subpage_ix);
+        self.num_tasks += 1;
         key as u64
     }
+
+    /// Removes the tasks whose handles have all been dropped. Removing a task drops everything it
+    /// owns, which may include the last handle to another task (e.g. a background task holding a
+    /// clone of the ARP peer), so we keep sweeping until a pass finds nothing to remove.
+    fn reap_dropped(&mut self) {
+        loop {
+            let mut reaped = false;
+            for page_ix in 0..self.pages.len() {
+                let dropped = self.pages[page_ix].take_dropped();
+                for subpage_ix in BitIter::from(dropped) {
+                    if subpage_ix != 0 {
+                        let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
+                        self.slab.remove(ix);
+                        self.pages[page_ix].clear(subpage_ix);
+                        self.num_tasks -= 1;
+                        reaped = true;
+                    }
+                }
+            }
+            if !reaped {
+                return;
+            }
+        }
+    }
 }
@@ -5,13 +5,29 @@ use crate::interop::{dmtr_sgarray_t, dmtr_sgaseg_t};
 use crate::{
     collections::bytes::{Bytes, BytesMut},
     engine::Engine,
-    protocols::{arp, ethernet2::MacAddress, tcp, udp},
-    runtime::{PacketBuf, Runtime, RECEIVE_BATCH_SIZE},
+    file_table::FileDescriptor,
+    protocols::{
+        arp::{self, ArpPdu},
+        ethernet2::{
+            frame::{serialize_frame, EtherType2, Ethernet2Header},
+            MacAddress,
+        },
+        icmpv4::{self, Icmpv4Header},
+        ipv4::{
+            self,
+            datagram::{Ipv4Header, Ipv4Protocol2},
+        },
+        tcp::{self, segment::TcpHeader},
+        udp::{self, UdpHeader},
+    },
+    runtime::{PacketBuf, Runtime, RuntimeBuf, RECEIVE_BATCH_SIZE},
     scheduler::{Operation, Scheduler, SchedulerHandle},
     timer::{Timer, TimerRc},
 };
 use arrayvec::ArrayVec;
-use futures::FutureExt;
+use byteorder::{ByteOrder, NetworkEndian};
+use futures::{task::noop_waker_ref, FutureExt};
+use must_let::must_let;
 use rand::{
     distributions::{Distribution, Standard},
     rngs::SmallRng,
@@ -27,7 +43,9 @@ use std::{
     collections::VecDeque,
     future::Future,
     net::Ipv4Addr,
+    pin::Pin,
     rc::Rc,
+    task::{Context, Poll},
     time::{Duration, Instant},
 };
 
@@ -65,6 +83,8 @@ impl TestRuntime {
         let tcp_options = tcp::Options::<Self>::default();
         let tcp_options = tcp_options.advertised_mss(2048);
         let tcp_options = tcp_options.window_scale(2);
+        // Most tests push a few small buffers in a row and expect each to go straight out.
+        let tcp_options = tcp_options.nodelay(true);
 
         let inner = Inner {
             name,
@@ -72,10 +92,15 @@ impl TestRuntime {
             rng: SmallRng::from_seed([0; 32]),
             incoming: VecDeque::new(),
             outgoing: VecDeque::new(),
+            pad_frames: true,
+            coalesce_transmits: false,
+            transmit_batches: vec![],
             link_addr,
             ipv4_addr,
+            mtu: None,
             tcp_options,
             arp_options,
+            icmpv4_options: icmpv4::Options::default(),
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
@@ -87,6 +112,46 @@ impl TestRuntime {
         self.inner.borrow_mut().outgoing.pop_front().unwrap()
     }
 
+    pub fn try_pop_frame(&self) -> Option<Bytes> {
+        self.inner.borrow_mut().outgoing.pop_front()
+    }
+
+    /// Replaces the TCP options, which take effect for connections established from now on.
+    pub fn set_tcp_options(&self, tcp_options: tcp::Options<TestRuntime>) {
+        self.inner.borrow_mut().tcp_options = tcp_options;
+    }
+
+    /// Sets the link MTU the runtime reports, which it doesn't by default.
+    pub fn set_mtu(&self, mtu: usize) {
+        self.inner.borrow_mut().mtu = Some(mtu);
+    }
+
+    /// Replaces the ARP options, which are read when an engine is created.
+    pub fn set_arp_options(&self, arp_options: arp::Options) {
+        self.inner.borrow_mut().arp_options = arp_options;
+    }
+
+    /// Replaces the ICMPv4 options, which are read when an engine is created.
+    pub fn set_icmpv4_options(&self, icmpv4_options: icmpv4::Options) {
+        self.inner.borrow_mut().icmpv4_options = icmpv4_options;
+    }
+
+    /// Sets whether we pad frames shorter than the Ethernet minimum, which we do by default.
+    pub fn set_pad_frames(&self, pad: bool) {
+        self.inner.borrow_mut().pad_frames = pad;
+    }
+
+    /// Has the stack hold the frames it transmits within a LibOS poll back, and pass them to
+    /// `transmit_batch` together at the end of it.
+    pub fn set_coalesce_transmits(&self, coalesce: bool) {
+        self.inner.borrow_mut().coalesce_transmits = coalesce;
+    }
+
+    /// Returns the number of frames in each batch transmitted so far.
+    pub fn transmit_batches(&self) -> Vec<usize> {
+        self.inner.borrow().transmit_batches.clone()
+    }
+
     pub fn push_frame(&self, buf: Bytes) {
         self.inner.borrow_mut().incoming.push_back(buf);
     }
@@ -97,6 +162,117 @@ impl TestRuntime {
     }
 }
 
+/// A frame transmitted by a [TestRuntime], parsed so tests can `must_let!` on its contents.
+#[derive(Debug)]
+pub enum DecodedFrame {
+    Arp(ArpPdu),
+    Ipv4 { header: Ipv4Header, payload: L4 },
+}
+
+#[derive(Debug)]
+pub enum L4 {
+    Tcp(TcpHeader),
+    Udp(UdpHeader, Bytes),
+    Icmp(Icmpv4Header, Bytes),
+}
+
+/// Parses a raw Ethernet frame, panicking if any of its headers are malformed.
+pub fn decode_frame(bytes: &[u8]) -> DecodedFrame {
+    let (eth_hdr, payload) = Ethernet2Header::parse(Bytes::from_slice(bytes)).unwrap();
+    match eth_hdr.ether_type {
+        EtherType2::Arp => DecodedFrame::Arp(ArpPdu::parse(payload).unwrap()),
+        EtherType2::Ipv4 => {
+            let (header, payload) = Ipv4Header::parse(payload).unwrap();
+            let payload = match header.protocol {
+                Ipv4Protocol2::Tcp => {
+                    let (tcp_hdr, _) = TcpHeader::parse(&header, payload, false).unwrap();
+                    L4::Tcp(tcp_hdr)
+                }
+                Ipv4Protocol2::Udp => {
+                    let (udp_hdr, data) = UdpHeader::parse(&header, payload, false).unwrap();
+                    L4::Udp(udp_hdr, data)
+                }
+                Ipv4Protocol2::Icmpv4 => {
+                    let (icmpv4_hdr, data) = Icmpv4Header::parse(payload).unwrap();
+                    L4::Icmp(icmpv4_hdr, data)
+                }
+            };
+            DecodedFrame::Ipv4 { header, payload }
+        }
+    }
+}
+
+/// Ways [build_udp_frame] can damage the frame it builds, for exercising receive-side validation.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameCorruption {
+    pub bad_ipv4_checksum: bool,
+    pub bad_udp_checksum: bool,
+    pub bad_udp_length: bool,
+    /// Cut the frame off this many bytes into the UDP header, fixing up the IPv4 total length so
+    /// that the frame gets as far as UDP parsing.
+    pub truncate_udp_header: Option<usize>,
+}
+
+/// Builds a complete Ethernet/IPv4/UDP frame to feed straight into `Engine::receive`.
+pub fn build_udp_frame(
+    src: (MacAddress, ipv4::Endpoint),
+    dst: (MacAddress, ipv4::Endpoint),
+    payload: &[u8],
+    corruption: FrameCorruption,
+) -> Bytes {
+    let (src_link_addr, src_endpoint) = src;
+    let (dst_link_addr, dst_endpoint) = dst;
+    let eth_hdr = Ethernet2Header::new(dst_link_addr, src_link_addr, EtherType2::Ipv4);
+    let ipv4_hdr = Ipv4Header::new(
+        src_endpoint.address(),
+        dst_endpoint.address(),
+        Ipv4Protocol2::Udp,
+    );
+    let udp_hdr = UdpHeader::new(Some(src_endpoint.port()), dst_endpoint.port());
+
+    let eth_hdr_size = eth_hdr.compute_size();
+    let ipv4_hdr_size = ipv4_hdr.compute_size();
+    let udp_start = eth_hdr_size + ipv4_hdr_size;
+    let udp_hdr_size = udp_hdr.size();
+    let mut buf = vec![0u8; udp_start + udp_hdr_size + payload.len()];
+
+    eth_hdr.serialize(&mut buf[..eth_hdr_size]);
+    udp_hdr.serialize(
+        &mut buf[udp_start..(udp_start + udp_hdr_size)],
+        &ipv4_hdr,
+        payload,
+        false,
+    );
+    buf[(udp_start + udp_hdr_size)..].copy_from_slice(payload);
+
+    if corruption.bad_udp_checksum {
+        let checksum = &mut buf[(udp_start + 6)..(udp_start + 8)];
+        // A zero checksum means "not computed", so make sure we don't end up there.
+        let bad_checksum = match !NetworkEndian::read_u16(checksum) {
+            0 => 1,
+            c => c,
+        };
+        NetworkEndian::write_u16(checksum, bad_checksum);
+    }
+    if corruption.bad_udp_length {
+        let length = &mut buf[(udp_start + 4)..(udp_start + 6)];
+        let bad_length = NetworkEndian::read_u16(length) + 1;
+        NetworkEndian::write_u16(length, bad_length);
+    }
+    if let Some(len) = corruption.truncate_udp_header {
+        assert!(len < udp_hdr_size);
+        buf.truncate(udp_start + len);
+    }
+
+    let ipv4_payload_len = buf.len() - udp_start;
+    ipv4_hdr.serialize(&mut buf[eth_hdr_size..udp_start], ipv4_payload_len);
+    if corruption.bad_ipv4_checksum {
+        buf[eth_hdr_size + 10] ^= 0xff;
+    }
+
+    Bytes::from_slice(&buf)
+}
+
 struct Inner {
     #[allow(unused)]
     name: &'static str,
@@ -105,10 +281,19 @@ struct Inner {
     incoming: VecDeque<Bytes>,
     outgoing: VecDeque<Bytes>,
 
+    /// Pad short frames to the Ethernet minimum, as opposed to leaving it to the NIC?
+    pad_frames: bool,
+    /// Have the stack coalesce the frames it transmits within each LibOS poll?
+    coalesce_transmits: bool,
+    /// Sizes of the batches that made it to `outgoing`, oldest first.
+    transmit_batches: Vec<usize>,
+
     link_addr: MacAddress,
     ipv4_addr: Ipv4Addr,
+    mtu: Option<usize>,
     tcp_options: tcp::Options<TestRuntime>,
     arp_options: arp::Options,
+    icmpv4_options: icmpv4::Options,
 }
 
 impl Runtime for TestRuntime {
@@ -176,15 +361,23 @@ impl Runtime for TestRuntime {
     }
 
     fn transmit(&self, pkt: impl PacketBuf<Bytes>) {
-        let header_size = pkt.header_size();
-        let body_size = pkt.body_size();
+        let buf = self.serialize(pkt);
+        self.transmit_batch(vec![buf]);
+    }
 
-        let mut buf = BytesMut::zeroed(header_size + body_size);
-        pkt.write_header(&mut buf[..header_size]);
-        if let Some(body) = pkt.take_body() {
-            buf[header_size..].copy_from_slice(&body[..]);
-        }
-        self.inner.borrow_mut().outgoing.push_back(buf.freeze());
+    fn serialize(&self, pkt: impl PacketBuf<Bytes>) -> Bytes {
+        let pad = self.inner.borrow().pad_frames;
+        serialize_frame(pkt, pad).freeze()
+    }
+
+    fn transmit_batch(&self, frames: Vec<Bytes>) {
+        let mut inner = self.inner.borrow_mut();
+        inner.transmit_batches.push(frames.len());
+        inner.outgoing.extend(frames);
+    }
+
+    fn coalesce_transmits(&self) -> bool {
+        self.inner.borrow().coalesce_transmits
     }
 
     fn receive(&self) -> ArrayVec<Bytes, RECEIVE_BATCH_SIZE> {
@@ -207,6 +400,10 @@ impl Runtime for TestRuntime {
         self.inner.borrow().ipv4_addr
     }
 
+    fn mtu(&self) -> Option<usize> {
+        self.inner.borrow().mtu
+    }
+
     fn tcp_options(&self) -> tcp::Options<TestRuntime> {
         self.inner.borrow().tcp_options.clone()
     }
@@ -215,6 +412,10 @@ impl Runtime for TestRuntime {
         udp::Options::default()
     }
 
+    fn icmpv4_options(&self) -> icmpv4::Options {
+        self.inner.borrow().icmpv4_options.clone()
+    }
+
     fn arp_options(&self) -> arp::Options {
         self.inner.borrow().arp_options.clone()
     }
@@ -281,12 +482,24 @@ pub fn new_alice2(now: Instant) -> Engine<TestRuntime> {
 }
 
 pub fn new_bob2(now: Instant) -> Engine<TestRuntime> {
+    Engine::new(new_bob_runtime(now)).unwrap()
+}
+
+/// Bob's runtime with Alice in its ARP cache, for tests that build something other than an
+/// [Engine] on top of it.
+pub fn new_bob_runtime(now: Instant) -> TestRuntime {
     let rt = TestRuntime::new("bob", now, BOB_MAC, BOB_IPV4);
     {
         let arp_options: &mut _ = &mut rt.inner.borrow_mut().arp_options;
         arp_options.initial_values.insert(BOB_IPV4, BOB_MAC);
         arp_options.initial_values.insert(ALICE_IPV4, ALICE_MAC);
     }
+    rt
+}
+
+pub fn new_alice_with_arp_options(now: Instant, arp_options: arp::Options) -> Engine<TestRuntime> {
+    let rt = TestRuntime::new("alice", now, ALICE_MAC, ALICE_IPV4);
+    rt.inner.borrow_mut().arp_options = arp_options;
     Engine::new(rt).unwrap()
 }
 
@@ -294,3 +507,45 @@ pub fn new_carrie(now: Instant) -> Engine<TestRuntime> {
     let rt = TestRuntime::new("carrie", now, CARRIE_MAC, CARRIE_IPV4);
     Engine::new(rt).unwrap()
 }
+
+/// Has Bob listen on `listen_addr` and Alice connect to it, passing the handshake segments
+/// between them. Returns Alice's and Bob's file descriptors for the new connection.
+pub fn handshake(
+    alice: &mut TestEngine,
+    bob: &mut TestEngine,
+    listen_addr: ipv4::Endpoint,
+) -> (FileDescriptor, FileDescriptor) {
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    let alice_fd = alice.tcp_socket();
+    let bob_fd = connect(alice, alice_fd, bob, listen_fd, listen_addr);
+    (alice_fd, bob_fd)
+}
+
+/// Connects Alice's socket `alice_fd` to Bob's listening socket `listen_fd`, which is bound to
+/// `listen_addr`, passing the handshake segments between them. Returns Bob's file descriptor for
+/// the new connection.
+pub fn connect(
+    alice: &mut TestEngine,
+    alice_fd: FileDescriptor,
+    bob: &mut TestEngine,
+    listen_fd: FileDescriptor,
+    listen_addr: ipv4::Endpoint,
+) -> FileDescriptor {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    bob_fd
+}
@@ -7,7 +7,7 @@ use catnip::{
     collections::bytes::{Bytes, BytesMut},
     interop::dmtr_sgarray_t,
     interop::dmtr_sgaseg_t,
-    protocols::ethernet2::MacAddress,
+    protocols::ethernet2::{frame::serialize_frame, MacAddress},
     protocols::{arp, tcp, udp},
     runtime::Runtime,
     runtime::{PacketBuf, RECEIVE_BATCH_SIZE},
@@ -167,14 +167,7 @@ impl Runtime for DummyRuntime {
     }
 
     fn transmit(&self, pkt: impl PacketBuf<Bytes>) {
-        let header_size = pkt.header_size();
-        let body_size = pkt.body_size();
-
-        let mut buf = BytesMut::zeroed(header_size + body_size);
-        pkt.write_header(&mut buf[..header_size]);
-        if let Some(body) = pkt.take_body() {
-            buf[header_size..].copy_from_slice(&body[..]);
-        }
+        let buf = serialize_frame(pkt, true);
         self.inner
             .borrow_mut()
             .outgoing